@@ -4,7 +4,14 @@ bits 64
 global apic_timer
 global syscall_handler
 global restore_context_wrapper
+global page_fault_trap
+global nmi_trap
+global irq_stub_table
 extern handle_context_switch
+extern handle_nmi
+
+IRQ_VECTOR_BASE equ 0x40
+IRQ_VECTOR_COUNT equ 64
 
 apic_timer:
     push r15
@@ -14,9 +21,31 @@ syscall_handler:
     push r15
     mov r15, 0x80
     jmp save_context
+page_fault_trap:
+    ; Store r15 over the error code so the stack matches the TrapFrame
+    xchg r15, [rsp]
+    mov r15, 0xE
+    jmp save_context
+
+; One 16 byte stub per dynamically allocated vector, the cause is the vector
+align 16
+irq_stub_table:
+%assign vector IRQ_VECTOR_BASE
+%rep IRQ_VECTOR_COUNT
+    align 16
+    push r15
+    mov r15, vector
+    jmp save_context
+%assign vector vector + 1
+%endrep
 
 
 save_context:
+    ; Switch to the kernel GS base if we came from user mode (CS.RPL = 3)
+    test qword [rsp + 16], 0x3
+    jz .kernel_entry
+    swapgs
+.kernel_entry:
     push r14
     push r13
     push r12
@@ -52,8 +81,50 @@ restore_context:
     pop r13
     pop r14
     pop r15
+    ; Switch back to the user GS base if we return to user mode
+    test qword [rsp + 8], 0x3
+    jz .kernel_return
+    swapgs
+.kernel_return:
     iretq
 
 restore_context_wrapper:
     add rsp, 8
-    jmp restore_context
\ No newline at end of file
+    jmp restore_context
+
+; Saves the registers as a TrapFrame like save_context, but leaves GS alone:
+; an NMI may arrive between a SWAPGS and the IRETQ that goes with it
+nmi_trap:
+    push r15
+    push r14
+    push r13
+    push r12
+    push r11
+    push r10
+    push r9
+    push r8
+    push rbp
+    push rdi
+    push rsi
+    push rbx
+    push rdx
+    push rcx
+    push rax
+    mov rdi, rsp
+    call handle_nmi
+    pop rax
+    pop rcx
+    pop rdx
+    pop rbx
+    pop rsi
+    pop rdi
+    pop rbp
+    pop r8
+    pop r9
+    pop r10
+    pop r11
+    pop r12
+    pop r13
+    pop r14
+    pop r15
+    iretq
\ No newline at end of file
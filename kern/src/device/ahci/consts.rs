@@ -30,6 +30,16 @@ pub const PxCMD_FIS_Running: u32 = 0x1 << 14;
 pub const PxCMD_CMD_Running: u32 = 0x1 << 15;
 pub const PxCMD_ICC_ACTIVE: u32 = 0x1 << 28;
 
+// AHCI Port IS/IE Bits
+/// Port Connect Change, follows PxSERR_DIAG_X
+pub const PxIS_PCS: u32 = 0x1 << 6;
+/// PhyRdy Change, follows PxSERR_DIAG_N
+pub const PxIS_PRCS: u32 = 0x1 << 22;
+
+// AHCI Port SERR Bits
+pub const PxSERR_DIAG_N: u32 = 0x1 << 16;
+pub const PxSERR_DIAG_X: u32 = 0x1 << 26;
+
 // AHCI Port SSTS
 pub const PxSSTS_DETMask: u32 = 0xf;
 pub const PxSSTS_DET_Ready: u32 = 3;
@@ -14,7 +14,8 @@ use super::consts::*;
 use core::cmp::min;
 use crate::device::ahci::device::{AHCISATADevice, AHCIDevice};
 use alloc::vec::Vec;
-use spin::{Mutex, RwLock};
+use spin::RwLock;
+use crate::sync::Mutex;
 use alloc::sync::Arc;
 use pc_keyboard::KeyCode::Mute;
 use core::ops::{Deref, DerefMut};
@@ -94,6 +95,11 @@ pub(super) struct AHCIHBAPort {
 const_assert_size!(AHCIHBAPort, 0x80);
 
 impl AHCIHBAPort {
+    /// A device is present and communicating
+    pub fn link_up(&self) -> bool {
+        self.SSTS.read() & PxSSTS_DETMask == PxSSTS_DET_Ready
+    }
+
     pub fn find_free_command_slot(&self) -> Option<u8> {
         let val = self.CI.read();
         for i in 0..32u8 {
@@ -299,6 +305,31 @@ impl AHCIController {
         let tmp = regs.generic_control.GHC.read();
         regs.generic_control.GHC.write(tmp | GHC_InterruptEnable);
 
+        // Completions are still polled, the handler acknowledges the HBA and
+        // passes on connection changes
+        let hba_is = &regs.generic_control.IS as *const Volatile<u32> as u64;
+        let hba_ports = &regs.ports as *const [AHCIHBAPort; 32] as u64;
+        let msi = self.dev.enable_msi(1, crate::sys::percpu::apic_id(), move |_, _| {
+            let is = unsafe { &mut *(hba_is as *mut Volatile<u32>) };
+            let ports = unsafe { &mut *(hba_ports as *mut [AHCIHBAPort; 32]) };
+            let pending = is.read();
+            for (n, port) in ports.iter_mut().enumerate() {
+                if pending >> n & 0x1 == 0 {
+                    continue;
+                }
+                if port.IS.read() & (PxIS_PCS | PxIS_PRCS) != 0 {
+                    // Both are cleared through the error register
+                    port.SERR.write(PxSERR_DIAG_N | PxSERR_DIAG_X);
+                    crate::storage::block::presence_changed();
+                }
+            }
+            is.write(pending);
+            trace!("[AHCI] Interrupt, ports: {:#b}", pending);
+        });
+        if let Err(e) = msi {
+            warn!("[AHCI] Unable to enable MSI: {:?}", e);
+        }
+
         let word = self.dev.read_config_word(crate::device::pci::consts::CONF_COMMAND_OFFSET);
         self.dev.write_config_word(crate::device::pci::consts::CONF_COMMAND_OFFSET,
                                    word | crate::device::pci::consts::PCI_COMMAND_MASTER);
@@ -393,6 +424,8 @@ impl AHCIController {
             port_reg.IS.write(tmp);
         }
         regs.generic_control.IS.write(0x1 << port);
+        // Interrupt when the device goes away
+        port_reg.IE.write(PxIS_PCS | PxIS_PRCS);
 
         let tmp = port_reg.SSTS.read();
         trace!("[AHCI] Port {} status: {:#x}", port, tmp);
@@ -2,7 +2,7 @@ use alloc::boxed::Box;
 use core::fmt::{Debug, Formatter};
 use crate::storage::block::device::BlockDevice;
 use crate::device::ahci::{AHCI, G_AHCI};
-use spin::Mutex;
+use crate::sync::Mutex;
 use crate::device::ahci::controller::{AHCIHBAPort, AHCIController};
 use x86_64::registers::rflags::read;
 use alloc::sync::Arc;
@@ -89,6 +89,8 @@ impl BlockDevice for AHCIBlockDevice {
         let mut port_comm = self.dev.comm_struct.lock();
         let mut alt_buffer = [0u16; 256];
         if AHCIController::read_sector(port_comm.deref_mut(), sector, &mut alt_buffer).is_err() {
+            // Maybe unplugged without an interrupt
+            crate::storage::block::presence_changed();
             return Err(ErrorKind::Other.into())
         }
         let alt_buffer: [u8; 512] = unsafe { core::mem::transmute(alt_buffer) };
@@ -98,6 +100,14 @@ impl BlockDevice for AHCIBlockDevice {
     }
 
     fn write_sector(&self, _sector: u64, _buf: &[u8]) -> core_io::Result<usize> {
-        panic!("Unsupported");
+        Err(ErrorKind::PermissionDenied.into())
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+
+    fn is_present(&self) -> bool {
+        self.dev.comm_struct.lock().port_reg.link_up()
     }
 }
\ No newline at end of file
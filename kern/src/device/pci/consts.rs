@@ -4,6 +4,7 @@ pub const CONF_HEADER_TYPE_OFFSET: u8 = 0x0e;
 pub const CONF_SECONDARY_BUS_OFFSET: u8 = 0x18;
 
 pub const PCI_COMMAND_MASTER: u16 = 1 << 2;
+pub const PCI_COMMAND_INTX_DISABLE: u16 = 1 << 10;
 
 
 // Vendor IDs
@@ -155,9 +155,9 @@ impl PCIDevice {
     pub fn write_config_word(&mut self, offset: u8, val: u16) {
         let old = self.read_config_dword(offset);
         let new = if offset % 4 >= 2 {
-            (old & 0x00FF) | ((val as u32) << 16)
+            (old & 0x0000_FFFF) | ((val as u32) << 16)
         } else {
-            (old & 0xFF00) | val as u32
+            (old & 0xFFFF_0000) | val as u32
         };
         self.write_config_dword(offset, new);
     }
@@ -2,6 +2,7 @@ use self::device::PCIDevice;
 use self::class::{PCIDeviceClass, HeaderType, PCISerialBusControllerClass, PCISerialBusUSB};
 use alloc::vec::Vec;
 use alloc::alloc::handle_alloc_error;
+use core::sync::atomic::{AtomicBool, Ordering};
 use spin::Mutex;
 use crate::device::ahci::{AHCI, G_AHCI};
 use crate::device::usb::G_USB;
@@ -10,12 +11,27 @@ use crate::device::uart::serial16650::Serial16650;
 pub mod device;
 pub mod class;
 pub mod consts;
+pub mod msi;
 
 pub static GLOBAL_PCI: Mutex<PCIController> = Mutex::new(PCIController {
     devices: None,
     max_bus_num: 0
 });
 
+/// PCI-to-ISA bridges whose PIRQ router delivers INTx on the ISA IRQ
+/// written to the interrupt line register: PIIX3 and PIIX4
+const PIRQ_TO_ISA_BRIDGES: [(u16, u16); 2] = [(0x8086, 0x7000), (0x8086, 0x7110)];
+
+/// Set by the bus scan if the interrupt line register can be trusted
+static INTX_ON_ISA_LINE: AtomicBool = AtomicBool::new(false);
+
+/// Whether INTx of PCI devices arrives at the ISA IRQ in their interrupt
+/// line register. Elsewhere, e.g. on ICH9 (q35) where INTx goes to GSIs
+/// 16-23, only ACPI `_PRT` knows the routing and it is not evaluated.
+pub fn intx_on_isa_line() -> bool {
+    INTX_ON_ISA_LINE.load(Ordering::Acquire)
+}
+
 pub struct PCIController {
     devices: Option<Vec<PCIDevice>>,
     max_bus_num: u8,
@@ -27,6 +43,10 @@ pub enum PCIError {
     FuncNumber,
     RegisterNumber,
     InvalidDevice,
+    /// Device has no MSI or MSI-X capability
+    NoCapability,
+    /// No free interrupt vectors
+    NoVectors,
 }
 
 #[derive(Debug, Clone)]
@@ -177,6 +197,10 @@ impl PCIController {
         let mut bus = Vec::<PCIDevice>::with_capacity(16);
         self.max_bus_num = 0;
         self.enumerate_bus(0, &mut bus);
+        let pirq_to_isa = bus.iter().any(|dev| {
+            PIRQ_TO_ISA_BRIDGES.contains(&(dev.info.vendor_id, dev.info.device_id))
+        });
+        INTX_ON_ISA_LINE.store(pirq_to_isa, Ordering::Release);
         self.devices = Some(bus);
     }
 
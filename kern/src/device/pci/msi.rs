@@ -0,0 +1,178 @@
+//! Message Signaled Interrupts
+//!
+//! Devices with an MSI or MSI-X capability write their interrupts straight
+//! to a local APIC, so they do not depend on pin routing. MSI-X is preferred
+//! when a device has both.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use volatile::Volatile;
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use super::{PCICapabilityID, PCIError};
+use super::device::PCIDevice;
+use crate::interrupts::irq::{self, InterruptContext};
+use crate::memory::frame_allocator::FrameAllocWrapper;
+use crate::memory::mmio_bump_allocator::VMALLOC;
+use crate::PAGE_TABLE;
+
+/// Interrupts are written to this address with the destination APIC ID in
+/// bits 12-19
+const MSI_ADDRESS_BASE: u32 = 0xFEE0_0000;
+
+const MSI_CTRL_ENABLE: u16 = 1 << 0;
+const MSI_CTRL_64BIT: u16 = 1 << 7;
+
+const MSIX_CTRL_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_CTRL_ENABLE: u16 = 1 << 15;
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_VECTOR_MASKED: u32 = 1 << 0;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MsiKind {
+    Msi,
+    MsiX,
+}
+
+/// Vectors assigned to a device by `enable_msi`
+#[derive(Debug, Copy, Clone)]
+pub struct MsiVectors {
+    pub kind: MsiKind,
+    pub first: u8,
+    pub count: usize,
+}
+
+fn message_address(apic_id: u8) -> u32 {
+    MSI_ADDRESS_BASE | (apic_id as u32) << 12
+}
+
+impl PCIDevice {
+    fn capability_addr(&self, id: PCICapabilityID) -> Option<u8> {
+        self.info.capabilities.iter()
+            .find(|c| core::mem::discriminant(&c.id) == core::mem::discriminant(&id))
+            .map(|c| c.addr)
+    }
+
+    /// Allocates up to `count` vectors delivered to the core with local APIC
+    /// ID `apic_id`, programs the device to use them and registers `handler`
+    /// for each of them. The handler is passed the message number.
+    /// The vectors show up in `lsirq` under the device's bus location.
+    ///
+    /// Plain MSI only supports a power of two number of vectors, fewer may
+    /// be assigned than requested. Returns `PCIError::NoCapability` if the
+    /// device supports neither MSI nor MSI-X.
+    pub fn enable_msi<F>(&mut self, count: usize, apic_id: u8, handler: F) -> Result<MsiVectors, PCIError>
+        where F: Fn(usize, &mut InterruptContext) + Send + Sync + 'static
+    {
+        let vectors = if let Some(cap) = self.capability_addr(PCICapabilityID::MSIX) {
+            self.program_msix(cap, count, apic_id)?
+        } else if let Some(cap) = self.capability_addr(PCICapabilityID::MSI) {
+            self.program_msi(cap, count, apic_id)?
+        } else {
+            return Err(PCIError::NoCapability);
+        };
+
+        let handler = Arc::new(handler);
+        for n in 0..vectors.count {
+            let handler = handler.clone();
+            irq::register_irq_handler(vectors.first + n as u8, Box::new(move |ctx| handler(n, ctx)))
+                .expect("MSI vector not allocated");
+        }
+
+        // Pin based interrupts are replaced, not shared
+        let cmd = self.read_config_word(super::consts::CONF_COMMAND_OFFSET);
+        self.write_config_word(super::consts::CONF_COMMAND_OFFSET, cmd | super::consts::PCI_COMMAND_INTX_DISABLE);
+        debug!("[PCI] {} uses {:?} vectors {:#x}..{:#x}", self.bus_location_str(), vectors.kind,
+               vectors.first, vectors.first as usize + vectors.count);
+        Ok(vectors)
+    }
+
+    fn program_msi(&mut self, cap: u8, count: usize, apic_id: u8) -> Result<MsiVectors, PCIError> {
+        let ctrl = self.read_config_word(cap + 2);
+        let capable = 1usize << ((ctrl >> 1) & 0b111);
+        let count = core::cmp::min(count.max(1).next_power_of_two(), capable);
+        let name = format!("pci {} msi", self.bus_location_str());
+        let first = irq::allocate_vectors(count, &name).ok_or(PCIError::NoVectors)?;
+
+        self.write_config_dword(cap + 4, message_address(apic_id));
+        let data_offset = if ctrl & MSI_CTRL_64BIT != 0 {
+            self.write_config_dword(cap + 8, 0);
+            cap + 0xC
+        } else {
+            cap + 8
+        };
+        // Fixed delivery, edge triggered, the device ORs in the message number
+        self.write_config_word(data_offset, first as u16);
+
+        let mme = (count.trailing_zeros() as u16) << 4;
+        self.write_config_word(cap + 2, (ctrl & !(0b111 << 4)) | mme | MSI_CTRL_ENABLE);
+        Ok(MsiVectors { kind: MsiKind::Msi, first, count })
+    }
+
+    fn program_msix(&mut self, cap: u8, count: usize, apic_id: u8) -> Result<MsiVectors, PCIError> {
+        let ctrl = self.read_config_word(cap + 2);
+        let table_size = (ctrl & 0x7FF) as usize + 1;
+        let count = core::cmp::min(count.max(1), table_size);
+        let name = format!("pci {} msi-x", self.bus_location_str());
+        // Vectors come in aligned powers of two, the rest of the block is
+        // handed back
+        let allocated = count.next_power_of_two();
+        let first = irq::allocate_vectors(allocated, &name).ok_or(PCIError::NoVectors)?;
+        if allocated > count {
+            irq::free_vectors(first + count as u8, allocated - count);
+        }
+
+        let table = self.read_config_dword(cap + 4);
+        let bir = (table & 0b111) as u8;
+        let offset = (table & !0b111) as u64;
+        let bar = match self.base_mmio_address(bir) {
+            Some(bar) => bar,
+            None => {
+                irq::free_vectors(first, count);
+                return Err(PCIError::InvalidDevice);
+            }
+        };
+        let table_va = map_msix_table(bar.as_u64() + offset, table_size as u64 * MSIX_ENTRY_SIZE);
+
+        // Keep the function masked while its table is being written
+        self.write_config_word(cap + 2, ctrl | MSIX_CTRL_ENABLE | MSIX_CTRL_FUNCTION_MASK);
+        for n in 0..table_size {
+            let entry = (table_va.as_u64() + n as u64 * MSIX_ENTRY_SIZE) as *mut Volatile<u32>;
+            unsafe {
+                if n < count {
+                    (*entry.add(0)).write(message_address(apic_id));
+                    (*entry.add(1)).write(0);
+                    (*entry.add(2)).write((first + n as u8) as u32);
+                    (*entry.add(3)).write(0);
+                } else {
+                    (*entry.add(3)).write(MSIX_VECTOR_MASKED);
+                }
+            }
+        }
+        self.write_config_word(cap + 2, (ctrl | MSIX_CTRL_ENABLE) & !MSIX_CTRL_FUNCTION_MASK);
+        Ok(MsiVectors { kind: MsiKind::MsiX, first, count })
+    }
+}
+
+/// Maps the MSI-X table, which lives in one of the device's memory BARs
+fn map_msix_table(pa: u64, size: u64) -> VirtAddr {
+    let base_offset = pa & (4096 - 1);
+    let alloc_base = pa - base_offset;
+    let va_root = without_interrupts(|| {
+        let (va, size) = VMALLOC.lock().allocate((base_offset + size) as usize);
+        let mut fallocw = FrameAllocWrapper {};
+        for offset in (0..size as u64).step_by(4096) {
+            unsafe {
+                PAGE_TABLE.write().map_to(
+                    Page::<Size4KiB>::from_start_address(va + offset).expect("va_align"),
+                    PhysFrame::<Size4KiB>::containing_address(x86_64::PhysAddr::new(alloc_base + offset)),
+                    PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::PRESENT,
+                    &mut fallocw,
+                ).expect("mapped").flush();
+            }
+        }
+        va
+    });
+    va_root + base_offset
+}
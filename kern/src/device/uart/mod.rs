@@ -1,15 +1,18 @@
-use spin::{RwLock, Mutex};
+use spin::Mutex;
+use crate::sync::SpinRwLock;
 use alloc::vec::Vec;
 use alloc::boxed::Box;
+use alloc::string::String;
 use alloc::sync::Arc;
 use hashbrown::HashMap;
 use core::sync::atomic::{AtomicU64, Ordering, AtomicBool};
 use crate::device::uart::serial16650::Serial16650;
+use crate::fs::devfs::{self, Device};
 
 pub mod serial16650;
 
 lazy_static! {
-    pub static ref SERIAL_PORTS: RwLock<SerialPorts> = RwLock::new(SerialPorts::new());
+    pub static ref SERIAL_PORTS: SpinRwLock<SerialPorts> = SpinRwLock::new("SERIAL_PORTS", SerialPorts::new());
 }
 
 pub static HAS_SERIAL: AtomicBool = AtomicBool::new(false);
@@ -34,12 +37,20 @@ impl SerialPorts {
         }
     }
 
+    /// Device file name of port `id`. The early port has id 0 and is
+    /// replaced by the first registered port, both are `ttyS0`.
+    pub fn tty_name(id: u64) -> String {
+        format!("ttyS{}", id.saturating_sub(1))
+    }
+
     pub fn register_port(&mut self, port: Arc<Mutex<dyn UART + Send + Sync>>) -> u64 {
         let id = self.next_id.fetch_add(1, Ordering::AcqRel);
+        // Replaces the early port
         if id == 1 {
-            self.ports.clear();
+            self.unregister_port(0);
         }
         writeln!(port.lock(), "=============== PORT REGISTERED ======================").unwrap();
+        devfs::add(&Self::tty_name(id), Device::Serial(port.clone()));
         self.ports.insert(id, port);
         HAS_SERIAL.store(true, Ordering::Relaxed);
         id
@@ -50,9 +61,20 @@ impl SerialPorts {
         if port.verify() {
             debug!("[Early Serial] Valid Serial Found");
             port.set_baudrate(115200);
-            self.ports.insert(0, Arc::new(Mutex::new(port)));
+            let port = Arc::new(Mutex::new(port));
+            devfs::add(&Self::tty_name(0), Device::Serial(port.clone()));
+            self.ports.insert(0, port);
             return true
         }
         false
     }
+
+    /// Removes port `id`, returns `false` if it is not registered
+    pub fn unregister_port(&mut self, id: u64) -> bool {
+        if self.ports.remove(&id).is_none() {
+            return false;
+        }
+        devfs::remove(&Self::tty_name(id));
+        true
+    }
 }
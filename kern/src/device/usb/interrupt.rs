@@ -1,13 +1,5 @@
-use crate::device::usb::G_USB;
-
+/// Processes the event rings of all xHCI controllers. Skipped if the
+/// controller list is being polled already.
 pub fn usb_interrupt_handler() {
-    // let xhci = G_USB.xhci.read();
-    // match xhci.as_ref() {
-    //     Some(r) => {
-    //         r.handle_interrupt();
-    //     },
-    //     _ => {
-    //         warn!("[XHCI] Interrupt while XHCI is None");
-    //     }
-    // }
+    crate::device::usb::xhci::poll_xhci_devices();
 }
\ No newline at end of file
@@ -9,7 +9,7 @@ use alloc::vec::Vec;
 use alloc::sync::Arc;
 use alloc::string::String;
 use x86_64::instructions::interrupts::without_interrupts;
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use crate::memory::mmio_bump_allocator::VMALLOC;
 use usb_host::{USBHost, HostCallbacks, USBResult, USBErrorKind};
 use core::time::Duration;
@@ -40,33 +40,56 @@ struct MassFSHook();
 
 impl MSDCallback for MassFSHook {
     fn on_new_scsi(scsi: TransparentSCSI) -> USBResult<()> {
-        let wrapper = USBSCSIWrapper(Mutex::new(scsi));
+        let wrapper = USBSCSIWrapper {
+            scsi: Mutex::new(scsi),
+            present: AtomicBool::new(true),
+        };
         let str = crate::storage::block::G_BLOCK_DEV_MGR.write().register_root_device(Arc::new(wrapper));
         debug!("USB MSD Attached as {}", str);
         Ok(())
     }
 }
 
-struct USBSCSIWrapper(Mutex<TransparentSCSI>);
+/// The host library does not report detaches, a device failing a read
+/// twice in a row is taken as unplugged
+struct USBSCSIWrapper {
+    scsi: Mutex<TransparentSCSI>,
+    present: AtomicBool,
+}
 
 impl BlockDevice for USBSCSIWrapper {
     fn sector_size(&self) -> u64 {
-        self.0.lock().sector_size()
+        self.scsi.lock().sector_size()
     }
 
 
     fn read_sector(&self, sector: u64, buf: &mut [u8]) -> core_io::Result<usize> {
-        match self.0.lock().read_sector(sector, buf) {
+        let mut scsi = self.scsi.lock();
+        let result = scsi.read_sector(sector, buf).or_else(|e| {
+            warn!("USB MSD ReadError: {:?}, retrying", e);
+            scsi.read_sector(sector, buf)
+        });
+        match result {
             Ok(num) => Ok(num),
             Err(e) => {
-                warn!("USB MSD ReadError: {:?}", e);
+                warn!("USB MSD ReadError: {:?}, detaching", e);
+                self.present.store(false, Ordering::Relaxed);
+                crate::storage::block::presence_changed();
                 Err(core_io::error::Error::from(ErrorKind::Other))
             }
         }
     }
 
-    fn write_sector(&self, sector: u64, buf: &[u8]) -> core_io::Result<usize> {
-        unimplemented!()
+    fn write_sector(&self, _sector: u64, _buf: &[u8]) -> core_io::Result<usize> {
+        Err(ErrorKind::PermissionDenied.into())
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+
+    fn is_present(&self) -> bool {
+        self.present.load(Ordering::Relaxed)
     }
 }
 
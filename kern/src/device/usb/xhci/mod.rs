@@ -17,6 +17,7 @@ use usb_host::consts::USBSpeed;
 use crate::device::usb::{USBHostCallback, USBHAL};
 use alloc::vec::Vec;
 use usb_host::traits::USBHostController;
+use alloc::boxed::Box;
 
 pub mod consts;
 static XHCI_HAL: XhciHAL = XhciHAL();
@@ -70,13 +71,26 @@ fn load_from_device_helper<H: UsbHAL>(mut dev: PCIDevice) {
 
         intel_ehci_xhci_handoff(&mut dev);
 
-        // Step2: Setup Interrupt Lines
-        // let interrupt_number = (InterruptIndex::XHCI).as_offset() as u32;
-        // dev.write_config_dword_dep(0xF, interrupt_number);
-        // let interrupt = dev.read_config_dword_dep(0xF);
-        // let int_line = (interrupt >> 8) as u8;
-        // let int_num = interrupt as u8;
-        // trace!("[XHCI] Interrupt Line: {}, Interrupt Number: {}", int_line, int_num);
+        // Step2: Setup Interrupts, MSI(-X) if possible, the INTx line otherwise
+        let apic_id = crate::sys::percpu::apic_id();
+        let msi = dev.enable_msi(1, apic_id, |_, _| {
+            crate::device::usb::interrupt::usb_interrupt_handler();
+        });
+        if let Err(e) = msi {
+            let int_line = dev.irq_line();
+            let name = format!("pci {} xhci", dev.bus_location_str());
+            let claimed = crate::sys::ioapic::GLOBAL_IOAPIC.write()
+                .claim_pci(int_line, apic_id, &name);
+            match claimed {
+                Ok(vector) => {
+                    crate::interrupts::irq::register_irq_handler(vector, Box::new(|_| {
+                        crate::device::usb::interrupt::usb_interrupt_handler();
+                    })).expect("xHCI vector not allocated");
+                    debug!("[XHCI] No MSI ({:?}), Interrupt Line: {} on vector {:#x}", e, int_line, vector);
+                }
+                Err(()) => warn!("[XHCI] Unable to route interrupt line {}, polling only", int_line),
+            }
+        }
 
         // Step3: Setup MMIO Registers
         let size = xhci_address_space_detect(&mut dev);
@@ -0,0 +1,261 @@
+//! devfs
+//!
+//! Device files in a single directory, mounted at `/dev`. The console and
+//! the memory devices `null`, `zero` and `random` always exist. Block
+//! devices and serial ports are added and removed by their registries in
+//! `storage::block` and `device::uart` as they come and go. A removed entry
+//! is dropped from the dentry cache, files already open keep the device
+//! until they are closed.
+//!
+//! Block devices are read and written at byte offsets like a file, the
+//! other devices ignore the offset. Devices that cannot be written, like
+//! the AHCI and USB disks, have mode 0o440 and cannot be opened for
+//! writing. Reading the console or a serial port
+//! fails with `WouldBlock` until there is input. Like any VFS file, reads
+//! and writes from user space run in the `fs::io` worker, not the syscall,
+//! so a block device may wait for its disk.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use kernel_api::{FileType, OsError, IOCTL_TTY_SET_BAUDRATE};
+use spin::{Mutex, RwLock};
+use crate::device::uart::UART;
+use crate::fs::traits::{DirEntry, FileSystem, Inode, InodeNumber, Metadata};
+use crate::fs::vfs;
+use crate::storage::block::device::BlockDevice;
+use crate::sys::stdin::STD_IN;
+
+const ROOT_INO: InodeNumber = 1;
+
+pub enum Device {
+    /// Keyboard and serial input, screen output, like `fd::File::Console`
+    Console,
+    /// Reads nothing, discards writes
+    Null,
+    /// Reads zeros, discards writes
+    Zero,
+    /// Reads random bytes, discards writes
+    Random,
+    Serial(Arc<Mutex<dyn UART + Send + Sync>>),
+    Block(Arc<dyn BlockDevice + Send + Sync>),
+}
+
+pub struct DevInode {
+    ino: InodeNumber,
+    device: Device,
+}
+
+struct Table {
+    entries: BTreeMap<String, Arc<DevInode>>,
+    next_ino: InodeNumber,
+}
+
+impl Table {
+    fn insert(&mut self, name: &str, device: Device) -> Option<Arc<DevInode>> {
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.entries.insert(name.to_string(), Arc::new(DevInode { ino, device }))
+    }
+}
+
+lazy_static! {
+    static ref DEVICES: RwLock<Table> = {
+        let mut table = Table {
+            entries: BTreeMap::new(),
+            next_ino: ROOT_INO + 1,
+        };
+        table.insert("console", Device::Console);
+        table.insert("null", Device::Null);
+        table.insert("zero", Device::Zero);
+        table.insert("random", Device::Random);
+        RwLock::new(table)
+    };
+}
+
+/// Adds `device` as `name`, replacing the device of that name if there is one
+pub fn add(name: &str, device: Device) {
+    let old = DEVICES.write().insert(name, device);
+    if let Some(old) = old {
+        vfs::forget(old.as_ref());
+    }
+}
+
+pub fn remove(name: &str) {
+    let old = DEVICES.write().entries.remove(name);
+    if let Some(old) = old {
+        vfs::forget(old.as_ref());
+    }
+}
+
+pub struct DevFs {
+    root: Arc<DevDir>,
+}
+
+impl DevFs {
+    pub fn new() -> DevFs {
+        DevFs {
+            root: Arc::new(DevDir),
+        }
+    }
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &str {
+        "devfs"
+    }
+
+    fn root(&self) -> Result<Arc<dyn Inode>, OsError> {
+        Ok(self.root.clone())
+    }
+}
+
+/// The directory holding all devices
+struct DevDir;
+
+impl Inode for DevDir {
+    fn metadata(&self) -> Result<Metadata, OsError> {
+        Ok(Metadata {
+            ino: ROOT_INO,
+            kind: FileType::Directory,
+            size: DEVICES.read().entries.len() as u64,
+            nlink: 2,
+            mode: 0o755,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, OsError> {
+        match DEVICES.read().entries.get(name) {
+            Some(inode) => Ok(inode.clone()),
+            None => Err(OsError::NoEntry),
+        }
+    }
+
+    /// Entries come from the drivers only
+    fn create(&self, _name: &str, _kind: FileType) -> Result<Arc<dyn Inode>, OsError> {
+        Err(OsError::NoAccess)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, OsError> {
+        Err(OsError::NoAccess)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), OsError> {
+        Err(OsError::NoAccess)
+    }
+
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, OsError> {
+        let devices = DEVICES.read();
+        match devices.entries.iter().nth(index) {
+            Some((name, inode)) => Ok(Some(DirEntry {
+                name: name.clone(),
+                ino: inode.ino,
+                kind: inode.kind()?,
+            })),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Inode for DevInode {
+    fn metadata(&self) -> Result<Metadata, OsError> {
+        let (kind, size, mode) = match &self.device {
+            Device::Block(dev) => {
+                let size = dev.sector_count().map_or(0, |count| count * dev.sector_size());
+                (FileType::BlockDevice, size, if dev.read_only() { 0o440 } else { 0o660 })
+            },
+            _ => (FileType::CharDevice, 0, 0o666),
+        };
+        Ok(Metadata {
+            ino: self.ino,
+            kind,
+            size,
+            nlink: 1,
+            mode,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, OsError> {
+        let count = match &self.device {
+            Device::Console => {
+                let mut count = 0;
+                while count < buf.len() {
+                    match STD_IN.pop() {
+                        Some(c) => buf[count] = c,
+                        None => break,
+                    }
+                    count += 1;
+                }
+                count
+            },
+            Device::Serial(port) => {
+                let mut port = port.lock();
+                let mut count = 0;
+                while count < buf.len() {
+                    match port.read_byte() {
+                        Some(c) => buf[count] = c,
+                        None => break,
+                    }
+                    count += 1;
+                }
+                count
+            },
+            Device::Null => return Ok(0),
+            Device::Zero => {
+                for b in buf.iter_mut() {
+                    *b = 0;
+                }
+                buf.len()
+            },
+            Device::Random => {
+                crate::sys::random::fill(buf);
+                buf.len()
+            },
+            Device::Block(dev) => return crate::storage::block::file::read_at(dev.as_ref(), offset, buf),
+        };
+        match count {
+            0 if !buf.is_empty() => Err(OsError::WouldBlock),
+            count => Ok(count),
+        }
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, OsError> {
+        match &self.device {
+            Device::Console => print!("{}", String::from_utf8_lossy(buf)),
+            Device::Serial(port) => {
+                let mut port = port.lock();
+                for &b in buf {
+                    port.write_byte(b);
+                }
+            },
+            Device::Null | Device::Zero | Device::Random => {},
+            Device::Block(dev) => return crate::storage::block::file::write_at(dev.as_ref(), offset, buf),
+        }
+        Ok(buf.len())
+    }
+
+    fn readable(&self) -> bool {
+        match &self.device {
+            Device::Console => STD_IN.has_data(),
+            // Asked by the scheduler, which must not wait for a reader
+            Device::Serial(port) => port.try_lock().map_or(false, |port| port.has_data()),
+            _ => true,
+        }
+    }
+
+    /// The serial ports run with their interrupts off
+    fn polled(&self) -> bool {
+        matches!(self.device, Device::Serial(_))
+    }
+
+    fn ioctl(&self, request: u64, arg: u64) -> Result<u64, OsError> {
+        match (&self.device, request) {
+            (Device::Block(dev), _) => crate::storage::block::file::ioctl(dev.as_ref(), request, arg),
+            (Device::Serial(port), IOCTL_TTY_SET_BAUDRATE) if arg > 0 && arg <= u32::MAX as u64 => {
+                port.lock().set_baudrate(arg as u32);
+                Ok(0)
+            },
+            _ => Err(OsError::InvalidArgument),
+        }
+    }
+}
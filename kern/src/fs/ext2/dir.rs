@@ -0,0 +1,128 @@
+//! ext2 directories
+//!
+//! A directory is a file of records, each holding an inode number, its own
+//! length, the name and with `INCOMPAT_FILETYPE` the kind of file. Records
+//! do not cross blocks and their lengths add up to the block size. A removed
+//! record is merged into the one before it, the first record of a block is
+//! marked unused with inode 0 instead.
+
+use alloc::vec::Vec;
+use kernel_api::{FileType, OsError};
+use crate::fs::ext2::structures::*;
+
+/// Inode, record length, name length and file type
+const HEADER_SIZE: usize = 8;
+
+const FT_UNKNOWN: u8 = 0;
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_CHRDEV: u8 = 3;
+const FT_BLKDEV: u8 = 4;
+const FT_FIFO: u8 = 5;
+const FT_SYMLINK: u8 = 7;
+
+#[derive(Debug, Copy, Clone)]
+pub struct Record {
+    /// Byte offset in the block
+    pub offset: usize,
+    /// 0 if the record is unused
+    pub inode: u32,
+    pub rec_len: usize,
+    pub name_len: usize,
+    pub file_type: u8,
+}
+
+impl Record {
+    pub fn name<'a>(&self, block: &'a [u8]) -> &'a [u8] {
+        &block[self.offset + HEADER_SIZE..self.offset + HEADER_SIZE + self.name_len]
+    }
+
+    /// Bytes the record needs, the rest of `rec_len` can hold others
+    pub fn used(&self) -> usize {
+        match self.inode {
+            0 => 0,
+            _ => record_len(self.name_len),
+        }
+    }
+
+    pub fn is_dot(&self, block: &[u8]) -> bool {
+        let name = self.name(block);
+        name == b"." || name == b".."
+    }
+}
+
+/// Length of a record for a name of `name_len` bytes
+pub fn record_len(name_len: usize) -> usize {
+    (HEADER_SIZE + name_len + 3) & !3
+}
+
+/// The records in the directory block `block`
+pub fn records(block: &[u8]) -> Result<Vec<Record>, OsError> {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while offset < block.len() {
+        if block.len() - offset < HEADER_SIZE {
+            return Err(OsError::IoErrorInvalidData);
+        }
+        let b = &block[offset..];
+        let record = Record {
+            offset,
+            inode: u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            rec_len: u16::from_le_bytes([b[4], b[5]]) as usize,
+            name_len: b[6] as usize,
+            file_type: b[7],
+        };
+        if record.rec_len < HEADER_SIZE || record.rec_len % 4 != 0 || record.rec_len > b.len()
+            || HEADER_SIZE + record.name_len > record.rec_len {
+            return Err(OsError::IoErrorInvalidData);
+        }
+        offset += record.rec_len;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Writes a record at `offset`. `file_type` is only stored with
+/// `INCOMPAT_FILETYPE`, otherwise the byte belongs to the name length.
+pub fn write_record(block: &mut [u8], offset: usize, inode: u32, rec_len: usize, name: &[u8], file_type: Option<u8>) {
+    let b = &mut block[offset..offset + rec_len];
+    b[0..4].copy_from_slice(&inode.to_le_bytes());
+    b[4..6].copy_from_slice(&(rec_len as u16).to_le_bytes());
+    b[6] = name.len() as u8;
+    b[7] = file_type.unwrap_or(0);
+    b[HEADER_SIZE..HEADER_SIZE + name.len()].copy_from_slice(name);
+}
+
+pub fn set_rec_len(block: &mut [u8], offset: usize, rec_len: usize) {
+    block[offset + 4..offset + 6].copy_from_slice(&(rec_len as u16).to_le_bytes());
+}
+
+pub fn set_inode(block: &mut [u8], offset: usize, inode: u32) {
+    block[offset..offset + 4].copy_from_slice(&inode.to_le_bytes());
+}
+
+/// The directory file type for an inode of mode `mode`
+pub fn file_type(mode: u16) -> u8 {
+    match mode & S_IFMT {
+        S_IFREG => FT_REG_FILE,
+        S_IFDIR => FT_DIR,
+        S_IFCHR => FT_CHRDEV,
+        S_IFBLK => FT_BLKDEV,
+        S_IFIFO => FT_FIFO,
+        S_IFLNK => FT_SYMLINK,
+        _ => FT_UNKNOWN,
+    }
+}
+
+/// The kind of file of a directory file type, `None` if it is not known
+pub fn kind(file_type: u8) -> Option<FileType> {
+    match file_type {
+        FT_REG_FILE => Some(FileType::Regular),
+        FT_DIR => Some(FileType::Directory),
+        FT_CHRDEV => Some(FileType::CharDevice),
+        FT_BLKDEV => Some(FileType::BlockDevice),
+        FT_FIFO => Some(FileType::Fifo),
+        FT_SYMLINK => Some(FileType::Symlink),
+        _ => None,
+    }
+}
@@ -0,0 +1,676 @@
+//! ext2 inodes
+//!
+//! Block `i` of a file is found through the pointers of its inode. The
+//! first `DIRECT_BLOCKS` pointers lead to data blocks, the next three to
+//! blocks of pointers nested one, two and three levels deep. A pointer of 0
+//! is a hole that reads as zeros, blocks are only allocated when written.
+//!
+//! Symlinks with a target shorter than the pointers keep it in their place
+//! and use no blocks.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::{max, min};
+use kernel_api::{FileType, OsError, MAX_NAME_LEN};
+use crate::fs::ext2::dir::{self, Record};
+use crate::fs::ext2::structures::*;
+use crate::fs::ext2::{as_bytes, Volume};
+use crate::fs::traits::{DirEntry, Inode, Metadata};
+use crate::sync::Mutex;
+
+/// Bytes of the block pointers, where a short symlink target is stored
+const FAST_SYMLINK_SIZE: usize = BLOCK_POINTERS * 4;
+
+/// Largest file without `RO_COMPAT_LARGE_FILE`
+const MAX_SMALL_FILE: u64 = i32::max_value() as u64;
+
+impl Volume {
+    fn pointers_per_block(&self) -> u64 {
+        self.block_size / 4
+    }
+
+    /// 512 byte units of a block, as counted in `RawInode::blocks`
+    fn block_units(&self) -> u32 {
+        (self.block_size / 512) as u32
+    }
+
+    /// The pointer in the inode and the indices into the indirect blocks
+    /// below it that lead to block `index` of a file
+    fn block_path(&self, index: u64) -> Result<(usize, Vec<usize>), OsError> {
+        let p = self.pointers_per_block();
+        let mut i = index;
+        if i < DIRECT_BLOCKS as u64 {
+            return Ok((i as usize, Vec::new()));
+        }
+        i -= DIRECT_BLOCKS as u64;
+        if i < p {
+            return Ok((DIRECT_BLOCKS, vec![i as usize]));
+        }
+        i -= p;
+        if i < p * p {
+            return Ok((DIRECT_BLOCKS + 1, vec![(i / p) as usize, (i % p) as usize]));
+        }
+        i -= p * p;
+        if i < p * p * p {
+            return Ok((DIRECT_BLOCKS + 2, vec![(i / (p * p)) as usize, (i / p % p) as usize, (i % p) as usize]));
+        }
+        Err(OsError::NoSpace)
+    }
+
+    /// Whether `raw` is a symlink keeping its target in the block pointers.
+    /// An extended attribute block is counted in `blocks` as well.
+    fn is_fast_symlink(&self, raw: &RawInode) -> bool {
+        let acl_units = if raw.file_acl != 0 { self.block_units() } else { 0 };
+        raw.kind() == S_IFLNK && raw.blocks == acl_units
+    }
+
+    fn read_pointer(&self, block: u32, index: usize) -> Result<u32, OsError> {
+        let mut buf = [0u8; 4];
+        self.read_bytes(block as u64 * self.block_size + index as u64 * 4, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn write_pointer(&self, block: u32, index: usize, value: u32) -> Result<(), OsError> {
+        self.write_bytes(block as u64 * self.block_size + index as u64 * 4, &value.to_le_bytes())
+    }
+
+    /// Block number of block `index` of a file, 0 for a hole
+    fn get_block(&self, raw: &RawInode, index: u64) -> Result<u32, OsError> {
+        let (slot, path) = self.block_path(index)?;
+        let mut block = raw.block[slot];
+        for i in path {
+            if block == 0 {
+                break;
+            }
+            block = self.read_pointer(block, i)?;
+        }
+        Ok(block)
+    }
+
+    /// Block number of block `index` of a file, allocating it and the
+    /// indirect blocks leading there if needed. The caller writes `raw`
+    /// back.
+    fn map_block(&self, raw: &mut RawInode, goal: u32, index: u64) -> Result<u32, OsError> {
+        let (slot, path) = self.block_path(index)?;
+        let mut block = raw.block[slot];
+        if block == 0 {
+            block = self.alloc_block(goal)?;
+            raw.block[slot] = block;
+            raw.blocks += self.block_units();
+        }
+        for i in path {
+            let mut next = self.read_pointer(block, i)?;
+            if next == 0 {
+                next = self.alloc_block(goal)?;
+                raw.blocks += self.block_units();
+                self.write_pointer(block, i, next)?;
+            }
+            block = next;
+        }
+        Ok(block)
+    }
+
+    /// Frees the blocks from block `keep` of a file on below the indirect
+    /// block `block` of `level`, which covers the file from block `base`.
+    /// Returns `true` if nothing is left below it, it is then up to the
+    /// caller to free it.
+    fn free_tree(&self, raw: &mut RawInode, block: u32, level: u32, base: u64, keep: u64) -> Result<bool, OsError> {
+        let p = self.pointers_per_block();
+        let span = p.pow(level - 1);
+        if base + span * p <= keep {
+            return Ok(false);
+        }
+        let mut buf = self.block_buf();
+        self.read_block(block, &mut buf)?;
+        let mut changed = false;
+        let mut empty = true;
+        for (j, pointer) in buf.chunks_exact_mut(4).enumerate() {
+            let child = u32::from_le_bytes([pointer[0], pointer[1], pointer[2], pointer[3]]);
+            if child == 0 {
+                continue;
+            }
+            let child_base = base + j as u64 * span;
+            let free = match level {
+                1 => child_base >= keep,
+                _ => self.free_tree(raw, child, level - 1, child_base, keep)?,
+            };
+            if free {
+                self.free_block(child)?;
+                raw.blocks -= self.block_units();
+                pointer.copy_from_slice(&[0; 4]);
+                changed = true;
+            } else {
+                empty = false;
+            }
+        }
+        if changed && !empty {
+            self.write_block(block, &buf)?;
+        }
+        Ok(empty)
+    }
+
+    /// Frees the blocks from block `keep` of a file on. The caller writes
+    /// `raw` back.
+    fn free_blocks(&self, raw: &mut RawInode, keep: u64) -> Result<(), OsError> {
+        for slot in keep as usize..DIRECT_BLOCKS {
+            let block = raw.block[slot];
+            if block != 0 {
+                self.free_block(block)?;
+                raw.blocks -= self.block_units();
+                raw.block[slot] = 0;
+            }
+        }
+        let p = self.pointers_per_block();
+        let mut base = DIRECT_BLOCKS as u64;
+        for level in 1..=3 {
+            let slot = DIRECT_BLOCKS + level as usize - 1;
+            let block = raw.block[slot];
+            if block != 0 && self.free_tree(raw, block, level, base, keep)? {
+                self.free_block(block)?;
+                raw.blocks -= self.block_units();
+                raw.block[slot] = 0;
+            }
+            base += p.pow(level);
+        }
+        Ok(())
+    }
+
+    /// Reads from `offset` of a file, which has to be within its size
+    fn read_data(&self, raw: &RawInode, offset: u64, buf: &mut [u8]) -> Result<(), OsError> {
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let within = position % self.block_size;
+            let count = min((self.block_size - within) as usize, buf.len() - done);
+            match self.get_block(raw, position / self.block_size)? {
+                0 => buf[done..done + count].iter_mut().for_each(|b| *b = 0),
+                block => self.read_bytes(block as u64 * self.block_size + within, &mut buf[done..done + count])?,
+            }
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Writes at `offset` of a file without changing its size. The caller
+    /// writes `raw` back.
+    fn write_data(&self, raw: &mut RawInode, goal: u32, offset: u64, buf: &[u8]) -> Result<(), OsError> {
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let within = position % self.block_size;
+            let count = min((self.block_size - within) as usize, buf.len() - done);
+            let block = self.map_block(raw, goal, position / self.block_size)?;
+            self.write_bytes(block as u64 * self.block_size + within, &buf[done..done + count])?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Frees the blocks and the inode `ino` once it is unlinked and no
+    /// longer in use, marking it deleted now
+    fn free_unlinked(&self, ino: u32) -> Result<(), OsError> {
+        let mut raw = self.read_inode(ino)?;
+        if raw.links_count != 0 {
+            return Ok(());
+        }
+        let is_dir = raw.kind() == S_IFDIR;
+        if !self.is_fast_symlink(&raw) {
+            self.free_blocks(&mut raw, 0)?;
+        }
+        // 0 means in use to `e2fsck`
+        raw.dtime = max(crate::sys::rtc::now() as u32, 1);
+        raw.set_size(0);
+        self.write_inode(ino, &raw)?;
+        self.free_inode(ino, is_dir)
+    }
+
+    /// Frees the inodes unlinked and dropped since the last call
+    pub(super) fn reclaim(&self) {
+        let orphans = self.orphans.take();
+        for ino in orphans {
+            if let Err(e) = self.free_unlinked(ino) {
+                warn!("[EXT2] Unable to free unlinked inode {}: {:?}", ino, e);
+            }
+        }
+    }
+
+    /// Zeroes the rest of the last block of a file from `size` up to `end`,
+    /// it may hold old data once the file grows
+    fn zero_tail(&self, raw: &RawInode, size: u64, end: u64) -> Result<(), OsError> {
+        let block_end = (size + self.block_size - 1) / self.block_size * self.block_size;
+        let end = min(end, block_end);
+        if size >= end {
+            return Ok(());
+        }
+        match self.get_block(raw, size / self.block_size)? {
+            0 => Ok(()),
+            block => self.write_bytes(block as u64 * self.block_size + size % self.block_size,
+                                      &vec![0u8; (end - size) as usize]),
+        }
+    }
+}
+
+pub struct Ext2Inode {
+    vol: Arc<Volume>,
+    ino: u32,
+    /// Held across I/O on the inode
+    raw: Mutex<RawInode>,
+}
+
+/// A record found in a directory
+struct Found {
+    /// Block number and contents of the block holding it
+    block: u32,
+    data: Vec<u8>,
+    record: Record,
+    /// The record before it in the same block
+    prev: Option<Record>,
+}
+
+impl Ext2Inode {
+    pub(super) fn new(vol: Arc<Volume>, ino: u32, raw: RawInode) -> Ext2Inode {
+        Ext2Inode {
+            vol,
+            ino,
+            raw: Mutex::new(raw),
+        }
+    }
+
+    fn kind_of(raw: &RawInode) -> FileType {
+        match raw.kind() {
+            S_IFREG => FileType::Regular,
+            S_IFDIR => FileType::Directory,
+            S_IFLNK => FileType::Symlink,
+            S_IFCHR => FileType::CharDevice,
+            S_IFBLK => FileType::BlockDevice,
+            S_IFIFO => FileType::Fifo,
+            _ => FileType::Unknown,
+        }
+    }
+
+    fn goal(&self) -> u32 {
+        self.vol.group_of(self.ino)
+    }
+
+    fn check_writable(&self) -> Result<(), OsError> {
+        match self.vol.read_only {
+            true => Err(OsError::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    /// Calls `f` with every directory block, its number and its records
+    /// until it returns `Some`
+    fn scan<T, F>(&self, raw: &RawInode, mut f: F) -> Result<Option<T>, OsError>
+        where F: FnMut(u32, &[u8], &[Record]) -> Result<Option<T>, OsError> {
+        let blocks = raw.size() / self.vol.block_size;
+        let mut data = self.vol.block_buf();
+        for index in 0..blocks {
+            let block = self.vol.get_block(raw, index)?;
+            if block == 0 {
+                continue;
+            }
+            self.vol.read_block(block, &mut data)?;
+            let records = dir::records(&data)?;
+            if let Some(result) = f(block, &data, &records)? {
+                return Ok(Some(result));
+            }
+        }
+        Ok(None)
+    }
+
+    fn find(&self, raw: &RawInode, name: &str) -> Result<Option<Found>, OsError> {
+        self.scan(raw, |block, data, records| {
+            let mut prev = None;
+            for record in records {
+                if record.inode != 0 && record.name(data) == name.as_bytes() {
+                    return Ok(Some(Found {
+                        block,
+                        data: data.to_vec(),
+                        record: *record,
+                        prev,
+                    }));
+                }
+                prev = Some(*record);
+            }
+            Ok(None)
+        })
+    }
+
+    fn is_empty_dir(&self, raw: &RawInode) -> Result<bool, OsError> {
+        let entry = self.scan(raw, |_, data, records| {
+            Ok(records.iter().find(|r| r.inode != 0 && !r.is_dot(data)).map(|_| ()))
+        })?;
+        Ok(entry.is_none())
+    }
+
+    /// Adds the entry `name` for `ino` of mode `mode` to this directory
+    fn add_entry(&self, raw: &mut RawInode, name: &str, ino: u32, mode: u16) -> Result<(), OsError> {
+        let file_type = match self.vol.filetype {
+            true => Some(dir::file_type(mode)),
+            false => None,
+        };
+        let needed = dir::record_len(name.len());
+        raw.flags &= !INDEX_FL;
+
+        let added = self.scan(raw, |block, data, records| {
+            let record = match records.iter().find(|r| r.rec_len - r.used() >= needed) {
+                Some(record) => record,
+                None => return Ok(None),
+            };
+            let mut data = data.to_vec();
+            let used = record.used();
+            if used > 0 {
+                dir::set_rec_len(&mut data, record.offset, used);
+            }
+            dir::write_record(&mut data, record.offset + used, ino, record.rec_len - used, name.as_bytes(), file_type);
+            self.vol.write_block(block, &data)?;
+            Ok(Some(()))
+        })?;
+        if added.is_some() {
+            return Ok(());
+        }
+
+        let size = raw.size();
+        let mut data = self.vol.block_buf();
+        let rec_len = data.len();
+        dir::write_record(&mut data, 0, ino, rec_len, name.as_bytes(), file_type);
+        self.vol.write_data(raw, self.goal(), size, &data)?;
+        raw.set_size(size + self.vol.block_size);
+        Ok(())
+    }
+
+    fn remove_entry(&self, raw: &mut RawInode, mut found: Found) -> Result<(), OsError> {
+        match found.prev {
+            Some(prev) => dir::set_rec_len(&mut found.data, prev.offset, prev.rec_len + found.record.rec_len),
+            None => dir::set_inode(&mut found.data, found.record.offset, 0),
+        }
+        raw.flags &= !INDEX_FL;
+        self.vol.write_block(found.block, &found.data)
+    }
+
+    /// Sets up a new inode of `mode` with `links` links. Directories get
+    /// their first block with `.` and `..` right away.
+    fn new_inode(&self, mode: u16, links: u16) -> Result<(u32, RawInode), OsError> {
+        let is_dir = mode & S_IFMT == S_IFDIR;
+        let ino = self.vol.alloc_inode(self.goal(), is_dir)?;
+        let mut raw = RawInode {
+            mode,
+            links_count: links,
+            ..RawInode::default()
+        };
+        let result = if is_dir {
+            let file_type = match self.vol.filetype {
+                true => Some(dir::file_type(S_IFDIR)),
+                false => None,
+            };
+            let mut data = self.vol.block_buf();
+            let dot_len = dir::record_len(1);
+            dir::write_record(&mut data, 0, ino, dot_len, b".", file_type);
+            let rest = data.len() - dot_len;
+            dir::write_record(&mut data, dot_len, self.ino, rest, b"..", file_type);
+            raw.set_size(self.vol.block_size);
+            self.vol.write_data(&mut raw, self.vol.group_of(ino), 0, &data)
+        } else {
+            Ok(())
+        };
+        match result.and_then(|_| self.vol.write_new_inode(ino, &raw)) {
+            Ok(()) => Ok((ino, raw)),
+            Err(e) => {
+                let _ = self.vol.free_blocks(&mut raw, 0);
+                let _ = self.vol.free_inode(ino, is_dir);
+                Err(e)
+            }
+        }
+    }
+
+    /// Creates the inode of `mode` and links it as `name` in this directory
+    fn create_entry(&self, name: &str, mode: u16, target: Option<&str>) -> Result<Arc<dyn Inode>, OsError> {
+        if self.kind()? != FileType::Directory {
+            return Err(OsError::NotADirectory);
+        }
+        self.check_writable()?;
+        if name.len() > MAX_NAME_LEN {
+            return Err(OsError::NameTooLong);
+        }
+        if name.is_empty() || name.contains('/') || name.contains('\0') {
+            return Err(OsError::InvalidArgument);
+        }
+        self.vol.reclaim();
+        let mut raw = self.raw.lock();
+        if raw.links_count == 0 {
+            return Err(OsError::NoEntry);
+        }
+        if self.find(&raw, name)?.is_some() {
+            return Err(OsError::FileExists);
+        }
+        let is_dir = mode & S_IFMT == S_IFDIR;
+        let (ino, mut child) = self.new_inode(mode, if is_dir { 2 } else { 1 })?;
+
+        if let Some(target) = target {
+            let result = if target.len() < FAST_SYMLINK_SIZE {
+                let mut pointers = [0u8; FAST_SYMLINK_SIZE];
+                pointers[..target.len()].copy_from_slice(target.as_bytes());
+                for (i, pointer) in pointers.chunks_exact(4).enumerate() {
+                    child.block[i] = u32::from_le_bytes([pointer[0], pointer[1], pointer[2], pointer[3]]);
+                }
+                Ok(())
+            } else {
+                self.vol.write_data(&mut child, self.vol.group_of(ino), 0, target.as_bytes())
+            };
+            child.set_size(target.len() as u64);
+            if let Err(e) = result.and_then(|_| self.vol.write_inode(ino, &child)) {
+                let _ = self.vol.free_blocks(&mut child, 0);
+                let _ = self.vol.free_inode(ino, false);
+                return Err(e);
+            }
+        }
+
+        if let Err(e) = self.add_entry(&mut raw, name, ino, mode) {
+            let _ = self.vol.free_blocks(&mut child, 0);
+            let _ = self.vol.free_inode(ino, is_dir);
+            return Err(e);
+        }
+        if is_dir {
+            raw.links_count += 1;
+        }
+        self.vol.write_inode(self.ino, &raw)?;
+        Ok(self.vol.inode(ino)?)
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn metadata(&self) -> Result<Metadata, OsError> {
+        let raw = self.raw.lock();
+        Ok(Metadata {
+            ino: self.ino as u64,
+            kind: Ext2Inode::kind_of(&raw),
+            size: raw.size(),
+            nlink: raw.links_count as u64,
+            mode: raw.mode & !S_IFMT,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, OsError> {
+        let raw = self.raw.lock();
+        if raw.kind() != S_IFREG {
+            drop(raw);
+            return Err(self.not_a_file());
+        }
+        let size = raw.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let count = min(buf.len() as u64, size - offset) as usize;
+        self.vol.read_data(&raw, offset, &mut buf[..count])?;
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, OsError> {
+        self.vol.reclaim();
+        let mut raw = self.raw.lock();
+        if raw.kind() != S_IFREG {
+            drop(raw);
+            return Err(self.not_a_file());
+        }
+        self.check_writable()?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let end = offset.checked_add(buf.len() as u64).ok_or(OsError::NoSpace)?;
+        if !self.vol.large_file && end > MAX_SMALL_FILE {
+            return Err(OsError::NoSpace);
+        }
+        let size = raw.size();
+        let result = self.vol.zero_tail(&raw, size, offset)
+            .and_then(|_| self.vol.write_data(&mut raw, self.goal(), offset, buf));
+        if result.is_ok() && end > size {
+            raw.set_size(end);
+        }
+        self.vol.write_inode(self.ino, &raw)?;
+        result.map(|_| buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), OsError> {
+        self.vol.reclaim();
+        let mut raw = self.raw.lock();
+        if raw.kind() != S_IFREG {
+            drop(raw);
+            return Err(self.not_a_file());
+        }
+        self.check_writable()?;
+        if !self.vol.large_file && size > MAX_SMALL_FILE {
+            return Err(OsError::NoSpace);
+        }
+        let old = raw.size();
+        let result = if size > old {
+            self.vol.zero_tail(&raw, old, size)
+        } else {
+            let keep = (size + self.vol.block_size - 1) / self.vol.block_size;
+            self.vol.free_blocks(&mut raw, keep)
+        };
+        if result.is_ok() {
+            raw.set_size(size);
+        }
+        self.vol.write_inode(self.ino, &raw)?;
+        result
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, OsError> {
+        let raw = self.raw.lock();
+        if raw.kind() != S_IFDIR {
+            return Err(OsError::NotADirectory);
+        }
+        let found = self.find(&raw, name)?.ok_or(OsError::NoEntry)?;
+        drop(raw);
+        Ok(self.vol.inode(found.record.inode)?)
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<Arc<dyn Inode>, OsError> {
+        match kind {
+            FileType::Regular => self.create_entry(name, S_IFREG | 0o644, None),
+            FileType::Directory => self.create_entry(name, S_IFDIR | 0o755, None),
+            _ => Err(OsError::InvalidArgument),
+        }
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, OsError> {
+        if target.is_empty() || target.len() as u64 > self.vol.block_size {
+            return Err(OsError::NameTooLong);
+        }
+        self.create_entry(name, S_IFLNK | 0o777, Some(target))
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), OsError> {
+        let mut raw = self.raw.lock();
+        if raw.kind() != S_IFDIR {
+            return Err(OsError::NotADirectory);
+        }
+        self.check_writable()?;
+        let found = self.find(&raw, name)?.ok_or(OsError::NoEntry)?;
+        let child = self.vol.inode(found.record.inode)?;
+        let mut child_raw = child.raw.lock();
+        let is_dir = child_raw.kind() == S_IFDIR;
+        if is_dir && !child.is_empty_dir(&child_raw)? {
+            return Err(OsError::DirectoryNotEmpty);
+        }
+
+        self.remove_entry(&mut raw, found)?;
+        if is_dir {
+            // `..` of the child links back here
+            raw.links_count = raw.links_count.saturating_sub(1);
+            child_raw.links_count = 0;
+        } else {
+            child_raw.links_count = child_raw.links_count.saturating_sub(1);
+        }
+        self.vol.write_inode(self.ino, &raw)?;
+        let result = self.vol.write_inode(child.ino, &child_raw);
+        drop(child_raw);
+        drop(child);
+        drop(raw);
+        // Frees the child unless it is still in use
+        self.vol.reclaim();
+        result
+    }
+
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, OsError> {
+        let raw = self.raw.lock();
+        if raw.kind() != S_IFDIR {
+            return Err(OsError::NotADirectory);
+        }
+        let mut remaining = index;
+        let found = self.scan(&raw, |_, data, records| {
+            for record in records.iter().filter(|r| r.inode != 0 && !r.is_dot(data)) {
+                if remaining == 0 {
+                    let name = String::from_utf8_lossy(record.name(data)).into_owned();
+                    return Ok(Some((name, record.inode, record.file_type)));
+                }
+                remaining -= 1;
+            }
+            Ok(None)
+        })?;
+        drop(raw);
+
+        let (name, ino, file_type) = match found {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let kind = match dir::kind(file_type).filter(|_| self.vol.filetype) {
+            Some(kind) => kind,
+            None => self.vol.inode(ino)?.kind()?,
+        };
+        Ok(Some(DirEntry {
+            name,
+            ino: ino as u64,
+            kind,
+        }))
+    }
+
+    fn readlink(&self) -> Result<String, OsError> {
+        let raw = self.raw.lock();
+        if raw.kind() != S_IFLNK {
+            return Err(OsError::InvalidArgument);
+        }
+        let size = raw.size() as usize;
+        let mut target = vec![0u8; size];
+        if self.vol.is_fast_symlink(&raw) && size < FAST_SYMLINK_SIZE {
+            let pointers = raw.block;
+            target.copy_from_slice(&as_bytes(&pointers)[..size]);
+        } else {
+            self.vol.read_data(&raw, 0, &mut target)?;
+        }
+        String::from_utf8(target).map_err(|_| OsError::IoErrorInvalidData)
+    }
+}
+
+impl Drop for Ext2Inode {
+    /// Queues an unlinked inode for `Volume::reclaim`, this may run
+    /// anywhere and must not do I/O
+    fn drop(&mut self) {
+        if self.raw.get_mut().links_count == 0 && !self.vol.read_only {
+            let ino = self.ino;
+            self.vol.orphans.push(ino);
+        }
+    }
+}
@@ -0,0 +1,722 @@
+//! ext2
+//!
+//! The volume is split into block groups of `blocks_per_group` blocks,
+//! described by the group descriptors in the block after the superblock.
+//! Every group has a block bitmap, an inode bitmap and a table of inodes.
+//! An inode points to the blocks of its file, the first 12 directly, the
+//! rest through indirect blocks of block numbers, see `inode`.
+//!
+//! The superblock and group descriptors are kept in memory. A group
+//! descriptor is written back whenever a block or inode of its group is
+//! allocated or freed, the free counts of the superblock only on sync. They
+//! are the sums over the groups, so `e2fsck` can fix them after a crash.
+//! Only the primary copies are updated, backups in other groups are left to
+//! `e2fsck`.
+//!
+//! An inode unlinked while in use keeps its blocks until the last reference
+//! goes away. Dropping it does no I/O, it only queues the inode for
+//! `Volume::reclaim`, which runs the next time the volume is changed or
+//! synced.
+//!
+//! Volumes with incompatible features other than file types in directory
+//! entries are refused, those with unknown read-only features are opened
+//! read-only. Blocks larger than 4 KiB are refused too, the `rec_len` of a
+//! directory record spanning such a block does not fit in 16 bits.
+
+pub mod structures;
+pub mod dir;
+mod inode;
+
+pub use self::inode::Ext2Inode;
+
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::cmp::min;
+use hashbrown::HashMap;
+use kernel_api::OsError;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::fs::ext2::structures::*;
+use crate::fs::traits::{FileSystem, Inode};
+use crate::storage::block::device::BlockDevice;
+use crate::sync::{Mutex, SpinMutex, WorkQueue};
+
+/// Views a packed on-disk structure as its bytes
+fn as_bytes<T: Copy>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, core::mem::size_of::<T>()) }
+}
+
+/// Reads a packed on-disk structure from the start of `bytes`
+fn from_bytes<T: Copy>(bytes: &[u8]) -> T {
+    assert!(bytes.len() >= core::mem::size_of::<T>());
+    unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const T) }
+}
+
+/// The superblock and group descriptors, changed with every allocation
+struct Allocation {
+    sb: Superblock,
+    groups: Vec<GroupDescriptor>,
+    /// Whether the superblock on disk is out of date
+    dirty: bool,
+}
+
+struct Volume {
+    dev: Arc<dyn BlockDevice + Send + Sync>,
+    sector_size: u64,
+    block_size: u64,
+    inode_size: u64,
+    inodes_per_group: u32,
+    blocks_per_group: u32,
+    first_data_block: u32,
+    blocks_count: u32,
+    inodes_count: u32,
+    first_ino: u32,
+    /// Block holding the first group descriptor
+    gdt_block: u64,
+    filetype: bool,
+    large_file: bool,
+    read_only: bool,
+    /// Held across the writes of bitmaps and group descriptors
+    alloc: Mutex<Allocation>,
+    inodes: SpinMutex<HashMap<u32, Weak<Ext2Inode>>>,
+    /// Unlinked inodes no longer in use
+    orphans: WorkQueue<u32>,
+}
+
+/// Reads `buf.len()` bytes from byte `offset` of `dev`
+fn read_bytes(dev: &dyn BlockDevice, offset: u64, buf: &mut [u8]) -> Result<(), OsError> {
+    let ss = dev.sector_size();
+    let mut sector = vec![0u8; ss as usize];
+    let mut done = 0;
+    while done < buf.len() {
+        let position = offset + done as u64;
+        let within = (position % ss) as usize;
+        let count = min(sector.len() - within, buf.len() - done);
+        dev.read_sector(position / ss, &mut sector).map_err(|_| OsError::IoError)?;
+        buf[done..done + count].copy_from_slice(&sector[within..within + count]);
+        done += count;
+    }
+    Ok(())
+}
+
+impl Volume {
+    fn read_bytes(&self, offset: u64, buf: &mut [u8]) -> Result<(), OsError> {
+        read_bytes(&*self.dev, offset, buf)
+    }
+
+    /// Writes `buf` at byte `offset` of the device
+    fn write_bytes(&self, offset: u64, buf: &[u8]) -> Result<(), OsError> {
+        if self.read_only {
+            return Err(OsError::ReadOnly);
+        }
+        let ss = self.sector_size;
+        let mut sector = vec![0u8; ss as usize];
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let within = (position % ss) as usize;
+            let count = min(sector.len() - within, buf.len() - done);
+            let result = if count == sector.len() {
+                self.dev.write_sector(position / ss, &buf[done..done + count])
+            } else {
+                self.dev.read_sector(position / ss, &mut sector)
+                    .and_then(|_| {
+                        sector[within..within + count].copy_from_slice(&buf[done..done + count]);
+                        self.dev.write_sector(position / ss, &sector)
+                    })
+            };
+            result.map_err(|_| OsError::IoError)?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    fn read_block(&self, block: u32, buf: &mut [u8]) -> Result<(), OsError> {
+        self.read_bytes(block as u64 * self.block_size, buf)
+    }
+
+    fn write_block(&self, block: u32, buf: &[u8]) -> Result<(), OsError> {
+        self.write_bytes(block as u64 * self.block_size, buf)
+    }
+
+    fn block_buf(&self) -> Vec<u8> {
+        vec![0u8; self.block_size as usize]
+    }
+
+    /// Group the inode `ino` belongs to, new blocks of it are taken from
+    /// there first
+    fn group_of(&self, ino: u32) -> u32 {
+        (ino - 1) / self.inodes_per_group
+    }
+
+    fn inode_position(&self, ino: u32) -> Result<u64, OsError> {
+        if ino == 0 || ino > self.inodes_count {
+            return Err(OsError::IoErrorInvalidData);
+        }
+        let group = self.group_of(ino) as usize;
+        let index = ((ino - 1) % self.inodes_per_group) as u64;
+        let table = self.alloc.lock().groups[group].inode_table as u64;
+        Ok(table * self.block_size + index * self.inode_size)
+    }
+
+    fn read_inode(&self, ino: u32) -> Result<RawInode, OsError> {
+        let mut buf = [0u8; core::mem::size_of::<RawInode>()];
+        self.read_bytes(self.inode_position(ino)?, &mut buf)?;
+        Ok(from_bytes(&buf))
+    }
+
+    fn write_inode(&self, ino: u32, raw: &RawInode) -> Result<(), OsError> {
+        self.write_bytes(self.inode_position(ino)?, as_bytes(raw))
+    }
+
+    /// Writes a new inode, clearing what a larger inode has after `raw`
+    fn write_new_inode(&self, ino: u32, raw: &RawInode) -> Result<(), OsError> {
+        let mut buf = vec![0u8; self.inode_size as usize];
+        buf[..core::mem::size_of::<RawInode>()].copy_from_slice(as_bytes(raw));
+        self.write_bytes(self.inode_position(ino)?, &buf)
+    }
+
+    fn write_group(&self, alloc: &Allocation, group: usize) -> Result<(), OsError> {
+        let offset = self.gdt_block * self.block_size + (group * core::mem::size_of::<GroupDescriptor>()) as u64;
+        self.write_bytes(offset, as_bytes(&alloc.groups[group]))
+    }
+
+    /// Writes the superblock back if it changed
+    fn flush_superblock(&self) -> Result<(), OsError> {
+        let mut alloc = self.alloc.lock();
+        if alloc.dirty {
+            self.write_bytes(SUPERBLOCK_OFFSET, as_bytes(&alloc.sb))?;
+            alloc.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// First clear bit below `limit` in `bitmap`, which is then set
+    fn take_bit(bitmap: &mut [u8], first: u32, limit: u32) -> Option<u32> {
+        let bit = (first..limit).find(|&bit| bitmap[(bit / 8) as usize] & 1 << (bit % 8) == 0)?;
+        bitmap[(bit / 8) as usize] |= 1 << (bit % 8);
+        Some(bit)
+    }
+
+    /// Groups in the order they are searched for free blocks or inodes
+    fn groups_from(&self, alloc: &Allocation, goal: u32) -> impl Iterator<Item = usize> {
+        let count = alloc.groups.len();
+        (0..count).map(move |i| (goal as usize + i) % count)
+    }
+
+    /// Takes a free block, preferably in group `goal`, and zeroes it
+    fn alloc_block(&self, goal: u32) -> Result<u32, OsError> {
+        if self.read_only {
+            return Err(OsError::ReadOnly);
+        }
+        let mut alloc = self.alloc.lock();
+        let mut bitmap = self.block_buf();
+        for group in self.groups_from(&alloc, goal) {
+            if alloc.groups[group].free_blocks_count == 0 {
+                continue;
+            }
+            let start = self.first_data_block + group as u32 * self.blocks_per_group;
+            let limit = min(self.blocks_per_group, self.blocks_count - start);
+            self.read_block(alloc.groups[group].block_bitmap, &mut bitmap)?;
+            let bit = match Volume::take_bit(&mut bitmap, 0, limit) {
+                Some(bit) => bit,
+                None => continue,
+            };
+            let block = start + bit;
+            self.write_block(block, &self.block_buf())?;
+            self.write_block(alloc.groups[group].block_bitmap, &bitmap)?;
+            alloc.groups[group].free_blocks_count -= 1;
+            alloc.sb.free_blocks_count -= 1;
+            alloc.dirty = true;
+            self.write_group(&alloc, group)?;
+            return Ok(block);
+        }
+        Err(OsError::NoSpace)
+    }
+
+    fn free_block(&self, block: u32) -> Result<(), OsError> {
+        if block < self.first_data_block || block >= self.blocks_count {
+            return Err(OsError::IoErrorInvalidData);
+        }
+        let mut alloc = self.alloc.lock();
+        let group = ((block - self.first_data_block) / self.blocks_per_group) as usize;
+        let bit = (block - self.first_data_block) % self.blocks_per_group;
+        let mut bitmap = self.block_buf();
+        self.read_block(alloc.groups[group].block_bitmap, &mut bitmap)?;
+        bitmap[(bit / 8) as usize] &= !(1 << (bit % 8));
+        self.write_block(alloc.groups[group].block_bitmap, &bitmap)?;
+        alloc.groups[group].free_blocks_count += 1;
+        alloc.sb.free_blocks_count += 1;
+        alloc.dirty = true;
+        self.write_group(&alloc, group)
+    }
+
+    /// Takes a free inode, preferably in group `goal`
+    fn alloc_inode(&self, goal: u32, dir: bool) -> Result<u32, OsError> {
+        if self.read_only {
+            return Err(OsError::ReadOnly);
+        }
+        let mut alloc = self.alloc.lock();
+        let mut bitmap = self.block_buf();
+        for group in self.groups_from(&alloc, goal) {
+            if alloc.groups[group].free_inodes_count == 0 {
+                continue;
+            }
+            let base = group as u32 * self.inodes_per_group;
+            // Reserved inodes are never handed out
+            let first = self.first_ino.saturating_sub(base + 1);
+            self.read_block(alloc.groups[group].inode_bitmap, &mut bitmap)?;
+            let bit = match Volume::take_bit(&mut bitmap, first, self.inodes_per_group) {
+                Some(bit) => bit,
+                None => continue,
+            };
+            self.write_block(alloc.groups[group].inode_bitmap, &bitmap)?;
+            alloc.groups[group].free_inodes_count -= 1;
+            if dir {
+                alloc.groups[group].used_dirs_count += 1;
+            }
+            alloc.sb.free_inodes_count -= 1;
+            alloc.dirty = true;
+            self.write_group(&alloc, group)?;
+            return Ok(base + bit + 1);
+        }
+        Err(OsError::NoSpace)
+    }
+
+    fn free_inode(&self, ino: u32, dir: bool) -> Result<(), OsError> {
+        let mut alloc = self.alloc.lock();
+        let group = self.group_of(ino) as usize;
+        let bit = (ino - 1) % self.inodes_per_group;
+        let mut bitmap = self.block_buf();
+        self.read_block(alloc.groups[group].inode_bitmap, &mut bitmap)?;
+        bitmap[(bit / 8) as usize] &= !(1 << (bit % 8));
+        self.write_block(alloc.groups[group].inode_bitmap, &bitmap)?;
+        alloc.groups[group].free_inodes_count += 1;
+        if dir {
+            alloc.groups[group].used_dirs_count -= 1;
+        }
+        alloc.sb.free_inodes_count += 1;
+        alloc.dirty = true;
+        self.write_group(&alloc, group)
+    }
+
+    /// The inode `ino`, shared with earlier lookups still in use
+    fn inode(self: &Arc<Self>, ino: u32) -> Result<Arc<Ext2Inode>, OsError> {
+        if let Some(inode) = without_interrupts(|| self.inodes.lock().get(&ino).and_then(Weak::upgrade)) {
+            return Ok(inode);
+        }
+        let raw = self.read_inode(ino)?;
+        Ok(without_interrupts(|| {
+            let mut inodes = self.inodes.lock();
+            // Someone else may have read it meanwhile
+            if let Some(inode) = inodes.get(&ino).and_then(Weak::upgrade) {
+                return inode;
+            }
+            inodes.retain(|_, inode| inode.strong_count() > 0);
+            let inode = Arc::new(Ext2Inode::new(self.clone(), ino, raw));
+            inodes.insert(ino, Arc::downgrade(&inode));
+            inode
+        }))
+    }
+}
+
+pub struct Ext2 {
+    vol: Arc<Volume>,
+}
+
+impl Ext2 {
+    /// Opens the ext2 volume on `dev`
+    pub fn new(dev: Arc<dyn BlockDevice + Send + Sync>) -> Result<Ext2, OsError> {
+        let sector_size = dev.sector_size();
+        let mut buf = vec![0u8; core::mem::size_of::<Superblock>()];
+        read_bytes(&*dev, SUPERBLOCK_OFFSET, &mut buf)?;
+        let sb: Superblock = from_bytes(&buf);
+
+        let block_size = sb.block_size();
+        let inode_size = sb.inode_size() as u64;
+        let valid = sb.magic == EXT2_MAGIC
+            && sb.log_block_size <= 2
+            && block_size % sector_size == 0
+            && sb.blocks_per_group > 0 && sb.blocks_per_group as u64 <= block_size * 8
+            && sb.inodes_per_group > 0 && sb.inodes_per_group as u64 <= block_size * 8
+            && sb.blocks_count > sb.first_data_block
+            && inode_size >= GOOD_OLD_INODE_SIZE as u64 && inode_size.is_power_of_two() && inode_size <= block_size;
+        if !valid {
+            return Err(OsError::IoErrorInvalidData);
+        }
+        let (incompat, ro_compat) = sb.features();
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            warn!("[EXT2] Unsupported incompatible features {:#x}", incompat & !INCOMPAT_SUPPORTED);
+            return Err(OsError::IoErrorInvalidData);
+        }
+        let mut read_only = ro_compat & !RO_COMPAT_SUPPORTED != 0;
+        if read_only {
+            warn!("[EXT2] Unsupported features {:#x}, opening read-only", ro_compat & !RO_COMPAT_SUPPORTED);
+        }
+        if dev.read_only() {
+            info!("[EXT2] The device cannot be written, opening read-only");
+            read_only = true;
+        }
+
+        let gdt_block = sb.first_data_block as u64 + 1;
+        let group_count = sb.group_count() as usize;
+        let mut table = vec![0u8; group_count * core::mem::size_of::<GroupDescriptor>()];
+        read_bytes(&*dev, gdt_block * block_size, &mut table)?;
+        let groups = table.chunks_exact(core::mem::size_of::<GroupDescriptor>())
+            .map(from_bytes::<GroupDescriptor>)
+            .collect();
+
+        let vol = Volume {
+            dev,
+            sector_size,
+            block_size,
+            inode_size,
+            inodes_per_group: sb.inodes_per_group,
+            blocks_per_group: sb.blocks_per_group,
+            first_data_block: sb.first_data_block,
+            blocks_count: sb.blocks_count,
+            inodes_count: min(sb.inodes_count, sb.inodes_per_group * sb.group_count()),
+            first_ino: sb.first_ino(),
+            gdt_block,
+            filetype: incompat & INCOMPAT_FILETYPE != 0,
+            large_file: ro_compat & RO_COMPAT_LARGE_FILE != 0,
+            read_only,
+            alloc: Mutex::new(Allocation { sb, groups, dirty: false }),
+            inodes: SpinMutex::new("EXT2_INODES", HashMap::new()),
+            orphans: WorkQueue::new(),
+        };
+
+        let name = {
+            let name = sb.volume_name;
+            let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+            String::from_utf8_lossy(&name[..len]).into_owned()
+        };
+        info!("[EXT2] Opened volume {}, {} blocks of {} bytes in {} groups, {} inodes",
+              name, { sb.blocks_count }, block_size, group_count, vol.inodes_count);
+        Ok(Ext2 { vol: Arc::new(vol) })
+    }
+}
+
+impl FileSystem for Ext2 {
+    fn name(&self) -> &str {
+        "ext2"
+    }
+
+    fn root(&self) -> Result<Arc<dyn Inode>, OsError> {
+        Ok(self.vol.inode(ROOT_INO)?)
+    }
+
+    fn sync(&self) -> Result<(), OsError> {
+        self.vol.reclaim();
+        self.vol.flush_superblock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+    use kernel_api::FileType;
+
+    /// Built by `testdata/mkimages.sh`
+    const IMAGE_1K: &[u8] = include_bytes!("testdata/1k.img");
+    const IMAGE_4K: &[u8] = include_bytes!("testdata/4k.img");
+
+    const SECTOR_SIZE: usize = 512;
+
+    /// A copy of an image in memory
+    struct MemoryDevice {
+        data: Mutex<Vec<u8>>,
+        read_only: bool,
+    }
+
+    impl BlockDevice for MemoryDevice {
+        fn sector_count(&self) -> Option<u64> {
+            Some((self.data.lock().len() / SECTOR_SIZE) as u64)
+        }
+
+        fn read_sector(&self, sector: u64, buf: &mut [u8]) -> core_io::Result<usize> {
+            let data = self.data.lock();
+            let start = sector as usize * SECTOR_SIZE;
+            if start + SECTOR_SIZE > data.len() {
+                return Err(core_io::error::ErrorKind::UnexpectedEof.into());
+            }
+            buf[..SECTOR_SIZE].copy_from_slice(&data[start..start + SECTOR_SIZE]);
+            Ok(SECTOR_SIZE)
+        }
+
+        fn write_sector(&self, sector: u64, buf: &[u8]) -> core_io::Result<usize> {
+            let mut data = self.data.lock();
+            let start = sector as usize * SECTOR_SIZE;
+            if start + SECTOR_SIZE > data.len() {
+                return Err(core_io::error::ErrorKind::UnexpectedEof.into());
+            }
+            data[start..start + SECTOR_SIZE].copy_from_slice(&buf[..SECTOR_SIZE]);
+            Ok(SECTOR_SIZE)
+        }
+
+        fn read_only(&self) -> bool {
+            self.read_only
+        }
+    }
+
+    fn open(image: &[u8]) -> (Arc<MemoryDevice>, Ext2) {
+        let dev = Arc::new(MemoryDevice { data: Mutex::new(image.to_vec()), read_only: false });
+        let fs = Ext2::new(dev.clone()).expect("the image opens");
+        (dev, fs)
+    }
+
+    fn disk_superblock(dev: &MemoryDevice) -> Superblock {
+        from_bytes(&dev.data.lock()[SUPERBLOCK_OFFSET as usize..])
+    }
+
+    fn resolve(fs: &Ext2, path: &str) -> Arc<dyn Inode> {
+        path.split('/').fold(fs.root().unwrap(), |dir, name| dir.lookup(name).unwrap())
+    }
+
+    fn read_all(inode: &dyn Inode) -> Vec<u8> {
+        let mut data = vec![0u8; inode.metadata().unwrap().size as usize];
+        assert_eq!(inode.read_at(0, &mut data).unwrap(), data.len());
+        data
+    }
+
+    /// Contents of `dir/big`
+    fn big() -> Vec<u8> {
+        (0..40000).step_by(8).flat_map(|i| format!("{:07}\n", i).into_bytes()).collect()
+    }
+
+    fn bit_set(vol: &Volume, bitmap: u32, bit: u32) -> bool {
+        let mut data = vol.block_buf();
+        vol.read_block(bitmap, &mut data).unwrap();
+        data[(bit / 8) as usize] & 1 << (bit % 8) != 0
+    }
+
+    fn block_in_use(vol: &Volume, block: u32) -> bool {
+        let group = ((block - vol.first_data_block) / vol.blocks_per_group) as usize;
+        let bitmap = vol.alloc.lock().groups[group].block_bitmap;
+        bit_set(vol, bitmap, (block - vol.first_data_block) % vol.blocks_per_group)
+    }
+
+    fn inode_in_use(vol: &Volume, ino: u32) -> bool {
+        let bitmap = vol.alloc.lock().groups[vol.group_of(ino) as usize].inode_bitmap;
+        bit_set(vol, bitmap, (ino - 1) % vol.inodes_per_group)
+    }
+
+    fn free_counts(vol: &Volume) -> (u32, u32) {
+        let alloc = vol.alloc.lock();
+        let blocks = alloc.groups.iter().map(|g| g.free_blocks_count as u32).sum();
+        let inodes = alloc.groups.iter().map(|g| g.free_inodes_count as u32).sum();
+        (blocks, inodes)
+    }
+
+    #[test]
+    fn test_superblock() {
+        let (_, fs) = open(IMAGE_1K);
+        let vol = &fs.vol;
+        assert_eq!((vol.block_size, vol.blocks_count, vol.first_data_block), (1024, 512, 1));
+        assert_eq!((vol.blocks_per_group, vol.inodes_per_group, vol.inodes_count), (256, 16, 32));
+        assert_eq!((vol.inode_size, vol.first_ino, vol.gdt_block), (128, 11, 2));
+        assert!(vol.filetype && vol.large_file && !vol.read_only);
+        assert_eq!(&vol.alloc.lock().sb.volume_name[..8], b"small1k\0");
+
+        let (_, fs) = open(IMAGE_4K);
+        let vol = &fs.vol;
+        assert_eq!((vol.block_size, vol.blocks_count, vol.first_data_block), (4096, 64, 0));
+        assert_eq!((vol.inode_size, vol.gdt_block), (256, 1));
+        assert!(!vol.filetype && !vol.read_only);
+    }
+
+    #[test]
+    fn test_bad_superblock() {
+        let mut image = IMAGE_1K.to_vec();
+        image[SUPERBLOCK_OFFSET as usize + 56] = 0;
+        let dev = Arc::new(MemoryDevice { data: Mutex::new(image), read_only: false });
+        assert_eq!(Ext2::new(dev).err(), Some(OsError::IoErrorInvalidData));
+
+        // 64 KiB blocks
+        let mut image = IMAGE_1K.to_vec();
+        image[SUPERBLOCK_OFFSET as usize + 24] = 6;
+        let dev = Arc::new(MemoryDevice { data: Mutex::new(image), read_only: false });
+        assert_eq!(Ext2::new(dev).err(), Some(OsError::IoErrorInvalidData));
+
+        // An unknown incompatible feature
+        let mut image = IMAGE_1K.to_vec();
+        image[SUPERBLOCK_OFFSET as usize + 96] |= 0x80;
+        let dev = Arc::new(MemoryDevice { data: Mutex::new(image), read_only: false });
+        assert_eq!(Ext2::new(dev).err(), Some(OsError::IoErrorInvalidData));
+    }
+
+    #[test]
+    fn test_read_only_device() {
+        let dev = Arc::new(MemoryDevice { data: Mutex::new(IMAGE_1K.to_vec()), read_only: true });
+        let fs = Ext2::new(dev.clone()).unwrap();
+        assert!(fs.vol.read_only);
+        assert_eq!(resolve(&fs, "hello.txt").write_at(0, b"x").err(), Some(OsError::ReadOnly));
+        assert_eq!(fs.root().unwrap().create("new", FileType::Regular).err(), Some(OsError::ReadOnly));
+        fs.sync().unwrap();
+        assert_eq!(&dev.data.lock()[..], IMAGE_1K);
+    }
+
+    #[test]
+    fn test_group_descriptors() {
+        let (_, fs) = open(IMAGE_1K);
+        let vol = &fs.vol;
+        let alloc = vol.alloc.lock();
+        assert_eq!(alloc.groups.len(), 2);
+        for (i, group) in alloc.groups.iter().enumerate() {
+            let start = vol.first_data_block + i as u32 * vol.blocks_per_group;
+            let range = start..start + vol.blocks_per_group;
+            assert!(range.contains(&{ group.block_bitmap }));
+            assert!(range.contains(&{ group.inode_bitmap }));
+            assert!(range.contains(&{ group.inode_table }));
+        }
+        let free_blocks: u32 = alloc.groups.iter().map(|g| g.free_blocks_count as u32).sum();
+        let free_inodes: u32 = alloc.groups.iter().map(|g| g.free_inodes_count as u32).sum();
+        let dirs: u32 = alloc.groups.iter().map(|g| g.used_dirs_count as u32).sum();
+        assert_eq!((free_blocks, free_inodes), ({ alloc.sb.free_blocks_count }, { alloc.sb.free_inodes_count }));
+        assert_eq!((free_blocks, free_inodes, dirs), (442, 16, 3));
+    }
+
+    #[test]
+    fn test_inodes() {
+        for &image in &[IMAGE_1K, IMAGE_4K] {
+            let (_, fs) = open(image);
+            let root = fs.root().unwrap();
+            let metadata = root.metadata().unwrap();
+            assert_eq!((metadata.ino, metadata.kind, metadata.nlink), (2, FileType::Directory, 4));
+
+            let hello = resolve(&fs, "hello.txt");
+            let metadata = hello.metadata().unwrap();
+            assert_eq!((metadata.kind, metadata.size, metadata.mode), (FileType::Regular, 13, 0o644));
+            assert_eq!(read_all(&*hello), b"Hello, ext2!\n");
+
+            assert_eq!(read_all(&*resolve(&fs, "dir/big")), big());
+            assert_eq!(resolve(&fs, "link").readlink().unwrap(), "hello.txt");
+            let long = (0..10).map(|i| format!("directory{:02}", i)).collect::<Vec<_>>().join("/");
+            assert_eq!(resolve(&fs, "long_link").readlink().unwrap(), long);
+
+            let mut entries = Vec::new();
+            while let Some(entry) = root.readdir(entries.len()).unwrap() {
+                entries.push((entry.name, entry.kind));
+            }
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            assert_eq!(entries, [
+                ("dir".to_string(), FileType::Directory),
+                ("hello.txt".to_string(), FileType::Regular),
+                ("link".to_string(), FileType::Symlink),
+                ("long_link".to_string(), FileType::Symlink),
+                ("lost+found".to_string(), FileType::Directory),
+            ]);
+            assert_eq!(root.lookup("missing").err(), Some(OsError::NoEntry));
+        }
+    }
+
+    #[test]
+    fn test_raw_inode() {
+        let (_, fs) = open(IMAGE_1K);
+        let vol = &fs.vol;
+        let ino = resolve(&fs, "dir/big").metadata().unwrap().ino as u32;
+        let raw = vol.read_inode(ino).unwrap();
+        assert_eq!((raw.kind(), raw.mode & !S_IFMT, { raw.links_count }), (S_IFREG, 0o644, 1));
+        assert_eq!(raw.size(), 40000);
+        // 40 data blocks, the last 28 through the single indirect block
+        assert_eq!({ raw.blocks }, 41 * 2);
+        assert_eq!({ raw.block[DIRECT_BLOCKS + 1] }, 0);
+        let mut pointers = vol.block_buf();
+        vol.read_block(raw.block[DIRECT_BLOCKS], &mut pointers).unwrap();
+        let pointers: Vec<u32> = pointers.chunks_exact(4).map(from_bytes::<u32>).collect();
+        assert!(pointers[..28].iter().all(|&p| p != 0));
+        assert!(pointers[28..].iter().all(|&p| p == 0));
+
+        // A short target is kept in the block pointers
+        let link = vol.read_inode(resolve(&fs, "link").metadata().unwrap().ino as u32).unwrap();
+        assert_eq!(({ link.blocks }, &as_bytes(&{ link.block })[..9]), (0, &b"hello.txt"[..]));
+        let long_link = vol.read_inode(resolve(&fs, "long_link").metadata().unwrap().ino as u32).unwrap();
+        assert_eq!({ long_link.blocks }, 2);
+    }
+
+    #[test]
+    fn test_take_bit() {
+        let mut bitmap = [0xFF, 0x0B];
+        assert_eq!(Volume::take_bit(&mut bitmap, 0, 16), Some(10));
+        assert_eq!(bitmap, [0xFF, 0x0F]);
+        assert_eq!(Volume::take_bit(&mut bitmap, 0, 12), None);
+        // Bits before `first` are reserved
+        let mut bitmap = [0x00];
+        assert_eq!(Volume::take_bit(&mut bitmap, 3, 8), Some(3));
+        assert_eq!(bitmap, [0x08]);
+    }
+
+    #[test]
+    fn test_allocation() {
+        let (dev, fs) = open(IMAGE_1K);
+        let vol = fs.vol.clone();
+        let root = fs.root().unwrap();
+        let (free_blocks, free_inodes) = free_counts(&vol);
+
+        let file = root.create("new", FileType::Regular).unwrap();
+        let data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        assert_eq!(file.write_at(0, &data).unwrap(), data.len());
+        assert_eq!(read_all(&*file), data);
+        let ino = file.metadata().unwrap().ino as u32;
+        let raw = vol.read_inode(ino).unwrap();
+        let blocks = &{ raw.block }[..3];
+        assert!(inode_in_use(&vol, ino));
+        assert!(blocks.iter().all(|&b| block_in_use(&vol, b)));
+        assert_eq!(free_counts(&vol), (free_blocks - 3, free_inodes - 1));
+
+        // The superblock is only written on sync
+        assert_eq!({ disk_superblock(&dev).free_blocks_count }, free_blocks);
+        fs.sync().unwrap();
+        assert_eq!({ disk_superblock(&dev).free_blocks_count }, free_blocks - 3);
+        assert_eq!({ disk_superblock(&dev).free_inodes_count }, free_inodes - 1);
+
+        // Still in use after the unlink
+        root.unlink("new").unwrap();
+        assert_eq!(root.lookup("new").err(), Some(OsError::NoEntry));
+        assert_eq!(read_all(&*file), data);
+        assert!(inode_in_use(&vol, ino));
+        drop(file);
+        fs.sync().unwrap();
+        assert!(!inode_in_use(&vol, ino));
+        assert!(blocks.iter().all(|&b| !block_in_use(&vol, b)));
+        assert_eq!(free_counts(&vol), (free_blocks, free_inodes));
+        assert_eq!({ disk_superblock(&dev).free_blocks_count }, free_blocks);
+        let raw = vol.read_inode(ino).unwrap();
+        assert_eq!(({ raw.links_count }, raw.size()), (0, 0));
+        assert_ne!({ raw.dtime }, 0);
+    }
+
+    #[test]
+    fn test_directories() {
+        let (dev, fs) = open(IMAGE_4K);
+        let root = fs.root().unwrap();
+        let (free_blocks, free_inodes) = free_counts(&fs.vol);
+
+        let sub = root.create("sub", FileType::Directory).unwrap();
+        assert_eq!(root.metadata().unwrap().nlink, 5);
+        sub.create("file", FileType::Regular).unwrap().write_at(0, b"data").unwrap();
+        sub.symlink("to_big", "../dir/big").unwrap();
+        assert_eq!(root.create("sub", FileType::Regular).err(), Some(OsError::FileExists));
+        assert_eq!(root.unlink("sub").err(), Some(OsError::DirectoryNotEmpty));
+        fs.sync().unwrap();
+
+        // Everything is on disk
+        let copy = dev.data.lock().clone();
+        let (_, reopened) = open(&copy);
+        assert_eq!(read_all(&*resolve(&reopened, "sub/file")), b"data");
+        assert_eq!(resolve(&reopened, "sub/to_big").readlink().unwrap(), "../dir/big");
+        drop(reopened);
+
+        sub.unlink("file").unwrap();
+        sub.unlink("to_big").unwrap();
+        root.unlink("sub").unwrap();
+        assert_eq!(root.metadata().unwrap().nlink, 4);
+        assert_eq!(sub.create("late", FileType::Regular).err(), Some(OsError::NoEntry));
+        drop(sub);
+        fs.sync().unwrap();
+        assert_eq!(free_counts(&fs.vol), (free_blocks, free_inodes));
+        let dirs: u32 = fs.vol.alloc.lock().groups.iter().map(|g| g.used_dirs_count as u32).sum();
+        assert_eq!(dirs, 3);
+    }
+}
@@ -0,0 +1,178 @@
+//! On-disk structures of ext2, all little endian
+
+/// Byte offset of the superblock from the start of the volume
+pub const SUPERBLOCK_OFFSET: u64 = 1024;
+pub const EXT2_MAGIC: u16 = 0xEF53;
+
+/// Inode numbers of reserved inodes
+pub const ROOT_INO: u32 = 2;
+/// First inode that is not reserved on revision 0 volumes
+pub const GOOD_OLD_FIRST_INO: u32 = 11;
+pub const GOOD_OLD_INODE_SIZE: u16 = 128;
+
+/// Incompatible features, a volume using others cannot be read
+pub const INCOMPAT_FILETYPE: u32 = 0x0002;
+pub const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE;
+
+/// Read-only compatible features, a volume using others can only be read
+pub const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+pub const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+pub const RO_COMPAT_SUPPORTED: u32 = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE;
+
+/// Mode bits of the kind of file
+pub const S_IFMT: u16 = 0xF000;
+pub const S_IFIFO: u16 = 0x1000;
+pub const S_IFCHR: u16 = 0x2000;
+pub const S_IFDIR: u16 = 0x4000;
+pub const S_IFBLK: u16 = 0x6000;
+pub const S_IFREG: u16 = 0x8000;
+pub const S_IFLNK: u16 = 0xA000;
+
+/// Inode flag of a directory with a hashed index. The index is hidden in
+/// entries that look unused, so it is dropped when the directory changes.
+pub const INDEX_FL: u32 = 0x1000;
+
+/// Block pointers in an inode, the direct ones followed by the single,
+/// double and triple indirect one
+pub const DIRECT_BLOCKS: usize = 12;
+pub const BLOCK_POINTERS: usize = 15;
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct Superblock {
+    pub inodes_count: u32,
+    pub blocks_count: u32,
+    pub r_blocks_count: u32,
+    pub free_blocks_count: u32,
+    pub free_inodes_count: u32,
+    pub first_data_block: u32,
+    pub log_block_size: u32,
+    pub log_frag_size: u32,
+    pub blocks_per_group: u32,
+    pub frags_per_group: u32,
+    pub inodes_per_group: u32,
+    pub mtime: u32,
+    pub wtime: u32,
+    pub mnt_count: u16,
+    pub max_mnt_count: u16,
+    pub magic: u16,
+    pub state: u16,
+    pub errors: u16,
+    pub minor_rev_level: u16,
+    pub lastcheck: u32,
+    pub checkinterval: u32,
+    pub creator_os: u32,
+    pub rev_level: u32,
+    pub def_resuid: u16,
+    pub def_resgid: u16,
+    // Revision 1
+    pub first_ino: u32,
+    pub inode_size: u16,
+    pub block_group_nr: u16,
+    pub feature_compat: u32,
+    pub feature_incompat: u32,
+    pub feature_ro_compat: u32,
+    pub uuid: [u8; 16],
+    pub volume_name: [u8; 16],
+    _last_mounted: [u8; 64],
+    _rest: [u8; 824],
+}
+
+const_assert_size!(Superblock, 1024);
+
+impl Superblock {
+    pub fn block_size(&self) -> u64 {
+        1024 << self.log_block_size
+    }
+
+    pub fn first_ino(&self) -> u32 {
+        match self.rev_level {
+            0 => GOOD_OLD_FIRST_INO,
+            _ => self.first_ino,
+        }
+    }
+
+    pub fn inode_size(&self) -> u16 {
+        match self.rev_level {
+            0 => GOOD_OLD_INODE_SIZE,
+            _ => self.inode_size,
+        }
+    }
+
+    /// Feature bits that are only set on revision 1
+    pub fn features(&self) -> (u32, u32) {
+        match self.rev_level {
+            0 => (0, 0),
+            _ => (self.feature_incompat, self.feature_ro_compat),
+        }
+    }
+
+    pub fn group_count(&self) -> u32 {
+        let blocks = self.blocks_count - self.first_data_block;
+        (blocks + self.blocks_per_group - 1) / self.blocks_per_group
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Default)]
+pub struct GroupDescriptor {
+    pub block_bitmap: u32,
+    pub inode_bitmap: u32,
+    pub inode_table: u32,
+    pub free_blocks_count: u16,
+    pub free_inodes_count: u16,
+    pub used_dirs_count: u16,
+    _pad: u16,
+    _reserved: [u8; 12],
+}
+
+const_assert_size!(GroupDescriptor, 32);
+
+/// The part of an inode every revision has, larger inodes keep the rest
+/// untouched
+#[repr(C, packed)]
+#[derive(Copy, Clone, Default)]
+pub struct RawInode {
+    pub mode: u16,
+    pub uid: u16,
+    pub size: u32,
+    pub atime: u32,
+    pub ctime: u32,
+    pub mtime: u32,
+    pub dtime: u32,
+    pub gid: u16,
+    pub links_count: u16,
+    /// Blocks in use, in 512 byte units
+    pub blocks: u32,
+    pub flags: u32,
+    pub osd1: u32,
+    pub block: [u32; BLOCK_POINTERS],
+    pub generation: u32,
+    pub file_acl: u32,
+    /// Upper 32 bits of the size of regular files with `RO_COMPAT_LARGE_FILE`
+    pub size_high: u32,
+    pub faddr: u32,
+    pub osd2: [u8; 12],
+}
+
+const_assert_size!(RawInode, 128);
+
+impl RawInode {
+    pub fn kind(&self) -> u16 {
+        self.mode & S_IFMT
+    }
+
+    pub fn size(&self) -> u64 {
+        match self.kind() {
+            S_IFREG => (self.size_high as u64) << 32 | self.size as u64,
+            _ => self.size as u64,
+        }
+    }
+
+    pub fn set_size(&mut self, size: u64) {
+        self.size = size as u32;
+        if self.kind() == S_IFREG {
+            self.size_high = (size >> 32) as u32;
+        }
+    }
+}
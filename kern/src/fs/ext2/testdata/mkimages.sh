@@ -0,0 +1,42 @@
+#!/bin/sh
+# Builds the ext2 images used by the tests in ../mod.rs with e2fsprogs.
+# Times, UUID and hash seed are fixed so the images come out the same.
+set -e
+cd "$(dirname "$0")"
+export E2FSPROGS_FAKE_TIME=1700000000
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+
+printf 'Hello, ext2!\n' > "$tmp/hello.txt"
+# 40000 bytes, more than the direct blocks of 1 KiB blocks reach
+i=0
+while [ $i -lt 40000 ]; do
+    printf '%07d\n' $i
+    i=$((i + 8))
+done > "$tmp/big"
+long=directory00
+for i in 01 02 03 04 05 06 07 08 09; do
+    long=$long/directory$i
+done
+
+mkimage() {
+    image=$1
+    blocks=$2
+    shift 2
+    rm -f "$image"
+    mke2fs -q -F -t ext2 -m 0 -O ^resize_inode -U 0b1f3c2e-6f0a-4e59-9a57-3b8f0c1d2e4f \
+        -E hash_seed=5d2b8b7c-1e2f-4a3b-8c4d-5e6f7a8b9c0d,root_owner=0:0 "$@" "$image" "$blocks"
+    debugfs -w -f - "$image" > /dev/null <<END
+mkdir dir
+write $tmp/hello.txt hello.txt
+write $tmp/big dir/big
+symlink link hello.txt
+symlink long_link $long
+END
+    e2fsck -fn "$image" > /dev/null
+}
+
+# Two groups of 256 blocks of 1 KiB, 128 byte inodes
+mkimage 1k.img 512 -b 1024 -g 256 -N 32 -I 128 -L small1k
+# One group of 4 KiB blocks, 256 byte inodes, no file types in entries
+mkimage 4k.img 64 -b 4096 -N 32 -I 256 -O ^filetype -L small4k
@@ -0,0 +1,111 @@
+use core::fmt;
+use core::fmt::{Debug, Formatter};
+use kernel_api::OsError;
+use crate::storage::block::device::BlockDevice;
+
+/// The boot sector of a FAT32 volume, the BIOS parameter block (BPB) and
+/// the FAT32 extended BPB (EBPB) following it.
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct BiosParameterBlock {
+    jump: [u8; 3],
+    pub oem_id: [u8; 8],
+    pub bytes_per_sector: u16,
+    pub sectors_per_cluster: u8,
+    pub reserved_sectors: u16,
+    pub num_fats: u8,
+    /// Entries of the fixed root directory, 0 on FAT32
+    pub root_entries: u16,
+    pub total_sectors_16: u16,
+    pub media: u8,
+    /// 0 on FAT32, see `fat_size_32`
+    pub fat_size_16: u16,
+    pub sectors_per_track: u16,
+    pub heads: u16,
+    pub hidden_sectors: u32,
+    pub total_sectors_32: u32,
+    // EBPB
+    pub fat_size_32: u32,
+    pub ext_flags: u16,
+    pub fs_version: u16,
+    pub root_cluster: u32,
+    pub fs_info_sector: u16,
+    pub backup_boot_sector: u16,
+    _reserved: [u8; 12],
+    pub drive_number: u8,
+    _reserved1: u8,
+    /// 0x29 if the volume id, label and type below are valid
+    pub boot_signature: u8,
+    pub volume_id: u32,
+    pub volume_label: [u8; 11],
+    pub fs_type: [u8; 8],
+    code: [u8; 420],
+    signature: [u8; 2], // 0x55 0xAA
+}
+
+const_assert_size!(BiosParameterBlock, 512);
+
+impl BiosParameterBlock {
+    /// Reads and checks the boot sector of `device`. Fails with
+    /// `IoErrorInvalidData` if it does not describe a FAT32 volume that can
+    /// be used with the sector size of `device`.
+    pub fn from<T: BlockDevice + ?Sized>(device: &T) -> Result<BiosParameterBlock, OsError> {
+        let mut sector = vec![0u8; device.sector_size() as usize];
+        if sector.len() < 512 {
+            return Err(OsError::IoErrorInvalidData);
+        }
+        device.read_sector(0, &mut sector).map_err(|_| OsError::IoError)?;
+        let bpb = unsafe { core::ptr::read_unaligned(sector.as_ptr() as *const BiosParameterBlock) };
+
+        let valid = bpb.signature == [0x55, 0xAA]
+            && bpb.bytes_per_sector as u64 == device.sector_size()
+            && bpb.sectors_per_cluster.is_power_of_two()
+            && bpb.num_fats > 0
+            && bpb.root_entries == 0
+            && bpb.fat_size_16 == 0
+            && bpb.fat_size_32 != 0
+            && bpb.total_sectors() > bpb.data_start();
+        if valid {
+            Ok(bpb)
+        } else {
+            Err(OsError::IoErrorInvalidData)
+        }
+    }
+
+    pub fn total_sectors(&self) -> u64 {
+        match self.total_sectors_16 {
+            0 => self.total_sectors_32 as u64,
+            n => n as u64,
+        }
+    }
+
+    /// First sector of the data region, which starts with cluster 2
+    pub fn data_start(&self) -> u64 {
+        self.reserved_sectors as u64 + self.num_fats as u64 * self.fat_size_32 as u64
+    }
+
+    pub fn label(&self) -> Option<&[u8]> {
+        if self.boot_signature != 0x29 {
+            return None;
+        }
+        let label = &self.volume_label;
+        let len = label.iter().rposition(|&c| c != b' ').map_or(0, |i| i + 1);
+        Some(&label[..len])
+    }
+}
+
+impl Debug for BiosParameterBlock {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BiosParameterBlock")
+            .field("bytes_per_sector", &{ self.bytes_per_sector })
+            .field("sectors_per_cluster", &{ self.sectors_per_cluster })
+            .field("reserved_sectors", &{ self.reserved_sectors })
+            .field("num_fats", &{ self.num_fats })
+            .field("total_sectors", &self.total_sectors())
+            .field("fat_size", &{ self.fat_size_32 })
+            .field("root_cluster", &{ self.root_cluster })
+            .field("fs_info_sector", &{ self.fs_info_sector })
+            .field("volume_id", &{ self.volume_id })
+            .finish()
+    }
+}
@@ -0,0 +1,416 @@
+//! FAT directory entries
+//!
+//! A directory is a file of 32 byte entries. Every file has a short entry
+//! holding its 8.3 name, attributes, first cluster and size. A long name is
+//! stored in the entries right before it, 13 UTF-16 units each, the last
+//! part first. Every long name entry carries a checksum of the short name it
+//! belongs to, so parts orphaned by an old driver are recognized.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub const ENTRY_SIZE: usize = 32;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+pub const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+/// First name byte of a free entry
+pub const FREE: u8 = 0xE5;
+/// First name byte of the entry after the last one in use
+pub const END: u8 = 0x00;
+/// Stored instead of a leading 0xE5 in a short name
+const KANJI_E5: u8 = 0x05;
+/// Order number flag of the first long name entry on disk
+const LAST_LONG_ENTRY: u8 = 0x40;
+/// Units of a long name in one entry
+const LONG_NAME_UNITS: usize = 13;
+/// Offsets of the name units in a long name entry
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_UNITS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// Windows NT flags for short names that are all lower case
+const NT_LOWER_BASE: u8 = 0x08;
+const NT_LOWER_EXT: u8 = 0x10;
+
+/// Creation and modification date of new entries, 1980-01-01
+const DEFAULT_DATE: u16 = 0x21;
+
+/// A file found in a directory
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    pub short: [u8; 11],
+    pub attr: u8,
+    pub cluster: u32,
+    pub size: u32,
+    /// Index of the short entry in the directory
+    pub slot: usize,
+    /// Long name entries before `slot` belonging to it
+    pub long_slots: usize,
+}
+
+impl Entry {
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    pub fn is_dot(&self) -> bool {
+        self.short[0] == b'.'
+    }
+}
+
+fn read_u16(b: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([b[offset], b[offset + 1]])
+}
+
+fn read_u32(b: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([b[offset], b[offset + 1], b[offset + 2], b[offset + 3]])
+}
+
+/// Checksum of a short name stored in its long name entries
+pub fn checksum(short: &[u8; 11]) -> u8 {
+    short.iter().fold(0u8, |sum, &c| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(c))
+}
+
+/// Display name of a short entry, `NAME.EXT` without padding
+fn short_name(raw: &[u8]) -> String {
+    let case = raw[12];
+    let part = |bytes: &[u8], lower: bool| -> String {
+        bytes.iter()
+            .take_while(|&&c| c != b' ')
+            .map(|&c| if lower { c.to_ascii_lowercase() as char } else { c as char })
+            .collect()
+    };
+    let mut base = [0u8; 8];
+    base.copy_from_slice(&raw[0..8]);
+    if base[0] == KANJI_E5 {
+        base[0] = FREE;
+    }
+    let mut name = part(&base, case & NT_LOWER_BASE != 0);
+    let ext = part(&raw[8..11], case & NT_LOWER_EXT != 0);
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(&ext);
+    }
+    name
+}
+
+/// Parts of a long name seen so far while walking the entries
+struct LongName {
+    units: Vec<u16>,
+    checksum: u8,
+    /// Order number of the next entry expected, counts down to 1
+    next: u8,
+    slots: usize,
+}
+
+/// Every file in the directory `data`, including `.`, `..` and the volume
+/// label
+pub fn parse(data: &[u8]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut long: Option<LongName> = None;
+
+    for (slot, raw) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+        match raw[0] {
+            END => break,
+            FREE => {
+                long = None;
+                continue;
+            },
+            _ => {},
+        }
+        let attr = raw[11];
+
+        if attr & ATTR_LONG_NAME == ATTR_LONG_NAME {
+            let order = raw[0] & !LAST_LONG_ENTRY;
+            if raw[0] & LAST_LONG_ENTRY != 0 && order > 0 {
+                let units = vec![0xFFFF; order as usize * LONG_NAME_UNITS];
+                long = Some(LongName { units, checksum: raw[13], next: order, slots: 0 });
+            }
+            long = match long.take() {
+                Some(mut l) if order > 0 && order == l.next && raw[13] == l.checksum => {
+                    let start = (order as usize - 1) * LONG_NAME_UNITS;
+                    for (i, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+                        l.units[start + i] = read_u16(raw, offset);
+                    }
+                    l.next -= 1;
+                    l.slots += 1;
+                    Some(l)
+                },
+                _ => None,
+            };
+            continue;
+        }
+
+        let mut short = [0u8; 11];
+        short.copy_from_slice(&raw[0..11]);
+        let (name, long_slots) = match long.take() {
+            Some(l) if l.next == 0 && l.checksum == checksum(&short) => {
+                let len = l.units.iter().position(|&u| u == 0 || u == 0xFFFF).unwrap_or(l.units.len());
+                let name: String = core::char::decode_utf16(l.units[..len].iter().cloned())
+                    .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+                    .collect();
+                (name, l.slots)
+            },
+            _ => (short_name(raw), 0),
+        };
+        entries.push(Entry {
+            name,
+            short,
+            attr,
+            cluster: cluster(raw),
+            size: size(raw),
+            slot,
+            long_slots,
+        });
+    }
+    entries
+}
+
+/// A short entry with zero timestamps besides the dates
+pub fn short_entry(short: &[u8; 11], attr: u8, cluster: u32, size: u32) -> [u8; ENTRY_SIZE] {
+    let mut raw = [0u8; ENTRY_SIZE];
+    raw[0..11].copy_from_slice(short);
+    raw[11] = attr;
+    raw[16..18].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+    raw[18..20].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+    raw[24..26].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+    set_cluster(&mut raw, cluster);
+    set_size(&mut raw, size);
+    raw
+}
+
+/// First cluster of the short entry `raw`
+pub fn cluster(raw: &[u8]) -> u32 {
+    (read_u16(raw, 20) as u32) << 16 | read_u16(raw, 26) as u32
+}
+
+/// Size of the short entry `raw`
+pub fn size(raw: &[u8]) -> u32 {
+    read_u32(raw, 28)
+}
+
+pub fn set_cluster(raw: &mut [u8], cluster: u32) {
+    raw[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    raw[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+pub fn set_size(raw: &mut [u8], size: u32) {
+    raw[28..32].copy_from_slice(&size.to_le_bytes());
+}
+
+/// The long name entries for `name` in the order they are stored
+pub fn long_entries(name: &str, checksum: u8) -> Vec<[u8; ENTRY_SIZE]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    let count = (units.len() + LONG_NAME_UNITS - 1) / LONG_NAME_UNITS;
+    // The name ends with a 0 unless it fills the last entry, padded with 0xFFFF
+    if units.len() % LONG_NAME_UNITS != 0 {
+        units.push(0);
+    }
+    units.resize(count * LONG_NAME_UNITS, 0xFFFF);
+
+    (1..=count).rev().map(|order| {
+        let mut raw = [0u8; ENTRY_SIZE];
+        raw[0] = order as u8 | if order == count { LAST_LONG_ENTRY } else { 0 };
+        raw[11] = ATTR_LONG_NAME;
+        raw[13] = checksum;
+        let part = &units[(order - 1) * LONG_NAME_UNITS..order * LONG_NAME_UNITS];
+        for (&unit, &offset) in part.iter().zip(LONG_NAME_OFFSETS.iter()) {
+            raw[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        raw
+    }).collect()
+}
+
+/// Whether `name` can be stored in a long name
+pub fn valid_name(name: &str) -> bool {
+    let units = name.encode_utf16().count();
+    units > 0 && units <= 255 && name != "." && name != ".."
+        && !name.chars().any(|c| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c))
+}
+
+fn valid_short_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"$%'-_@~`!(){}^#&".contains(&c)
+}
+
+/// `name` as a short name if it is a valid upper case 8.3 name
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    let valid = |part: &str, max: usize| part.len() <= max && part.bytes().all(valid_short_char);
+    if base.is_empty() || !valid(base, 8) || !valid(ext, 3) || (name.ends_with('.')) {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    Some(short)
+}
+
+/// The short name for a new file `name` and whether it needs a long name.
+/// Names that do not fit 8.3 get a generated `BASE~N.EXT` not `taken` yet.
+pub fn short_name_for<F: Fn(&[u8; 11]) -> bool>(name: &str, taken: F) -> Option<([u8; 11], bool)> {
+    if let Some(short) = exact_short_name(name) {
+        if !taken(&short) {
+            return Some((short, false));
+        }
+    }
+
+    let convert = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| {
+                let c = c.to_ascii_uppercase();
+                if c.is_ascii() && valid_short_char(c as u8) { c as u8 } else { b'_' }
+            })
+            .collect()
+    };
+    let trimmed = name.trim_start_matches('.');
+    let (base, ext) = match trimmed.rfind('.') {
+        Some(dot) => (convert(&trimmed[..dot]), convert(&trimmed[dot + 1..])),
+        None => (convert(trimmed), Vec::new()),
+    };
+
+    let mut short = [b' '; 11];
+    let ext_len = core::cmp::min(ext.len(), 3);
+    short[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+    for n in 1..1_000_000u32 {
+        let tail = format!("~{}", n);
+        let base_len = core::cmp::min(base.len(), 8 - tail.len());
+        short[..8].copy_from_slice(b"        ");
+        short[..base_len].copy_from_slice(&base[..base_len]);
+        short[base_len..base_len + tail.len()].copy_from_slice(tail.as_bytes());
+        if !taken(&short) {
+            return Some((short, true));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn directory(entries: &[[u8; ENTRY_SIZE]]) -> Vec<u8> {
+        let mut data: Vec<u8> = entries.iter().flat_map(|e| e.iter().cloned()).collect();
+        data.resize(data.len() + ENTRY_SIZE, 0);
+        data
+    }
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(checksum(b"README  TXT"), 0x73);
+        assert_eq!(checksum(b"ALONGF~1TXT"), 0x02);
+    }
+
+    #[test]
+    fn test_parse_short() {
+        let mut lower = short_entry(b"README  TXT", ATTR_ARCHIVE, 3, 100);
+        lower[12] = NT_LOWER_BASE | NT_LOWER_EXT;
+        let kanji = short_entry(b"\x05BC        ", ATTR_ARCHIVE, 0, 0);
+        let data = directory(&[
+            short_entry(b"FOO        ", ATTR_DIRECTORY, 0x12_3456, 0),
+            lower,
+            kanji,
+        ]);
+        let entries = parse(&data);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].name, "FOO");
+        assert!(entries[0].is_dir());
+        assert_eq!(entries[0].cluster, 0x12_3456);
+        assert_eq!(entries[1].name, "readme.txt");
+        assert_eq!((entries[1].cluster, entries[1].size, entries[1].slot), (3, 100, 1));
+        assert_eq!(entries[2].name, "\u{e5}BC");
+    }
+
+    #[test]
+    fn test_parse_free_and_end() {
+        let mut free = short_entry(b"GONE       ", ATTR_ARCHIVE, 0, 0);
+        free[0] = FREE;
+        let mut data = directory(&[
+            short_entry(b"A          ", ATTR_ARCHIVE, 0, 0),
+            free,
+            short_entry(b"B          ", ATTR_ARCHIVE, 0, 0),
+        ]);
+        // Stale entries after the end marker are not files
+        data.extend_from_slice(&short_entry(b"C          ", ATTR_ARCHIVE, 0, 0));
+        let names: Vec<String> = parse(&data).into_iter().map(|e| e.name).collect();
+        assert_eq!(names, ["A", "B"]);
+        assert_eq!(parse(&data)[1].slot, 2);
+    }
+
+    #[test]
+    fn test_long_name_round_trip() {
+        let name = "A long file name.txt";
+        let (short, long) = short_name_for(name, |_| false).unwrap();
+        assert_eq!(&short, b"ALONGF~1TXT");
+        assert!(long);
+
+        let mut raw = long_entries(name, checksum(&short));
+        assert_eq!(raw.len(), 2);
+        assert_eq!(raw[0][0], LAST_LONG_ENTRY | 2);
+        raw.push(short_entry(&short, ATTR_ARCHIVE, 5, 42));
+        let entries = parse(&directory(&raw));
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, name);
+        assert_eq!((entries[0].slot, entries[0].long_slots), (2, 2));
+        assert_eq!((entries[0].cluster, entries[0].size), (5, 42));
+
+        // Exactly 13 units have no terminator
+        let name = "thirteen char";
+        let mut raw = long_entries(name, checksum(b"THIRTE~1   "));
+        assert_eq!(raw.len(), 1);
+        raw.push(short_entry(b"THIRTE~1   ", ATTR_ARCHIVE, 0, 0));
+        assert_eq!(parse(&directory(&raw))[0].name, name);
+    }
+
+    #[test]
+    fn test_long_name_mismatch() {
+        // Left behind by a driver that only knows short names
+        let mut raw = long_entries("A long file name.txt", checksum(b"ALONGF~1TXT").wrapping_add(1));
+        raw.push(short_entry(b"ALONGF~1TXT", ATTR_ARCHIVE, 0, 0));
+        let entries = parse(&directory(&raw));
+        assert_eq!(entries[0].name, "ALONGF~1.TXT");
+        assert_eq!(entries[0].long_slots, 0);
+
+        // A part missing in the middle
+        let mut raw = long_entries("a name needing three entries", checksum(b"ANAMEN~1   "));
+        raw.remove(1);
+        raw.push(short_entry(b"ANAMEN~1   ", ATTR_ARCHIVE, 0, 0));
+        assert_eq!(parse(&directory(&raw))[0].name, "ANAMEN~1");
+    }
+
+    #[test]
+    fn test_short_name_for() {
+        assert_eq!(short_name_for("README.TXT", |_| false), Some((*b"README  TXT", false)));
+        assert_eq!(short_name_for("README.TXT", |s| s == b"README  TXT"), Some((*b"README~1TXT", true)));
+        assert_eq!(short_name_for("readme.txt", |_| false), Some((*b"README~1TXT", true)));
+        assert_eq!(short_name_for(".profile", |_| false), Some((*b"PROFIL~1   ", true)));
+        assert_eq!(short_name_for("a+b.tar.gz", |_| false), Some((*b"A_BTAR~1GZ ", true)));
+        let taken = |s: &[u8; 11]| s.starts_with(b"FILENA~") && s != b"FILENA~3EXT";
+        assert_eq!(short_name_for("filename.ext", taken), Some((*b"FILENA~3EXT", true)));
+        // The tail takes room from the base once it has more digits
+        let taken = |s: &[u8; 11]| &s[..8] != b"FILEN~10";
+        assert_eq!(short_name_for("filename.ext", taken), Some((*b"FILEN~10EXT", true)));
+    }
+
+    #[test]
+    fn test_valid_name() {
+        assert!(valid_name("hello world.txt"));
+        assert!(valid_name(".hidden"));
+        assert!(!valid_name(""));
+        assert!(!valid_name("."));
+        assert!(!valid_name(".."));
+        assert!(!valid_name("a/b"));
+        assert!(!valid_name("a:b"));
+        assert!(!valid_name("tab\there"));
+        assert!(valid_name(&"x".repeat(255)));
+        assert!(!valid_name(&"x".repeat(256)));
+    }
+}
@@ -0,0 +1,921 @@
+//! FAT32
+//!
+//! The volume starts with the boot sector, see `bpb`, followed by reserved
+//! sectors holding FSInfo, then the copies of the file allocation table
+//! (FAT) and the data region. The data region is split into clusters,
+//! numbered from 2. The FAT has an entry per cluster with the number of the
+//! next cluster of the same file, so every file and directory is a chain of
+//! clusters starting at the cluster in its directory entry. Directories are
+//! described in `dir`, the root directory is a chain like any other.
+//!
+//! FSInfo caches the number of free clusters and where to look for the next
+//! one. It is only a hint and is written back after clusters are allocated
+//! or freed.
+//!
+//! FAT has no inodes, files are identified by the position of their entry
+//! on disk. Inodes in use are kept per volume, so every lookup of a file
+//! shares its size and first cluster. Clusters of a file that is unlinked
+//! while in use are freed after the last reference goes away: dropping the
+//! inode only queues its chain, `Volume::reclaim` frees queued chains the
+//! next time the volume is changed or synced.
+//!
+//! Disk I/O sleeps, so it is only done under sleeping locks. Every inode has
+//! one held across its I/O, a directory keeps its parsed entries in it until
+//! they change. Recently used sectors of the FAT are cached, changes are
+//! written through to every copy. The spin locks only guard what metadata
+//! and the inode table need and are never held across I/O.
+//!
+//! Locks are taken in this order: the inode of a directory, the inode of a
+//! file in it, the allocation, the FAT cache, directory entries.
+
+pub mod bpb;
+pub mod dir;
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::cmp::{max, min};
+use hashbrown::hash_map;
+use hashbrown::HashMap;
+use kernel_api::{FileType, OsError};
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::fs::fat32::bpb::BiosParameterBlock;
+use crate::fs::fat32::dir::{Entry, ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_READ_ONLY, ATTR_VOLUME_ID, END, ENTRY_SIZE, FREE};
+use crate::fs::traits::{DirEntry, FileSystem, Inode, InodeNumber, Metadata};
+use crate::storage::block::device::BlockDevice;
+use crate::sync::{Mutex, SpinMutex, WorkQueue};
+
+/// FAT entries use the lower 28 bits
+const FAT_MASK: u32 = 0x0FFF_FFFF;
+/// Entries from here on end a chain
+const END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+/// Marks a cluster that must not be used, no cluster has this number
+const BAD_CLUSTER: u32 = 0x0FFF_FFF7;
+const FIRST_CLUSTER: u32 = 2;
+/// FSInfo value for an unknown count or hint
+const UNKNOWN: u32 = 0xFFFF_FFFF;
+/// The root directory has no entry, no entry can be in the boot sector
+const ROOT_INO: InodeNumber = 1;
+/// Entries a directory can have
+const MAX_DIR_ENTRIES: usize = 65536;
+/// FAT sectors kept in the cache
+const FAT_CACHE_SECTORS: usize = 64;
+
+const FS_INFO_LEAD_SIG: u32 = 0x4161_5252;
+const FS_INFO_STRUCT_SIG: u32 = 0x6141_7272;
+
+fn read_u32(b: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([b[offset], b[offset + 1], b[offset + 2], b[offset + 3]])
+}
+
+struct Allocation {
+    /// Free clusters or `UNKNOWN`
+    free: u32,
+    /// Where the search for a free cluster starts
+    next: u32,
+    /// Whether FSInfo is out of date
+    dirty: bool,
+}
+
+/// Sectors of the first FAT read recently
+struct FatCache {
+    sectors: HashMap<u64, Vec<u8>>,
+    /// Cached sectors, the oldest first
+    order: VecDeque<u64>,
+}
+
+impl FatCache {
+    fn forget(&mut self, sector: u64) {
+        self.sectors.remove(&sector);
+        self.order.retain(|&s| s != sector);
+    }
+}
+
+struct Volume {
+    dev: Arc<dyn BlockDevice + Send + Sync>,
+    sector_size: u64,
+    sectors_per_cluster: u64,
+    fat_start: u64,
+    fat_sectors: u64,
+    num_fats: u64,
+    data_start: u64,
+    root_cluster: u32,
+    /// One past the highest cluster
+    cluster_end: u32,
+    fs_info: Option<u64>,
+    /// Set if the device cannot be written
+    read_only: bool,
+    alloc: Mutex<Allocation>,
+    fat: Mutex<FatCache>,
+    /// Held while a directory entry is read or changed, entries of
+    /// different files share sectors
+    entries: Mutex<()>,
+    inodes: SpinMutex<HashMap<InodeNumber, Weak<FatInode>>>,
+    /// First clusters of unlinked files no longer in use
+    orphans: WorkQueue<u32>,
+}
+
+impl Volume {
+    fn check_writable(&self) -> Result<(), OsError> {
+        match self.read_only {
+            true => Err(OsError::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), OsError> {
+        self.dev.read_sector(sector, buf).map(|_| ()).map_err(|_| OsError::IoError)
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), OsError> {
+        self.check_writable()?;
+        self.dev.write_sector(sector, buf).map(|_| ()).map_err(|_| OsError::IoError)
+    }
+
+    fn sector_buf(&self) -> Vec<u8> {
+        vec![0u8; self.sector_size as usize]
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.sector_size * self.sectors_per_cluster
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster - FIRST_CLUSTER) as u64 * self.sectors_per_cluster
+    }
+
+    fn valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= FIRST_CLUSTER && cluster < self.cluster_end
+    }
+
+    /// Sector and byte in it of the entry for `cluster` in the first FAT
+    fn fat_position(&self, cluster: u32) -> (u64, usize) {
+        let offset = cluster as u64 * 4;
+        (self.fat_start + offset / self.sector_size, (offset % self.sector_size) as usize)
+    }
+
+    /// `sector` of the first FAT, read into `fat` unless it is cached
+    fn fat_sector<'a>(&self, fat: &'a mut FatCache, sector: u64) -> Result<&'a mut Vec<u8>, OsError> {
+        if !fat.sectors.contains_key(&sector) && fat.order.len() >= FAT_CACHE_SECTORS {
+            if let Some(oldest) = fat.order.pop_front() {
+                fat.sectors.remove(&oldest);
+            }
+        }
+        match fat.sectors.entry(sector) {
+            hash_map::Entry::Occupied(cached) => Ok(cached.into_mut()),
+            hash_map::Entry::Vacant(slot) => {
+                let mut buf = self.sector_buf();
+                self.read(sector, &mut buf)?;
+                fat.order.push_back(sector);
+                Ok(slot.insert(buf))
+            },
+        }
+    }
+
+    fn fat_entry(&self, fat: &mut FatCache, cluster: u32) -> Result<u32, OsError> {
+        let (sector, offset) = self.fat_position(cluster);
+        Ok(read_u32(self.fat_sector(fat, sector)?, offset) & FAT_MASK)
+    }
+
+    /// Sets the entry for `cluster` in every FAT, keeping the reserved bits
+    fn set_fat_entry(&self, fat: &mut FatCache, cluster: u32, value: u32) -> Result<(), OsError> {
+        let (sector, offset) = self.fat_position(cluster);
+        let buf = self.fat_sector(fat, sector)?;
+        let entry = read_u32(buf, offset) & !FAT_MASK | value & FAT_MASK;
+        buf[offset..offset + 4].copy_from_slice(&entry.to_le_bytes());
+        let result = (0..self.num_fats).try_for_each(|copy| self.write(sector + copy * self.fat_sectors, buf));
+        // The cached sector may not match the disk anymore
+        if result.is_err() {
+            fat.forget(sector);
+        }
+        result
+    }
+
+    /// The clusters of the chain starting at `first`, none for cluster 0
+    fn chain(&self, first: u32) -> Result<Vec<u32>, OsError> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        if cluster == 0 {
+            return Ok(chain);
+        }
+        let mut fat = self.fat.lock();
+        loop {
+            // Free or bad clusters in a chain, or a chain running in circles
+            if !self.valid_cluster(cluster) || chain.len() >= (self.cluster_end - FIRST_CLUSTER) as usize {
+                return Err(OsError::IoErrorInvalidData);
+            }
+            chain.push(cluster);
+            cluster = self.fat_entry(&mut fat, cluster)?;
+            if cluster >= END_OF_CHAIN {
+                return Ok(chain);
+            }
+        }
+    }
+
+    /// Ends the chain at `last`, which is kept
+    fn end_chain(&self, last: u32) -> Result<(), OsError> {
+        self.set_fat_entry(&mut self.fat.lock(), last, END_OF_CHAIN)
+    }
+
+    /// Takes a free cluster, zeroes it and appends it to the chain ending
+    /// at `last`
+    fn allocate(&self, last: Option<u32>) -> Result<u32, OsError> {
+        let mut alloc = self.alloc.lock();
+        let mut fat = self.fat.lock();
+        let count = self.cluster_end - FIRST_CLUSTER;
+        let mut found = None;
+        for i in 0..count {
+            let cluster = FIRST_CLUSTER + (alloc.next - FIRST_CLUSTER + i) % count;
+            if self.fat_entry(&mut fat, cluster)? == 0 {
+                found = Some(cluster);
+                break;
+            }
+        }
+        let cluster = found.ok_or(OsError::NoSpace)?;
+
+        let zeros = self.sector_buf();
+        for i in 0..self.sectors_per_cluster {
+            self.write(self.cluster_sector(cluster) + i, &zeros)?;
+        }
+        self.set_fat_entry(&mut fat, cluster, END_OF_CHAIN)?;
+        if let Some(last) = last {
+            self.set_fat_entry(&mut fat, last, cluster)?;
+        }
+        if alloc.free != UNKNOWN {
+            alloc.free = alloc.free.saturating_sub(1);
+        }
+        alloc.next = if cluster + 1 < self.cluster_end { cluster + 1 } else { FIRST_CLUSTER };
+        alloc.dirty = true;
+        Ok(cluster)
+    }
+
+    fn free_clusters(&self, clusters: &[u32]) -> Result<(), OsError> {
+        let mut alloc = self.alloc.lock();
+        let mut fat = self.fat.lock();
+        for &cluster in clusters {
+            self.set_fat_entry(&mut fat, cluster, 0)?;
+            if alloc.free != UNKNOWN {
+                alloc.free += 1;
+            }
+            alloc.dirty = true;
+        }
+        Ok(())
+    }
+
+    /// Frees the chains of unlinked files dropped since the last call
+    fn reclaim(&self) {
+        let orphans = self.orphans.take();
+        for first in orphans {
+            if let Err(e) = self.chain(first).and_then(|chain| self.free_clusters(&chain)) {
+                warn!("[FAT32] Unable to free the clusters of an unlinked file at {}: {:?}", first, e);
+            }
+        }
+    }
+
+    /// Writes the free cluster count and hint back to FSInfo
+    fn flush_info(&self) -> Result<(), OsError> {
+        let mut alloc = self.alloc.lock();
+        let sector = match self.fs_info {
+            Some(sector) if alloc.dirty => sector,
+            _ => return Ok(()),
+        };
+        let mut buf = self.sector_buf();
+        self.read(sector, &mut buf)?;
+        if read_u32(&buf, 0) != FS_INFO_LEAD_SIG || read_u32(&buf, 484) != FS_INFO_STRUCT_SIG {
+            return Err(OsError::IoErrorInvalidData);
+        }
+        buf[488..492].copy_from_slice(&alloc.free.to_le_bytes());
+        buf[492..496].copy_from_slice(&alloc.next.to_le_bytes());
+        self.write(sector, &buf)?;
+        alloc.dirty = false;
+        Ok(())
+    }
+
+    /// Sector and byte in it of `offset` into the file made of `chain`
+    fn data_position(&self, chain: &[u32], offset: u64) -> (u64, usize) {
+        let cluster = chain[(offset / self.cluster_size()) as usize];
+        let within = offset % self.cluster_size();
+        (self.cluster_sector(cluster) + within / self.sector_size, (within % self.sector_size) as usize)
+    }
+
+    /// Reads from `offset` into the file made of `chain`, which has to be
+    /// long enough
+    fn read_data(&self, chain: &[u32], offset: u64, buf: &mut [u8]) -> Result<(), OsError> {
+        let mut sector_buf = self.sector_buf();
+        let mut done = 0;
+        while done < buf.len() {
+            let (sector, within) = self.data_position(chain, offset + done as u64);
+            let count = min(sector_buf.len() - within, buf.len() - done);
+            self.read(sector, &mut sector_buf)?;
+            buf[done..done + count].copy_from_slice(&sector_buf[within..within + count]);
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Writes at `offset` into the file made of `chain`, which has to be
+    /// long enough
+    fn write_data(&self, chain: &[u32], offset: u64, buf: &[u8]) -> Result<(), OsError> {
+        let mut sector_buf = self.sector_buf();
+        let mut done = 0;
+        while done < buf.len() {
+            let (sector, within) = self.data_position(chain, offset + done as u64);
+            let count = min(sector_buf.len() - within, buf.len() - done);
+            if count == sector_buf.len() {
+                self.write(sector, &buf[done..done + count])?;
+            } else {
+                self.read(sector, &mut sector_buf)?;
+                sector_buf[within..within + count].copy_from_slice(&buf[done..done + count]);
+                self.write(sector, &sector_buf)?;
+            }
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Position on disk of entry `slot` of the directory made of `chain`
+    fn slot_position(&self, chain: &[u32], slot: usize) -> u64 {
+        let (sector, within) = self.data_position(chain, (slot * ENTRY_SIZE) as u64);
+        sector * self.sector_size + within as u64
+    }
+
+    /// The directory entry at `position`
+    fn read_entry(&self, position: u64) -> Result<[u8; ENTRY_SIZE], OsError> {
+        let within = (position % self.sector_size) as usize;
+        let mut buf = self.sector_buf();
+        let _entries = self.entries.lock();
+        self.read(position / self.sector_size, &mut buf)?;
+        let mut raw = [0u8; ENTRY_SIZE];
+        raw.copy_from_slice(&buf[within..within + ENTRY_SIZE]);
+        Ok(raw)
+    }
+
+    /// Changes the directory entry at `position`
+    fn modify_entry<F: FnOnce(&mut [u8])>(&self, position: u64, f: F) -> Result<(), OsError> {
+        let sector = position / self.sector_size;
+        let within = (position % self.sector_size) as usize;
+        let mut buf = self.sector_buf();
+        let _entries = self.entries.lock();
+        self.read(sector, &mut buf)?;
+        f(&mut buf[within..within + ENTRY_SIZE]);
+        self.write(sector, &buf)
+    }
+
+    fn open_inode(&self, ino: InodeNumber) -> Option<Arc<FatInode>> {
+        without_interrupts(|| self.inodes.lock().get(&ino).and_then(Weak::upgrade))
+    }
+
+    /// The inode for a file, shared with earlier lookups still in use
+    fn inode(self: &Arc<Self>, ino: InodeNumber, kind: FileType, read_only: bool, state: NodeState) -> Arc<FatInode> {
+        let new = Arc::new(FatInode {
+            vol: self.clone(),
+            ino,
+            kind,
+            read_only,
+            state: SpinMutex::new("FAT32_INODE", state),
+            io: Mutex::new(None),
+        });
+        let existing = without_interrupts(|| {
+            let mut inodes = self.inodes.lock();
+            if let Some(inode) = inodes.get(&ino).and_then(Weak::upgrade) {
+                return Some(inode);
+            }
+            inodes.retain(|_, inode| inode.strong_count() > 0);
+            inodes.insert(ino, Arc::downgrade(&new));
+            None
+        });
+        // The unused one is dropped after the lock is released
+        existing.unwrap_or(new)
+    }
+
+    /// The inode for the file with `entry` at `position`. Its first cluster
+    /// and size are read from disk unless it is in use, the entry may be
+    /// out of date.
+    fn entry_inode(self: &Arc<Self>, entry: &Entry, position: u64) -> Result<Arc<FatInode>, OsError> {
+        let ino = position / ENTRY_SIZE as u64;
+        if let Some(inode) = self.open_inode(ino) {
+            return Ok(inode);
+        }
+        let (kind, read_only) = match entry.is_dir() {
+            true => (FileType::Directory, false),
+            false => (FileType::Regular, entry.attr & ATTR_READ_ONLY != 0),
+        };
+        let raw = self.read_entry(position)?;
+        Ok(self.inode(ino, kind, read_only, NodeState {
+            cluster: dir::cluster(&raw),
+            size: dir::size(&raw),
+            entry: Some(position),
+            unlinked: false,
+        }))
+    }
+}
+
+pub struct Fat32 {
+    vol: Arc<Volume>,
+}
+
+impl Fat32 {
+    /// Opens the FAT32 volume on `dev`
+    pub fn new(dev: Arc<dyn BlockDevice + Send + Sync>) -> Result<Fat32, OsError> {
+        let bpb = BiosParameterBlock::from(&*dev)?;
+        debug!("[FAT32] {:?}", bpb);
+
+        let sector_size = bpb.bytes_per_sector as u64;
+        let sectors_per_cluster = bpb.sectors_per_cluster as u64;
+        let data_start = bpb.data_start();
+        let fat_sectors = bpb.fat_size_32 as u64;
+        let clusters = (bpb.total_sectors() - data_start) / sectors_per_cluster;
+        let fat_entries = fat_sectors * sector_size / 4;
+        let cluster_end = min(clusters + FIRST_CLUSTER as u64, min(fat_entries, BAD_CLUSTER as u64)) as u32;
+        if cluster_end <= FIRST_CLUSTER
+            || bpb.root_cluster < FIRST_CLUSTER
+            || bpb.root_cluster >= cluster_end {
+            return Err(OsError::IoErrorInvalidData);
+        }
+
+        let read_only = dev.read_only();
+        if read_only {
+            info!("[FAT32] The device cannot be written, opening read-only");
+        }
+
+        let mut alloc = Allocation {
+            free: UNKNOWN,
+            next: FIRST_CLUSTER,
+            dirty: false,
+        };
+        let mut fs_info = Some(bpb.fs_info_sector as u64).filter(|&s| s > 0 && s < bpb.reserved_sectors as u64);
+        if let Some(sector) = fs_info {
+            let mut buf = vec![0u8; sector_size as usize];
+            dev.read_sector(sector, &mut buf).map_err(|_| OsError::IoError)?;
+            if read_u32(&buf, 0) == FS_INFO_LEAD_SIG && read_u32(&buf, 484) == FS_INFO_STRUCT_SIG {
+                let free = read_u32(&buf, 488);
+                let next = read_u32(&buf, 492);
+                if free <= cluster_end - FIRST_CLUSTER {
+                    alloc.free = free;
+                }
+                if next >= FIRST_CLUSTER && next < cluster_end {
+                    alloc.next = next;
+                }
+            } else {
+                warn!("[FAT32] FSInfo sector {} is invalid, ignoring it", sector);
+                fs_info = None;
+            }
+        }
+
+        let vol = Volume {
+            dev,
+            sector_size,
+            sectors_per_cluster,
+            fat_start: bpb.reserved_sectors as u64,
+            fat_sectors,
+            num_fats: bpb.num_fats as u64,
+            data_start,
+            root_cluster: bpb.root_cluster,
+            cluster_end,
+            fs_info,
+            read_only,
+            alloc: Mutex::new(alloc),
+            fat: Mutex::new(FatCache {
+                sectors: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+            entries: Mutex::new(()),
+            inodes: SpinMutex::new("FAT32_INODES", HashMap::new()),
+            orphans: WorkQueue::new(),
+        };
+
+        match bpb.label() {
+            Some(label) => info!("[FAT32] Opened volume {}, {} clusters of {} bytes",
+                                 String::from_utf8_lossy(label), cluster_end - FIRST_CLUSTER, vol.cluster_size()),
+            None => info!("[FAT32] Opened volume, {} clusters of {} bytes",
+                          cluster_end - FIRST_CLUSTER, vol.cluster_size()),
+        }
+        Ok(Fat32 { vol: Arc::new(vol) })
+    }
+}
+
+impl FileSystem for Fat32 {
+    fn name(&self) -> &str {
+        "fat32"
+    }
+
+    fn root(&self) -> Result<Arc<dyn Inode>, OsError> {
+        Ok(self.vol.inode(ROOT_INO, FileType::Directory, false, NodeState {
+            cluster: self.vol.root_cluster,
+            size: 0,
+            entry: None,
+            unlinked: false,
+        }))
+    }
+
+    fn sync(&self) -> Result<(), OsError> {
+        self.vol.reclaim();
+        self.vol.flush_info()
+    }
+}
+
+#[derive(Copy, Clone)]
+struct NodeState {
+    /// First cluster, 0 for an empty file
+    cluster: u32,
+    size: u32,
+    /// Position of the directory entry on disk, `None` for the root and
+    /// unlinked files
+    entry: Option<u64>,
+    unlinked: bool,
+}
+
+/// The entries of a directory as read from disk
+struct Listing {
+    chain: Vec<u32>,
+    data: Vec<u8>,
+    entries: Vec<Entry>,
+    /// Indices of the entries listed by `readdir` and found by `lookup`
+    visible: Vec<usize>,
+}
+
+impl Listing {
+    /// The entry `name`, names are compared ignoring ASCII case like FAT does
+    fn find(&self, name: &str) -> Option<&Entry> {
+        self.visible.iter().map(|&i| &self.entries[i]).find(|e| e.name.eq_ignore_ascii_case(name))
+    }
+}
+
+pub struct FatInode {
+    vol: Arc<Volume>,
+    ino: InodeNumber,
+    kind: FileType,
+    read_only: bool,
+    /// Changed only with `io` held
+    state: SpinMutex<NodeState>,
+    /// Held across I/O on the inode. Directories keep their entries in it,
+    /// `None` until they are read and after they change.
+    io: Mutex<Option<Listing>>,
+}
+
+fn visible(entry: &Entry) -> bool {
+    !entry.is_dot() && entry.attr & ATTR_VOLUME_ID == 0
+}
+
+/// First slot of `count` free slots in a row in the directory `data`
+fn free_slots(data: &[u8], count: usize) -> Option<usize> {
+    let mut start = 0;
+    let mut end_seen = false;
+    for (slot, raw) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+        end_seen |= raw[0] == END;
+        if !end_seen && raw[0] != FREE {
+            start = slot + 1;
+        } else if slot + 1 - start == count {
+            return Some(start);
+        }
+    }
+    None
+}
+
+impl FatInode {
+    fn state(&self) -> NodeState {
+        without_interrupts(|| *self.state.lock())
+    }
+
+    fn set_state(&self, state: NodeState) {
+        without_interrupts(|| *self.state.lock() = state);
+    }
+
+    /// Stores `state` and writes the first cluster and size back to the
+    /// directory entry
+    fn update(&self, state: NodeState) -> Result<(), OsError> {
+        self.set_state(state);
+        match state.entry {
+            Some(position) => self.vol.modify_entry(position, |raw| {
+                dir::set_cluster(raw, state.cluster);
+                dir::set_size(raw, state.size);
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Makes the file `size` bytes long if it is shorter, the new bytes
+    /// read as zero
+    fn grow(&self, state: &mut NodeState, chain: &mut Vec<u32>, size: u64) -> Result<(), OsError> {
+        if size <= state.size as u64 {
+            return Ok(());
+        }
+        let cluster_size = self.vol.cluster_size();
+        // New clusters are zeroed, the rest of the last one may not be
+        let zero_end = min(size, chain.len() as u64 * cluster_size);
+        if (state.size as u64) < zero_end {
+            let zeros = vec![0u8; (zero_end - state.size as u64) as usize];
+            self.vol.write_data(chain, state.size as u64, &zeros)?;
+        }
+        let needed = ((size + cluster_size - 1) / cluster_size) as usize;
+        let mut result = Ok(());
+        while chain.len() < needed {
+            match self.vol.allocate(chain.last().cloned()) {
+                Ok(cluster) => chain.push(cluster),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                },
+            }
+        }
+        if state.cluster == 0 && !chain.is_empty() {
+            state.cluster = chain[0];
+        }
+        result?;
+        state.size = size as u32;
+        Ok(())
+    }
+
+    /// The entries of this directory, read unless `io` has them
+    fn listing<'a>(&self, io: &'a mut Option<Listing>) -> Result<&'a mut Listing, OsError> {
+        let listing = match io.take() {
+            Some(listing) => listing,
+            None => {
+                let chain = self.vol.chain(self.state().cluster)?;
+                let mut data = vec![0u8; chain.len() * self.vol.cluster_size() as usize];
+                self.vol.read_data(&chain, 0, &mut data)?;
+                let entries = dir::parse(&data);
+                let visible = entries.iter().enumerate().filter(|(_, e)| visible(e)).map(|(i, _)| i).collect();
+                Listing { chain, data, entries, visible }
+            },
+        };
+        Ok(io.get_or_insert(listing))
+    }
+
+    /// Adds the entries for `name` to the directory in `listing`
+    fn add_entry(&self, listing: &mut Listing, name: &str, kind: FileType, attr: u8) -> Result<Arc<dyn Inode>, OsError> {
+        if listing.find(name).is_some() {
+            return Err(OsError::FileExists);
+        }
+        let entries = &listing.entries;
+        let (short, long) = dir::short_name_for(name, |short| entries.iter().any(|e| &e.short == short))
+            .ok_or(OsError::FileExists)?;
+        let mut raw = match long {
+            true => dir::long_entries(name, dir::checksum(&short)),
+            false => Vec::new(),
+        };
+
+        // Room for the entries, growing the directory if it is full
+        let chain = &mut listing.chain;
+        let data = &mut listing.data;
+        let slot = loop {
+            if let Some(slot) = free_slots(data, raw.len() + 1) {
+                break slot;
+            }
+            if data.len() / ENTRY_SIZE + raw.len() + 1 > MAX_DIR_ENTRIES {
+                return Err(OsError::NoSpace);
+            }
+            chain.push(self.vol.allocate(chain.last().cloned())?);
+            data.resize(data.len() + self.vol.cluster_size() as usize, 0);
+        };
+
+        // A directory starts with `.` and `..`, the latter is 0 in the root
+        let cluster = match kind {
+            FileType::Directory => {
+                let cluster = self.vol.allocate(None)?;
+                let parent = if self.ino == ROOT_INO { 0 } else { self.state().cluster };
+                let mut dots = [b' '; 11];
+                dots[0] = b'.';
+                let mut init = dir::short_entry(&dots, ATTR_DIRECTORY, cluster, 0).to_vec();
+                dots[1] = b'.';
+                init.extend_from_slice(&dir::short_entry(&dots, ATTR_DIRECTORY, parent, 0));
+                if let Err(e) = self.vol.write_data(&[cluster], 0, &init) {
+                    self.vol.free_clusters(&[cluster])?;
+                    return Err(e);
+                }
+                cluster
+            },
+            _ => 0,
+        };
+        raw.push(dir::short_entry(&short, attr, cluster, 0));
+
+        for (i, entry) in raw.iter().enumerate() {
+            self.vol.modify_entry(self.vol.slot_position(chain, slot + i), |r| r.copy_from_slice(entry))?;
+        }
+        // Entries after the end marker may be stale, so it moves behind
+        // the new ones
+        let after = slot + raw.len();
+        let end = data.chunks_exact(ENTRY_SIZE).position(|r| r[0] == END);
+        if end.map_or(false, |end| end < after) && after < data.len() / ENTRY_SIZE {
+            self.vol.modify_entry(self.vol.slot_position(chain, after), |r| r[0] = END)?;
+        }
+
+        let position = self.vol.slot_position(chain, after - 1);
+        Ok(self.vol.inode(position / ENTRY_SIZE as u64, kind, false, NodeState {
+            cluster,
+            size: 0,
+            entry: Some(position),
+            unlinked: false,
+        }))
+    }
+}
+
+impl Inode for FatInode {
+    fn metadata(&self) -> Result<Metadata, OsError> {
+        let state = self.state();
+        Ok(Metadata {
+            ino: self.ino,
+            kind: self.kind,
+            size: state.size as u64,
+            nlink: if state.unlinked { 0 } else { 1 },
+            mode: if self.read_only || self.vol.read_only { 0o555 } else { 0o777 },
+        })
+    }
+
+    fn kind(&self) -> Result<FileType, OsError> {
+        Ok(self.kind)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, OsError> {
+        if self.kind != FileType::Regular {
+            return Err(self.not_a_file());
+        }
+        let _io = self.io.lock();
+        let state = self.state();
+        if offset >= state.size as u64 {
+            return Ok(0);
+        }
+        let count = min(buf.len() as u64, state.size as u64 - offset) as usize;
+        let chain = self.vol.chain(state.cluster)?;
+        if (chain.len() as u64) * self.vol.cluster_size() < state.size as u64 {
+            return Err(OsError::IoErrorInvalidData);
+        }
+        self.vol.read_data(&chain, offset, &mut buf[..count])?;
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, OsError> {
+        if self.kind != FileType::Regular {
+            return Err(self.not_a_file());
+        }
+        if self.read_only {
+            return Err(OsError::NoAccess);
+        }
+        self.vol.check_writable()?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let end = offset.checked_add(buf.len() as u64)
+            .filter(|&end| end <= u32::max_value() as u64)
+            .ok_or(OsError::NoSpace)?;
+        self.vol.reclaim();
+        let _io = self.io.lock();
+        let mut state = self.state();
+        let mut chain = self.vol.chain(state.cluster)?;
+        let size = max(end, state.size as u64);
+        let result = self.grow(&mut state, &mut chain, size)
+            .and_then(|_| self.vol.write_data(&chain, offset, buf));
+        self.update(state)?;
+        self.vol.flush_info()?;
+        result.map(|_| buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), OsError> {
+        if self.kind != FileType::Regular {
+            return Err(self.not_a_file());
+        }
+        if self.read_only {
+            return Err(OsError::NoAccess);
+        }
+        self.vol.check_writable()?;
+        if size > u32::max_value() as u64 {
+            return Err(OsError::NoSpace);
+        }
+        self.vol.reclaim();
+        let _io = self.io.lock();
+        let mut state = self.state();
+        let mut chain = self.vol.chain(state.cluster)?;
+        let result = if size > state.size as u64 {
+            self.grow(&mut state, &mut chain, size)
+        } else {
+            let cluster_size = self.vol.cluster_size();
+            let keep = ((size + cluster_size - 1) / cluster_size) as usize;
+            if keep < chain.len() {
+                match keep {
+                    0 => state.cluster = 0,
+                    keep => self.vol.end_chain(chain[keep - 1])?,
+                }
+                self.vol.free_clusters(&chain[keep..])?;
+            }
+            state.size = size as u32;
+            Ok(())
+        };
+        self.update(state)?;
+        self.vol.flush_info()?;
+        result
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, OsError> {
+        if self.kind != FileType::Directory {
+            return Err(OsError::NotADirectory);
+        }
+        let mut io = self.io.lock();
+        let listing = self.listing(&mut io)?;
+        let entry = listing.find(name).ok_or(OsError::NoEntry)?;
+        let position = self.vol.slot_position(&listing.chain, entry.slot);
+        Ok(self.vol.entry_inode(entry, position)?)
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<Arc<dyn Inode>, OsError> {
+        if self.kind != FileType::Directory {
+            return Err(OsError::NotADirectory);
+        }
+        if name.encode_utf16().count() > 255 {
+            return Err(OsError::NameTooLong);
+        }
+        if !dir::valid_name(name) {
+            return Err(OsError::InvalidArgument);
+        }
+        let attr = match kind {
+            FileType::Regular => ATTR_ARCHIVE,
+            FileType::Directory => ATTR_DIRECTORY,
+            _ => return Err(OsError::InvalidArgument),
+        };
+        self.vol.check_writable()?;
+        self.vol.reclaim();
+        let mut io = self.io.lock();
+        if self.state().unlinked {
+            return Err(OsError::NoEntry);
+        }
+        let result = self.listing(&mut io).and_then(|listing| self.add_entry(listing, name, kind, attr));
+        // Read again on the next use, the entries may be half written
+        *io = None;
+        drop(io);
+        self.vol.flush_info()?;
+        result
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, OsError> {
+        match self.kind {
+            FileType::Directory => Err(OsError::InvalidArgument),
+            _ => Err(OsError::NotADirectory),
+        }
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), OsError> {
+        if self.kind != FileType::Directory {
+            return Err(OsError::NotADirectory);
+        }
+        self.vol.check_writable()?;
+        let mut io = self.io.lock();
+        let listing = self.listing(&mut io)?;
+        let entry = listing.find(name).ok_or(OsError::NoEntry)?.clone();
+        let positions: Vec<u64> = (entry.slot - entry.long_slots..=entry.slot)
+            .map(|slot| self.vol.slot_position(&listing.chain, slot))
+            .collect();
+        let position = positions[positions.len() - 1];
+
+        // Nothing may use the file while it goes, it may be a new inode
+        // only held here
+        let inode = self.vol.entry_inode(&entry, position)?;
+        let mut inode_io = inode.io.lock();
+        if entry.is_dir() && inode.listing(&mut inode_io)?.entries.iter().any(|e| !e.is_dot()) {
+            return Err(OsError::DirectoryNotEmpty);
+        }
+
+        *io = None;
+        for &position in &positions {
+            self.vol.modify_entry(position, |r| r[0] = FREE)?;
+        }
+        // A new file in the same slot gets a new inode
+        without_interrupts(|| self.vol.inodes.lock().remove(&inode.ino));
+        let mut state = inode.state();
+        state.entry = None;
+        state.unlinked = true;
+        inode.set_state(state);
+        drop(inode_io);
+        drop(inode);
+        drop(io);
+        // Frees the clusters unless the file is still in use
+        self.vol.reclaim();
+        self.vol.flush_info()
+    }
+
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, OsError> {
+        if self.kind != FileType::Directory {
+            return Err(OsError::NotADirectory);
+        }
+        let mut io = self.io.lock();
+        let listing = self.listing(&mut io)?;
+        Ok(listing.visible.get(index).map(|&i| {
+            let e = &listing.entries[i];
+            DirEntry {
+                ino: self.vol.slot_position(&listing.chain, e.slot) / ENTRY_SIZE as u64,
+                kind: if e.is_dir() { FileType::Directory } else { FileType::Regular },
+                name: e.name.clone(),
+            }
+        }))
+    }
+}
+
+impl Drop for FatInode {
+    /// Queues the clusters of an unlinked file for `Volume::reclaim`, this
+    /// may run anywhere and must not do I/O
+    fn drop(&mut self) {
+        let state = self.state();
+        if state.unlinked && state.cluster != 0 {
+            self.vol.orphans.push(state.cluster);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//! Files opened through the VFS. The offset belongs to the open file, so
+//! duplicated descriptors share it.
+//!
+//! The offset lock is a spin lock that syscalls take with interrupts
+//! disabled, so it is not held across the read or write of the inode, which
+//! may wait for a disk. Reads and writes are serialized by `fs::io`.
+
+use alloc::sync::Arc;
+use kernel_api::{DirEnt, OsError, Stat, MAX_NAME_LEN, O_APPEND, O_READ, O_WRITE, SEEK_CUR, SEEK_END, SEEK_SET};
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::fs::traits::Inode;
+use crate::sync::SpinMutex;
+
+pub struct OpenFile {
+    inode: Arc<dyn Inode>,
+    flags: u64,
+    /// Byte offset, or the index of the next entry of a directory
+    offset: SpinMutex<u64>,
+}
+
+impl OpenFile {
+    pub fn new(inode: Arc<dyn Inode>, flags: u64) -> OpenFile {
+        OpenFile {
+            inode,
+            flags,
+            offset: SpinMutex::new("FILE_OFFSET", 0),
+        }
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    pub fn flags(&self) -> u64 {
+        self.flags
+    }
+
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, OsError> {
+        if self.flags & O_READ == 0 {
+            return Err(OsError::NoAccess);
+        }
+        let offset = self.offset();
+        let count = self.inode.read_at(offset, buf)?;
+        self.set_offset(offset + count as u64);
+        Ok(count)
+    }
+
+    pub fn write(&self, buf: &[u8]) -> Result<usize, OsError> {
+        if self.flags & O_WRITE == 0 {
+            return Err(OsError::NoAccess);
+        }
+        let offset = match self.flags & O_APPEND {
+            0 => self.offset(),
+            _ => self.inode.metadata()?.size,
+        };
+        let count = self.inode.write_at(offset, buf)?;
+        self.set_offset(offset + count as u64);
+        Ok(count)
+    }
+
+    fn offset(&self) -> u64 {
+        without_interrupts(|| *self.offset.lock())
+    }
+
+    fn set_offset(&self, offset: u64) {
+        without_interrupts(|| *self.offset.lock() = offset);
+    }
+
+    /// Moves the offset to `offset` from `whence`, one of the `SEEK_*`
+    /// origins, and returns the new offset
+    pub fn seek(&self, offset: i64, whence: u64) -> Result<u64, OsError> {
+        // The size is read before the lock is taken
+        let end = match whence {
+            SEEK_END => Some(self.inode.metadata()?.size),
+            _ => None,
+        };
+        without_interrupts(|| {
+            let mut current = self.offset.lock();
+            let base = match whence {
+                SEEK_SET => 0,
+                SEEK_CUR => *current,
+                SEEK_END => end.expect("read above"),
+                _ => return Err(OsError::InvalidArgument),
+            };
+            let new = if offset < 0 {
+                base.checked_sub(offset.wrapping_neg() as u64)
+            } else {
+                base.checked_add(offset as u64)
+            };
+            *current = new.ok_or(OsError::InvalidArgument)?;
+            Ok(*current)
+        })
+    }
+
+    pub fn ioctl(&self, request: u64, arg: u64) -> Result<u64, OsError> {
+        self.inode.ioctl(request, arg)
+    }
+
+    pub fn stat(&self) -> Result<Stat, OsError> {
+        self.inode.metadata().map(Stat::from)
+    }
+
+    /// Fills `entry` with the next directory entry, `false` after the last
+    pub fn readdir(&self, entry: &mut DirEnt) -> Result<bool, OsError> {
+        let index = self.offset();
+        match self.inode.readdir(index as usize)? {
+            Some(dirent) => {
+                let name = dirent.name.as_bytes();
+                let len = core::cmp::min(name.len(), MAX_NAME_LEN);
+                entry.ino = dirent.ino;
+                entry.kind = dirent.kind as u64;
+                entry.name_len = len as u64;
+                entry.name[..len].copy_from_slice(&name[..len]);
+                self.set_offset(index + 1);
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+}
@@ -0,0 +1,547 @@
+//! Initial RAM file system
+//!
+//! GRUB loads an archive as the multiboot2 module named `initrd`. Early boot
+//! keeps its frames out of the frame allocators and registers it here with
+//! the kernel command line. `unpack` copies the files into the root file
+//! system once it is mounted and gives the frames back.
+//!
+//! Archives are newc cpio (`find . | cpio -o -H newc`) or ustar (`tar
+//! --format=ustar`). Files, directories and symlinks are unpacked, hard
+//! links become copies of the file they link to and anything else is
+//! skipped. Names leaving the archive with `..` are skipped too.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::str;
+use kernel_api::{OsError, O_CREATE, O_TRUNC, O_WRITE};
+use spin::Mutex;
+use x86_64::PhysAddr;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::PhysFrame;
+use crate::fs::path::{Component, Path, PathBuf};
+use crate::fs::vfs;
+use crate::init::init::LOW_MEMORY_TOP;
+use crate::memory::{align_down, align_up};
+use crate::memory::paging::PHYSMAP_BASE;
+use crate::{FRAME_ALLOC, LOW_FALLOC};
+
+/// Name of the module in `grub.cfg`, `module2 /boot/initrd initrd`
+pub const MODULE_NAME: &str = "initrd";
+
+/// Started when the command line has no `init=`
+const DEFAULT_INIT: &str = "/sbin/init";
+
+const PAGE_SIZE: u64 = 4096;
+
+const CPIO_MAGIC: &[u8] = b"070701";
+/// The same with checksums, which are not verified
+const CPIO_CRC_MAGIC: &[u8] = b"070702";
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+const S_IFMT: u64 = 0o170000;
+const S_IFREG: u64 = 0o100000;
+const S_IFDIR: u64 = 0o040000;
+const S_IFLNK: u64 = 0o120000;
+
+const TAR_BLOCK: usize = 512;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+/// Physical start and end of the module, until it is unpacked
+static MODULE: Mutex<Option<(u64, u64)>> = Mutex::new(None);
+static COMMAND_LINE: Mutex<String> = Mutex::new(String::new());
+
+enum Entry<'a> {
+    File(&'a [u8]),
+    Dir,
+    Symlink(&'a str),
+    /// Hard link to an earlier entry
+    Link(&'a str),
+}
+
+/// Records the module at `[start, end)` and the kernel command line
+pub fn register(module: Option<(u64, u64)>, command_line: &str) {
+    *MODULE.lock() = module;
+    *COMMAND_LINE.lock() = command_line.to_string();
+}
+
+/// The first user program, `init=` on the command line or `/sbin/init`
+pub fn init_path() -> String {
+    COMMAND_LINE.lock().split_whitespace()
+        .find(|arg| arg.starts_with("init="))
+        .map(|arg| arg["init=".len()..].to_string())
+        .unwrap_or_else(|| DEFAULT_INIT.to_string())
+}
+
+/// Unpacks the module into `/` and frees its memory
+pub fn unpack() {
+    let (start, end) = match MODULE.lock().take() {
+        Some(module) => module,
+        None => {
+            info!("[INITRAMFS] No {} module", MODULE_NAME);
+            return;
+        }
+    };
+    let archive = unsafe { core::slice::from_raw_parts((start + PHYSMAP_BASE) as *const u8, (end - start) as usize) };
+    let result = if archive.starts_with(CPIO_MAGIC) || archive.starts_with(CPIO_CRC_MAGIC) {
+        unpack_cpio(archive)
+    } else if archive.len() >= TAR_BLOCK && &archive[257..262] == TAR_MAGIC {
+        unpack_tar(archive)
+    } else {
+        Err(OsError::IoErrorInvalidData)
+    };
+    match result {
+        Ok(count) => info!("[INITRAMFS] Unpacked {} entries", count),
+        Err(e) => error!("[INITRAMFS] Archive is damaged or not cpio or ustar: {:?}", e),
+    }
+    release(start, end);
+}
+
+/// Returns the frames of `[start, end)` to the allocator they were kept
+/// from. Early boot keeps whole pages, including the first and last one
+/// that the module only partly covers.
+fn release(start: u64, end: u64) {
+    let mut addr = align_down(start as usize, PAGE_SIZE as usize) as u64;
+    let end = align_up(end as usize, PAGE_SIZE as usize) as u64;
+    without_interrupts(|| {
+        while addr < end {
+            let frame = PhysFrame::containing_address(PhysAddr::new(addr));
+            // The archive is no longer referenced
+            unsafe {
+                if addr < LOW_MEMORY_TOP {
+                    LOW_FALLOC.lock().deallocate_frame(frame);
+                } else {
+                    FRAME_ALLOC.lock().deallocate_frame(frame);
+                }
+            }
+            addr += PAGE_SIZE;
+        }
+    });
+}
+
+/// `name` below `/`, `None` if it leaves the archive
+fn target_path(name: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::from("/");
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::RootDir | Component::CurDir => {},
+            _ => return None,
+        }
+    }
+    Some(path)
+}
+
+/// Creates the directories leading to `path`
+fn make_parents(path: &Path) -> Result<(), OsError> {
+    let mut dir = PathBuf::from("/");
+    if let Some(parent) = path.parent() {
+        for component in parent.components().skip(1) {
+            dir.push(component);
+            match vfs::mkdir(&dir) {
+                Ok(()) | Err(OsError::FileExists) => {},
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_file(path: &Path, data: &[u8]) -> Result<(), OsError> {
+    let file = vfs::open(path, O_WRITE | O_CREATE | O_TRUNC)?;
+    let mut written = 0;
+    while written < data.len() {
+        match file.write(&data[written..])? {
+            0 => return Err(OsError::NoSpace),
+            n => written += n,
+        }
+    }
+    Ok(())
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, OsError> {
+    let inode = vfs::lookup(path)?;
+    let mut data = vec![0u8; inode.metadata()?.size as usize];
+    let mut read = 0;
+    while read < data.len() {
+        match inode.read_at(read as u64, &mut data[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    data.truncate(read);
+    Ok(data)
+}
+
+/// Creates `entry` as `path`
+fn add(path: &Path, entry: Entry) -> Result<(), OsError> {
+    make_parents(path)?;
+    match entry {
+        Entry::File(data) => write_file(path, data),
+        Entry::Dir => match vfs::mkdir(path) {
+            Ok(()) | Err(OsError::FileExists) => Ok(()),
+            Err(e) => Err(e),
+        },
+        Entry::Symlink(target) => vfs::symlink(path, target),
+        Entry::Link(target) => {
+            let target = target_path(target).ok_or(OsError::InvalidArgument)?;
+            write_file(path, &read_file(&target)?)
+        }
+    }
+}
+
+/// Unpacks the entry `name`, returns `true` if it was created. A broken
+/// entry does not stop the rest of the archive.
+fn extract(name: &str, entry: Entry) -> bool {
+    let path = match target_path(name) {
+        Some(path) => path,
+        None => {
+            warn!("[INITRAMFS] Skipping {} outside of the archive", name);
+            return false;
+        }
+    };
+    // The archive root is `/` itself
+    if path.parent().is_none() {
+        return false;
+    }
+    match add(&path, entry) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("[INITRAMFS] Unable to create {}: {:?}", path.display(), e);
+            false
+        }
+    }
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+/// Parses the hexadecimal header field `field`
+fn hex(field: &[u8]) -> Result<u64, OsError> {
+    str::from_utf8(field).ok()
+        .and_then(|s| u64::from_str_radix(s, 16).ok())
+        .ok_or(OsError::IoErrorInvalidData)
+}
+
+/// A newc cpio header with the name and data following it
+struct CpioHeader<'a> {
+    ino: u64,
+    mode: u64,
+    nlink: u64,
+    name: &'a str,
+    data: &'a [u8],
+    /// Offset of the next header
+    next: usize,
+}
+
+/// Parses the cpio header at `offset`. The header is made of hexadecimal
+/// fields, the name and the data after it are both padded to 4 bytes.
+fn cpio_header(archive: &[u8], offset: usize) -> Result<CpioHeader, OsError> {
+    let header = archive.get(offset..offset + CPIO_HEADER_SIZE).ok_or(OsError::IoErrorInvalidData)?;
+    if &header[..6] != CPIO_MAGIC && &header[..6] != CPIO_CRC_MAGIC {
+        return Err(OsError::IoErrorInvalidData);
+    }
+    let field = |n: usize| hex(&header[6 + n * 8..14 + n * 8]);
+    let (ino, mode, nlink, size, name_size) = (field(0)?, field(1)?, field(4)?, field(6)? as usize, field(11)? as usize);
+
+    // The name size counts the terminating NUL
+    let name_start = offset + CPIO_HEADER_SIZE;
+    let name = archive.get(name_start..name_start + name_size)
+        .and_then(|name| name.split_last())
+        .and_then(|(_, name)| str::from_utf8(name).ok())
+        .ok_or(OsError::IoErrorInvalidData)?;
+    let data_start = align4(name_start + name_size);
+    let data = archive.get(data_start..data_start + size).ok_or(OsError::IoErrorInvalidData)?;
+    Ok(CpioHeader { ino, mode, nlink, name, data, next: align4(data_start + size) })
+}
+
+/// Unpacks a newc cpio archive, which ends with an entry named
+/// `TRAILER!!!`
+fn unpack_cpio(archive: &[u8]) -> Result<usize, OsError> {
+    // Paths of hard linked files by inode, the data comes with the last link
+    let mut links: BTreeMap<u64, Vec<PathBuf>> = BTreeMap::new();
+    let mut count = 0;
+    let mut offset = 0;
+    loop {
+        let CpioHeader { ino, mode, nlink, name, data, next } = cpio_header(archive, offset)?;
+        offset = next;
+
+        if name == CPIO_TRAILER {
+            return Ok(count);
+        }
+        let entry = match mode & S_IFMT {
+            S_IFREG => Entry::File(data),
+            S_IFDIR => Entry::Dir,
+            S_IFLNK => Entry::Symlink(str::from_utf8(data).map_err(|_| OsError::IoErrorInvalidData)?),
+            _ => {
+                warn!("[INITRAMFS] Skipping {}, mode {:o} is not supported", name, mode);
+                continue;
+            }
+        };
+        if extract(name, entry) {
+            count += 1;
+        }
+
+        if mode & S_IFMT == S_IFREG && nlink > 1 {
+            let paths = links.entry(ino).or_insert_with(Vec::new);
+            if !data.is_empty() {
+                for path in paths.iter() {
+                    if let Err(e) = write_file(path, data) {
+                        warn!("[INITRAMFS] Unable to write {}: {:?}", path.display(), e);
+                    }
+                }
+            }
+            paths.extend(target_path(name));
+        }
+    }
+}
+
+/// Parses the octal header field `field`, padded with NULs or spaces
+fn octal(field: &[u8]) -> Result<u64, OsError> {
+    let digits = str::from_utf8(field).map_err(|_| OsError::IoErrorInvalidData)?
+        .trim_matches(|c| c == '\0' || c == ' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| OsError::IoErrorInvalidData)
+}
+
+/// The NUL terminated string in `field`
+fn tar_str(field: &[u8]) -> Result<&str, OsError> {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    str::from_utf8(&field[..len]).map_err(|_| OsError::IoErrorInvalidData)
+}
+
+/// A ustar header with the data following it
+struct TarHeader<'a> {
+    /// The name with the prefix field in front
+    name: String,
+    kind: u8,
+    /// Target of a link
+    link: &'a str,
+    data: &'a [u8],
+    /// Offset of the next header
+    next: usize,
+}
+
+/// Parses the ustar header at `offset`, `None` at the end of the archive.
+/// The header is a 512 byte block of octal and NUL terminated fields, the
+/// data after it is padded to 512 bytes.
+fn tar_header(archive: &[u8], offset: usize) -> Result<Option<TarHeader>, OsError> {
+    let header = match archive.get(offset..offset + TAR_BLOCK) {
+        Some(header) if header.iter().any(|&b| b != 0) => header,
+        // Two zero blocks end the archive, a missing end marker is tolerated
+        _ => return Ok(None),
+    };
+    if &header[257..262] != TAR_MAGIC {
+        return Err(OsError::IoErrorInvalidData);
+    }
+    // Sum of the header bytes, counting the checksum field as spaces
+    let sum: u64 = header.iter().enumerate()
+        .map(|(n, &b)| if (148..156).contains(&n) { b' ' as u64 } else { b as u64 })
+        .sum();
+    if octal(&header[148..156])? != sum {
+        return Err(OsError::IoErrorInvalidData);
+    }
+
+    let size = octal(&header[124..136])? as usize;
+    let data_start = offset + TAR_BLOCK;
+    let data = archive.get(data_start..data_start + size).ok_or(OsError::IoErrorInvalidData)?;
+
+    let prefix = tar_str(&header[345..500])?;
+    let name = tar_str(&header[0..100])?;
+    let name = if prefix.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", prefix, name)
+    };
+    Ok(Some(TarHeader {
+        name,
+        kind: header[156],
+        link: tar_str(&header[157..257])?,
+        data,
+        next: data_start + (size + TAR_BLOCK - 1) / TAR_BLOCK * TAR_BLOCK,
+    }))
+}
+
+/// Unpacks a ustar archive
+fn unpack_tar(archive: &[u8]) -> Result<usize, OsError> {
+    let mut count = 0;
+    let mut offset = 0;
+    while let Some(TarHeader { name, kind, link, data, next }) = tar_header(archive, offset)? {
+        offset = next;
+        let entry = match kind {
+            b'0' | 0 => Entry::File(data),
+            b'1' => Entry::Link(link),
+            b'2' => Entry::Symlink(link),
+            b'5' => Entry::Dir,
+            kind => {
+                warn!("[INITRAMFS] Skipping {}, type {:?} is not supported", name, kind as char);
+                continue;
+            }
+        };
+        if extract(&name, entry) {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A newc cpio entry as `cpio -o -H newc` writes it
+    fn cpio_entry(ino: u64, mode: u64, nlink: u64, name: &str, data: &[u8]) -> Vec<u8> {
+        let mut entry = format!("070701{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}",
+                                ino, mode, 0, 0, nlink, 0, data.len(), 0, 0, 0, 0, name.len() + 1, 0).into_bytes();
+        entry.extend_from_slice(name.as_bytes());
+        entry.push(0);
+        entry.resize(align4(entry.len()), 0);
+        entry.extend_from_slice(data);
+        entry.resize(align4(entry.len()), 0);
+        entry
+    }
+
+    /// A ustar entry as `tar --format=ustar` writes it
+    fn tar_entry(prefix: &str, name: &str, kind: u8, link: &str, data: &[u8]) -> Vec<u8> {
+        let mut header = vec![0u8; TAR_BLOCK];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..108].copy_from_slice(b"0000644\0");
+        header[124..136].copy_from_slice(format!("{:011o}\0", data.len()).as_bytes());
+        header[156] = kind;
+        header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+        header[257..265].copy_from_slice(b"ustar\x0000");
+        header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+        header[148..156].copy_from_slice(b"        ");
+        let sum: u64 = header.iter().map(|&b| b as u64).sum();
+        header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+        header.extend_from_slice(data);
+        header.resize((header.len() + TAR_BLOCK - 1) / TAR_BLOCK * TAR_BLOCK, 0);
+        header
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(hex(b"0000001F"), Ok(31));
+        assert_eq!(hex(b"0000001f"), Ok(31));
+        assert_eq!(hex(b"FFFFFFFF"), Ok(0xFFFF_FFFF));
+        assert_eq!(hex(b"0000001G"), Err(OsError::IoErrorInvalidData));
+        assert_eq!(hex(b""), Err(OsError::IoErrorInvalidData));
+    }
+
+    #[test]
+    fn test_octal() {
+        assert_eq!(octal(b"0000644\0"), Ok(0o644));
+        assert_eq!(octal(b"    17 \0"), Ok(0o17));
+        assert_eq!(octal(b"\0\0\0\0"), Ok(0));
+        assert_eq!(octal(b"        "), Ok(0));
+        assert_eq!(octal(b"0000008\0"), Err(OsError::IoErrorInvalidData));
+    }
+
+    #[test]
+    fn test_tar_str() {
+        assert_eq!(tar_str(b"name\0\0\0"), Ok("name"));
+        assert_eq!(tar_str(b"full"), Ok("full"));
+        assert_eq!(tar_str(b"\0rest"), Ok(""));
+        assert_eq!(tar_str(b"\xff\0"), Err(OsError::IoErrorInvalidData));
+    }
+
+    #[test]
+    fn test_cpio_header() {
+        let mut archive = cpio_entry(7, S_IFREG | 0o644, 2, "dir/file", b"hello");
+        let second = archive.len();
+        archive.extend(cpio_entry(8, S_IFLNK | 0o777, 1, "link", b"dir/file"));
+        let trailer = archive.len();
+        archive.extend(cpio_entry(0, 0, 1, CPIO_TRAILER, b""));
+
+        let header = cpio_header(&archive, 0).unwrap();
+        assert_eq!((header.ino, header.mode, header.nlink), (7, S_IFREG | 0o644, 2));
+        assert_eq!(header.name, "dir/file");
+        assert_eq!(header.data, b"hello");
+        assert_eq!(header.next, second);
+
+        let header = cpio_header(&archive, second).unwrap();
+        assert_eq!(header.mode & S_IFMT, S_IFLNK);
+        assert_eq!((header.name, header.data), ("link", &b"dir/file"[..]));
+        assert_eq!(header.next, trailer);
+
+        let header = cpio_header(&archive, trailer).unwrap();
+        assert_eq!(header.name, CPIO_TRAILER);
+        assert_eq!(header.next, archive.len());
+    }
+
+    #[test]
+    fn test_cpio_header_crc() {
+        let mut archive = cpio_entry(1, S_IFDIR | 0o755, 2, "dir", b"");
+        archive[..6].copy_from_slice(CPIO_CRC_MAGIC);
+        let header = cpio_header(&archive, 0).unwrap();
+        assert_eq!((header.mode & S_IFMT, header.name), (S_IFDIR, "dir"));
+    }
+
+    #[test]
+    fn test_cpio_header_invalid() {
+        let archive = cpio_entry(1, S_IFREG | 0o644, 1, "file", b"data");
+        let invalid = Err(OsError::IoErrorInvalidData);
+
+        let mut magic = archive.clone();
+        magic[5] = b'3';
+        assert_eq!(cpio_header(&magic, 0).map(|h| h.name), invalid);
+        // Data, name and header cut short
+        assert_eq!(cpio_header(&archive[..archive.len() - 4], 0).map(|h| h.name), invalid);
+        assert_eq!(cpio_header(&archive[..CPIO_HEADER_SIZE + 2], 0).map(|h| h.name), invalid);
+        assert_eq!(cpio_header(&archive[..CPIO_HEADER_SIZE - 1], 0).map(|h| h.name), invalid);
+        assert_eq!(cpio_header(&archive, archive.len()).map(|h| h.name), invalid);
+
+        let mut field = archive.clone();
+        field[6 + 6 * 8] = b'x';
+        assert_eq!(cpio_header(&field, 0).map(|h| h.name), invalid);
+    }
+
+    #[test]
+    fn test_tar_header() {
+        let data = [0x5Au8; 600];
+        let mut archive = tar_entry("some/prefix", "file", b'0', "", &data);
+        let second = archive.len();
+        assert_eq!(second, 3 * TAR_BLOCK);
+        archive.extend(tar_entry("", "link", b'2', "some/prefix/file", b""));
+        let end = archive.len();
+        archive.extend(vec![0u8; 2 * TAR_BLOCK]);
+
+        let header = tar_header(&archive, 0).unwrap().unwrap();
+        assert_eq!(header.name, "some/prefix/file");
+        assert_eq!(header.kind, b'0');
+        assert_eq!(header.data, &data[..]);
+        assert_eq!(header.next, second);
+
+        let header = tar_header(&archive, second).unwrap().unwrap();
+        assert_eq!((header.name.as_str(), header.kind, header.link), ("link", b'2', "some/prefix/file"));
+        assert!(header.data.is_empty());
+        assert_eq!(header.next, end);
+
+        assert!(tar_header(&archive, end).unwrap().is_none());
+        // A missing end marker
+        assert!(tar_header(&archive[..end], end).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_tar_header_invalid() {
+        let archive = tar_entry("", "file", b'0', "", b"data");
+
+        let mut checksum = archive.clone();
+        checksum[0] = b'F';
+        assert_eq!(tar_header(&checksum, 0).err(), Some(OsError::IoErrorInvalidData));
+
+        let mut magic = archive.clone();
+        magic[257] = b'x';
+        assert_eq!(tar_header(&magic, 0).err(), Some(OsError::IoErrorInvalidData));
+
+        let mut size = tar_entry("", "file", b'0', "", b"");
+        let sum = octal(&size[148..156]).unwrap();
+        size[124..136].copy_from_slice(b"00000000001\0");
+        size[148..156].copy_from_slice(format!("{:06o}\0 ", sum + 1).as_bytes());
+        assert_eq!(tar_header(&size, 0).err(), Some(OsError::IoErrorInvalidData));
+    }
+}
@@ -0,0 +1,121 @@
+//! File I/O Worker
+//!
+//! Reading or writing a file may wait for a disk, which a syscall cannot do:
+//! it runs with interrupts disabled on the kernel stack of the core.
+//! `sys_read` and `sys_write` hand the transfers of VFS files to `worker`, a
+//! kernel process, and park the caller until it is done.
+//!
+//! The worker never touches user memory, the caller may unmap its buffer or
+//! die while the transfer runs. Data to write is copied into a kernel buffer
+//! by `submit`, data read is copied out by `Request::take_result` once the
+//! caller picks up the result, with the buffer checked again. Transfers are cut
+//! to `MAX_TRANSFER` bytes, so a read or write may do less than asked.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use kernel_api::OsError;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::SCHEDULER;
+use crate::memory::user::{user_slice, user_slice_mut};
+use crate::process::fd::File;
+use crate::process::process::Id;
+use crate::sync::{SpinMutex, WorkQueue};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Op {
+    Read,
+    Write,
+}
+
+/// Longest transfer, in bytes
+pub const MAX_TRANSFER: usize = 1 << 20;
+
+/// A transfer of `len` bytes at user address `addr` for process `pid`
+pub struct Request {
+    file: Arc<File>,
+    op: Op,
+    addr: u64,
+    len: usize,
+    pid: Id,
+    /// The data to write, or the data read once `worker` is done
+    buf: SpinMutex<Vec<u8>>,
+    /// Filled in by `worker`
+    result: SpinMutex<Option<Result<usize, OsError>>>,
+}
+
+impl Request {
+    /// The result, once `worker` is done with the request. Data read is
+    /// copied to the user buffer.
+    pub fn take_result(&self) -> Option<Result<usize, OsError>> {
+        let result = without_interrupts(|| self.result.lock().take())?;
+        Some(match (self.op, result) {
+            (Op::Read, Ok(count)) => {
+                let buf = without_interrupts(|| core::mem::take(&mut *self.buf.lock()));
+                // Checked again, the buffer may have been unmapped meanwhile
+                match unsafe { user_slice_mut(self.addr, count) } {
+                    Some(dest) => {
+                        dest.copy_from_slice(&buf[..count]);
+                        Ok(count)
+                    },
+                    None => Err(OsError::BadAddress),
+                }
+            },
+            (_, result) => result,
+        })
+    }
+
+    fn run(&self) -> Result<usize, OsError> {
+        let mut buf = without_interrupts(|| core::mem::take(&mut *self.buf.lock()));
+        match self.op {
+            Op::Read => {
+                buf.resize(self.len, 0);
+                let result = self.file.read(&mut buf);
+                without_interrupts(|| *self.buf.lock() = buf);
+                result
+            },
+            Op::Write => self.file.write(&buf),
+        }
+    }
+}
+
+impl core::fmt::Debug for Request {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Request({:?} {} bytes at {:#x} for {})", self.op, self.len, self.addr, self.pid)
+    }
+}
+
+lazy_static! {
+    /// Requests waiting for `worker`
+    static ref QUEUE: WorkQueue<Arc<Request>> = WorkQueue::new();
+}
+
+/// Queues a transfer for `worker`, copying the data of a write from user
+/// address `addr`. Process `pid` is made ready once it is done. Must be
+/// called by `pid` without the scheduler lock held.
+pub fn submit(file: Arc<File>, op: Op, addr: u64, len: usize, pid: Id) -> Result<Arc<Request>, OsError> {
+    let len = core::cmp::min(len, MAX_TRANSFER);
+    let buf = match op {
+        Op::Read => Vec::new(),
+        Op::Write => unsafe { user_slice(addr, len) }.ok_or(OsError::BadAddress)?.to_vec(),
+    };
+    let request = Arc::new(Request {
+        file,
+        op,
+        addr,
+        len,
+        pid,
+        buf: SpinMutex::new("IO_BUFFER", buf),
+        result: SpinMutex::new("IO_RESULT", None),
+    });
+    QUEUE.push(request.clone());
+    Ok(request)
+}
+
+/// Kernel process running queued transfers in order
+pub extern fn worker() -> ! {
+    QUEUE.run(|request| {
+        let result = request.run();
+        without_interrupts(|| *request.result.lock() = Some(result));
+        SCHEDULER.critical(|s| s.unpark(request.pid));
+    })
+}
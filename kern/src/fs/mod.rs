@@ -1,10 +1,16 @@
 //! fs/mod.rs: File System
-
-use alloc::vec::Vec;
-use crate::fs::path::Path;
-use alloc::sync::Arc;
-use alloc::string::String;
-use spin::RwLock;
+//!
+//! File systems implement the traits in `traits` and are attached to one
+//! tree by `vfs`. Open files are reached through the descriptor table of a
+//! process, see `process::fd`. Syscalls read and write them through `io`.
 
 pub mod traits;
 pub mod path;
+pub mod vfs;
+pub mod file;
+pub mod fat32;
+pub mod ext2;
+pub mod tmpfs;
+pub mod initramfs;
+pub mod devfs;
+pub mod io;
@@ -0,0 +1,315 @@
+//! tmpfs
+//!
+//! A file system kept in kernel memory and gone on reboot. File contents
+//! and symlink targets live on the heap, together they may not take more
+//! than the size the file system was created with. What is counted is the
+//! capacity allocated for them, not their length. Every inode but the root
+//! is also charged `INODE_COST` plus the length of its name, so empty files
+//! and directories cannot fill the heap either. Writes and creations past
+//! that fail with `NoSpace`.
+//!
+//! Directories hold their entries by name and own the inodes in them, an
+//! unlinked inode is freed when the last reference to it goes away.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::{max, min};
+use core::sync::atomic::{AtomicU64, Ordering};
+use kernel_api::{FileType, OsError, MAX_NAME_LEN};
+use spin::RwLock;
+use crate::fs::path::{Component, Path};
+use crate::fs::traits::{DirEntry, FileSystem, Inode, InodeNumber, Metadata};
+
+/// Bytes charged for an inode besides its contents, about what it and its
+/// directory entry take on the heap
+const INODE_COST: u64 = 256;
+
+/// Accounting shared by all inodes of one tmpfs
+struct Shared {
+    size: u64,
+    used: AtomicU64,
+    next_ino: AtomicU64,
+}
+
+impl Shared {
+    /// Takes `bytes` from what is left, `NoSpace` if there is not enough
+    fn reserve(&self, bytes: u64) -> Result<(), OsError> {
+        let mut used = self.used.load(Ordering::Relaxed);
+        loop {
+            let new = used.checked_add(bytes).filter(|&new| new <= self.size).ok_or(OsError::NoSpace)?;
+            match self.used.compare_exchange_weak(used, new, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return Ok(()),
+                Err(current) => used = current,
+            }
+        }
+    }
+
+    fn release(&self, bytes: u64) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+enum Node {
+    File(Vec<u8>),
+    Dir(BTreeMap<String, Arc<TmpInode>>),
+    Symlink(String),
+}
+
+struct State {
+    node: Node,
+    nlink: u64,
+}
+
+pub struct TmpInode {
+    fs: Arc<Shared>,
+    ino: InodeNumber,
+    /// Charged on creation besides the contents, released on drop
+    cost: u64,
+    state: RwLock<State>,
+}
+
+pub struct TmpFs {
+    root: Arc<TmpInode>,
+}
+
+impl TmpFs {
+    /// An empty tmpfs holding at most `size` bytes
+    pub fn new(size: u64) -> TmpFs {
+        let shared = Arc::new(Shared {
+            size,
+            used: AtomicU64::new(0),
+            next_ino: AtomicU64::new(1),
+        });
+        TmpFs {
+            root: TmpInode::new(&shared, Node::Dir(BTreeMap::new()), 0),
+        }
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Result<Arc<dyn Inode>, OsError> {
+        Ok(self.root.clone())
+    }
+}
+
+/// Checks that `name` is a single normal path component
+fn entry_name(name: &str) -> Result<&str, OsError> {
+    if name.len() > MAX_NAME_LEN {
+        return Err(OsError::NameTooLong);
+    }
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(n)), None) if n.to_str() == Some(name) => Ok(name),
+        _ => Err(OsError::InvalidArgument),
+    }
+}
+
+impl TmpInode {
+    fn new(fs: &Arc<Shared>, node: Node, cost: u64) -> Arc<TmpInode> {
+        let nlink = match node {
+            Node::Dir(_) => 2,
+            _ => 1,
+        };
+        Arc::new(TmpInode {
+            fs: fs.clone(),
+            ino: fs.next_ino.fetch_add(1, Ordering::Relaxed),
+            cost,
+            state: RwLock::new(State { node, nlink }),
+        })
+    }
+
+    /// Adds a new inode holding `node` as `name`
+    fn add(&self, name: &str, node: Node) -> Result<Arc<dyn Inode>, OsError> {
+        let name = entry_name(name)?;
+        let mut state = self.state.write();
+        if state.nlink == 0 {
+            return Err(OsError::NoEntry);
+        }
+        let is_dir = match node {
+            Node::Dir(_) => true,
+            _ => false,
+        };
+        let entries = match &mut state.node {
+            Node::Dir(entries) => entries,
+            _ => return Err(OsError::NotADirectory),
+        };
+        if entries.contains_key(name) {
+            return Err(OsError::FileExists);
+        }
+        let cost = INODE_COST + name.len() as u64;
+        self.fs.reserve(cost)?;
+        let inode = TmpInode::new(&self.fs, node, cost);
+        entries.insert(name.to_string(), inode.clone());
+        // `..` of the new directory
+        if is_dir {
+            state.nlink += 1;
+        }
+        Ok(inode)
+    }
+}
+
+impl Inode for TmpInode {
+    fn metadata(&self) -> Result<Metadata, OsError> {
+        let state = self.state.read();
+        let (kind, size, mode) = match &state.node {
+            Node::File(data) => (FileType::Regular, data.len() as u64, 0o644),
+            Node::Dir(entries) => (FileType::Directory, entries.len() as u64, 0o755),
+            Node::Symlink(target) => (FileType::Symlink, target.len() as u64, 0o777),
+        };
+        Ok(Metadata {
+            ino: self.ino,
+            kind,
+            size,
+            nlink: state.nlink,
+            mode,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, OsError> {
+        let state = self.state.read();
+        let data = match &state.node {
+            Node::File(data) => data,
+            Node::Dir(_) => return Err(OsError::IsADirectory),
+            _ => return Err(OsError::InvalidArgument),
+        };
+        if offset >= data.len() as u64 {
+            return Ok(0);
+        }
+        let offset = offset as usize;
+        let count = min(buf.len(), data.len() - offset);
+        buf[..count].copy_from_slice(&data[offset..offset + count]);
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, OsError> {
+        let mut state = self.state.write();
+        let data = match &mut state.node {
+            Node::File(data) => data,
+            Node::Dir(_) => return Err(OsError::IsADirectory),
+            _ => return Err(OsError::InvalidArgument),
+        };
+        let end = offset.checked_add(buf.len() as u64).ok_or(OsError::NoSpace)?;
+        let capacity = data.capacity() as u64;
+        if end > capacity {
+            // Doubled like `Vec` would, or just enough if that does not fit
+            let doubled = max(end, capacity * 2);
+            let new = match self.fs.reserve(doubled - capacity) {
+                Ok(()) => doubled,
+                Err(_) => self.fs.reserve(end - capacity).map(|_| end)?,
+            };
+            data.reserve_exact(new as usize - data.len());
+        }
+        if end > data.len() as u64 {
+            data.resize(end as usize, 0);
+        }
+        data[offset as usize..end as usize].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), OsError> {
+        let mut state = self.state.write();
+        let data = match &mut state.node {
+            Node::File(data) => data,
+            Node::Dir(_) => return Err(OsError::IsADirectory),
+            _ => return Err(OsError::InvalidArgument),
+        };
+        // Shrunk to fit, the capacity is `size` afterwards
+        let capacity = data.capacity() as u64;
+        if size > capacity {
+            self.fs.reserve(size - capacity)?;
+        } else {
+            self.fs.release(capacity - size);
+        }
+        data.resize(size as usize, 0);
+        data.shrink_to_fit();
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, OsError> {
+        let name = entry_name(name)?;
+        match &self.state.read().node {
+            Node::Dir(entries) => entries.get(name).map(|i| i.clone() as Arc<dyn Inode>).ok_or(OsError::NoEntry),
+            _ => Err(OsError::NotADirectory),
+        }
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<Arc<dyn Inode>, OsError> {
+        match kind {
+            FileType::Regular => self.add(name, Node::File(Vec::new())),
+            FileType::Directory => self.add(name, Node::Dir(BTreeMap::new())),
+            _ => Err(OsError::InvalidArgument),
+        }
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, OsError> {
+        let target = target.to_string();
+        let capacity = target.capacity() as u64;
+        self.fs.reserve(capacity)?;
+        self.add(name, Node::Symlink(target)).map_err(|e| {
+            self.fs.release(capacity);
+            e
+        })
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), OsError> {
+        let name = entry_name(name)?;
+        let mut state = self.state.write();
+        let entries = match &mut state.node {
+            Node::Dir(entries) => entries,
+            _ => return Err(OsError::NotADirectory),
+        };
+        let inode = entries.get(name).ok_or(OsError::NoEntry)?.clone();
+        let mut child = inode.state.write();
+        let is_dir = match &child.node {
+            Node::Dir(children) if !children.is_empty() => return Err(OsError::DirectoryNotEmpty),
+            Node::Dir(_) => true,
+            _ => false,
+        };
+        entries.remove(name);
+        child.nlink = 0;
+        if is_dir {
+            state.nlink -= 1;
+        }
+        Ok(())
+    }
+
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, OsError> {
+        let state = self.state.read();
+        let entries = match &state.node {
+            Node::Dir(entries) => entries,
+            _ => return Err(OsError::NotADirectory),
+        };
+        match entries.iter().nth(index) {
+            Some((name, inode)) => Ok(Some(DirEntry {
+                name: name.clone(),
+                ino: inode.ino,
+                kind: inode.kind()?,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    fn readlink(&self) -> Result<String, OsError> {
+        match &self.state.read().node {
+            Node::Symlink(target) => Ok(target.clone()),
+            _ => Err(OsError::InvalidArgument),
+        }
+    }
+}
+
+impl Drop for TmpInode {
+    fn drop(&mut self) {
+        let bytes = match &self.state.read().node {
+            Node::File(data) => data.capacity(),
+            Node::Symlink(target) => target.capacity(),
+            Node::Dir(_) => 0,
+        };
+        self.fs.release(bytes as u64 + self.cost);
+    }
+}
@@ -1,5 +1,144 @@
 //! Traits for File Systems
+//!
+//! A file system hands out its root directory as an `Inode`, everything else
+//! is reached by looking names up in directories. Inodes are shared through
+//! `Arc`, an inode stays usable while referenced even if it is unlinked.
+//!
+//! Operations a kind of inode does not support keep their default, which
+//! fails with the error a caller expects for it.
 
-pub trait FileSystem {
+use alloc::string::String;
+use alloc::sync::Arc;
+use kernel_api::{FileType, OsError, Stat};
 
-}
\ No newline at end of file
+/// Inode number, unique within one file system
+pub type InodeNumber = u64;
+
+#[derive(Debug, Copy, Clone)]
+pub struct Metadata {
+    pub ino: InodeNumber,
+    pub kind: FileType,
+    pub size: u64,
+    pub nlink: u64,
+    /// Permission bits
+    pub mode: u16,
+}
+
+impl From<Metadata> for Stat {
+    fn from(m: Metadata) -> Stat {
+        Stat {
+            ino: m.ino,
+            kind: m.kind as u64,
+            size: m.size,
+            nlink: m.nlink,
+            mode: m.mode as u64,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub ino: InodeNumber,
+    pub kind: FileType,
+}
+
+pub trait FileSystem: Send + Sync {
+    /// Short name of the kind of file system, such as `tmpfs`
+    fn name(&self) -> &str;
+
+    fn root(&self) -> Result<Arc<dyn Inode>, OsError>;
+
+    /// Writes cached changes back to the device
+    fn sync(&self) -> Result<(), OsError> {
+        Ok(())
+    }
+}
+
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Result<Metadata, OsError>;
+
+    fn kind(&self) -> Result<FileType, OsError> {
+        self.metadata().map(|m| m.kind)
+    }
+
+    /// Reads from `offset`, 0 at the end of the file
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, OsError> {
+        Err(self.not_a_file())
+    }
+
+    /// Writes at `offset`, growing the file if it ends before
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, OsError> {
+        Err(self.not_a_file())
+    }
+
+    /// Sets the size of the file, new bytes read as zero
+    fn truncate(&self, _size: u64) -> Result<(), OsError> {
+        Err(self.not_a_file())
+    }
+
+    /// The entry `name` of this directory. `.` and `..` are handled by the
+    /// VFS and never looked up.
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, OsError> {
+        Err(OsError::NotADirectory)
+    }
+
+    /// Creates an empty file or directory `name` in this directory
+    fn create(&self, _name: &str, _kind: FileType) -> Result<Arc<dyn Inode>, OsError> {
+        Err(OsError::NotADirectory)
+    }
+
+    /// Creates a symlink `name` in this directory pointing to `target`
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, OsError> {
+        Err(OsError::NotADirectory)
+    }
+
+    /// Removes the entry `name` from this directory. Directories must be
+    /// empty.
+    fn unlink(&self, _name: &str) -> Result<(), OsError> {
+        Err(OsError::NotADirectory)
+    }
+
+    /// Entry number `index` of this directory, `None` past the last one.
+    /// `.` and `..` are not listed.
+    fn readdir(&self, _index: usize) -> Result<Option<DirEntry>, OsError> {
+        Err(OsError::NotADirectory)
+    }
+
+    /// Target of this symlink
+    fn readlink(&self) -> Result<String, OsError> {
+        Err(OsError::InvalidArgument)
+    }
+
+    /// `false` while `read_at` would fail with `WouldBlock`, only devices
+    /// wait for input
+    fn readable(&self) -> bool {
+        true
+    }
+
+    /// `false` while `write_at` would fail with `WouldBlock`
+    fn writable(&self) -> bool {
+        true
+    }
+
+    /// `true` if `readable` and `writable` change without a
+    /// `GlobalScheduler::notify`, processes waiting for them are then woken
+    /// every `syscall::POLL_INTERVAL` to check
+    fn polled(&self) -> bool {
+        false
+    }
+
+    /// Device specific request `request`, one of the `IOCTL_*` constants,
+    /// with argument `arg`
+    fn ioctl(&self, _request: u64, _arg: u64) -> Result<u64, OsError> {
+        Err(OsError::InvalidArgument)
+    }
+
+    /// The error for file operations on something else, mostly directories
+    fn not_a_file(&self) -> OsError {
+        match self.kind() {
+            Ok(FileType::Directory) => OsError::IsADirectory,
+            _ => OsError::InvalidArgument,
+        }
+    }
+}
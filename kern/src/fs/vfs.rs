@@ -0,0 +1,332 @@
+//! Virtual File System
+//!
+//! File systems are attached to the tree with `mount` at a directory, the
+//! first one at `/`. Mounts are keyed by their canonical path, the absolute
+//! path without `.`, `..` or symlinks, and cover the directory they are
+//! mounted on.
+//!
+//! Paths are walked one component at a time from the root, relative paths
+//! as well. The walk keeps the inodes of the directories it passed, so `..`
+//! goes back to where it came from, also across mounts. A symlink is
+//! replaced by its target, at most `MAX_SYMLINKS` times per walk.
+//!
+//! Inodes found by a walk are kept in the dentry cache by canonical path.
+//! Only entries that exist are cached. Entries are dropped when they are
+//! unlinked, when a mount covers or uncovers them, or to make room.
+//!
+//! The mount table and the dentry cache are spin locks that syscalls take as
+//! well, so they are taken with interrupts disabled and never held across a
+//! call into a file system. Inodes leaving the cache are dropped after the
+//! lock is released.
+
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use hashbrown::HashMap;
+use kernel_api::{FileType, OsError, MAX_NAME_LEN, O_CREATE, O_DIRECTORY, O_EXCL, O_NOFOLLOW, O_TRUNC, O_WRITE};
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::fs::file::OpenFile;
+use crate::fs::path::{Component, Path, PathBuf};
+use crate::fs::traits::{FileSystem, Inode, Metadata};
+use crate::sync::{SpinMutex, SpinRwLock};
+
+/// Symlinks followed in one walk before it fails with `FilesystemLoop`
+pub const MAX_SYMLINKS: usize = 40;
+/// Inodes kept in the dentry cache
+const DENTRY_CACHE_SIZE: usize = 512;
+
+struct Mount {
+    fs: Arc<dyn FileSystem>,
+    root: Arc<dyn Inode>,
+}
+
+lazy_static! {
+    static ref MOUNTS: SpinRwLock<HashMap<PathBuf, Mount>> = SpinRwLock::new("VFS_MOUNTS", HashMap::new());
+    static ref DENTRIES: SpinMutex<HashMap<PathBuf, Arc<dyn Inode>>> = SpinMutex::new("VFS_DENTRIES", HashMap::new());
+}
+
+/// An inode and the canonical path it was found at
+pub struct Resolved {
+    pub path: PathBuf,
+    pub inode: Arc<dyn Inode>,
+}
+
+fn with_mounts<F, R>(f: F) -> R
+    where F: FnOnce(&HashMap<PathBuf, Mount>) -> R
+{
+    without_interrupts(|| f(&MOUNTS.read()))
+}
+
+fn root() -> Result<Arc<dyn Inode>, OsError> {
+    with_mounts(|mounts| mounts.get(Path::new("/")).map(|m| m.root.clone())).ok_or(OsError::NoEntry)
+}
+
+fn cached(path: &Path) -> Option<Arc<dyn Inode>> {
+    without_interrupts(|| DENTRIES.lock().get(path).cloned())
+}
+
+fn cache(path: PathBuf, inode: Arc<dyn Inode>) {
+    let (victim, replaced) = without_interrupts(|| {
+        let mut dentries = DENTRIES.lock();
+        let mut victim = None;
+        if dentries.len() >= DENTRY_CACHE_SIZE {
+            let key = dentries.keys().next().cloned();
+            victim = key.and_then(|key| dentries.remove(&key));
+        }
+        (victim, dentries.insert(path, inode))
+    });
+    drop((victim, replaced));
+}
+
+/// Removes the cached entries for which `stale` holds
+fn evict<F>(mut stale: F)
+    where F: FnMut(&Path, &Arc<dyn Inode>) -> bool
+{
+    let evicted: Vec<Arc<dyn Inode>> = without_interrupts(|| {
+        let mut dentries = DENTRIES.lock();
+        let keys: Vec<PathBuf> = dentries.iter()
+            .filter(|(p, inode)| stale(p, inode))
+            .map(|(p, _)| p.clone())
+            .collect();
+        keys.iter().filter_map(|p| dentries.remove(p)).collect()
+    });
+    drop(evicted);
+}
+
+/// Drops the cached entries at and below `path`
+fn invalidate(path: &Path) {
+    evict(|p, _| p.starts_with(path));
+}
+
+/// Drops the cached entries of `inode` under any path, also used by file
+/// systems whose entries go away without `unlink`
+pub fn forget(inode: &dyn Inode) {
+    let inode = inode as *const dyn Inode as *const u8;
+    evict(|_, cached| &**cached as *const dyn Inode as *const u8 == inode);
+}
+
+/// The entry `name` of `dir` found at `path`, or the root of what is
+/// mounted there
+fn lookup_child(dir: &Arc<dyn Inode>, name: &str, path: &Path) -> Result<Arc<dyn Inode>, OsError> {
+    if let Some(inode) = cached(path) {
+        return Ok(inode);
+    }
+    let inode = with_mounts(|mounts| mounts.get(path).map(|mount| mount.root.clone()));
+    let inode = match inode {
+        Some(inode) => inode,
+        None => dir.lookup(name)?,
+    };
+    cache(path.to_path_buf(), inode.clone());
+    Ok(inode)
+}
+
+/// Pushes the components of `path` to the front of `pending`. Returns
+/// `true` if the path is absolute.
+fn push_components(path: &Path, pending: &mut VecDeque<String>) -> Result<bool, OsError> {
+    let mut names = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => names.push(name.to_str().ok_or(OsError::InvalidArgument)?.to_string()),
+            Component::ParentDir => names.push(String::from("..")),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {},
+        }
+    }
+    for name in names.into_iter().rev() {
+        pending.push_front(name);
+    }
+    Ok(path.has_root())
+}
+
+/// Walks `path` from the root. A symlink in the last component is followed
+/// if `follow` is set, otherwise it is returned itself.
+pub fn resolve<P: AsRef<Path>>(path: P, follow: bool) -> Result<Resolved, OsError> {
+    let mut stack = vec![root()?];
+    let mut canonical = PathBuf::from("/");
+    let mut pending = VecDeque::new();
+    push_components(path.as_ref(), &mut pending)?;
+    let mut links = 0;
+
+    while let Some(name) = pending.pop_front() {
+        if name == ".." {
+            if stack.len() > 1 {
+                stack.pop();
+                canonical.pop();
+            }
+            continue;
+        }
+        if name.len() > MAX_NAME_LEN {
+            return Err(OsError::NameTooLong);
+        }
+        let dir = stack.last().expect("root").clone();
+        if dir.kind()? != FileType::Directory {
+            return Err(OsError::NotADirectory);
+        }
+        let child_path = canonical.join(&name);
+        let child = lookup_child(&dir, &name, &child_path)?;
+
+        if child.kind()? == FileType::Symlink && (follow || !pending.is_empty()) {
+            links += 1;
+            if links > MAX_SYMLINKS {
+                return Err(OsError::FilesystemLoop);
+            }
+            let target = child.readlink()?;
+            // Relative targets start at the directory holding the link
+            if push_components(Path::new(&target), &mut pending)? {
+                stack.truncate(1);
+                canonical = PathBuf::from("/");
+            }
+            continue;
+        }
+        stack.push(child);
+        canonical = child_path;
+    }
+
+    Ok(Resolved {
+        path: canonical,
+        inode: stack.pop().expect("root"),
+    })
+}
+
+/// The directory `path` is in, following symlinks, and the last component
+fn resolve_parent(path: &Path) -> Result<(Resolved, String), OsError> {
+    let name = match path.components().next_back() {
+        Some(Component::Normal(name)) => name.to_str().ok_or(OsError::InvalidArgument)?.to_string(),
+        // `/`, `.` and `..` always exist
+        _ => return Err(OsError::FileExists),
+    };
+    if name.len() > MAX_NAME_LEN {
+        return Err(OsError::NameTooLong);
+    }
+    let parent = resolve(path.parent().unwrap_or(Path::new("/")), true)?;
+    if parent.inode.kind()? != FileType::Directory {
+        return Err(OsError::NotADirectory);
+    }
+    Ok((parent, name))
+}
+
+pub fn lookup<P: AsRef<Path>>(path: P) -> Result<Arc<dyn Inode>, OsError> {
+    resolve(path, true).map(|r| r.inode)
+}
+
+/// Metadata of `path`, of the symlink itself if `follow` is not set
+pub fn stat<P: AsRef<Path>>(path: P, follow: bool) -> Result<Metadata, OsError> {
+    resolve(path, follow)?.inode.metadata()
+}
+
+/// Creates an empty file or directory at `path`
+pub fn create<P: AsRef<Path>>(path: P, kind: FileType) -> Result<Arc<dyn Inode>, OsError> {
+    let (parent, name) = resolve_parent(path.as_ref())?;
+    let inode = parent.inode.create(&name, kind)?;
+    cache(parent.path.join(&name), inode.clone());
+    Ok(inode)
+}
+
+pub fn mkdir<P: AsRef<Path>>(path: P) -> Result<(), OsError> {
+    create(path, FileType::Directory).map(|_| ())
+}
+
+/// Creates a symlink at `path` pointing to `target`
+pub fn symlink<P: AsRef<Path>>(path: P, target: &str) -> Result<(), OsError> {
+    let (parent, name) = resolve_parent(path.as_ref())?;
+    parent.inode.symlink(&name, target).map(|_| ())
+}
+
+/// Removes the file, symlink or empty directory at `path`
+pub fn unlink<P: AsRef<Path>>(path: P) -> Result<(), OsError> {
+    let (parent, name) = resolve_parent(path.as_ref())?;
+    let child_path = parent.path.join(&name);
+    if with_mounts(|mounts| mounts.contains_key(&child_path)) {
+        return Err(OsError::Busy);
+    }
+    let child = parent.inode.lookup(&name)?;
+    parent.inode.unlink(&name)?;
+    invalidate(&child_path);
+    // It may also be cached under other names, through hard links
+    forget(&*child);
+    Ok(())
+}
+
+/// Opens `path` with the `O_*` flags in `flags`
+pub fn open<P: AsRef<Path>>(path: P, flags: u64) -> Result<OpenFile, OsError> {
+    let path = path.as_ref();
+    let inode = match resolve(path, flags & O_NOFOLLOW == 0) {
+        Ok(_) if flags & O_CREATE != 0 && flags & O_EXCL != 0 => return Err(OsError::FileExists),
+        Ok(resolved) => resolved.inode,
+        Err(OsError::NoEntry) if flags & O_CREATE != 0 => create(path, FileType::Regular)?,
+        Err(e) => return Err(e),
+    };
+    match inode.kind()? {
+        FileType::Directory if flags & O_WRITE != 0 => return Err(OsError::IsADirectory),
+        FileType::Directory => {},
+        _ if flags & O_DIRECTORY != 0 => return Err(OsError::NotADirectory),
+        FileType::Symlink => return Err(OsError::FilesystemLoop),
+        // Devices that cannot be written have no write permission bits
+        FileType::BlockDevice if flags & O_WRITE != 0 && inode.metadata()?.mode & 0o222 == 0 => {
+            return Err(OsError::ReadOnly)
+        },
+        FileType::Regular if flags & O_TRUNC != 0 && flags & O_WRITE != 0 => inode.truncate(0)?,
+        _ => {},
+    }
+    Ok(OpenFile::new(inode, flags))
+}
+
+/// Attaches `fs` at the directory `path`. The first file system has to be
+/// mounted at `/`.
+pub fn mount<P: AsRef<Path>>(path: P, fs: Arc<dyn FileSystem>) -> Result<(), OsError> {
+    let root = fs.root()?;
+    let path = if with_mounts(|mounts| mounts.is_empty()) {
+        if path.as_ref() != Path::new("/") {
+            return Err(OsError::NoEntry);
+        }
+        PathBuf::from("/")
+    } else {
+        let resolved = resolve(path, true)?;
+        if resolved.inode.kind()? != FileType::Directory {
+            return Err(OsError::NotADirectory);
+        }
+        resolved.path
+    };
+
+    let name = fs.name().to_string();
+    without_interrupts(|| {
+        let mut mounts = MOUNTS.write();
+        if mounts.contains_key(&path) {
+            return Err(OsError::Busy);
+        }
+        mounts.insert(path.clone(), Mount { fs, root });
+        Ok(())
+    })?;
+    invalidate(&path);
+    info!("[VFS] Mounted {} at {}", name, path.display());
+    Ok(())
+}
+
+/// Detaches the file system mounted at `path`. Files that are open on it
+/// stay usable. Fails with `Busy` while other file systems are mounted on
+/// it.
+pub fn unmount<P: AsRef<Path>>(path: P) -> Result<Arc<dyn FileSystem>, OsError> {
+    let path = resolve(path, true)?.path;
+    let mount = without_interrupts(|| {
+        let mut mounts = MOUNTS.write();
+        if !mounts.contains_key(&path) {
+            return Err(OsError::InvalidArgument);
+        }
+        if mounts.keys().any(|p| p != &path && p.starts_with(&path)) {
+            return Err(OsError::Busy);
+        }
+        Ok(mounts.remove(&path).expect("checked above"))
+    })?;
+    invalidate(&path);
+    // Nothing is left to sync to once it is gone
+    if let Err(e) = mount.fs.sync() {
+        warn!("[VFS] Unable to sync {} at {}: {:?}", mount.fs.name(), path.display(), e);
+    }
+    info!("[VFS] Unmounted {} from {}", mount.fs.name(), path.display());
+    Ok(mount.fs)
+}
+
+/// Mount points and the kind of file system mounted there
+pub fn mounts() -> Vec<(PathBuf, String)> {
+    with_mounts(|mounts| mounts.iter().map(|(p, m)| (p.clone(), m.fs.name().to_string())).collect())
+}
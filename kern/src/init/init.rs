@@ -1,5 +1,6 @@
 use alloc::boxed::Box;
 use core::cmp::min;
+use core::convert::TryFrom;
 use core::ops::Add;
 use core::sync::atomic::Ordering;
 use core::time::Duration;
@@ -22,18 +23,23 @@ use crate::sys::pit::GLOBAL_PIT;
 use crate::init::smp::CORE_BOOT_FLAG;
 use crate::interrupts::{PICS, InterruptIndex};
 use crate::KERNEL_PDPS;
-use crate::memory::align_down;
-use crate::memory::frame_allocator::MemorySegment;
+use crate::memory::{align_down, align_up};
+use crate::memory::frame_allocator::{MemorySegment, SegmentFrameAllocator};
 use crate::memory::paging::{KERNEL_HEAP_BASE, KERNEL_HEAP_TOP, KERNEL_PML4_TABLE, KERNEL_TEXT_BASE, PHYSMAP_BASE};
 use crate::sys::resman::GLOBAL_RESMAN;
+use crate::sys::ioapic::GLOBAL_IOAPIC;
 use crate::device::uart::serial16650::COM1_BASE_ADDR;
+use crate::process::cpu::CpuState;
+use crate::fs::initramfs;
 
 extern "C" {
     static mut __kernel_start: u64;
     static mut __kernel_end: u64;
-    static mut __ap_stack_top: u64;
 }
 
+/// Memory below this is handed out by `LOW_FALLOC`, the rest by `FRAME_ALLOC`
+pub const LOW_MEMORY_TOP: u64 = 16 * 1024 * 1024;
+
 pub struct BootArgs {
     early_serial_port: u16
 }
@@ -55,11 +61,23 @@ pub fn boostrap_core_init(boot_info: BootInformation) {
     debug!("Kern Start - End: {:#08x} - {:#08x} ({:#x})", kernel_start, kernel_end, kernel_end_pa);
     debug!("Max PhysMem {:#x}", max_phys_mem);
 
-    let max_kern_mem = 16u64 * 1024 * 1024; // Reserved 16 MB
+    let max_kern_mem = LOW_MEMORY_TOP; // Reserved 16 MB
 
     debug!("MAX KERN MEM {:#x}, free: {}", max_kern_mem, max_kern_mem - kernel_end_pa);
 
-    LOW_FALLOC.lock().add_segment(MemorySegment::new(kernel_end_pa as usize, (max_kern_mem - kernel_end_pa) as usize).expect(""));
+    // GRUB loads the initramfs after the kernel, its frames are handed out
+    // once `fs::initramfs::unpack` is done with it
+    let initrd = boot_info.module_tags()
+        .find(|module| module.name() == initramfs::MODULE_NAME)
+        .map(|module| (module.start_address() as u64, module.end_address() as u64));
+    let reserved = initrd.map(|(start, end)| {
+        (align_down(start as usize, 4096) as u64, align_up(end as usize, 4096) as u64)
+    });
+    if let Some((start, end)) = initrd {
+        debug!("Initramfs at {:#x} - {:#x}", start, end);
+    }
+
+    add_memory(&mut LOW_FALLOC.lock(), kernel_end_pa, max_kern_mem, reserved);
     debug!("[LOW FALLOC] Free: {} MiB", LOW_FALLOC.lock().free_space() / 1024 / 1024);
 
     for seg in mem_tags.memory_areas() {
@@ -74,10 +92,7 @@ pub fn boostrap_core_init(boot_info: BootInformation) {
         }
         trace!("[FALLOC] AddSeg: {:016X} - {:016X}", seg_start, seg_end);
         without_interrupts(|| {
-            FRAME_ALLOC.lock().add_segment(
-                MemorySegment::new(seg_start, seg_end - seg_start)
-                    .expect("Unable to create")
-            )
+            add_memory(&mut FRAME_ALLOC.lock(), seg_start as u64, seg_end as u64, reserved)
         });
     }
 
@@ -179,6 +194,8 @@ pub fn boostrap_core_init(boot_info: BootInformation) {
     }
     debug!("[kALLOC] Kernel Allocator Initialized");
 
+    initramfs::register(initrd, boot_info.command_line_tag().map_or("", |tag| tag.command_line()));
+
     // Initialize Early Serial
     if !crate::device::uart::SERIAL_PORTS.write().register_early_serial(COM1_BASE_ADDR) {
         crate::device::uart::SERIAL_PORTS.write().register_early_serial(0xd000);
@@ -194,23 +211,31 @@ pub fn boostrap_core_init(boot_info: BootInformation) {
     GLOBAL_APIC.read().lint0_set_lvt(APICDeliveryMode::ExtINT, false);
 
     // Setup TSS / GDT / IDT
+    // Cores are only known by 8 bit IDs, see `CpuSet`
+    let apic_id = match u8::try_from(GLOBAL_APIC.read().apic_id()) {
+        Ok(id) => id,
+        Err(_) => panic!("[APIC] Boot core has x2APIC ID {}, IDs above 255 are not supported",
+                         GLOBAL_APIC.read().apic_id()),
+    };
     {
         let mut x = GLOBAL_RESMAN.write();
         x.initialize();
-        x.register_core(GLOBAL_APIC.read().apic_id());
+        x.register_core(apic_id);
     }
 
-    unsafe { GLOBAL_RESMAN.read().get_gdt(GLOBAL_APIC.read().apic_id()).load(); }
+    unsafe { GLOBAL_RESMAN.read().get_gdt(apic_id).load(); }
     interrupts::init_idt();
 
     unsafe {
         PICS.lock().initialize();
     };
 
-
     // Start Clock
-    trace!("starting clock");
-    GLOBAL_PIT.write().start_clock();
+    // Time is kept by the TSC, so the PIT does not need to interrupt us.
+    trace!("calibrating TSC clocksource");
+    crate::sys::tsc::calibrate();
+    crate::sys::rtc::init();
+    PICS.lock().mask_interrupt(InterruptIndex::Timer.as_u8());
 
     // ENABLE Interrupt at the END
     x86_64::instructions::interrupts::enable();
@@ -237,35 +262,64 @@ pub fn boostrap_core_init(boot_info: BootInformation) {
             error!("[ACPI] Failed to located ACPI: {:?}", e);
         }
     }
+
+    // Per-CPU block, the processor UID comes from the MADT
+    let proc_id = ACPI.read().as_ref()
+        .and_then(|acpi| acpi.boot_processor.as_ref())
+        .map(|bsp| bsp.processor_uid)
+        .unwrap_or(0);
+    let block = GLOBAL_RESMAN.write().register_percpu(apic_id, proc_id);
+    unsafe { block.load(); }
+    block.cpu.set_state(CpuState::Online);
+
+    // Route device interrupts through the I/O APIC if the MADT has one
+    if GLOBAL_IOAPIC.write().initialize() {
+        without_interrupts(|| {
+            PICS.lock().disable();
+            GLOBAL_APIC.read().lint0_set_lvt(APICDeliveryMode::ExtINT, true);
+        });
+        let vector = GLOBAL_IOAPIC.write().claim_isa(1, apic_id, "keyboard")
+            .expect("no I/O APIC input for the keyboard");
+        crate::interrupts::irq::register_irq_handler(vector, Box::new(|_| {
+            crate::sys::keyboard::GLOB_KEYBOARD.process_key_stroke();
+        })).expect("keyboard vector not allocated");
+        info!("[IOAPIC] Device interrupts routed through the I/O APIC");
+    } else {
+        warn!("[IOAPIC] No I/O APIC, using the 8259 PICs");
+    }
+}
+
+/// Adds the frames of `[start, end)` to `alloc`, leaving out `reserved`
+fn add_memory(alloc: &mut SegmentFrameAllocator, start: u64, end: u64, reserved: Option<(u64, u64)>) {
+    let mut add = |start: u64, end: u64| {
+        if end > start {
+            alloc.add_segment(MemorySegment::new(start as usize, (end - start) as usize).expect("Unable to create"));
+        }
+    };
+    match reserved {
+        Some((r_start, r_end)) if r_start < end && r_end > start => {
+            add(start, r_start);
+            add(r_end, end);
+        }
+        _ => add(start, end),
+    }
 }
 
 pub fn mp_initialization() {
+    use acpi::ProcessorState;
+
     let acpi_handle = ACPI.read();
     let acpi = acpi_handle.as_ref().expect("no table >>_<<");
 
     for x in &acpi.application_processors {
-        CORE_BOOT_FLAG.store(true, Ordering::Release);
-        let frame = LOW_FALLOC.lock().allocate_frame().expect("");
-        let sp = frame.start_address().as_u64() + 4096 + KERNEL_TEXT_BASE;
-        let ap_stack_top: &mut u64 = unsafe {
-            &mut *VirtAddr::from_ptr(&__ap_stack_top as *const u64).add(PHYSMAP_BASE).as_mut_ptr()
-        };
-        *ap_stack_top = sp;
         let apic_id = x.local_apic_id;
-        println!("Core {} stack: {:#x}", apic_id,sp);
-
-        // Register core with Resman
-        GLOBAL_RESMAN.write().register_core(apic_id);
-
-        crate::sys::apic::send_ipi(apic_id, 0, IPIDeliveryMode::INIT, IPIDestinationShorthand::NoShorthand);
-        sleep(Duration::from_millis(10)).expect("");
-        crate::sys::apic::send_ipi(apic_id, 0x8, IPIDeliveryMode::StartUp, IPIDestinationShorthand::NoShorthand);
-        sleep(Duration::from_millis(1)).unwrap();
-        if CORE_BOOT_FLAG.load(Ordering::Relaxed) {
-            crate::sys::apic::send_ipi(apic_id, 0x8, IPIDeliveryMode::StartUp, IPIDestinationShorthand::NoShorthand);
+        if matches!(x.state, ProcessorState::Disabled) {
+            info!("[SMP] Core {} is disabled by the firmware", apic_id);
+            continue;
         }
-        while CORE_BOOT_FLAG.load(Ordering::Relaxed) {
-            sleep(Duration::from_millis(1)).expect("");
+        match crate::init::smp::start_ap(apic_id, x.processor_uid) {
+            Ok(()) => info!("[SMP] Core {} is online", apic_id),
+            Err(e) => warn!("[SMP] Core {} did not start: {:?}", apic_id, e),
         }
     }
 }
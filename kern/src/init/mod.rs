@@ -9,18 +9,22 @@ pub mod smp;
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    let cpuid_out: u32;
-    unsafe {
-        asm!(
-             "mov eax, 1",
-             "cpuid",
-             lateout("eax") _,
-             lateout("ebx") cpuid_out,
-             lateout("ecx") _,
-             lateout("edx") _
-        );
+    // May panic before the per-CPU block is loaded
+    let apic_id: u8 = match crate::sys::percpu::from_msr() {
+        Some(block) => block.cpu.apic_id,
+        None => (unsafe { core::arch::x86_64::__cpuid(1) }.ebx >> 24) as u8,
+    };
+
+    x86_64::instructions::interrupts::disable();
+    if !crate::sys::ipi::stop_other_cpus(apic_id) {
+        // Either another core panicked and is about to stop this one, or
+        // this is a panic while panicking
+        loop {
+            hlt();
+        }
     }
-    let apic_id: u8 = (cpuid_out >> 24) as u8;
+    // A stopped core may have been printing
+    unsafe { CONSOLE.force_unlock() };
 
     println!("\n---------- PANIC ---------- (Core {})", apic_id);
     match info.location() {
@@ -34,6 +38,9 @@ fn panic(info: &PanicInfo) -> ! {
         Some(s) => { con.write_fmt(*s).unwrap() }
         None => {}
     }
+    drop(con);
+    println!();
+    crate::sys::ipi::print_stopped_cpus();
     loop {
         hlt();
     }
@@ -13,20 +13,85 @@ use crate::sys::resman::GLOBAL_RESMAN;
 use crate::interrupts::{init_idt, InterruptIndex};
 use x86_64::instructions::interrupts::int3;
 use core::time::Duration;
+use core::convert::TryFrom;
 use crate::sys::apic::timer::APICTimerMode;
+use crate::sys::percpu;
+use crate::process::cpu::{CpuSet, CpuState, LocalCPU};
+use crate::sys::apic::{IPIDeliveryMode, IPIDestinationShorthand, send_ipi};
+use crate::sys::pit::spin_wait;
+use crate::{ACPI, LOW_FALLOC};
+use alloc::vec::Vec;
+use kernel_api::syscall::sleep;
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::FrameAllocator;
+
+extern "C" {
+    static mut __ap_stack_top: u64;
+}
+
+/// APs start in real mode at this page, see `smp_wakeup.asm`
+const AP_TRAMPOLINE_PAGE: u8 = 0x8;
+/// How long an AP may take from INIT to running the scheduler
+const AP_BOOT_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long a core may take to reach its next scheduling point and park
+const CPU_DOWN_TIMEOUT: Duration = Duration::from_millis(500);
 
 /// Is the core still booting?
 pub static CORE_BOOT_FLAG: AtomicBool = AtomicBool::new(false);
 
+/// Boot stacks by local APIC ID, from `LOW_FALLOC`. A core keeps its stack
+/// for when it is started again, the stack of a core that did not come up
+/// is freed.
+static AP_STACKS: Mutex<Vec<(u8, PhysFrame)>> = Mutex::new(Vec::new());
+
+lazy_static! {
+    /// Serializes bring-up, `__ap_stack_top` and `CORE_BOOT_FLAG` are
+    /// shared. Held while waiting for the AP, so it is a sleeping lock.
+    static ref AP_START: crate::sync::Mutex<()> = crate::sync::Mutex::new(());
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum HotplugError {
+    /// Not a core of the MADT, or disabled by the firmware
+    NoSuchCpu,
+    /// Device interrupts are routed to the boot processor
+    BootProcessor,
+    /// Taking the core down would leave no core to run processes
+    LastCpu,
+    /// The core is not in a state the operation applies to
+    InvalidState(CpuState),
+    NoStack,
+    Timeout,
+}
+
 #[no_mangle]
 pub fn ap_entry() -> ! {
+    unsafe { percpu::unload() };
     let reference_table_base = KERNEL_PML4_TABLE.lock().as_ref()
         .expect("has kRefPT").as_ref() as *const PageTable as u64 - PHYSMAP_BASE;
     let table_pa = PhysAddr::new(reference_table_base);
 
     unsafe { x86_64::registers::control::Cr3::write(PhysFrame::from_start_address(table_pa).expect(""), Cr3Flags::empty()); }
 
-    unsafe { GLOBAL_RESMAN.read().get_gdt(GLOBAL_APIC.read().apic_id()).load() };
+    // Everything after this finds the core through its per-CPU block
+    GLOBAL_APIC.read().enable_local_mode();
+    let apic_id = match u8::try_from(GLOBAL_APIC.read().apic_id()) {
+        Ok(id) => id,
+        Err(_) => {
+            // Not one of the MADT cores, the BSP marks it failed when it
+            // does not report in
+            warn!("[SMP] Core with x2APIC ID {} above 255 is not supported, halting it",
+                  GLOBAL_APIC.read().apic_id());
+            loop {
+                hlt();
+            }
+        }
+    };
+    unsafe {
+        GLOBAL_RESMAN.read().get_percpu(apic_id).load();
+        GLOBAL_RESMAN.read().get_gdt(apic_id).load();
+    }
     init_idt();
 
     // LAPIC Setup
@@ -35,12 +100,19 @@ pub fn ap_entry() -> ! {
     GLOBAL_APIC.read().timer_set_lvt(InterruptIndex::ApicTimer as u8, APICTimerMode::Periodic, false);
     GLOBAL_APIC.read().lint0_set_lvt(APICDeliveryMode::ExtINT, true);
 
+    // The BSP gives up on cores that take too long and resets them
+    if !percpu::current().cpu.transition(CpuState::Booting, CpuState::Online) {
+        loop {
+            hlt();
+        }
+    }
+
     // Enable Interrupts
     x86_64::instructions::interrupts::enable();
 
     // Clear the pending flag so next core can boot while we setup ourselves.
     CORE_BOOT_FLAG.store(false, Ordering::Release);
-    println!("Core with LAPIC: {} is ready", GLOBAL_APIC.read().apic_id());
+    println!("Core with LAPIC: {} is ready", percpu::apic_id());
 
     SCHEDULER.start();
 }
@@ -50,4 +122,170 @@ pub extern fn thing() -> ! {
     loop {
         shell.shell("1> ");
     }
-}
\ No newline at end of file
+}
+
+fn boot_processor_id() -> Option<u8> {
+    ACPI.read().as_ref().and_then(|a| a.boot_processor.as_ref()).map(|p| p.local_apic_id)
+}
+
+/// ACPI processor UID of an enabled AP
+fn processor_uid(apic_id: u8) -> Option<u8> {
+    use acpi::ProcessorState;
+
+    ACPI.read().as_ref().and_then(|acpi| {
+        acpi.application_processors.iter()
+            .find(|p| p.local_apic_id == apic_id && !matches!(p.state, ProcessorState::Disabled))
+            .map(|p| p.processor_uid)
+    })
+}
+
+/// The boot stack of a core, reusing the one it had before
+fn boot_stack(apic_id: u8) -> Option<PhysFrame> {
+    let mut stacks = AP_STACKS.lock();
+    if let Some((_, frame)) = stacks.iter().find(|(owner, _)| *owner == apic_id) {
+        return Some(*frame);
+    }
+    let frame = LOW_FALLOC.lock().allocate_frame()?;
+    stacks.push((apic_id, frame));
+    Some(frame)
+}
+
+/// Frees the stack of a core that did not come up. It was put back into
+/// INIT, so nothing runs on the stack anymore.
+fn release_boot_stack(apic_id: u8) {
+    let mut stacks = AP_STACKS.lock();
+    if let Some(idx) = stacks.iter().position(|(owner, _)| *owner == apic_id) {
+        let (_, frame) = stacks.swap_remove(idx);
+        unsafe { LOW_FALLOC.lock().deallocate_frame(frame) };
+    }
+}
+
+/// Starts AP `apic_id` and waits until it runs the scheduler. Used at boot
+/// and to bring a core back online, both go through `ap_entry`.
+pub fn start_ap(apic_id: u8, proc_id: u8) -> Result<(), HotplugError> {
+    let _start = AP_START.lock();
+
+    let block = GLOBAL_RESMAN.write().register_percpu(apic_id, proc_id);
+    match block.cpu.state() {
+        CpuState::Offline | CpuState::Failed => {},
+        state => return Err(HotplugError::InvalidState(state)),
+    }
+
+    let frame = boot_stack(apic_id).ok_or(HotplugError::NoStack)?;
+    let sp = frame.start_address().as_u64() + 4096 + KERNEL_TEXT_BASE;
+    let ap_stack_top: &mut u64 = unsafe {
+        &mut *VirtAddr::from_ptr(&__ap_stack_top as *const u64).add(PHYSMAP_BASE).as_mut_ptr()
+    };
+    *ap_stack_top = sp;
+    debug!("[SMP] Core {} stack: {:#x}", apic_id, sp);
+
+    // Fresh GDT on the TSS the core had before, the GDT is dropped when the
+    // core goes offline
+    GLOBAL_RESMAN.write().register_core(apic_id);
+
+    if boot_ap(apic_id, &block.cpu) {
+        Ok(())
+    } else {
+        GLOBAL_RESMAN.write().unregister_core(apic_id);
+        release_boot_stack(apic_id);
+        Err(HotplugError::Timeout)
+    }
+}
+
+/// Starts an AP with INIT-SIPI-SIPI and waits until it reports in.
+/// An AP that times out is put back into INIT so its stack can be reused.
+fn boot_ap(apic_id: u8, cpu: &LocalCPU) -> bool {
+    CORE_BOOT_FLAG.store(true, Ordering::Release);
+    cpu.set_state(CpuState::Booting);
+
+    send_ipi(apic_id.into(), 0, IPIDeliveryMode::INIT, IPIDestinationShorthand::NoShorthand);
+    sleep(Duration::from_millis(10)).expect("");
+    // The second SIPI is only needed if the first one was lost
+    send_ipi(apic_id.into(), AP_TRAMPOLINE_PAGE, IPIDeliveryMode::StartUp, IPIDestinationShorthand::NoShorthand);
+    spin_wait(Duration::from_micros(200));
+    if CORE_BOOT_FLAG.load(Ordering::Acquire) {
+        send_ipi(apic_id.into(), AP_TRAMPOLINE_PAGE, IPIDeliveryMode::StartUp, IPIDestinationShorthand::NoShorthand);
+    }
+
+    let deadline = crate::sys::tsc::current_time() + AP_BOOT_TIMEOUT;
+    while CORE_BOOT_FLAG.load(Ordering::Acquire) {
+        if crate::sys::tsc::current_time() >= deadline {
+            // The AP may still report in until the INIT is delivered, it
+            // halts if it finds itself failed
+            if cpu.transition(CpuState::Booting, CpuState::Failed) {
+                send_ipi(apic_id.into(), 0, IPIDeliveryMode::INIT, IPIDestinationShorthand::NoShorthand);
+                CORE_BOOT_FLAG.store(false, Ordering::Release);
+                return false;
+            }
+        }
+        sleep(Duration::from_millis(1)).expect("");
+    }
+    cpu.is_online()
+}
+
+/// Brings an offline core back online
+pub fn cpu_up(apic_id: u8) -> Result<(), HotplugError> {
+    let proc_id = processor_uid(apic_id).ok_or(HotplugError::NoSuchCpu)?;
+    start_ap(apic_id, proc_id)?;
+    SCHEDULER.critical(|s| s.restore_affinity());
+    info!("[SMP] Core {} is online", apic_id);
+    Ok(())
+}
+
+/// Takes a core offline. Processes that may only run on it are allowed on
+/// every core until `cpu_up` brings it back, the core parks at its next scheduling point and is then put
+/// back into INIT, which releases its GDT.
+pub fn cpu_down(apic_id: u8) -> Result<(), HotplugError> {
+    let _start = AP_START.lock();
+
+    if boot_processor_id() == Some(apic_id) {
+        return Err(HotplugError::BootProcessor);
+    }
+    let block = GLOBAL_RESMAN.read().try_get_percpu(apic_id).ok_or(HotplugError::NoSuchCpu)?;
+    let mut others = SCHEDULER.critical(|s| s.cpus.cpu_set());
+    others.remove(apic_id);
+    if others.is_empty() {
+        return Err(HotplugError::LastCpu);
+    }
+    if !block.cpu.transition(CpuState::Online, CpuState::Stopping) {
+        return Err(HotplugError::InvalidState(block.cpu.state()));
+    }
+    SCHEDULER.critical(|s| s.migrate_from(apic_id));
+
+    // Reach a scheduling point right away instead of at the next tick
+    crate::sys::ipi::smp_call_function(CpuSet::single(apic_id), || {
+        GLOBAL_APIC.read().timer_oneshot(InterruptIndex::ApicTimer.as_u8(), Duration::from_micros(1))
+            .expect("unable to set timer");
+    }, false);
+
+    let deadline = crate::sys::tsc::current_time() + CPU_DOWN_TIMEOUT;
+    while block.cpu.state() == CpuState::Stopping {
+        if crate::sys::tsc::current_time() >= deadline && block.cpu.transition(CpuState::Stopping, CpuState::Online) {
+            SCHEDULER.critical(|s| s.restore_affinity());
+            return Err(HotplugError::Timeout);
+        }
+        sleep(Duration::from_millis(1)).expect("");
+    }
+
+    send_ipi(apic_id.into(), 0, IPIDeliveryMode::INIT, IPIDestinationShorthand::NoShorthand);
+    GLOBAL_RESMAN.write().unregister_core(apic_id);
+    info!("[SMP] Core {} is offline", apic_id);
+    Ok(())
+}
+
+/// Parks the current core if it is being taken offline. Called by the
+/// scheduler once the current process has been scheduled out.
+pub fn park_if_stopping() {
+    if percpu::current().cpu.state() != CpuState::Stopping {
+        return;
+    }
+    x86_64::instructions::interrupts::disable();
+    GLOBAL_APIC.read().timer_stop(InterruptIndex::ApicTimer.as_u8());
+    crate::ALLOCATOR.drain_cache();
+    percpu::current().cpu.set_current_pid(None);
+    crate::sys::ipi::set_offline();
+    // Only INIT gets the core out of here
+    loop {
+        hlt();
+    }
+}
@@ -8,13 +8,16 @@ use crate::{FRAME_ALLOC, PAGE_TABLE};
 use x86_64::structures::paging::{PageTable, Mapper, FrameAllocator, Page, PageTableFlags};
 use core::borrow::BorrowMut;
 use crate::memory::frame_allocator::FrameAllocWrapper;
-use crate::interrupts::context_switch::{apic_timer, syscall_handler};
+use crate::interrupts::context_switch::{apic_timer, syscall_handler, page_fault_trap, nmi_trap, TrapFrame};
 use crate::sys::pit::GLOBAL_PIT;
 use keyboard::*;
 use crate::interrupts::InterruptIndex::XHCI;
 use x86_64::PrivilegeLevel;
+use crate::sys::apic::GLOBAL_APIC;
+use crate::sys::ioapic::GLOBAL_IOAPIC;
 
 pub mod context_switch;
+pub mod irq;
 mod keyboard;
 mod syscall;
 
@@ -27,8 +30,8 @@ lazy_static! {
             idt.double_fault.set_handler_fn(double_fault_handler)
                 .set_stack_index(DOUBLE_FAULT_IST_INDEX as u16);
         }
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
+        idt.page_fault.set_handler_addr(page_fault_trap as u64);
+        idt.non_maskable_interrupt.set_handler_addr(nmi_trap as u64);
         idt.alignment_check.set_handler_fn(alignment_check_handler);
         idt.divide_error.set_handler_fn(div_by_zero_handler);
         idt.overflow.set_handler_fn(overflow_handler);
@@ -38,11 +41,16 @@ lazy_static! {
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
         idt[InterruptIndex::ApicTimer.as_usize()].set_handler_addr(apic_timer as u64);
+        idt[InterruptIndex::TlbShootdown.as_usize()].set_handler_fn(crate::memory::tlb::tlb_shootdown_handler);
+        idt[InterruptIndex::CallFunction.as_usize()].set_handler_fn(crate::sys::ipi::call_function_handler);
         idt[InterruptIndex::XHCI.as_usize()].set_handler_addr(xhci_handler as u64);
         // Syscall
         idt[InterruptIndex::SysCall.as_usize()].set_handler_addr(syscall_handler as u64)
             .set_privilege_level(PrivilegeLevel::Ring3);
 
+        // Device IRQs
+        irq::install(&mut idt);
+
         // LAPIC Spurious
         idt[0xFF].set_handler_fn(spurious_irq);
         idt
@@ -57,6 +65,8 @@ pub enum InterruptIndex {
     Spurious = PIC1_OFFSET + 7,
     XHCI = PIC1_OFFSET + 11,
     ApicTimer = 0x30,
+    TlbShootdown = 0x31,
+    CallFunction = 0x32,
     SysCall = 0x80,
 }
 
@@ -84,31 +94,60 @@ pub fn init_idt() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn xhci_handler(_stack_frame: &mut InterruptStackFrame) {
-    use crate::device::usb::interrupt::usb_interrupt_handler;
-    usb_interrupt_handler();
-    unsafe {PICS.lock().notify_end_of_interrupt(InterruptIndex::XHCI as u8) };
+/// Acknowledges a device interrupt at whichever controller delivered it
+pub fn end_of_interrupt(vector: u8) {
+    if GLOBAL_IOAPIC.read().is_enabled() {
+        GLOBAL_APIC.read().end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(vector) };
+    }
 }
 
-extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut InterruptStackFrame, _ec: PageFaultErrorCode) {
-    use x86_64::registers::control::Cr2;
+/// Held by `x86-interrupt` handlers for their whole body: loads the kernel
+/// GS base and tells lockdep about the trap, `irq` if it handles an interrupt.
+pub(crate) struct TrapGuard {
+    _gs: crate::sys::percpu::KernelGs,
+}
 
-    let faulting_addr = Cr2::read();
-    error!("Faulting ADDR: {:?}", faulting_addr);
-    error!("Error: {:?} \n{:#?}", _ec, stack_frame);
-    panic!("PAGE FAULT");
+impl TrapGuard {
+    #[cfg_attr(not(feature = "lockdep"), allow(unused_variables))]
+    pub fn enter(stack_frame: &InterruptStackFrame, irq: bool) -> TrapGuard {
+        let guard = TrapGuard { _gs: crate::sys::percpu::KernelGs::enter(stack_frame) };
+        #[cfg(feature = "lockdep")]
+        crate::sync::lockdep::trap_enter(irq);
+        guard
+    }
+}
+
+impl Drop for TrapGuard {
+    // Runs before the GS base is swapped back
+    fn drop(&mut self) {
+        #[cfg(feature = "lockdep")]
+        crate::sync::lockdep::trap_exit();
+    }
+}
+
+extern "x86-interrupt" fn xhci_handler(stack_frame: &mut InterruptStackFrame) {
+    let _trap = TrapGuard::enter(stack_frame, true);
+    use crate::device::usb::interrupt::usb_interrupt_handler;
+    usb_interrupt_handler();
+    end_of_interrupt(InterruptIndex::XHCI.as_u8());
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    // trace!("PIT Interrupt");
-    GLOBAL_PIT.read().interrupt();
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
+    let _trap = TrapGuard::enter(stack_frame, true);
+    // The PIT is masked once the TSC clocksource is calibrated
     unsafe {
         PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
 }
 
-extern "x86-interrupt" fn nmi_handler(tf: &mut InterruptStackFrame) {
-    println!("NMI: {:#?}", tf);
+/// Called by `nmi_trap` with the interrupted registers
+#[no_mangle]
+pub extern "C" fn handle_nmi(tf: &mut TrapFrame) {
+    // Does not return if another core panicked
+    crate::sys::ipi::nmi_stop(tf);
+    println!("NMI: {:#x?}", tf);
 }
 
 extern "x86-interrupt" fn div_by_zero_handler(tf: &mut InterruptStackFrame) {
@@ -116,18 +155,22 @@ extern "x86-interrupt" fn div_by_zero_handler(tf: &mut InterruptStackFrame) {
 }
 
 extern "x86-interrupt" fn overflow_handler(tf: &mut InterruptStackFrame) {
+    let _trap = TrapGuard::enter(tf, false);
     println!("overflow: {:#?}", tf);
 }
 
 extern "x86-interrupt" fn alignment_check_handler(tf: &mut InterruptStackFrame, ec: u64) {
+    let _trap = TrapGuard::enter(tf, false);
     println!("ALIGNMENT: EC: {}\n{:#?}",ec, tf);
 }
 
 extern "x86-interrupt" fn breakpoint_handler(tf: &mut InterruptStackFrame) {
+    let _trap = TrapGuard::enter(tf, false);
     println!("TRAP: break\n{:#?}", tf);
 }
 
 extern "x86-interrupt" fn other_handler(tf: &mut InterruptStackFrame) {
+    let _trap = TrapGuard::enter(tf, false);
     println!("Other: break\n{:#?}", tf);
 }
 
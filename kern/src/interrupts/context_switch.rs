@@ -5,11 +5,25 @@ use crate::SCHEDULER;
 use crate::process::state::State::Ready;
 use crate::interrupts::syscall::handle_syscall;
 
-#[repr(u8)]
 #[derive(Debug, Copy, Clone)]
 pub enum InterruptSource {
-    APICTimer = 0x1,
-    SysCall = 0x80
+    APICTimer,
+    PageFault,
+    SysCall,
+    /// Dynamically allocated vector, see `interrupts::irq`
+    Device(u8),
+}
+
+impl From<u64> for InterruptSource {
+    /// Decodes the cause passed by the stubs in `interrupt.asm`
+    fn from(cause: u64) -> Self {
+        match cause {
+            0x1 => InterruptSource::APICTimer,
+            0xE => InterruptSource::PageFault,
+            0x80 => InterruptSource::SysCall,
+            vector => InterruptSource::Device(vector as u8),
+        }
+    }
 }
 
 #[repr(C)]
@@ -67,19 +81,64 @@ impl Default for TrapFrame {
 extern "C" {
     pub fn apic_timer();
     pub fn syscall_handler();
+    pub fn page_fault_trap();
+    pub fn nmi_trap();
+    pub static irq_stub_table: u8;
     pub fn restore_context_wrapper() -> !;
 }
 
 #[no_mangle]
-pub extern "C" fn handle_context_switch(tf: &mut TrapFrame, cause: InterruptSource) {
-    match cause {
+pub extern "C" fn handle_context_switch(tf: &mut TrapFrame, cause: u64) {
+    let source = InterruptSource::from(cause);
+    #[cfg(feature = "lockdep")]
+    crate::sync::lockdep::trap_enter(matches!(source, InterruptSource::APICTimer | InterruptSource::Device(_)));
+    handle_trap(source, tf);
+    #[cfg(feature = "lockdep")]
+    crate::sync::lockdep::trap_exit();
+}
+
+fn handle_trap(source: InterruptSource, tf: &mut TrapFrame) {
+    match source {
         InterruptSource::APICTimer => {
             SCHEDULER.switch(Ready, tf);
         },
         InterruptSource::SysCall => {
             handle_syscall(tf);
         },
-        // _ => {}
+        InterruptSource::PageFault => {
+            // Exceptions are not delivered by the LAPIC, no EOI
+            handle_page_fault(tf);
+            return;
+        },
+        InterruptSource::Device(vector) => {
+            crate::interrupts::irq::dispatch(vector, tf);
+        },
     }
     GLOBAL_APIC.read().end_of_interrupt();
+}
+
+/// Faults in user mode send `SIGSEGV` to the faulting process,
+/// faults in the kernel are fatal.
+fn handle_page_fault(tf: &mut TrapFrame) {
+    use x86_64::registers::control::Cr2;
+
+    let faulting_addr = Cr2::read();
+    if tf.cs & 0b11 != 0b11 {
+        error!("Faulting ADDR: {:?}", faulting_addr);
+        panic!("PAGE FAULT\n{:#x?}", tf);
+    }
+    warn!("[SIG] Segmentation fault at {:?}, rip: {:#x}", faulting_addr, tf.rip);
+    let raised = SCHEDULER.critical(|s| {
+        match s.current_process() {
+            Some(p) => {
+                p.signals.force(kernel_api::SIGSEGV);
+                true
+            },
+            None => false,
+        }
+    });
+    if !raised {
+        panic!("PAGE FAULT in user mode without a process\n{:#x?}", tf);
+    }
+    SCHEDULER.switch(Ready, tf);
 }
\ No newline at end of file
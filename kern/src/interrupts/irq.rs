@@ -0,0 +1,197 @@
+//! Device IRQs
+//!
+//! Vectors `IRQ_VECTOR_BASE..IRQ_VECTOR_BASE + IRQ_VECTOR_COUNT` are handed
+//! out at runtime. Their IDT entries point at the stubs in `interrupt.asm`,
+//! which enter through `save_context` and dispatch to the registered handlers.
+//!
+//! A vector can be shared, all handlers registered on it are called in
+//! registration order. Every core counts the interrupts it handles per vector
+//! in its per-CPU block.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::RwLock;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::idt::InterruptDescriptorTable;
+use crate::interrupts::context_switch::{TrapFrame, irq_stub_table};
+use crate::sys::percpu;
+
+pub const IRQ_VECTOR_BASE: u8 = 0x40;
+pub const IRQ_VECTOR_COUNT: usize = 64;
+/// Size of each stub in `irq_stub_table`
+const IRQ_STUB_SIZE: u64 = 16;
+
+/// What a device IRQ handler is passed
+pub struct InterruptContext<'a> {
+    pub vector: u8,
+    /// State of the interrupted code
+    pub frame: &'a mut TrapFrame,
+}
+
+pub type IrqHandler = Box<dyn Fn(&mut InterruptContext) + Send + Sync>;
+
+/// Identifies a registered handler for `unregister_irq_handler`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct HandlerId(u64);
+
+struct IrqVector {
+    /// Owner given to `allocate_vectors`, `None` if the vector is free
+    name: Option<String>,
+    handlers: Vec<(HandlerId, IrqHandler)>,
+    /// Interrupts that arrived without a handler
+    unhandled: AtomicU64,
+}
+
+/// Per-vector statistics, as shown by `lsirq`
+#[derive(Debug, Clone)]
+pub struct IrqStat {
+    pub vector: u8,
+    pub name: String,
+    pub handlers: usize,
+    /// Interrupts handled by each core, keyed by local APIC ID
+    pub per_cpu: Vec<(u8, u64)>,
+    pub unhandled: u64,
+}
+
+lazy_static! {
+    static ref VECTORS: RwLock<Vec<IrqVector>> = RwLock::new((0..IRQ_VECTOR_COUNT).map(|_| IrqVector {
+        name: None,
+        handlers: Vec::new(),
+        unhandled: AtomicU64::new(0),
+    }).collect());
+}
+
+static NEXT_HANDLER_ID: AtomicU64 = AtomicU64::new(1);
+
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    let base = unsafe { &irq_stub_table as *const u8 as u64 };
+    for i in 0..IRQ_VECTOR_COUNT {
+        idt[IRQ_VECTOR_BASE as usize + i].set_handler_addr(base + i as u64 * IRQ_STUB_SIZE);
+    }
+}
+
+fn index_of(vector: u8) -> Result<usize, ()> {
+    if is_device_vector(vector) {
+        Ok((vector - IRQ_VECTOR_BASE) as usize)
+    } else {
+        Err(())
+    }
+}
+
+pub fn is_device_vector(vector: u8) -> bool {
+    vector >= IRQ_VECTOR_BASE && ((vector - IRQ_VECTOR_BASE) as usize) < IRQ_VECTOR_COUNT
+}
+
+/// Allocates `count` consecutive vectors aligned to `count`, as multiple
+/// message MSI requires. `count` must be a power of two. `name` identifies
+/// the owner in `lsirq`.
+pub fn allocate_vectors(count: usize, name: &str) -> Option<u8> {
+    assert!(count.is_power_of_two() && count <= IRQ_VECTOR_COUNT, "invalid vector count");
+    without_interrupts(|| {
+        let mut vectors = VECTORS.write();
+        for first in (0..IRQ_VECTOR_COUNT).step_by(count) {
+            if vectors[first..first + count].iter().all(|v| v.name.is_none()) {
+                for v in vectors[first..first + count].iter_mut() {
+                    v.name = Some(String::from(name));
+                    v.unhandled.store(0, Ordering::Relaxed);
+                }
+                return Some(IRQ_VECTOR_BASE + first as u8);
+            }
+        }
+        None
+    })
+}
+
+/// Releases vectors from `allocate_vectors` and removes their handlers
+pub fn free_vectors(first: u8, count: usize) {
+    without_interrupts(|| {
+        let mut vectors = VECTORS.write();
+        for vector in first..first + count as u8 {
+            if let Ok(idx) = index_of(vector) {
+                vectors[idx].name = None;
+                vectors[idx].handlers.clear();
+            }
+        }
+    });
+}
+
+/// Adds `handler` to the handlers of an allocated vector.
+/// Returns `Err` if the vector is not allocated.
+pub fn register_irq_handler(vector: u8, handler: IrqHandler) -> Result<HandlerId, ()> {
+    let idx = index_of(vector)?;
+    without_interrupts(|| {
+        let mut vectors = VECTORS.write();
+        if vectors[idx].name.is_none() {
+            return Err(());
+        }
+        let id = HandlerId(NEXT_HANDLER_ID.fetch_add(1, Ordering::Relaxed));
+        vectors[idx].handlers.push((id, handler));
+        Ok(id)
+    })
+}
+
+/// Removes a handler added by `register_irq_handler`. The vector stays
+/// allocated. Returns `Err` if there is no such handler.
+pub fn unregister_irq_handler(vector: u8, id: HandlerId) -> Result<(), ()> {
+    let idx = index_of(vector)?;
+    without_interrupts(|| {
+        let mut vectors = VECTORS.write();
+        let handlers = &mut vectors[idx].handlers;
+        let pos = handlers.iter().position(|(h, _)| *h == id).ok_or(())?;
+        handlers.remove(pos);
+        Ok(())
+    })
+}
+
+/// Runs the handlers of `vector`, called from `handle_context_switch`
+pub fn dispatch(vector: u8, tf: &mut TrapFrame) {
+    let idx = match index_of(vector) {
+        Ok(idx) => idx,
+        Err(()) => {
+            warn!("[IRQ] Unexpected vector {:#x}", vector);
+            return;
+        }
+    };
+    percpu::current().irq_counts[idx].fetch_add(1, Ordering::Relaxed);
+
+    let vectors = VECTORS.read();
+    let entry = &vectors[idx];
+    if entry.handlers.is_empty() {
+        entry.unhandled.fetch_add(1, Ordering::Relaxed);
+        trace!("[IRQ] Unhandled vector {:#x}", vector);
+        return;
+    }
+    let mut ctx = InterruptContext { vector, frame: tf };
+    for (_, handler) in entry.handlers.iter() {
+        handler(&mut ctx);
+    }
+}
+
+/// Statistics of every allocated vector
+pub fn stats() -> Vec<IrqStat> {
+    use crate::sys::resman::GLOBAL_RESMAN;
+
+    without_interrupts(|| {
+        let resman = GLOBAL_RESMAN.read();
+        let vectors = VECTORS.read();
+        vectors.iter().enumerate()
+            .filter_map(|(idx, v)| v.name.as_ref().map(|name| (idx, v, name)))
+            .map(|(idx, v, name)| {
+                let mut per_cpu: Vec<(u8, u64)> = resman.percpu_blocks()
+                    .map(|b| (b.cpu.apic_id, b.irq_counts[idx].load(Ordering::Relaxed)))
+                    .collect();
+                per_cpu.sort();
+                IrqStat {
+                    vector: IRQ_VECTOR_BASE + idx as u8,
+                    name: name.clone(),
+                    handlers: v.handlers.len(),
+                    per_cpu,
+                    unhandled: v.unhandled.load(Ordering::Relaxed),
+                }
+            })
+            .collect()
+    })
+}
@@ -4,7 +4,7 @@ use spin::MutexGuard;
 
 use x86_64::structures::idt::InterruptStackFrame;
 
-use crate::interrupts::{InterruptIndex, PICS};
+use crate::interrupts::InterruptIndex;
 use crate::sys::apic::GLOBAL_APIC;
 use crate::sys::keyboard::GLOB_KEYBOARD;
 
@@ -15,9 +15,8 @@ lazy_static! {
     );
 }
 
-pub(super) extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+pub(super) extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
+    let _trap = crate::interrupts::TrapGuard::enter(stack_frame, true);
     GLOB_KEYBOARD.process_key_stroke();
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+    crate::interrupts::end_of_interrupt(InterruptIndex::Keyboard.as_u8());
 }
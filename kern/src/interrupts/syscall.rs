@@ -8,6 +8,19 @@ use crate::sys::pit::PIT;
 use crate::SCHEDULER;
 use kernel_api::OsError;
 use kernel_api::*;
+use crate::process::signal::{self, SigAction};
+use crate::process::cpu::CpuSet;
+use crate::memory::user::{is_user_writable, user_slice, user_slice_mut, write_user};
+use crate::sync::Waiter;
+use crate::process::futex;
+use crate::process::fd::{Fd, FdTable, File};
+use crate::ipc::pipe::Pipe;
+use crate::ipc::channel::{Channel, Endpoint, Message};
+use crate::ipc::shm::SharedMemory;
+use crate::fs::{io, vfs};
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use alloc::sync::Arc;
 
 // Syscall Calling Convention
 // int 0x80
@@ -15,12 +28,108 @@ use kernel_api::*;
 // Argument are passed in SystemV ABI
 // Status is returned in rax, return value in rdx
 
+/// Parks a kernel process on a `sync::Waiter`, not available to user mode
+pub const NR_KERNEL_WAIT: u64 = 0x1000;
+
+/// How often a process waiting on a file that is `polled` checks it
+pub const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
 pub fn handle_syscall(tf: &mut TrapFrame) {
     trace!("syscall: {}", tf.rax);
     match tf.rax {
         NR_SLEEP => {
             sys_sleep(tf);
         },
+        NR_KILL => {
+            sys_kill(tf);
+        },
+        NR_SIGACTION => {
+            sys_sigaction(tf);
+        },
+        NR_SIGRETURN => {
+            sys_sigreturn(tf);
+        },
+        NR_SCHED_SETAFFINITY => {
+            sys_sched_setaffinity(tf);
+        },
+        NR_SCHED_GETAFFINITY => {
+            sys_sched_getaffinity(tf);
+        },
+        NR_FUTEX_WAIT => {
+            sys_futex_wait(tf);
+        },
+        NR_FUTEX_WAKE => {
+            sys_futex_wake(tf);
+        },
+        NR_PIPE => {
+            sys_pipe(tf);
+        },
+        NR_READ => {
+            sys_read(tf);
+        },
+        NR_WRITE => {
+            sys_write(tf);
+        },
+        NR_CLOSE => {
+            sys_close(tf);
+        },
+        NR_CHANNEL_CREATE => {
+            sys_channel_create(tf);
+        },
+        NR_CHANNEL_SEND => {
+            sys_channel_send(tf);
+        },
+        NR_CHANNEL_RECV => {
+            sys_channel_recv(tf);
+        },
+        NR_SHM_CREATE => {
+            sys_shm_create(tf);
+        },
+        NR_SHM_MAP => {
+            sys_shm_map(tf);
+        },
+        NR_SHM_UNMAP => {
+            sys_shm_unmap(tf);
+        },
+        NR_DUP => {
+            sys_dup(tf);
+        },
+        NR_DUP2 => {
+            sys_dup2(tf);
+        },
+        NR_FD_GETFLAGS => {
+            sys_fd_getflags(tf);
+        },
+        NR_FD_SETFLAGS => {
+            sys_fd_setflags(tf);
+        },
+        NR_OPEN => {
+            sys_open(tf);
+        },
+        NR_SEEK => {
+            sys_seek(tf);
+        },
+        NR_STAT => {
+            sys_stat(tf);
+        },
+        NR_FSTAT => {
+            sys_fstat(tf);
+        },
+        NR_READDIR => {
+            sys_readdir(tf);
+        },
+        NR_MKDIR => {
+            sys_mkdir(tf);
+        },
+        NR_UNLINK => {
+            sys_unlink(tf);
+        },
+        NR_IOCTL => {
+            sys_ioctl(tf);
+        },
+        NR_KERNEL_WAIT => {
+            sys_kernel_wait(tf);
+        },
         _ => {
             warn!("Unknown syscall with id: {}", tf.rax);
             tf.rax = 0;
@@ -46,5 +155,811 @@ pub fn sys_sleep(tf: &mut TrapFrame) {
             false
         }
     }));
+    SCHEDULER.critical(|s| s.add_timer(target));
+    SCHEDULER.switch(wait, tf);
+}
+
+/// pid in rdi, signal in rsi
+pub fn sys_kill(tf: &mut TrapFrame) {
+    let (pid, sig) = (tf.rdi, tf.rsi);
+    if !signal::is_valid(sig) {
+        tf.rax = OsError::InvalidArgument as u64;
+        return;
+    }
+    let (result, is_self) = SCHEDULER.critical(|s| {
+        (s.signal(pid, sig), s.cpus.current_cpu().current_pid() == Some(pid))
+    });
+    if let Err(e) = result {
+        tf.rax = e as u64;
+        return;
+    }
+    tf.rax = OsError::Ok as u64;
+    if is_self {
+        // Act on the signal before returning to user mode
+        SCHEDULER.switch(State::Ready, tf);
+    }
+}
+
+/// signal in rdi, handler in rsi, handler return address in rdx.
+/// Previous handler is returned in rdx
+pub fn sys_sigaction(tf: &mut TrapFrame) {
+    let action = SigAction::from_raw(tf.rsi, tf.rdx);
+    let sig = tf.rdi;
+    let result = SCHEDULER.critical(|s| {
+        s.current_process().ok_or(())
+            .and_then(|p| p.signals.set_action(sig, action))
+    });
+    match result {
+        Ok(old) => {
+            tf.rdx = old.as_raw();
+            tf.rax = OsError::Ok as u64;
+        },
+        Err(_) => {
+            tf.rax = OsError::InvalidArgument as u64;
+        }
+    }
+}
+
+/// Restores the context interrupted by a signal handler
+pub fn sys_sigreturn(tf: &mut TrapFrame) {
+    let restored = SCHEDULER.critical(|s| {
+        s.current_process().ok_or(())
+            .and_then(|p| p.signals.restore(tf))
+    });
+    if restored.is_err() {
+        warn!("sigreturn outside of a signal handler");
+        tf.rax = OsError::InvalidArgument as u64;
+    }
+}
+
+/// Resolves pid 0 to the calling process
+fn resolve_pid(pid: u64) -> u64 {
+    if pid != 0 {
+        return pid;
+    }
+    SCHEDULER.critical(|s| s.cpus.current_cpu().current_pid()).unwrap_or(0)
+}
+
+/// pid in rdi, mask pointer in rsi, mask length in bytes in rdx
+pub fn sys_sched_setaffinity(tf: &mut TrapFrame) {
+    let pid = resolve_pid(tf.rdi);
+    let set = match unsafe { user_slice(tf.rsi, tf.rdx as usize) } {
+        Some(mask) => CpuSet::from_bytes(mask),
+        None => {
+            tf.rax = OsError::BadAddress as u64;
+            return;
+        }
+    };
+    let (result, migrate) = SCHEDULER.critical(|s| {
+        let result = s.set_affinity(pid, set);
+        let cpu = s.cpus.current_cpu();
+        (result, cpu.current_pid() == Some(pid) && !set.contains(cpu.apic_id))
+    });
+    match result {
+        Ok(()) => {
+            tf.rax = OsError::Ok as u64;
+            if migrate {
+                // This core is no longer allowed, move to one that is
+                SCHEDULER.switch(State::Ready, tf);
+            }
+        },
+        Err(e) => tf.rax = e as u64,
+    }
+}
+
+/// pid in rdi, mask pointer in rsi, mask length in bytes in rdx.
+/// Number of bytes written is returned in rdx
+pub fn sys_sched_getaffinity(tf: &mut TrapFrame) {
+    let pid = resolve_pid(tf.rdi);
+    let mask = match unsafe { user_slice_mut(tf.rsi, tf.rdx as usize) } {
+        Some(mask) => mask,
+        None => {
+            tf.rax = OsError::BadAddress as u64;
+            return;
+        }
+    };
+    match SCHEDULER.critical(|s| s.get_affinity(pid)) {
+        Ok(set) => {
+            tf.rdx = set.to_bytes(mask) as u64;
+            tf.rax = OsError::Ok as u64;
+        },
+        Err(e) => tf.rax = e as u64,
+    }
+}
+
+/// futex address in rdi, expected value in rsi, timeout in ms in rdx
+/// (`NO_TIMEOUT` waits forever)
+pub fn sys_futex_wait(tf: &mut TrapFrame) {
+    let (addr, expected) = (tf.rdi, tf.rsi as u32);
+    let deadline = match tf.rdx {
+        NO_TIMEOUT => None,
+        ms => Some(PIT::current_time() + Duration::from_millis(ms)),
+    };
+    let waiter = match futex::enqueue(addr, expected) {
+        Ok(waiter) => waiter,
+        Err(e) => {
+            tf.rax = e as u64;
+            return;
+        }
+    };
+    let wait = State::Waiting(Box::new(move |p: &mut Process| -> bool {
+        if waiter.is_woken() {
+            p.context.rax = OsError::Ok as u64;
+            return true;
+        }
+        match deadline {
+            Some(deadline) if PIT::current_time() >= deadline => {
+                // A wake that raced with the timeout is not lost
+                p.context.rax = if waiter.cancel() {
+                    OsError::IoErrorTimedOut as u64
+                } else {
+                    OsError::Ok as u64
+                };
+                true
+            },
+            _ => false,
+        }
+    }));
+    if let Some(deadline) = deadline {
+        SCHEDULER.critical(|s| s.add_timer(deadline));
+    }
+    // Returned if the scheduler is busy and the process keeps running,
+    // futex users have to expect spurious wake ups anyway
+    tf.rax = OsError::Ok as u64;
+    SCHEDULER.switch(wait, tf);
+}
+
+/// futex address in rdi, maximum number of processes to wake in rsi.
+/// Number of processes woken is returned in rdx
+pub fn sys_futex_wake(tf: &mut TrapFrame) {
+    match futex::wake(tf.rdi, tf.rsi as usize) {
+        Ok(woken) => {
+            tf.rdx = woken as u64;
+            tf.rax = OsError::Ok as u64;
+        },
+        Err(e) => tf.rax = e as u64,
+    }
+}
+
+/// Parks the caller until `ready` holds for `file`, then runs syscall `nr`
+/// again with the same arguments. A `polled` file is checked again after
+/// `POLL_INTERVAL` by running the syscall again.
+fn restart_when(nr: u64, tf: &mut TrapFrame, file: Arc<File>, ready: fn(&File) -> bool) {
+    let deadline = match file.polled() {
+        true => Some(PIT::current_time() + POLL_INTERVAL),
+        false => None,
+    };
+    if let Some(deadline) = deadline {
+        SCHEDULER.critical(|s| s.add_timer(deadline));
+    }
+    // Back to the `int 0x80`, which is two bytes long
+    tf.rip -= 2;
+    tf.rax = nr;
+    SCHEDULER.switch(State::Waiting(Box::new(move |_: &mut Process| {
+        ready(&file) || deadline.map_or(false, |deadline| PIT::current_time() >= deadline)
+    })), tf);
+}
+
+/// Runs `f` on the descriptor table of the calling process under the
+/// scheduler lock. Files taken out of the table have to be returned and
+/// dropped by the caller, see `process::fd`.
+fn with_fds<F, R>(f: F) -> Result<R, OsError>
+    where F: FnOnce(&mut FdTable) -> Result<R, OsError>
+{
+    SCHEDULER.critical(|s| {
+        s.current_process().ok_or(OsError::Unknown).and_then(|p| f(&mut p.fds))
+    })
+}
+
+/// The open file `fd` of the calling process
+fn current_file(fd: Fd) -> Result<Arc<File>, OsError> {
+    with_fds(|fds| fds.get(fd))
+}
+
+/// Parks the caller until `poll` returns `true`, `poll` completes the
+/// syscall. If the scheduler is busy the caller keeps running and the
+/// syscall is run again.
+fn block_on<F>(nr: u64, tf: &mut TrapFrame, mut poll: F)
+    where F: FnMut(&mut Process) -> bool + Send + 'static
+{
+    tf.rip -= 2;
+    tf.rax = nr;
+    SCHEDULER.switch(State::Waiting(Box::new(move |p: &mut Process| -> bool {
+        if poll(p) {
+            p.context.rip += 2;
+            true
+        } else {
+            false
+        }
+    })), tf);
+}
+
+/// Opens `first` and `second` in the calling process and stores their
+/// descriptors in the two u64 at user address `addr`
+fn open_pair(tf: &mut TrapFrame, addr: u64, first: File, second: File) {
+    let fds = match unsafe { user_slice_mut(addr, 16) } {
+        Some(fds) => fds,
+        None => {
+            tf.rax = OsError::BadAddress as u64;
+            return;
+        }
+    };
+    // Only clones go into the table, so files that could not be opened are
+    // dropped here and not under the scheduler lock
+    let (first, second) = (Arc::new(first), Arc::new(second));
+    let result = with_fds(|table| {
+        let first_fd = table.install(first.clone())?;
+        match table.install(second.clone()) {
+            Ok(second_fd) => Ok((first_fd, second_fd)),
+            Err(e) => {
+                table.take(first_fd).expect("just opened");
+                Err(e)
+            }
+        }
+    });
+    match result {
+        Ok((first_fd, second_fd)) => {
+            fds[..8].copy_from_slice(&first_fd.to_ne_bytes());
+            fds[8..].copy_from_slice(&second_fd.to_ne_bytes());
+            tf.rax = OsError::Ok as u64;
+        },
+        Err(e) => tf.rax = e as u64,
+    }
+}
+
+/// Pointer to two u64 in rdi, the read end is stored in the first, the
+/// write end in the second
+pub fn sys_pipe(tf: &mut TrapFrame) {
+    let (reader, writer) = Pipe::new();
+    open_pair(tf, tf.rdi, File::PipeReader(reader), File::PipeWriter(writer));
+}
+
+/// Pointer to two u64 in rdi, the endpoints are stored in them
+pub fn sys_channel_create(tf: &mut TrapFrame) {
+    let (first, second) = Channel::new();
+    open_pair(tf, tf.rdi, File::Channel(first), File::Channel(second));
+}
+
+/// Endpoint fd in rdi, data in rsi, data length in rdx, array of u64 fds to
+/// move to the receiver in rcx, number of fds in r8
+pub fn sys_channel_send(tf: &mut TrapFrame) {
+    let (len, count) = (tf.rdx as usize, tf.r8 as usize);
+    if len > MAX_MESSAGE_SIZE || count > MAX_MESSAGE_HANDLES {
+        tf.rax = OsError::InvalidArgument as u64;
+        return;
+    }
+    let (data, raw_handles) = match unsafe { (user_slice(tf.rsi, len), user_slice(tf.rcx, count * 8)) } {
+        (Some(data), Some(raw_handles)) => (data, raw_handles),
+        _ => {
+            tf.rax = OsError::BadAddress as u64;
+            return;
+        }
+    };
+    let handles: Vec<Fd> = raw_handles.chunks(8)
+        .map(|raw| u64::from_ne_bytes(raw.try_into().expect("8 bytes")))
+        .collect();
+    let file = match current_file(tf.rdi) {
+        Ok(file) => file,
+        Err(e) => {
+            tf.rax = e as u64;
+            return;
+        }
+    };
+    let endpoint = match &*file {
+        File::Channel(endpoint) => endpoint,
+        _ => {
+            tf.rax = OsError::InvalidArgument as u64;
+            return;
+        }
+    };
+
+    let result = SCHEDULER.critical(|s| {
+        let table = &mut s.current_process().ok_or(OsError::Unknown)?.fds;
+        for (i, fd) in handles.iter().enumerate() {
+            if handles[..i].contains(fd) {
+                return Err(OsError::InvalidArgument);
+            }
+            // An endpoint queued on its own channel would never be dropped
+            if let File::Channel(other) = &*table.get(*fd)? {
+                if other.same_channel(endpoint) {
+                    return Err(OsError::InvalidArgument);
+                }
+            }
+        }
+        endpoint.send(|| Ok(Message {
+            data: data.to_vec(),
+            handles: handles.iter().map(|fd| table.take(*fd).expect("checked above")).collect(),
+        }))
+    });
+    match result {
+        Ok(()) => tf.rax = OsError::Ok as u64,
+        Err(OsError::WouldBlock) => restart_when(NR_CHANNEL_SEND, tf, file, File::writable),
+        Err(e) => tf.rax = e as u64,
+    }
+}
+
+/// Moves the next message of `endpoint` into the user buffers `buf` and
+/// `handles`, given as address and length. On success the data length is
+/// returned in rdx and the number of handles in rcx. A message that does not
+/// fit fails with `InvalidArgument` and the sizes it needs, it stays queued.
+///
+/// The buffers are checked on every call, a waiting receiver may have
+/// unmapped them since the syscall.
+fn channel_recv(endpoint: &Endpoint, fds: &mut FdTable, frame: &mut TrapFrame,
+                buf: (u64, usize), handles: (u64, usize)) -> Result<(), OsError> {
+    let message = endpoint.recv(|len, count| {
+        if len > buf.1 || count * 8 > handles.1 {
+            frame.rdx = len as u64;
+            frame.rcx = count as u64;
+            return Err(OsError::InvalidArgument);
+        }
+        if !is_user_writable(buf.0, len) || !is_user_writable(handles.0, count * 8) {
+            return Err(OsError::BadAddress);
+        }
+        if count > fds.free() {
+            return Err(OsError::NoMemory);
+        }
+        Ok(())
+    })?;
+    let (len, count) = (message.data.len(), message.handles.len());
+    let data = unsafe { user_slice_mut(buf.0, len) }.ok_or(OsError::BadAddress)?;
+    data.copy_from_slice(&message.data);
+    for (i, handle) in message.handles.into_iter().enumerate() {
+        let fd = fds.install(handle).expect("checked free descriptors");
+        if !write_user(handles.0 + i as u64 * 8, fd) {
+            return Err(OsError::BadAddress);
+        }
+    }
+    frame.rdx = len as u64;
+    frame.rcx = count as u64;
+    frame.rax = OsError::Ok as u64;
+    Ok(())
+}
+
+/// Endpoint fd in rdi, buffer in rsi, buffer length in rdx, array of u64 to
+/// store received fds in rcx, its length in r8, timeout in ms in r9
+/// (`NO_TIMEOUT` waits forever). See `channel_recv` for the results.
+pub fn sys_channel_recv(tf: &mut TrapFrame) {
+    let deadline = match tf.r9 {
+        NO_TIMEOUT => None,
+        ms => Some(PIT::current_time() + Duration::from_millis(ms)),
+    };
+    let (buf, handles) = ((tf.rsi, tf.rdx as usize), (tf.rcx, (tf.r8 as usize).saturating_mul(8)));
+    if !is_user_writable(buf.0, buf.1) || !is_user_writable(handles.0, handles.1) {
+        tf.rax = OsError::BadAddress as u64;
+        return;
+    }
+    let file = match current_file(tf.rdi) {
+        Ok(file) => file,
+        Err(e) => {
+            tf.rax = e as u64;
+            return;
+        }
+    };
+    if !matches!(&*file, File::Channel(_)) {
+        tf.rax = OsError::InvalidArgument as u64;
+        return;
+    }
+
+    let result = SCHEDULER.critical(|s| {
+        let p = s.current_process().ok_or(OsError::Unknown)?;
+        match &*file {
+            File::Channel(endpoint) => channel_recv(endpoint, &mut p.fds, tf, buf, handles),
+            _ => unreachable!(),
+        }
+    });
+    match result {
+        Ok(()) => {},
+        Err(OsError::WouldBlock) => {
+            if let Some(deadline) = deadline {
+                SCHEDULER.critical(|s| s.add_timer(deadline));
+            }
+            block_on(NR_CHANNEL_RECV, tf, move |p: &mut Process| -> bool {
+                let result = match &*file {
+                    File::Channel(endpoint) => channel_recv(endpoint, &mut p.fds, &mut p.context, buf, handles),
+                    _ => unreachable!(),
+                };
+                match result {
+                    Ok(()) => true,
+                    Err(OsError::WouldBlock) => match deadline {
+                        Some(deadline) if PIT::current_time() >= deadline => {
+                            p.context.rax = OsError::IoErrorTimedOut as u64;
+                            true
+                        },
+                        _ => false,
+                    },
+                    Err(e) => {
+                        p.context.rax = e as u64;
+                        true
+                    },
+                }
+            });
+        },
+        Err(e) => tf.rax = e as u64,
+    }
+}
+
+/// fd in rdi, buffer in rsi, buffer length in rdx.
+/// Number of bytes read is returned in rdx, 0 at the end of the file
+pub fn sys_read(tf: &mut TrapFrame) {
+    let file = match current_file(tf.rdi) {
+        Ok(file) => file,
+        Err(e) => {
+            tf.rax = e as u64;
+            return;
+        }
+    };
+    let buf = match unsafe { user_slice_mut(tf.rsi, tf.rdx as usize) } {
+        Some(buf) => buf,
+        None => {
+            tf.rax = OsError::BadAddress as u64;
+            return;
+        }
+    };
+    if let File::Vfs(_) = &*file {
+        if !file.readable() {
+            restart_when(NR_READ, tf, file, File::readable);
+        } else {
+            transfer(NR_READ, tf, file, io::Op::Read);
+        }
+        return;
+    }
+    match file.read(buf) {
+        Ok(count) => {
+            tf.rdx = count as u64;
+            tf.rax = OsError::Ok as u64;
+        },
+        Err(OsError::WouldBlock) => restart_when(NR_READ, tf, file, File::readable),
+        Err(e) => tf.rax = e as u64,
+    }
+}
+
+/// fd in rdi, buffer in rsi, buffer length in rdx.
+/// Number of bytes written is returned in rdx, it may be less than asked
+pub fn sys_write(tf: &mut TrapFrame) {
+    let file = match current_file(tf.rdi) {
+        Ok(file) => file,
+        Err(e) => {
+            tf.rax = e as u64;
+            return;
+        }
+    };
+    let buf = match unsafe { user_slice(tf.rsi, tf.rdx as usize) } {
+        Some(buf) => buf,
+        None => {
+            tf.rax = OsError::BadAddress as u64;
+            return;
+        }
+    };
+    if let File::Vfs(_) = &*file {
+        if !file.writable() {
+            restart_when(NR_WRITE, tf, file, File::writable);
+        } else {
+            transfer(NR_WRITE, tf, file, io::Op::Write);
+        }
+        return;
+    }
+    match file.write(buf) {
+        Ok(count) => {
+            tf.rdx = count as u64;
+            tf.rax = OsError::Ok as u64;
+        },
+        Err(OsError::WouldBlock) => restart_when(NR_WRITE, tf, file, File::writable),
+        Err(e) => tf.rax = e as u64,
+    }
+}
+
+/// Hands the read or write of syscall `nr` on a VFS file to `fs::io` and
+/// parks the caller until it is done. A syscall run again because the
+/// scheduler was busy picks up the request it queued before.
+fn transfer(nr: u64, tf: &mut TrapFrame, file: Arc<File>, op: io::Op) {
+    let (addr, len) = (tf.rsi, tf.rdx as usize);
+    let current = SCHEDULER.critical(|s| s.current_process().map(|p| (p.pid, p.io.is_some())));
+    match current {
+        Some((pid, false)) => {
+            // Queued outside the lock, the worker may finish right away
+            let request = match io::submit(file, op, addr, len, pid) {
+                Ok(request) => request,
+                Err(e) => {
+                    tf.rax = e as u64;
+                    return;
+                }
+            };
+            SCHEDULER.critical(|s| {
+                if let Some(p) = s.current_process() {
+                    p.io = Some(request);
+                }
+            });
+        },
+        Some((_, true)) => {},
+        None => {
+            tf.rax = OsError::Unknown as u64;
+            return;
+        }
+    }
+    block_on(nr, tf, move |p: &mut Process| -> bool {
+        let result = match p.io.as_ref().and_then(|r| r.take_result()) {
+            Some(result) => result,
+            None => return false,
+        };
+        p.io = None;
+        match result {
+            Ok(count) => {
+                p.context.rdx = count as u64;
+                p.context.rax = OsError::Ok as u64;
+            },
+            // No longer ready, run the syscall again to wait for it
+            Err(OsError::WouldBlock) => p.context.rip -= 2,
+            Err(e) => p.context.rax = e as u64,
+        }
+        true
+    });
+}
+
+/// fd in rdi
+pub fn sys_close(tf: &mut TrapFrame) {
+    let fd = tf.rdi;
+    tf.rax = match with_fds(|fds| fds.take(fd)) {
+        Ok(file) => {
+            drop(file);
+            OsError::Ok as u64
+        },
+        Err(e) => e as u64,
+    };
+}
+
+/// fd in rdi. The new descriptor is returned in rdx
+pub fn sys_dup(tf: &mut TrapFrame) {
+    let fd = tf.rdi;
+    match with_fds(|fds| fds.dup(fd)) {
+        Ok(new) => {
+            tf.rdx = new;
+            tf.rax = OsError::Ok as u64;
+        },
+        Err(e) => tf.rax = e as u64,
+    }
+}
+
+/// Old fd in rdi, new fd in rsi. The new descriptor is returned in rdx
+pub fn sys_dup2(tf: &mut TrapFrame) {
+    let (old, new) = (tf.rdi, tf.rsi);
+    match with_fds(|fds| fds.dup2(old, new)) {
+        Ok(replaced) => {
+            drop(replaced);
+            tf.rdx = new;
+            tf.rax = OsError::Ok as u64;
+        },
+        Err(e) => tf.rax = e as u64,
+    }
+}
+
+/// fd in rdi. The `FD_*` flags are returned in rdx
+pub fn sys_fd_getflags(tf: &mut TrapFrame) {
+    let fd = tf.rdi;
+    match with_fds(|fds| fds.close_on_exec(fd)) {
+        Ok(close_on_exec) => {
+            tf.rdx = if close_on_exec { FD_CLOEXEC } else { 0 };
+            tf.rax = OsError::Ok as u64;
+        },
+        Err(e) => tf.rax = e as u64,
+    }
+}
+
+/// fd in rdi, `FD_*` flags in rsi
+pub fn sys_fd_setflags(tf: &mut TrapFrame) {
+    let (fd, flags) = (tf.rdi, tf.rsi);
+    if flags & !FD_CLOEXEC != 0 {
+        tf.rax = OsError::InvalidArgument as u64;
+        return;
+    }
+    tf.rax = match with_fds(|fds| fds.set_close_on_exec(fd, flags & FD_CLOEXEC != 0)) {
+        Ok(()) => OsError::Ok as u64,
+        Err(e) => e as u64,
+    };
+}
+
+/// Size in bytes in rdi. The descriptor is returned in rdx
+pub fn sys_shm_create(tf: &mut TrapFrame) {
+    let result = SharedMemory::new(tf.rdi as usize).and_then(|shm| {
+        // Freed here if it cannot be installed, see `with_fds`
+        let file = Arc::new(File::SharedMemory(Arc::new(shm)));
+        with_fds(|fds| fds.install(file.clone()))
+    });
+    match result {
+        Ok(fd) => {
+            tf.rdx = fd;
+            tf.rax = OsError::Ok as u64;
+        },
+        Err(e) => tf.rax = e as u64,
+    }
+}
+
+/// Shared memory fd in rdi, `PROT_*` flags in rsi. The address of the
+/// mapping is returned in rdx
+pub fn sys_shm_map(tf: &mut TrapFrame) {
+    let file = match current_file(tf.rdi) {
+        Ok(file) => file,
+        Err(e) => {
+            tf.rax = e as u64;
+            return;
+        }
+    };
+    let mapping = match &*file {
+        File::SharedMemory(shm) => SharedMemory::map(shm, tf.rsi),
+        _ => Err(OsError::InvalidArgument),
+    };
+    match mapping {
+        Ok(mapping) => {
+            tf.rdx = mapping.start().as_u64();
+            tf.rax = OsError::Ok as u64;
+            // Dropped mappings are unmapped later, see `ipc::shm`
+            SCHEDULER.critical(|s| {
+                if let Some(p) = s.current_process() {
+                    p.mappings.push(mapping);
+                }
+            });
+        },
+        Err(e) => tf.rax = e as u64,
+    }
+}
+
+/// Address of a mapping returned by `sys_shm_map` in rdi
+pub fn sys_shm_unmap(tf: &mut TrapFrame) {
+    let addr = tf.rdi;
+    let mapping = SCHEDULER.critical(|s| {
+        let mappings = &mut s.current_process()?.mappings;
+        let idx = mappings.iter().position(|m| m.start().as_u64() == addr)?;
+        Some(mappings.swap_remove(idx))
+    });
+    match mapping {
+        Some(mapping) => {
+            // The shootdown needs interrupts, `unmapper` does it
+            drop(mapping);
+            tf.rax = OsError::Ok as u64;
+        },
+        None => tf.rax = OsError::InvalidArgument as u64,
+    }
+}
+
+/// Borrows a path of `len` bytes at user address `addr`
+fn user_path<'a>(addr: u64, len: u64) -> Result<&'a str, OsError> {
+    if len as usize > MAX_PATH_LEN {
+        return Err(OsError::NameTooLong);
+    }
+    let bytes = unsafe { user_slice(addr, len as usize) }.ok_or(OsError::BadAddress)?;
+    match core::str::from_utf8(bytes) {
+        Ok("") => Err(OsError::NoEntry),
+        Ok(path) => Ok(path),
+        Err(_) => Err(OsError::InvalidArgument),
+    }
+}
+
+/// Path in rdi, path length in rsi, `O_*` flags in rdx. The descriptor is
+/// returned in rdx
+pub fn sys_open(tf: &mut TrapFrame) {
+    let flags = tf.rdx;
+    let result = user_path(tf.rdi, tf.rsi)
+        .and_then(|path| vfs::open(path, flags))
+        .and_then(|file| {
+            // Closed here if it cannot be installed, see `with_fds`
+            let file = Arc::new(File::Vfs(file));
+            with_fds(|fds| {
+                let fd = fds.install(file.clone())?;
+                fds.set_close_on_exec(fd, flags & O_CLOEXEC != 0)?;
+                Ok(fd)
+            })
+        });
+    match result {
+        Ok(fd) => {
+            tf.rdx = fd;
+            tf.rax = OsError::Ok as u64;
+        },
+        Err(e) => tf.rax = e as u64,
+    }
+}
+
+/// fd in rdi, signed offset in rsi, `SEEK_*` origin in rdx. The new offset
+/// is returned in rdx
+pub fn sys_seek(tf: &mut TrapFrame) {
+    let (offset, whence) = (tf.rsi as i64, tf.rdx);
+    match current_file(tf.rdi).and_then(|file| file.seek(offset, whence)) {
+        Ok(pos) => {
+            tf.rdx = pos;
+            tf.rax = OsError::Ok as u64;
+        },
+        Err(e) => tf.rax = e as u64,
+    }
+}
+
+/// Path in rdi, path length in rsi, pointer to a `Stat` in rdx
+pub fn sys_stat(tf: &mut TrapFrame) {
+    let addr = tf.rdx;
+    let result = user_path(tf.rdi, tf.rsi)
+        .and_then(|path| vfs::stat(path, true))
+        .and_then(|metadata| match write_user(addr, Stat::from(metadata)) {
+            true => Ok(()),
+            false => Err(OsError::BadAddress),
+        });
+    tf.rax = match result {
+        Ok(()) => OsError::Ok as u64,
+        Err(e) => e as u64,
+    };
+}
+
+/// fd in rdi, pointer to a `Stat` in rsi
+pub fn sys_fstat(tf: &mut TrapFrame) {
+    let addr = tf.rsi;
+    let result = current_file(tf.rdi)
+        .and_then(|file| file.stat())
+        .and_then(|stat| match write_user(addr, stat) {
+            true => Ok(()),
+            false => Err(OsError::BadAddress),
+        });
+    tf.rax = match result {
+        Ok(()) => OsError::Ok as u64,
+        Err(e) => e as u64,
+    };
+}
+
+/// Directory fd in rdi, pointer to a `DirEnt` in rsi. 1 is returned in rdx
+/// if an entry was read, 0 after the last one
+pub fn sys_readdir(tf: &mut TrapFrame) {
+    let addr = tf.rsi;
+    let mut entry = DirEnt::new();
+    let result = current_file(tf.rdi).and_then(|file| file.readdir(&mut entry));
+    match result {
+        Ok(true) if !write_user(addr, entry) => tf.rax = OsError::BadAddress as u64,
+        Ok(found) => {
+            tf.rdx = found as u64;
+            tf.rax = OsError::Ok as u64;
+        },
+        Err(e) => tf.rax = e as u64,
+    }
+}
+
+/// Path in rdi, path length in rsi
+pub fn sys_mkdir(tf: &mut TrapFrame) {
+    tf.rax = match user_path(tf.rdi, tf.rsi).and_then(|path| vfs::mkdir(path)) {
+        Ok(()) => OsError::Ok as u64,
+        Err(e) => e as u64,
+    };
+}
+
+/// Path in rdi, path length in rsi
+pub fn sys_unlink(tf: &mut TrapFrame) {
+    tf.rax = match user_path(tf.rdi, tf.rsi).and_then(|path| vfs::unlink(path)) {
+        Ok(()) => OsError::Ok as u64,
+        Err(e) => e as u64,
+    };
+}
+
+/// fd in rdi, `IOCTL_*` request in rsi, argument in rdx. The result is
+/// returned in rdx
+pub fn sys_ioctl(tf: &mut TrapFrame) {
+    let (request, arg) = (tf.rsi, tf.rdx);
+    match current_file(tf.rdi).and_then(|file| file.ioctl(request, arg)) {
+        Ok(result) => {
+            tf.rdx = result;
+            tf.rax = OsError::Ok as u64;
+        },
+        Err(e) => tf.rax = e as u64,
+    }
+}
+
+/// `Arc<Waiter>` from `Arc::into_raw` in rdi
+pub fn sys_kernel_wait(tf: &mut TrapFrame) {
+    if tf.cs & 0b11 == 0b11 {
+        tf.rax = OsError::InvalidArgument as u64;
+        return;
+    }
+    let waiter = unsafe { Arc::from_raw(tf.rdi as *const Waiter) };
+    let wait = State::Waiting(Box::new(move |p: &mut Process| -> bool {
+        if waiter.is_woken() {
+            p.context.rax = OsError::Ok as u64;
+            true
+        } else {
+            false
+        }
+    }));
     SCHEDULER.switch(wait, tf);
 }
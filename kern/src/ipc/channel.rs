@@ -0,0 +1,153 @@
+//! Channels
+//!
+//! A channel is a pair of endpoints. Each endpoint sends messages to the
+//! other, a message is a byte string and a few handles. Handles are open
+//! files of the sender, sending moves them into the message and receiving
+//! installs them in the receiver's descriptor table, so a server can be
+//! handed exactly the objects it is meant to use.
+//!
+//! Processes parked on an endpoint are woken through
+//! `GlobalScheduler::notify` whenever a message is queued or taken.
+//!
+//! Closing an endpoint drops the messages queued for it. Sending to a
+//! closed endpoint fails with `IoErrorBrokenPipe`, receiving from one whose
+//! peer is closed fails with `IoErrorEof` once the queue is empty.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use kernel_api::{MAX_MESSAGE_HANDLES, MAX_MESSAGE_SIZE, OsError};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::SCHEDULER;
+use crate::process::fd::File;
+
+/// Messages an endpoint queues before senders block
+pub const MAX_QUEUED_MESSAGES: usize = 16;
+
+pub struct Message {
+    pub data: Vec<u8>,
+    pub handles: Vec<Arc<File>>,
+}
+
+struct ChannelState {
+    /// Messages waiting to be received by each endpoint
+    queues: [VecDeque<Message>; 2],
+    open: [bool; 2],
+}
+
+pub struct Channel {
+    state: Mutex<ChannelState>,
+}
+
+impl Channel {
+    /// Creates a channel and returns its two endpoints
+    pub fn new() -> (Endpoint, Endpoint) {
+        let channel = Arc::new(Channel {
+            state: Mutex::new(ChannelState {
+                queues: [VecDeque::new(), VecDeque::new()],
+                open: [true, true],
+            }),
+        });
+        (Endpoint { channel: channel.clone(), side: 0 }, Endpoint { channel, side: 1 })
+    }
+
+    fn with_state<F, R>(&self, f: F) -> R
+        where F: FnOnce(&mut ChannelState) -> R
+    {
+        // Also used from the scheduler, which polls with interrupts disabled
+        without_interrupts(|| f(&mut self.state.lock()))
+    }
+
+    /// Like `with_state`, but wakes parked processes if `f` succeeds, they
+    /// may wait for what it changed
+    fn change<F, T>(&self, f: F) -> Result<T, OsError>
+        where F: FnOnce(&mut ChannelState) -> Result<T, OsError>
+    {
+        let result = self.with_state(f);
+        if result.is_ok() {
+            SCHEDULER.notify();
+        }
+        result
+    }
+}
+
+pub struct Endpoint {
+    channel: Arc<Channel>,
+    side: usize,
+}
+
+impl Endpoint {
+    fn peer(&self) -> usize {
+        1 - self.side
+    }
+
+    /// Is `other` the peer of this endpoint, or the endpoint itself?
+    pub fn same_channel(&self, other: &Endpoint) -> bool {
+        Arc::ptr_eq(&self.channel, &other.channel)
+    }
+
+    /// Queues the message built by `make` for the peer. `make` is only
+    /// called once the message can be queued, so it may take the handles
+    /// out of the sender's table. Returns `WouldBlock` if the peer's queue
+    /// is full.
+    pub fn send<F>(&self, make: F) -> Result<(), OsError>
+        where F: FnOnce() -> Result<Message, OsError>
+    {
+        let peer = self.peer();
+        self.channel.change(|s| {
+            if !s.open[peer] {
+                return Err(OsError::IoErrorBrokenPipe);
+            }
+            if s.queues[peer].len() >= MAX_QUEUED_MESSAGES {
+                return Err(OsError::WouldBlock);
+            }
+            let message = make()?;
+            debug_assert!(message.data.len() <= MAX_MESSAGE_SIZE && message.handles.len() <= MAX_MESSAGE_HANDLES);
+            s.queues[peer].push_back(message);
+            Ok(())
+        })
+    }
+
+    /// Takes the next message if `accept` agrees, it is passed the size of
+    /// the data and the number of handles. Returns `WouldBlock` if no
+    /// message is queued.
+    pub fn recv<F>(&self, accept: F) -> Result<Message, OsError>
+        where F: FnOnce(usize, usize) -> Result<(), OsError>
+    {
+        let (side, peer) = (self.side, self.peer());
+        self.channel.change(|s| {
+            match s.queues[side].front() {
+                Some(message) => accept(message.data.len(), message.handles.len())?,
+                None if !s.open[peer] => return Err(OsError::IoErrorEof),
+                None => return Err(OsError::WouldBlock),
+            }
+            Ok(s.queues[side].pop_front().expect("checked above"))
+        })
+    }
+
+    /// Would `recv` return without `WouldBlock`?
+    pub fn readable(&self) -> bool {
+        let (side, peer) = (self.side, self.peer());
+        self.channel.with_state(|s| !s.queues[side].is_empty() || !s.open[peer])
+    }
+
+    /// Would `send` return without `WouldBlock`?
+    pub fn writable(&self) -> bool {
+        let peer = self.peer();
+        self.channel.with_state(|s| s.queues[peer].len() < MAX_QUEUED_MESSAGES || !s.open[peer])
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        let side = self.side;
+        let pending = self.channel.with_state(|s| {
+            s.open[side] = false;
+            core::mem::replace(&mut s.queues[side], VecDeque::new())
+        });
+        SCHEDULER.notify();
+        // Outside the lock, the messages may hold the other endpoint
+        drop(pending);
+    }
+}
@@ -0,0 +1,5 @@
+//! Inter-Process Communication
+
+pub mod pipe;
+pub mod channel;
+pub mod shm;
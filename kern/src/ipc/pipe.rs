@@ -0,0 +1,127 @@
+//! Pipes
+//!
+//! A pipe is a bounded byte buffer with a read end and a write end. Either
+//! end reports `WouldBlock` instead of waiting, the syscalls park the process
+//! until `readable` or `writable` holds and run again. Every change wakes
+//! the parked processes through `GlobalScheduler::notify`. Once every write end
+//! is gone reads return 0 at the end of the data, once every read end is
+//! gone writes fail with `IoErrorBrokenPipe`.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::cmp::min;
+use kernel_api::OsError;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::SCHEDULER;
+
+/// Bytes a pipe buffers before writers block
+pub const PIPE_CAPACITY: usize = 4096;
+
+struct PipeState {
+    buf: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+}
+
+pub struct Pipe {
+    state: Mutex<PipeState>,
+}
+
+impl Pipe {
+    /// Creates a pipe and returns its two ends
+    pub fn new() -> (PipeReader, PipeWriter) {
+        let pipe = Arc::new(Pipe {
+            state: Mutex::new(PipeState {
+                buf: VecDeque::with_capacity(PIPE_CAPACITY),
+                readers: 1,
+                writers: 1,
+            }),
+        });
+        (PipeReader(pipe.clone()), PipeWriter(pipe))
+    }
+
+    fn with_state<F, R>(&self, f: F) -> R
+        where F: FnOnce(&mut PipeState) -> R
+    {
+        // Also used from the scheduler, which polls with interrupts disabled
+        without_interrupts(|| f(&mut self.state.lock()))
+    }
+
+    /// Like `with_state`, but wakes parked processes if `f` succeeds, they
+    /// may wait for what it changed
+    fn change<F, T>(&self, f: F) -> Result<T, OsError>
+        where F: FnOnce(&mut PipeState) -> Result<T, OsError>
+    {
+        let result = self.with_state(f);
+        if result.is_ok() {
+            SCHEDULER.notify();
+        }
+        result
+    }
+}
+
+/// Read end of a pipe
+pub struct PipeReader(Arc<Pipe>);
+
+/// Write end of a pipe
+pub struct PipeWriter(Arc<Pipe>);
+
+impl PipeReader {
+    /// Moves up to `buf.len()` bytes out of the pipe. Returns 0 at the end
+    /// of the data and `WouldBlock` if the pipe is empty.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, OsError> {
+        self.0.change(|s| {
+            if s.buf.is_empty() {
+                return if s.writers == 0 || buf.is_empty() { Ok(0) } else { Err(OsError::WouldBlock) };
+            }
+            let count = min(buf.len(), s.buf.len());
+            for (dst, src) in buf.iter_mut().zip(s.buf.drain(..count)) {
+                *dst = src;
+            }
+            Ok(count)
+        })
+    }
+
+    /// Would `read` return without `WouldBlock`?
+    pub fn readable(&self) -> bool {
+        self.0.with_state(|s| !s.buf.is_empty() || s.writers == 0)
+    }
+}
+
+impl PipeWriter {
+    /// Copies as much of `buf` into the pipe as fits. Returns `WouldBlock`
+    /// if the pipe is full.
+    pub fn write(&self, buf: &[u8]) -> Result<usize, OsError> {
+        self.0.change(|s| {
+            if s.readers == 0 {
+                return Err(OsError::IoErrorBrokenPipe);
+            }
+            let count = min(buf.len(), PIPE_CAPACITY - s.buf.len());
+            if count == 0 && !buf.is_empty() {
+                return Err(OsError::WouldBlock);
+            }
+            s.buf.extend(buf[..count].iter());
+            Ok(count)
+        })
+    }
+
+    /// Would `write` return without `WouldBlock`?
+    pub fn writable(&self) -> bool {
+        self.0.with_state(|s| s.buf.len() < PIPE_CAPACITY || s.readers == 0)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.0.with_state(|s| s.readers -= 1);
+        SCHEDULER.notify();
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.0.with_state(|s| s.writers -= 1);
+        SCHEDULER.notify();
+    }
+}
@@ -0,0 +1,228 @@
+//! Shared Memory
+//!
+//! A shared memory object is a set of physical frames that processes map,
+//! so they can exchange data without copying it through syscalls. The
+//! object is reference counted by its descriptors and its mappings, the
+//! frames go back to `FRAME_ALLOC` once the last of them is gone.
+//!
+//! All processes share the kernel page table, so each mapping gets its own
+//! range in the shared memory area of the lower half. A range goes back to
+//! the area once it has been unmapped on every core.
+//!
+//! Shared memory does not control access. A mapping is user accessible to
+//! every process, so any process can read it through its address, and
+//! write it if it was mapped writable. A process does not need the
+//! descriptor to use a mapping someone else made, the descriptor only keeps
+//! the object alive. Leaving out `PROT_WRITE` is the only protection, and
+//! it holds for every process alike.
+//!
+//! Unmapping waits for a TLB shootdown before the frames can be freed,
+//! which cannot be done under the scheduler lock or on the stack of a
+//! syscall, which runs with interrupts disabled. Mappings that are unmapped
+//! or whose process dies are handed to `unmapper` instead, a kernel
+//! process. Until it got to them they stay accessible.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use kernel_api::{MAX_SHM_SIZE, OsError, PROT_READ, PROT_WRITE};
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use crate::memory::frame_allocator::FrameAllocWrapper;
+use crate::memory::paging::{PHYSMAP_BASE, unmap_pages};
+use crate::memory::user::USER_SPACE_TOP;
+use crate::sync::{SpinMutex, WorkQueue};
+use crate::{FRAME_ALLOC, PAGE_TABLE};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Start of the area mappings are placed in, the upper half of user space
+pub const SHM_BASE: u64 = 0x0000_4000_0000_0000;
+
+/// Address ranges of the shared memory area
+static AREA: SpinMutex<Area> = SpinMutex::new("SHM_AREA", Area::new());
+
+lazy_static! {
+    /// Mappings of dead processes, unmapped by `unmapper`
+    static ref ORPHANS: WorkQueue<Mapping> = WorkQueue::new();
+}
+
+/// Ranges of the shared memory area, taken first fit from the ranges of
+/// unmapped mappings and then from the untouched rest
+struct Area {
+    /// Start of the part never handed out
+    next: u64,
+    /// Freed ranges as start and length, sorted and coalesced
+    free: Vec<(u64, u64)>,
+}
+
+impl Area {
+    const fn new() -> Area {
+        Area {
+            next: SHM_BASE,
+            free: Vec::new(),
+        }
+    }
+
+    fn alloc(&mut self, len: u64) -> Option<u64> {
+        if let Some(idx) = self.free.iter().position(|&(_, free)| free >= len) {
+            let (start, free) = self.free[idx];
+            if free == len {
+                self.free.remove(idx);
+            } else {
+                self.free[idx] = (start + len, free - len);
+            }
+            return Some(start);
+        }
+        let end = self.next.checked_add(len).filter(|&end| end <= USER_SPACE_TOP)?;
+        let start = self.next;
+        self.next = end;
+        Some(start)
+    }
+
+    fn free(&mut self, start: u64, len: u64) {
+        let idx = self.free.iter().position(|&(free, _)| free > start).unwrap_or(self.free.len());
+        self.free.insert(idx, (start, len));
+        if idx + 1 < self.free.len() && start + len == self.free[idx + 1].0 {
+            self.free[idx].1 += self.free.remove(idx + 1).1;
+        }
+        if idx > 0 && self.free[idx - 1].0 + self.free[idx - 1].1 == start {
+            self.free[idx - 1].1 += self.free.remove(idx).1;
+        }
+        // Give the end back to the untouched rest
+        if let Some(&(last, len)) = self.free.last() {
+            if last + len == self.next {
+                self.next = last;
+                self.free.pop();
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SharedMemory {
+    frames: Vec<PhysFrame>,
+}
+
+impl SharedMemory {
+    /// Allocates `size` bytes of zeroed memory, rounded up to whole pages
+    pub fn new(size: usize) -> Result<SharedMemory, OsError> {
+        if size == 0 || size > MAX_SHM_SIZE {
+            return Err(OsError::InvalidArgument);
+        }
+        let pages = (size as u64 + PAGE_SIZE - 1) / PAGE_SIZE;
+        // Frames allocated before running out are freed by `drop`
+        let mut shm = SharedMemory {
+            frames: Vec::with_capacity(pages as usize),
+        };
+        for _ in 0..pages {
+            let frame = without_interrupts(|| FRAME_ALLOC.lock().allocate_frame()).ok_or(OsError::NoMemory)?;
+            unsafe {
+                core::ptr::write_bytes((frame.start_address().as_u64() + PHYSMAP_BASE) as *mut u8, 0, PAGE_SIZE as usize);
+            }
+            shm.frames.push(frame);
+        }
+        Ok(shm)
+    }
+
+    pub fn pages(&self) -> u64 {
+        self.frames.len() as u64
+    }
+
+    pub fn size(&self) -> usize {
+        self.frames.len() * PAGE_SIZE as usize
+    }
+
+    /// Maps all of `shm` with `prot` at a fresh address
+    pub fn map(shm: &Arc<SharedMemory>, prot: u64) -> Result<Mapping, OsError> {
+        if prot & !(PROT_READ | PROT_WRITE) != 0 || prot == 0 {
+            return Err(OsError::InvalidArgument);
+        }
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if prot & PROT_WRITE != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+
+        // One unmapped page between mappings catches overruns
+        let len = (shm.pages() + 1) * PAGE_SIZE;
+        let start = without_interrupts(|| AREA.lock().alloc(len)).ok_or(OsError::NoVmSpace)?;
+
+        // The range is unmapped and was shot down before it was freed, no
+        // other core can have it cached
+        let first = Page::<Size4KiB>::containing_address(VirtAddr::new(start));
+        let mut mapping = Mapping {
+            start: first.start_address(),
+            pages: 0,
+            shm: Some(shm.clone()),
+        };
+        let result: Result<(), OsError> = without_interrupts(|| {
+            let mut table = PAGE_TABLE.write();
+            for (n, frame) in shm.frames.iter().enumerate() {
+                unsafe { table.map_to(first + n as u64, *frame, flags, &mut FrameAllocWrapper {}) }
+                    .map_err(|_| OsError::NoMemory)?
+                    .flush();
+                mapping.pages += 1;
+            }
+            Ok(())
+        });
+        // On failure the mapping is dropped, `unmapper` shoots down the
+        // pages mapped so far
+        result.map(|()| mapping)
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        without_interrupts(|| {
+            let mut alloc = FRAME_ALLOC.lock();
+            for frame in self.frames.drain(..) {
+                // Every mapping holding a reference was unmapped and shot down
+                unsafe { alloc.deallocate_frame(frame) };
+            }
+        });
+    }
+}
+
+/// A shared memory object mapped into a process. Dropping it hands it to
+/// `unmapper`.
+#[derive(Debug)]
+pub struct Mapping {
+    start: VirtAddr,
+    pages: u64,
+    /// Taken once unmapped
+    shm: Option<Arc<SharedMemory>>,
+}
+
+impl Mapping {
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    /// Unmaps the pages on every core, then drops the reference to the
+    /// memory and frees the range. Waits for the shootdown, see `unmapper`.
+    fn unmap(mut self) {
+        unmap_pages(self.start, self.pages);
+        if let Some(shm) = self.shm.take() {
+            let len = (shm.pages() + 1) * PAGE_SIZE;
+            without_interrupts(|| AREA.lock().free(self.start.as_u64(), len));
+        }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if let Some(shm) = self.shm.take() {
+            let orphan = Mapping {
+                start: self.start,
+                pages: self.pages,
+                shm: Some(shm),
+            };
+            ORPHANS.push(orphan);
+        }
+    }
+}
+
+/// Kernel process unmapping dropped mappings
+pub extern fn unmapper() -> ! {
+    ORPHANS.run(Mapping::unmap)
+}
@@ -43,7 +43,7 @@ use core::time::Duration;
 use acpi::Acpi;
 use lazy_static::lazy_static;
 use multiboot2::BootInformation;
-use spin::{Mutex, MutexGuard, RwLock};
+use spin::{MutexGuard, RwLock};
 
 use kernel_api::syscall::sleep;
 use x86_64::{PhysAddr, VirtAddr};
@@ -57,7 +57,7 @@ use crate::arch::x86_64::KernACPIHandler;
 use crate::device::ahci::G_AHCI;
 use crate::device::pci::GLOBAL_PCI;
 use crate::device::usb::G_USB;
-use crate::sys::apic::{APICDeliveryMode, GLOBAL_APIC, IPIDeliveryMode, APIC};
+use crate::sys::apic::{APICDeliveryMode, IPIDeliveryMode, APIC};
 use crate::sys::apic::timer::{APICTimerDividerOption, APICTimerMode};
 use crate::sys::pit::{GLOBAL_PIT, PIT, spin_wait};
 use crate::init::init::{boostrap_core_init, mp_initialization};
@@ -73,6 +73,7 @@ use crate::process::scheduler::GlobalScheduler;
 use crate::shell::Shell;
 use crate::vga_buffer::disable_cursor;
 use crate::sys::resman::GLOBAL_RESMAN;
+use crate::sync::{SpinMutex, SpinRwLock};
 use x86_64::structures::idt::InterruptDescriptorTable;
 
 #[macro_use]
@@ -90,19 +91,21 @@ pub mod memory;
 pub mod shell;
 pub mod logger;
 pub mod process;
+pub mod sync;
 pub mod arch;
 pub mod fs;
+pub mod ipc;
 
 lazy_static! {
-    static ref PAGE_TABLE: RwLock<OffsetPageTable<'static>> = {
-        unsafe {RwLock::new(
+    static ref PAGE_TABLE: SpinRwLock<OffsetPageTable<'static>> = {
+        unsafe {SpinRwLock::new("PAGE_TABLE",
             OffsetPageTable::new(&mut *(KERNEL_PML4_TABLE.lock().as_mut().unwrap().as_mut() as *mut PageTable), VirtAddr::new(PHYSMAP_BASE))
         )}
     };
 }
 
-pub static FRAME_ALLOC: Mutex<SegmentFrameAllocator> = Mutex::new(SegmentFrameAllocator::new());
-pub static LOW_FALLOC: Mutex<SegmentFrameAllocator> = Mutex::new(SegmentFrameAllocator::new());
+pub static FRAME_ALLOC: SpinMutex<SegmentFrameAllocator> = SpinMutex::new("FRAME_ALLOC", SegmentFrameAllocator::new());
+pub static LOW_FALLOC: SpinMutex<SegmentFrameAllocator> = SpinMutex::new("LOW_FALLOC", SegmentFrameAllocator::new());
 
 #[cfg_attr(not(test), global_allocator)]
 pub static ALLOCATOR: Allocator = Allocator::uninitialized();
@@ -120,6 +123,7 @@ extern "C" {
 #[no_mangle]
 #[naked]
 pub extern "C" fn kinit(multiboot_ptr: usize) -> ! {
+    unsafe { crate::sys::percpu::unload() };
     disable_cursor();
     debug!("Multiboot at {:#x}", multiboot_ptr);
     unsafe { crate::logger::init_logger() };
@@ -159,7 +163,7 @@ pub extern "C" fn kinit(multiboot_ptr: usize) -> ! {
         }
         debug!("User Process Copied");
 
-        let selectors = GLOBAL_RESMAN.read().get_gdt(GLOBAL_APIC.read().apic_id()).selectors.clone();
+        let selectors = GLOBAL_RESMAN.read().get_gdt(crate::sys::percpu::apic_id()).selectors.clone();
 
         let mut user_proc = Process::new();
         user_proc.context.cs = (selectors.user_cs.0 | 0b11) as u64;
@@ -179,19 +183,59 @@ pub extern "C" fn kinit(multiboot_ptr: usize) -> ! {
 }
 
 pub extern fn kernel_initialization_process() {
+    // Root file system, disks found later are mounted below it. Its files
+    // are on the heap, so it gets a quarter of the memory that is free now.
+    let free = without_interrupts(|| FRAME_ALLOC.lock().free_space()) as u64;
+    let root = crate::fs::tmpfs::TmpFs::new(min(free, KERNEL_HEAP_TOP - KERNEL_HEAP_BASE) / 4);
+    crate::fs::vfs::mount("/", alloc::sync::Arc::new(root)).expect("mount root tmpfs");
+    crate::fs::vfs::mkdir("/mnt").expect("create /mnt");
+    crate::fs::initramfs::unpack();
+    // The archive may come with its own /dev
+    if crate::fs::vfs::stat("/dev", true).is_err() {
+        crate::fs::vfs::mkdir("/dev").expect("create /dev");
+    }
+    crate::fs::vfs::mount("/dev", alloc::sync::Arc::new(crate::fs::devfs::DevFs::new())).expect("mount devfs");
+
     // PCI
     GLOBAL_PCI.lock().initialize_bus_with_devices();
 
     // MP initialization
     mp_initialization();
-    //
-    // Usb Proc
-    // let usbproc = Process::new_kern(usb_process as u64);
-    // SCHEDULER.add(usbproc);
+
+    // Unmaps the shared memory of dead processes
+    SCHEDULER.add(Process::new_kern(crate::ipc::shm::unmapper as u64));
+    // Reads and writes files for syscalls
+    SCHEDULER.add(Process::new_kern(crate::fs::io::worker as u64));
+    // Unregisters block devices that went away
+    SCHEDULER.add(Process::new_kern(crate::storage::block::detacher as u64));
+
+    // First user program, from the initramfs
+    let init = crate::fs::initramfs::init_path();
+    match crate::process::elf::load(&init) {
+        Ok(process) => {
+            info!("[INIT] Starting {}", init);
+            SCHEDULER.add(process);
+        }
+        Err(e) => warn!("[INIT] Unable to start {}: {:?}", init, e),
+    }
+
+    // Keep the xHCI poller and the shell on separate cores when there are two
+    let shell_core = crate::sys::percpu::apic_id();
+    let poller_core = SCHEDULER.critical(|s| {
+        if let Some(shell) = s.current_process() {
+            shell.bind_to(shell_core);
+        }
+        s.cpus.cpu_set().iter().find(|&id| id != shell_core)
+    });
+    let mut usbproc = Process::new_kern(usb_process as u64);
+    if let Some(core) = poller_core {
+        usbproc.bind_to(core);
+    }
+    SCHEDULER.add(usbproc);
 
     let mut shell = Shell::new();
     loop {
-        let str = format!("Core: {}> ", GLOBAL_APIC.read().apic_id());
+        let str = format!("Core: {}> ", crate::sys::percpu::apic_id());
         shell.shell(&str);
     }
 }
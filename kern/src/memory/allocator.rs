@@ -6,8 +6,17 @@ mod bump;
 type AllocatorImpl = bin::Allocator;
 
 use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
 use core::fmt;
 use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::memory::allocator::linked_list::LinkedList;
+use crate::sys::percpu;
+
+/// Bins served from the per-CPU caches, up to 256 byte blocks
+const CACHED_BINS: usize = 6;
+/// Blocks kept per cached bin before frees go back to the heap
+const CACHE_DEPTH: usize = 32;
 
 /// `LocalAlloc` is an analogous trait to the standard library's `GlobalAlloc`,
 /// but it takes `&mut self` in `alloc()` and `dealloc()`.
@@ -38,10 +47,31 @@ impl Allocator {
     pub unsafe fn initialize(&self, start: usize, end: usize) {
         *self.0.lock() = Some(AllocatorImpl::new(start, end));
     }
+
+    /// Hands the blocks cached by the current core back to the heap. Called
+    /// by a core going offline, the cache is not used until it is back.
+    pub fn drain_cache(&self) {
+        without_interrupts(|| {
+            let cpu = match percpu::try_current() {
+                Some(cpu) => cpu,
+                None => return,
+            };
+            let mut heap = self.0.lock();
+            let heap = heap.as_mut().expect("allocator uninitialized");
+            unsafe { cpu.heap_cache.drain(|ptr, layout| heap.dealloc(ptr, layout)) };
+        });
+    }
 }
 
 unsafe impl GlobalAlloc for Allocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let cached = without_interrupts(|| match percpu::try_current() {
+            Some(cpu) => cpu.heap_cache.alloc(layout),
+            None => core::ptr::null_mut(),
+        });
+        if !cached.is_null() {
+            return cached;
+        }
         self.0
             .lock()
             .as_mut()
@@ -50,6 +80,13 @@ unsafe impl GlobalAlloc for Allocator {
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let cached = without_interrupts(|| match percpu::try_current() {
+            Some(cpu) => cpu.heap_cache.dealloc(ptr, layout),
+            None => false,
+        });
+        if cached {
+            return;
+        }
         self.0
             .lock()
             .as_mut()
@@ -58,6 +95,76 @@ unsafe impl GlobalAlloc for Allocator {
     }
 }
 
+struct CacheBins {
+    bins: [LinkedList; CACHED_BINS],
+    len: [usize; CACHED_BINS],
+}
+
+/// Per-CPU cache of small freed blocks. Blocks are binned exactly like the
+/// bin allocator does, on the larger of size and alignment, so they can move
+/// between the cache and the heap. A core going offline drains its cache,
+/// see `Allocator::drain_cache`.
+pub struct HeapCache(UnsafeCell<CacheBins>);
+
+impl HeapCache {
+    pub fn new() -> HeapCache {
+        HeapCache(UnsafeCell::new(CacheBins {
+            bins: [LinkedList::new(); CACHED_BINS],
+            len: [0; CACHED_BINS],
+        }))
+    }
+
+    /// Pops a cached block for `layout`, null if there is none suitable.
+    ///
+    /// # Safety
+    /// Only the owning core may use the cache, with interrupts disabled.
+    pub unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let cache = &mut *self.0.get();
+        let bin = bin::layout_bin(&layout);
+        if bin >= CACHED_BINS {
+            return core::ptr::null_mut();
+        }
+        match cache.bins[bin].peek() {
+            Some(ptr) if (ptr as usize) & (layout.align() - 1) == 0 => {
+                cache.len[bin] -= 1;
+                cache.bins[bin].pop().expect("peeked") as *mut u8
+            },
+            _ => core::ptr::null_mut(),
+        }
+    }
+
+    /// Keeps a freed block. Returns `false` if it has to go back to the heap.
+    ///
+    /// # Safety
+    /// Only the owning core may use the cache, with interrupts disabled.
+    pub unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) -> bool {
+        let cache = &mut *self.0.get();
+        let bin = bin::layout_bin(&layout);
+        if bin >= CACHED_BINS || cache.len[bin] >= CACHE_DEPTH {
+            return false;
+        }
+        cache.bins[bin].push(ptr as *mut usize);
+        cache.len[bin] += 1;
+        true
+    }
+
+    /// Empties the cache, handing every block to `free` with a layout of its
+    /// bin.
+    ///
+    /// # Safety
+    /// Only the owning core may use the cache, with interrupts disabled.
+    pub unsafe fn drain<F: FnMut(*mut u8, Layout)>(&self, mut free: F) {
+        let cache = &mut *self.0.get();
+        for bin in 0..CACHED_BINS {
+            let layout = Layout::from_size_align_unchecked(bin::get_bin_size(bin), 8);
+            while let Some(ptr) = cache.bins[bin].pop() {
+                free(ptr as *mut u8, layout);
+            }
+            cache.len[bin] = 0;
+        }
+    }
+}
+
 extern "C" {
     static __text_end: u8;
 }
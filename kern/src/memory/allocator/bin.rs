@@ -40,11 +40,11 @@ impl Allocator {
     }
 }
 
-fn get_bin_size(bin_number: usize) -> usize {
+pub(super) fn get_bin_size(bin_number: usize) -> usize {
     1usize << (bin_number + 3)
 }
 
-fn get_bin_number(mut size: usize) -> usize {
+pub(super) fn get_bin_number(mut size: usize) -> usize {
     let mut bin = 0usize;
     size = (size - 1) / 8;
 
@@ -56,6 +56,12 @@ fn get_bin_number(mut size: usize) -> usize {
     bin
 }
 
+/// Bin of the blocks serving `layout`. A block is at least as large as its
+/// alignment, so the same bin serves the same layout again after a free.
+pub(super) fn layout_bin(layout: &Layout) -> usize {
+    get_bin_number(core::cmp::max(layout.size(), layout.align()))
+}
+
 impl Allocator {
     unsafe fn alloc_from_block(&mut self, layout: Layout, bin_number: usize) -> *mut u8 {
         use crate::PAGE_TABLE;
@@ -63,11 +69,6 @@ impl Allocator {
         let aligned
             = align_up(self.block_current, layout.align());
 
-        if aligned > self.block_current {
-            let bin_num = get_bin_number(aligned - self.block_current);
-            self.bins[bin_num].push(self.block_current as *mut usize);
-        }
-
         let target = aligned.saturating_add(get_bin_size(bin_number));
         if target - get_bin_size(bin_number) != aligned ||
             target > self.block_end {
@@ -80,16 +81,43 @@ impl Allocator {
             let mut falloc = FrameAllocWrapper{};
             let mut pt = PAGE_TABLE.write();
             for x in (self.block_current..aligned_up_target).step_by(4096) {
-                let frame = FRAME_ALLOC.lock().allocate_frame().expect("no frame");
-                pt.map_to(
-                    Page::<Size4KiB>::from_start_address(VirtAddr::new(x as u64)).expect(""),
-                    frame,
-                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-                    &mut falloc
-                ).expect("failed to map").flush();
+                let frame = FRAME_ALLOC.lock().allocate_frame();
+                let mapped = match frame {
+                    Some(frame) => match pt.map_to(
+                        Page::<Size4KiB>::from_start_address(VirtAddr::new(x as u64)).expect(""),
+                        frame,
+                        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                        &mut falloc
+                    ) {
+                        Ok(flush) => {
+                            flush.flush();
+                            true
+                        },
+                        // No frame left for a page table
+                        Err(_) => {
+                            FRAME_ALLOC.lock().deallocate_frame(frame);
+                            false
+                        },
+                    },
+                    None => false,
+                };
+                if !mapped {
+                    // Out of memory, the pages mapped so far still serve
+                    // smaller allocations
+                    for page in (self.block_current..x).step_by(4096) {
+                        self.bins[get_bin_number(4096)].push(page as *mut usize);
+                    }
+                    self.block_current = x;
+                    return core::ptr::null_mut();
+                }
             }
         }
 
+        if aligned > self.block_current {
+            let bin_num = get_bin_number(aligned - self.block_current);
+            self.bins[bin_num].push(self.block_current as *mut usize);
+        }
+
         // TODO Add target - aligned_up_target to blocks
 
         self.block_current = aligned_up_target;
@@ -147,7 +175,7 @@ impl LocalAlloc for Allocator {
     /// or `layout` does not meet this allocator's
     /// size or alignment constraints.
     unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
-        let bin_number = get_bin_number(layout.size());
+        let bin_number = layout_bin(&layout);
         if bin_number >= 30 {
             return core::ptr::null_mut();
         }
@@ -201,7 +229,7 @@ impl LocalAlloc for Allocator {
     /// Parameters not meeting these conditions may result in undefined
     /// behavior.
     unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
-        let bin_num = get_bin_number(layout.size());
+        let bin_num = layout_bin(&layout);
         self.bins[bin_num].push(ptr as *mut usize);
     }
 }
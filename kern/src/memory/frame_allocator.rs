@@ -4,6 +4,7 @@ use crate::memory::is_aligned;
 use stack_vec::StackVec;
 use core::borrow::BorrowMut;
 use crate::FRAME_ALLOC;
+use crate::memory::paging::PHYSMAP_BASE;
 
 const FRAME_SIZE: usize = 4096;
 
@@ -56,9 +57,16 @@ impl MemorySegment {
     }
 }
 
+/// Ends the free list, frame 0 may be a valid frame
+const FREE_LIST_END: u64 = u64::MAX;
+
 pub struct SegmentFrameAllocator {
     segments: [MemorySegment; 16],
     count: usize,
+    /// Physical address of the first freed frame. Each freed frame holds the
+    /// address of the next one in its first 8 bytes, read through the physmap.
+    free_list: u64,
+    free_frames: usize,
 }
 
 impl SegmentFrameAllocator {
@@ -66,6 +74,8 @@ impl SegmentFrameAllocator {
         let alloc = SegmentFrameAllocator {
             segments: [MemorySegment::zeroed(); 16],
             count: 0,
+            free_list: FREE_LIST_END,
+            free_frames: 0,
         };
         alloc
     }
@@ -92,12 +102,36 @@ impl SegmentFrameAllocator {
         for seg in self.segments[0..self.count].iter() {
             size += seg.size - (seg.current - seg.start);
         }
-        size
+        size + self.free_frames * FRAME_SIZE
+    }
+
+    /// Returns `frame` to the allocator.
+    ///
+    /// The frame must have come from this allocator and nothing may access
+    /// it anymore, including stale TLB entries on other cores.
+    pub unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let addr = frame.start_address().as_u64();
+        *((addr + PHYSMAP_BASE) as *mut u64) = self.free_list;
+        self.free_list = addr;
+        self.free_frames += 1;
+    }
+
+    fn pop_free(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        if self.free_list == FREE_LIST_END {
+            return None;
+        }
+        let addr = self.free_list;
+        self.free_list = unsafe { *((addr + PHYSMAP_BASE) as *const u64) };
+        self.free_frames -= 1;
+        Some(PhysFrame::from_start_address(PhysAddr::new(addr)).expect("Alignment"))
     }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for SegmentFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        if let Some(frame) = self.pop_free() {
+            return Some(frame);
+        }
         for s in self.segments[0..self.count].as_mut().iter_mut() {
             if let Some(frame) = s.allocate_frame() {
                 return Some(frame);
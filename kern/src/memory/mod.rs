@@ -2,6 +2,8 @@ pub mod frame_allocator;
 pub mod paging;
 pub mod allocator;
 pub mod mmio_bump_allocator;
+pub mod tlb;
+pub mod user;
 
 
 // Utils
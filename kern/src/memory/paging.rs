@@ -21,9 +21,11 @@ __________________|____________|__________________|_________|___________________
 
 
 use spin::{RwLock, Mutex};
-use x86_64::structures::paging::PageTable;
+use x86_64::structures::paging::{Page, PageTable, PhysFrame, Size4KiB};
+use x86_64::instructions::interrupts::without_interrupts;
 use alloc::boxed::Box;
-use x86_64::PhysAddr;
+use alloc::vec::Vec;
+use x86_64::{PhysAddr, VirtAddr};
 
 extern "C" {
     static mut __kernel_pdps: u64;
@@ -45,3 +47,27 @@ lazy_static! {
         })
     };
 }
+
+/// Unmaps `pages` 4KiB pages from `start` and returns the frames that were
+/// mapped. Pages that are not mapped are skipped.
+///
+/// Every core may cache the old translations, so this waits for the TLB
+/// shootdown, see `memory::tlb`.
+pub fn unmap_pages(start: VirtAddr, pages: u64) -> Vec<PhysFrame> {
+    use x86_64::structures::paging::Mapper;
+    use crate::PAGE_TABLE;
+
+    let first = Page::<Size4KiB>::containing_address(start);
+    let frames = without_interrupts(|| {
+        let mut table = PAGE_TABLE.write();
+        (0..pages).filter_map(|n| {
+            // Flushed below, for all cores at once
+            table.unmap(first + n).ok().map(|(frame, flush)| {
+                flush.ignore();
+                frame
+            })
+        }).collect()
+    });
+    crate::memory::tlb::shootdown(first.start_address(), pages);
+    frames
+}
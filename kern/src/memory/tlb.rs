@@ -0,0 +1,101 @@
+//! TLB Shootdown
+//!
+//! All cores share the kernel page table, so a translation that is removed
+//! or downgraded may still be cached by any core that is running. The core
+//! changing the mapping flushes its own TLB, posts the range and sends the
+//! other cores an IPI, then waits until each of them has flushed.
+//!
+//! Shootdowns are serialized. A core waiting for its turn keeps answering
+//! requests, so two cores shooting down at once cannot deadlock even with
+//! interrupts disabled. The page table lock must not be held while waiting,
+//! since the other cores may be spinning on it with interrupts disabled.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::idt::InterruptStackFrame;
+use crate::interrupts::InterruptIndex;
+use crate::sys::apic::{GLOBAL_APIC, IPIDeliveryMode, IPIDestinationShorthand};
+use crate::sys::percpu::{self, PerCpu};
+use crate::sys::resman::GLOBAL_RESMAN;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Above this many pages the whole TLB is flushed instead
+const FULL_FLUSH_THRESHOLD: u64 = 32;
+
+/// Serializes shootdowns, the request below belongs to the holder
+static SHOOTDOWN: Mutex<()> = Mutex::new(());
+static REQUEST_START: AtomicU64 = AtomicU64::new(0);
+/// Pages to flush, 0 flushes everything
+static REQUEST_PAGES: AtomicU64 = AtomicU64::new(0);
+
+/// Flushes `pages` pages from `start` on the current core, 0 flushes all
+fn flush_local(start: u64, pages: u64) {
+    if pages == 0 || pages > FULL_FLUSH_THRESHOLD {
+        // No global pages are mapped, so reloading CR3 flushes everything
+        x86_64::instructions::tlb::flush_all();
+    } else {
+        for page in 0..pages {
+            x86_64::instructions::tlb::flush(VirtAddr::new(start + page * PAGE_SIZE));
+        }
+    }
+}
+
+/// Answers the posted request if it is addressed to the current core
+fn service() {
+    if let Some(block) = percpu::try_current() {
+        if block.tlb_pending.load(Ordering::Acquire) {
+            flush_local(REQUEST_START.load(Ordering::Relaxed), REQUEST_PAGES.load(Ordering::Relaxed));
+            block.tlb_pending.store(false, Ordering::Release);
+        }
+    }
+}
+
+/// Invalidates `pages` pages from `start` on every running core and returns
+/// once all of them have flushed. 0 pages flushes the whole TLB.
+pub fn shootdown(start: VirtAddr, pages: u64) {
+    let start = start.align_down(PAGE_SIZE).as_u64();
+    without_interrupts(|| {
+        flush_local(start, pages);
+
+        // Before the per-CPU blocks exist there are no other cores
+        let this = match percpu::try_current() {
+            Some(block) => block.cpu.apic_id,
+            None => return,
+        };
+
+        let _guard = loop {
+            match SHOOTDOWN.try_lock() {
+                Some(guard) => break guard,
+                None => service(),
+            }
+        };
+        REQUEST_START.store(start, Ordering::Relaxed);
+        REQUEST_PAGES.store(pages, Ordering::Relaxed);
+
+        // Blocks are never freed, so the resource manager is not held while
+        // waiting for cores that may be waiting for it
+        let targets: Vec<&'static PerCpu> = GLOBAL_RESMAN.read().percpu_blocks()
+            .filter(|b| b.cpu.apic_id != this && b.cpu.is_running())
+            .collect();
+        for block in targets.iter() {
+            block.tlb_pending.store(true, Ordering::Release);
+            GLOBAL_APIC.read().send_ipi(block.cpu.apic_id.into(), InterruptIndex::TlbShootdown.as_u8(),
+                                        IPIDeliveryMode::Fixed, IPIDestinationShorthand::NoShorthand);
+        }
+        // A core that parks meanwhile is reset before it runs again
+        for block in targets.iter() {
+            while block.tlb_pending.load(Ordering::Acquire) && block.cpu.is_running() {}
+            block.tlb_pending.store(false, Ordering::Release);
+        }
+    });
+}
+
+pub(crate) extern "x86-interrupt" fn tlb_shootdown_handler(stack_frame: &mut InterruptStackFrame) {
+    let _trap = crate::interrupts::TrapGuard::enter(stack_frame, true);
+    service();
+    GLOBAL_APIC.read().end_of_interrupt();
+}
@@ -0,0 +1,131 @@
+//! Access to user memory from syscalls
+//!
+//! User processes share the kernel page table, so user buffers can be
+//! accessed directly once the range is known to be mapped user space.
+
+use x86_64::PhysAddr;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::PAGE_TABLE;
+use crate::memory::paging::PHYSMAP_BASE;
+
+/// End of the lower half, see the memory map in `paging.rs`
+pub const USER_SPACE_TOP: u64 = 0x0000_8000_0000_0000;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Walks the page table for `addr`. Returns the physical address and the
+/// flags of the mapping, where `WRITABLE` and `USER_ACCESSIBLE` are only set
+/// if every level of the walk grants them, as the MMU checks them.
+///
+/// The caller must hold `PAGE_TABLE`.
+fn walk(addr: u64) -> Option<(PhysAddr, PageTableFlags)> {
+    let mut table = Cr3::read().0.start_address().as_u64();
+    let mut granted = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    for level in 0..4 {
+        let shift = 39 - 9 * level;
+        let index = (addr >> shift) as usize & 0x1FF;
+        let entry = unsafe { &(*((table + PHYSMAP_BASE) as *const PageTable))[index] };
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        granted &= flags;
+        if level == 3 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            let offset = addr & ((1 << shift) - 1);
+            let flags = flags - (PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE) | granted;
+            return Some((PhysAddr::new(entry.addr().as_u64() + offset), flags));
+        }
+        table = entry.addr().as_u64();
+    }
+    unreachable!()
+}
+
+/// Returns `true` if `[addr, addr + len)` lies in user space and every page
+/// of it is mapped with at least `required`.
+fn check_range(addr: u64, len: usize, required: PageTableFlags) -> bool {
+    let end = match addr.checked_add(len as u64) {
+        Some(end) if end <= USER_SPACE_TOP => end,
+        _ => return false,
+    };
+    if len == 0 {
+        return true;
+    }
+    without_interrupts(|| {
+        let _table = PAGE_TABLE.read();
+        let mut page = addr & !(PAGE_SIZE - 1);
+        while page < end {
+            match walk(page) {
+                Some((_, flags)) if flags.contains(required) => {},
+                _ => return false,
+            }
+            page += PAGE_SIZE;
+        }
+        true
+    })
+}
+
+/// Returns `true` if `[addr, addr + len)` lies in user space and every page
+/// of it is mapped for user mode.
+pub fn is_user_range(addr: u64, len: usize) -> bool {
+    check_range(addr, len, PageTableFlags::USER_ACCESSIBLE)
+}
+
+/// Returns `true` if `[addr, addr + len)` lies in user space and every page
+/// of it is mapped writable for user mode.
+pub fn is_user_writable(addr: u64, len: usize) -> bool {
+    check_range(addr, len, PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE)
+}
+
+/// Physical address of the user byte at `addr`, `None` if it is not mapped
+/// user memory.
+pub fn translate_user(addr: u64) -> Option<PhysAddr> {
+    if addr >= USER_SPACE_TOP {
+        return None;
+    }
+    without_interrupts(|| {
+        let _table = PAGE_TABLE.read();
+        match walk(addr) {
+            Some((phys, flags)) if flags.contains(PageTableFlags::USER_ACCESSIBLE) => Some(phys),
+            _ => None,
+        }
+    })
+}
+
+/// Borrows a user buffer. Returns `None` if it is not mapped user memory.
+///
+/// The caller must make sure the mapping outlives the returned slice.
+pub unsafe fn user_slice<'a>(addr: u64, len: usize) -> Option<&'a [u8]> {
+    if !is_user_range(addr, len) {
+        return None;
+    }
+    if len == 0 {
+        return Some(&[]);
+    }
+    Some(core::slice::from_raw_parts(addr as *const u8, len))
+}
+
+/// Copies `value` to user address `addr`, which does not have to be aligned.
+/// Returns `false` if it is not mapped writable user memory.
+pub fn write_user<T: Copy>(addr: u64, value: T) -> bool {
+    if !is_user_writable(addr, core::mem::size_of::<T>()) {
+        return false;
+    }
+    unsafe { core::ptr::write_unaligned(addr as *mut T, value) };
+    true
+}
+
+/// Mutably borrows a user buffer. Returns `None` if it is not mapped
+/// writable user memory.
+///
+/// The caller must make sure the mapping outlives the returned slice.
+pub unsafe fn user_slice_mut<'a>(addr: u64, len: usize) -> Option<&'a mut [u8]> {
+    if !is_user_writable(addr, len) {
+        return None;
+    }
+    if len == 0 {
+        return Some(&mut []);
+    }
+    Some(core::slice::from_raw_parts_mut(addr as *mut u8, len))
+}
@@ -1,52 +1,65 @@
 use crate::process::process::{Id, Process};
-use crate::sys::apic::GLOBAL_APIC;
-use hashbrown::HashMap;
 use core::fmt::{Debug, Formatter};
-use crate::ACPI;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
 use crate::process::scheduler::idle_process;
+use crate::sys::percpu;
+use crate::sys::resman::GLOBAL_RESMAN;
 
-pub struct Processors {
-    cpus: HashMap<u8, LocalCPU>,
-}
+/// The scheduler's view of the cores. The state of each core lives in its
+/// per-CPU block.
+#[derive(Default)]
+pub struct Processors;
 
-impl Default for Processors {
-    fn default() -> Self {
-        let mut procs = Self {
-            cpus: Default::default(),
-        };
-
-        if let Some(acpi) = ACPI.read().as_ref() {
-            let bsp = acpi.boot_processor.as_ref().expect("no bsp?");
-            procs.cpus.insert(bsp.local_apic_id, LocalCPU::new(bsp.local_apic_id, bsp.processor_uid));
-
-            for ap in acpi.application_processors.iter() {
-                use acpi::ProcessorState;
-                if let ProcessorState::Disabled = ap.state  {
-                } else {
-                    procs.cpus.insert(ap.local_apic_id, LocalCPU::new(ap.local_apic_id, ap.processor_uid));
-                }
-            }
-        }
-        procs
+impl Debug for Processors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let resman = GLOBAL_RESMAN.read();
+        write!(f, "Current Processes:\n")?;
+        f.debug_list().entries(resman.percpu_blocks().map(|b| &b.cpu)).finish()
     }
 }
 
-impl Debug for Processors {
-    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
-        write!(f, "Current Processes:\n{:#?}", self.cpus)
+/// Bring-up state of a core
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum CpuState {
+    /// Registered but not started
+    Offline = 0,
+    /// Sent INIT-SIPI-SIPI, has not answered yet
+    Booting = 1,
+    /// Runs processes and takes interrupts
+    Online = 2,
+    /// Did not answer the startup IPIs in time and was put back into INIT
+    Failed = 3,
+    /// Being taken offline, parks at its next scheduling point
+    Stopping = 4,
+}
+
+impl From<u8> for CpuState {
+    fn from(v: u8) -> CpuState {
+        match v {
+            1 => CpuState::Booting,
+            2 => CpuState::Online,
+            3 => CpuState::Failed,
+            4 => CpuState::Stopping,
+            _ => CpuState::Offline,
+        }
     }
 }
 
 pub struct LocalCPU {
-    pub current_pid: Option<Id>,
+    /// Process running on the core, 0 if none. Written with the scheduler
+    /// lock held, read by other cores without it.
+    current_pid: AtomicU64,
     pub idle_task: Process,
     pub proc_id: u8,
     pub apic_id: u8,
+    /// `CpuState`, read by other cores without the scheduler lock
+    state: AtomicU8,
 }
 
 impl Debug for LocalCPU {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
-        write!(f, "[CPUID: {}, LAPIC: {},  Running: {:?}]", self.proc_id ,self.apic_id, self.current_pid)
+        write!(f, "[CPUID: {}, LAPIC: {}, {:?}, Running: {:?}]", self.proc_id ,self.apic_id, self.state(), self.current_pid())
     }
 }
 
@@ -56,17 +69,145 @@ impl LocalCPU {
             apic_id,
             idle_task: Process::new_idle(idle_process as u64),
             proc_id: cpuid,
-            current_pid: None
+            current_pid: AtomicU64::new(0),
+            state: AtomicU8::new(CpuState::Offline as u8),
+        }
+    }
+
+    pub fn current_pid(&self) -> Option<Id> {
+        match self.current_pid.load(Ordering::Acquire) {
+            0 => None,
+            pid => Some(pid),
         }
     }
+
+    pub fn set_current_pid(&self, pid: Option<Id>) {
+        self.current_pid.store(pid.unwrap_or(0), Ordering::Release);
+    }
+
+    pub fn state(&self) -> CpuState {
+        CpuState::from(self.state.load(Ordering::Acquire))
+    }
+
+    pub fn set_state(&self, state: CpuState) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    /// Moves from `from` to `to`, returns `false` if the core was not in `from`
+    pub fn transition(&self, from: CpuState, to: CpuState) -> bool {
+        self.state.compare_exchange(from as u8, to as u8, Ordering::AcqRel, Ordering::Acquire).is_ok()
+    }
+
+    /// Schedules processes
+    pub fn is_online(&self) -> bool {
+        self.state() == CpuState::Online
+    }
+
+    /// Executes kernel code, so it caches translations and answers IPIs
+    pub fn is_running(&self) -> bool {
+        matches!(self.state(), CpuState::Online | CpuState::Stopping)
+    }
 }
 
 impl Processors {
-    pub fn current_cpu(&mut self) -> &mut LocalCPU {
-        let cpuid = GLOBAL_APIC.read().apic_id();
-        if !self.cpus.contains_key(&cpuid) {
-            panic!("current cpu is not registered?");
+    /// Set of all online CPUs
+    pub fn cpu_set(&self) -> CpuSet {
+        let mut set = CpuSet::empty();
+        for block in GLOBAL_RESMAN.read().percpu_blocks().filter(|b| b.cpu.is_online()) {
+            set.insert(block.cpu.apic_id);
+        }
+        set
+    }
+
+    pub fn current_cpu(&self) -> &'static LocalCPU {
+        &percpu::current().cpu
+    }
+}
+
+/// A set of CPUs, identified by their local APIC ID.
+///
+/// APIC IDs are 8 bit throughout the kernel: the MADT processor entries,
+/// I/O APIC redirection entries and MSI addresses hold no more without
+/// interrupt remapping, which is not supported. Cores with an x2APIC ID
+/// above 255 are never started, a boot core with one is refused.
+#[derive(Copy, Clone, PartialEq)]
+pub struct CpuSet([u64; 4]);
+
+impl CpuSet {
+    /// Size of the set in bytes, as seen by `sched_setaffinity`
+    pub const BYTES: usize = 32;
+
+    pub const fn empty() -> CpuSet {
+        CpuSet([0; 4])
+    }
+
+    pub const fn all() -> CpuSet {
+        CpuSet([!0; 4])
+    }
+
+    pub fn single(apic_id: u8) -> CpuSet {
+        let mut set = CpuSet::empty();
+        set.insert(apic_id);
+        set
+    }
+
+    pub fn insert(&mut self, apic_id: u8) {
+        self.0[apic_id as usize / 64] |= 1 << (apic_id % 64);
+    }
+
+    pub fn remove(&mut self, apic_id: u8) {
+        self.0[apic_id as usize / 64] &= !(1 << (apic_id % 64));
+    }
+
+    pub fn contains(&self, apic_id: u8) -> bool {
+        self.0[apic_id as usize / 64] >> (apic_id % 64) & 0x1 == 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|w| *w == 0)
+    }
+
+    pub fn intersection(&self, other: &CpuSet) -> CpuSet {
+        let mut set = *self;
+        for (w, o) in set.0.iter_mut().zip(other.0.iter()) {
+            *w &= *o;
+        }
+        set
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=u8> + '_ {
+        (0..=255u8).filter(move |id| self.contains(*id))
+    }
+
+    /// Decodes a little endian bitmap where bit `n` is APIC ID `n`.
+    /// Bytes beyond `BYTES` are ignored.
+    pub fn from_bytes(bytes: &[u8]) -> CpuSet {
+        let mut set = CpuSet::empty();
+        for (idx, byte) in bytes.iter().take(Self::BYTES).enumerate() {
+            set.0[idx / 8] |= (*byte as u64) << (idx % 8 * 8);
         }
-        self.cpus.get_mut(&cpuid).expect("has cpu")
+        set
+    }
+
+    /// Encodes the set into `buf` as a little endian bitmap.
+    /// Returns the number of bytes written.
+    pub fn to_bytes(&self, buf: &mut [u8]) -> usize {
+        let len = core::cmp::min(buf.len(), Self::BYTES);
+        for idx in 0..len {
+            buf[idx] = (self.0[idx / 8] >> (idx % 8 * 8)) as u8;
+        }
+        len
+    }
+}
+
+impl Default for CpuSet {
+    fn default() -> Self {
+        CpuSet::all()
+    }
+}
+
+impl Debug for CpuSet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
     }
-}
\ No newline at end of file
+}
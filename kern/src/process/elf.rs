@@ -0,0 +1,235 @@
+//! ELF Loader
+//!
+//! Loads statically linked ELF64 executables for x86_64 as user processes.
+//! Processes share the kernel page table (see `memory::user`), so the
+//! loadable segments are mapped at the addresses they were linked for and a
+//! program cannot be loaded twice while its pages are still mapped. The
+//! pages stay mapped after the process exits, there is no per-process page
+//! table to tear down yet.
+//!
+//! Pages are writable if a segment on them has `PF_W` and executable only if
+//! one has `PF_X`, the stack is never executable.
+//!
+//! The stack lies right below the shared memory area. Programs start with
+//! no arguments, environment or auxiliary vector, `rsp` points at a zero
+//! `argc` as the System V ABI expects.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::cmp::min;
+use kernel_api::OsError;
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::structures::paging::mapper::MapToError;
+use crate::fs::path::Path;
+use crate::fs::vfs;
+use crate::ipc::shm::SHM_BASE;
+use crate::memory::frame_allocator::FrameAllocWrapper;
+use crate::memory::paging::{PHYSMAP_BASE, unmap_pages};
+use crate::process::process::Process;
+use crate::sys::resman::GLOBAL_RESMAN;
+use crate::{FRAME_ALLOC, PAGE_TABLE};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// One unmapped page between the stack and the shared memory area
+const USER_STACK_TOP: u64 = SHM_BASE - PAGE_SIZE;
+const USER_STACK_PAGES: u64 = 16;
+const USER_STACK_BOTTOM: u64 = USER_STACK_TOP - USER_STACK_PAGES * PAGE_SIZE;
+
+/// `argc`, the ends of `argv` and `envp` and an `AT_NULL` entry, rounded to
+/// keep `rsp` 16 byte aligned
+const ENTRY_FRAME_SIZE: u64 = 48;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+struct Header {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+const_assert_size!(Header, 64);
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+const_assert_size!(ProgramHeader, 56);
+
+/// Reads a `T` at `offset` of `image`, `None` if the image is too short
+fn read<T: Copy>(image: &[u8], offset: u64) -> Option<T> {
+    let end = offset.checked_add(core::mem::size_of::<T>() as u64)?;
+    if end > image.len() as u64 {
+        return None;
+    }
+    Some(unsafe { core::ptr::read_unaligned(image[offset as usize..].as_ptr() as *const T) })
+}
+
+/// The loadable segments of `image`
+fn segments(image: &[u8]) -> Result<(u64, Vec<ProgramHeader>), OsError> {
+    let header: Header = read(image, 0).ok_or(OsError::IoErrorInvalidData)?;
+    if &header.ident[..4] != ELF_MAGIC || header.ident[4] != ELFCLASS64 || header.ident[5] != ELFDATA2LSB
+        || { header.kind } != ET_EXEC || { header.machine } != EM_X86_64
+        || header.phentsize as usize != core::mem::size_of::<ProgramHeader>() {
+        return Err(OsError::IoErrorInvalidData);
+    }
+
+    let mut segments = Vec::new();
+    for n in 0..header.phnum as u64 {
+        let ph: ProgramHeader = { header.phoff }.checked_add(n * header.phentsize as u64)
+            .and_then(|offset| read(image, offset))
+            .ok_or(OsError::IoErrorInvalidData)?;
+        let (kind, offset, vaddr, filesz, memsz) = (ph.kind, ph.offset, ph.vaddr, ph.filesz, ph.memsz);
+        if kind != PT_LOAD || memsz == 0 {
+            continue;
+        }
+        let file_end = offset.checked_add(filesz).ok_or(OsError::IoErrorInvalidData)?;
+        let end = vaddr.checked_add(memsz).ok_or(OsError::IoErrorInvalidData)?;
+        if filesz > memsz || file_end > image.len() as u64 || vaddr < PAGE_SIZE || end > USER_STACK_BOTTOM {
+            return Err(OsError::IoErrorInvalidData);
+        }
+        segments.push(ph);
+    }
+    Ok((header.entry, segments))
+}
+
+/// Maps a zeroed frame at each page of `pages`. Nothing stays mapped if one
+/// of them fails.
+fn map(pages: &BTreeMap<u64, PageTableFlags>) -> Result<BTreeMap<u64, PhysFrame>, OsError> {
+    let mut frames = BTreeMap::new();
+    for (&addr, &flags) in pages {
+        let result = without_interrupts(|| FRAME_ALLOC.lock().allocate_frame())
+            .ok_or(OsError::NoMemory)
+            .and_then(|frame| {
+                unsafe {
+                    core::ptr::write_bytes((frame.start_address().as_u64() + PHYSMAP_BASE) as *mut u8, 0, PAGE_SIZE as usize);
+                }
+                let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+                let mapped = without_interrupts(|| unsafe {
+                    PAGE_TABLE.write().map_to(page, frame, flags, &mut FrameAllocWrapper {})
+                });
+                match mapped {
+                    Ok(flush) => {
+                        flush.flush();
+                        Ok(frame)
+                    }
+                    Err(e) => {
+                        // Never mapped, nothing can hold it
+                        without_interrupts(|| unsafe { FRAME_ALLOC.lock().deallocate_frame(frame) });
+                        match e {
+                            MapToError::FrameAllocationFailed => Err(OsError::NoMemory),
+                            _ => Err(OsError::NoVmSpace),
+                        }
+                    }
+                }
+            });
+        match result {
+            Ok(frame) => {
+                frames.insert(addr, frame);
+            }
+            Err(e) => {
+                for &addr in frames.keys() {
+                    for frame in unmap_pages(VirtAddr::new(addr), 1) {
+                        without_interrupts(|| unsafe { FRAME_ALLOC.lock().deallocate_frame(frame) });
+                    }
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(frames)
+}
+
+/// Copies `bytes` to user address `addr` through the physmap, the pages
+/// may not be writable from user space
+fn copy_to(frames: &BTreeMap<u64, PhysFrame>, addr: u64, bytes: &[u8]) {
+    let mut done = 0;
+    while done < bytes.len() {
+        let va = addr + done as u64;
+        let page = va & !(PAGE_SIZE - 1);
+        let offset = va - page;
+        let count = min(bytes.len() - done, (PAGE_SIZE - offset) as usize);
+        let dst = frames[&page].start_address().as_u64() + PHYSMAP_BASE + offset;
+        unsafe { core::ptr::copy_nonoverlapping(bytes[done..].as_ptr(), dst as *mut u8, count) };
+        done += count;
+    }
+}
+
+/// Loads the executable at `path` into a new user process, which still
+/// has to be added to the scheduler
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Process, OsError> {
+    let inode = vfs::lookup(path)?;
+    let mut image = vec![0u8; inode.metadata()?.size as usize];
+    let mut filled = 0;
+    while filled < image.len() {
+        match inode.read_at(filled as u64, &mut image[filled..])? {
+            0 => return Err(OsError::IoErrorEof),
+            n => filled += n,
+        }
+    }
+    let (entry, segments) = segments(&image)?;
+
+    // A page shared by two segments gets the permissions of both
+    let user = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE;
+    let mut pages = BTreeMap::new();
+    for ph in &segments {
+        let mut page = ph.vaddr & !(PAGE_SIZE - 1);
+        while page < ph.vaddr + ph.memsz {
+            let flags = pages.entry(page).or_insert(user);
+            if ph.flags & PF_W != 0 {
+                *flags |= PageTableFlags::WRITABLE;
+            }
+            if ph.flags & PF_X != 0 {
+                flags.remove(PageTableFlags::NO_EXECUTE);
+            }
+            page += PAGE_SIZE;
+        }
+    }
+    for n in 0..USER_STACK_PAGES {
+        pages.insert(USER_STACK_BOTTOM + n * PAGE_SIZE, user | PageTableFlags::WRITABLE);
+    }
+
+    let frames = map(&pages)?;
+    for ph in &segments {
+        copy_to(&frames, ph.vaddr, &image[ph.offset as usize..(ph.offset + ph.filesz) as usize]);
+    }
+
+    let selectors = GLOBAL_RESMAN.read().get_gdt(crate::sys::percpu::apic_id()).selectors.clone();
+    let mut process = Process::new();
+    process.context.cs = (selectors.user_cs.0 | 0b11) as u64;
+    process.context.ss = (selectors.user_ds.0 | 0b11) as u64;
+    process.context.rip = entry;
+    process.context.rsp = USER_STACK_TOP - ENTRY_FRAME_SIZE;
+    Ok(process)
+}
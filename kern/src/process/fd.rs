@@ -0,0 +1,267 @@
+//! File Descriptors
+//!
+//! Each process owns a table of the kernel objects it has open, indexed by
+//! small integers. Entries are reference counted, a syscall blocked on an
+//! object keeps it alive even if the descriptor is closed meanwhile.
+//!
+//! Duplicated descriptors share the object, including its offset. The
+//! close-on-exec flag is only recorded, there is no exec yet.
+//!
+//! Closing a file may take locks of its own or wait for a device, so files
+//! taken out of a table are dropped once the scheduler lock is released.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use kernel_api::{DirEnt, FileType, OsError, Stat, STDERR, STDIN};
+use crate::fs::file::OpenFile;
+use crate::ipc::channel::Endpoint;
+use crate::ipc::pipe::{PipeReader, PipeWriter};
+use crate::ipc::shm::SharedMemory;
+use crate::sys::stdin::STD_IN;
+
+pub type Fd = u64;
+
+/// Most descriptors a process can have open
+pub const MAX_FDS: usize = 64;
+
+pub enum File {
+    /// Keyboard and serial input, screen output
+    Console,
+    PipeReader(PipeReader),
+    PipeWriter(PipeWriter),
+    Channel(Endpoint),
+    SharedMemory(Arc<SharedMemory>),
+    /// A file or directory opened through the VFS
+    Vfs(OpenFile),
+}
+
+impl File {
+    /// Reads into `buf`, `WouldBlock` if the caller has to wait for `readable`
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, OsError> {
+        match self {
+            File::Console => {
+                let mut count = 0;
+                while count < buf.len() {
+                    match STD_IN.pop() {
+                        Some(c) => buf[count] = c,
+                        None => break,
+                    }
+                    count += 1;
+                }
+                match count {
+                    0 if !buf.is_empty() => Err(OsError::WouldBlock),
+                    count => Ok(count),
+                }
+            },
+            File::PipeReader(r) => r.read(buf),
+            File::Vfs(f) => f.read(buf),
+            _ => Err(OsError::NoAccess),
+        }
+    }
+
+    /// Writes from `buf`, `WouldBlock` if the caller has to wait for `writable`
+    pub fn write(&self, buf: &[u8]) -> Result<usize, OsError> {
+        match self {
+            File::Console => {
+                print!("{}", String::from_utf8_lossy(buf));
+                Ok(buf.len())
+            },
+            File::PipeWriter(w) => w.write(buf),
+            File::Vfs(f) => f.write(buf),
+            _ => Err(OsError::NoAccess),
+        }
+    }
+
+    pub fn readable(&self) -> bool {
+        match self {
+            File::Console => STD_IN.has_data(),
+            File::PipeReader(r) => r.readable(),
+            File::Channel(c) => c.readable(),
+            File::Vfs(f) => f.inode().readable(),
+            _ => true,
+        }
+    }
+
+    pub fn writable(&self) -> bool {
+        match self {
+            File::PipeWriter(w) => w.writable(),
+            File::Channel(c) => c.writable(),
+            File::Vfs(f) => f.inode().writable(),
+            _ => true,
+        }
+    }
+
+    /// See `Inode::polled`, the other files wake their waiters themselves
+    pub fn polled(&self) -> bool {
+        match self {
+            File::Vfs(f) => f.inode().polled(),
+            _ => false,
+        }
+    }
+
+    /// Moves the offset, see `OpenFile::seek`
+    pub fn seek(&self, offset: i64, whence: u64) -> Result<u64, OsError> {
+        match self {
+            File::Vfs(f) => f.seek(offset, whence),
+            _ => Err(OsError::InvalidArgument),
+        }
+    }
+
+    /// Device specific request, see `Inode::ioctl`
+    pub fn ioctl(&self, request: u64, arg: u64) -> Result<u64, OsError> {
+        match self {
+            File::Vfs(f) => f.ioctl(request, arg),
+            _ => Err(OsError::InvalidArgument),
+        }
+    }
+
+    pub fn stat(&self) -> Result<Stat, OsError> {
+        let kind = match self {
+            File::Vfs(f) => return f.stat(),
+            File::Console => FileType::CharDevice,
+            File::PipeReader(_) | File::PipeWriter(_) => FileType::Fifo,
+            File::Channel(_) | File::SharedMemory(_) => FileType::Unknown,
+        };
+        let size = match self {
+            File::SharedMemory(shm) => shm.size() as u64,
+            _ => 0,
+        };
+        Ok(Stat {
+            kind: kind as u64,
+            size,
+            nlink: 1,
+            ..Stat::default()
+        })
+    }
+
+    /// Reads the next directory entry, see `OpenFile::readdir`
+    pub fn readdir(&self, entry: &mut DirEnt) -> Result<bool, OsError> {
+        match self {
+            File::Vfs(f) => f.readdir(entry),
+            _ => Err(OsError::NotADirectory),
+        }
+    }
+}
+
+impl core::fmt::Debug for File {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            File::Console => write!(f, "Console"),
+            File::PipeReader(_) => write!(f, "PipeReader"),
+            File::PipeWriter(_) => write!(f, "PipeWriter"),
+            File::Channel(_) => write!(f, "Channel"),
+            File::SharedMemory(shm) => write!(f, "SharedMemory({} bytes)", shm.size()),
+            File::Vfs(_) => write!(f, "Vfs"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    file: Arc<File>,
+    close_on_exec: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct FdTable {
+    files: Vec<Option<Entry>>,
+}
+
+impl FdTable {
+    pub fn new() -> FdTable {
+        FdTable {
+            files: Vec::new(),
+        }
+    }
+
+    /// A table with the console open as `STDIN`, `STDOUT` and `STDERR`
+    pub fn stdio() -> FdTable {
+        let console = Arc::new(File::Console);
+        let mut table = FdTable::new();
+        for _ in STDIN..=STDERR {
+            table.install(console.clone()).expect("empty table");
+        }
+        table
+    }
+
+    /// Installs `file` at the lowest free descriptor
+    pub fn insert(&mut self, file: File) -> Result<Fd, OsError> {
+        self.install(Arc::new(file))
+    }
+
+    /// Installs a file that may also be open elsewhere
+    pub fn install(&mut self, file: Arc<File>) -> Result<Fd, OsError> {
+        let entry = Some(Entry {
+            file,
+            close_on_exec: false,
+        });
+        match self.files.iter().position(|f| f.is_none()) {
+            Some(fd) => {
+                self.files[fd] = entry;
+                Ok(fd as Fd)
+            }
+            None if self.files.len() < MAX_FDS => {
+                self.files.push(entry);
+                Ok((self.files.len() - 1) as Fd)
+            }
+            None => Err(OsError::NoMemory),
+        }
+    }
+
+    fn entry(&self, fd: Fd) -> Result<&Entry, OsError> {
+        self.files.get(fd as usize).and_then(|f| f.as_ref()).ok_or(OsError::BadDescriptor)
+    }
+
+    pub fn get(&self, fd: Fd) -> Result<Arc<File>, OsError> {
+        self.entry(fd).map(|e| e.file.clone())
+    }
+
+    /// Removes `fd` from the table and returns what it referred to
+    pub fn take(&mut self, fd: Fd) -> Result<Arc<File>, OsError> {
+        self.files.get_mut(fd as usize).and_then(|f| f.take()).map(|e| e.file).ok_or(OsError::BadDescriptor)
+    }
+
+    /// Opens what `fd` refers to again at the lowest free descriptor. The
+    /// new descriptor is kept across exec.
+    pub fn dup(&mut self, fd: Fd) -> Result<Fd, OsError> {
+        let file = self.get(fd)?;
+        self.install(file)
+    }
+
+    /// Makes `new` refer to what `old` refers to, closing `new` first if it
+    /// is open. The new descriptor is kept across exec. Returns what `new`
+    /// referred to before.
+    pub fn dup2(&mut self, old: Fd, new: Fd) -> Result<Option<Arc<File>>, OsError> {
+        let file = self.get(old)?;
+        if new as usize >= MAX_FDS {
+            return Err(OsError::BadDescriptor);
+        }
+        if old == new {
+            return Ok(None);
+        }
+        if self.files.len() <= new as usize {
+            self.files.resize(new as usize + 1, None);
+        }
+        let replaced = self.files[new as usize].replace(Entry {
+            file,
+            close_on_exec: false,
+        });
+        Ok(replaced.map(|e| e.file))
+    }
+
+    pub fn close_on_exec(&self, fd: Fd) -> Result<bool, OsError> {
+        self.entry(fd).map(|e| e.close_on_exec)
+    }
+
+    pub fn set_close_on_exec(&mut self, fd: Fd, close_on_exec: bool) -> Result<(), OsError> {
+        let entry = self.files.get_mut(fd as usize).and_then(|f| f.as_mut()).ok_or(OsError::BadDescriptor)?;
+        entry.close_on_exec = close_on_exec;
+        Ok(())
+    }
+
+    /// Number of descriptors that can still be opened
+    pub fn free(&self) -> usize {
+        MAX_FDS - self.files.iter().filter(|f| f.is_some()).count()
+    }
+}
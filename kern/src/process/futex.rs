@@ -0,0 +1,118 @@
+//! Futexes
+//!
+//! A futex is a 32 bit word in user memory that processes wait on until
+//! another process wakes them, the kernel half of the user space locks in
+//! `kernel_api::sync`. Waiters are keyed by the physical address of the word,
+//! so processes mapping the same frame at different addresses share a queue.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use hashbrown::HashMap;
+use kernel_api::OsError;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::memory::paging::PHYSMAP_BASE;
+use crate::memory::user::translate_user;
+use crate::sync::{WaitQueue, Waiter};
+
+lazy_static! {
+    /// Queues of the futexes someone waits on, by physical address
+    static ref FUTEXES: Mutex<HashMap<u64, WaitQueue>> = Mutex::new(HashMap::new());
+}
+
+/// Physical address of the futex word at user address `addr`. The word
+/// must be 4 byte aligned, so it does not cross a page, and lie in a page
+/// mapped `USER_ACCESSIBLE`.
+fn key(addr: u64) -> Result<u64, OsError> {
+    if addr % 4 != 0 {
+        return Err(OsError::InvalidArgument);
+    }
+    translate_user(addr).map(|pa| pa.as_u64()).ok_or(OsError::BadAddress)
+}
+
+/// A process queued on a futex. Dropping it leaves the queue, so a process
+/// killed while waiting does not swallow a wake.
+pub struct FutexWaiter {
+    key: u64,
+    waiter: Arc<Waiter>,
+}
+
+impl FutexWaiter {
+    pub fn is_woken(&self) -> bool {
+        self.waiter.is_woken()
+    }
+
+    /// Leaves the queue. Returns `false` if the waiter was woken meanwhile,
+    /// in which case the wake has to be honoured.
+    pub fn cancel(&self) -> bool {
+        without_interrupts(|| {
+            let mut futexes = FUTEXES.lock();
+            match futexes.get_mut(&self.key) {
+                Some(queue) => {
+                    let removed = queue.remove(&self.waiter);
+                    if queue.is_empty() {
+                        futexes.remove(&self.key);
+                    }
+                    removed
+                }
+                None => false,
+            }
+        })
+    }
+}
+
+impl Drop for FutexWaiter {
+    fn drop(&mut self) {
+        if !self.is_woken() {
+            self.cancel();
+        }
+    }
+}
+
+/// Queues the caller on the futex at user address `addr` if the word still
+/// holds `expected`, fails with `WouldBlock` otherwise.
+pub fn enqueue(addr: u64, expected: u32) -> Result<FutexWaiter, OsError> {
+    let key = key(addr)?;
+    without_interrupts(|| {
+        let mut futexes = FUTEXES.lock();
+        // Wakers change the word before taking the lock, so checking it
+        // under the lock cannot miss a wake. It is read through the physmap,
+        // the user mapping may go away once `key` checked it.
+        let word = unsafe { &*((key + PHYSMAP_BASE) as *const AtomicU32) };
+        if word.load(Ordering::SeqCst) != expected {
+            return Err(OsError::WouldBlock);
+        }
+        let waiter = futexes.entry(key).or_insert_with(WaitQueue::new).enqueue();
+        Ok(FutexWaiter { key, waiter })
+    })
+}
+
+/// Wakes up to `count` processes waiting on the futex at user address
+/// `addr`, longest waiting first. Returns how many were woken.
+pub fn wake(addr: u64, count: usize) -> Result<usize, OsError> {
+    let key = key(addr)?;
+    let woken = without_interrupts(|| {
+        let mut futexes = FUTEXES.lock();
+        let mut woken = Vec::new();
+        if let Some(queue) = futexes.get_mut(&key) {
+            while woken.len() < count {
+                match queue.wake_one() {
+                    Some(wakeup) => woken.push(wakeup),
+                    None => break,
+                }
+            }
+            if queue.is_empty() {
+                futexes.remove(&key);
+            }
+        }
+        woken
+    });
+    // The scheduler lock is taken after the futex lock is released, the
+    // scheduler takes them the other way round when a wait times out
+    let count = woken.len();
+    for wakeup in woken {
+        wakeup.unpark();
+    }
+    Ok(count)
+}
@@ -2,4 +2,9 @@ pub mod process;
 pub mod stack;
 pub mod state;
 pub mod scheduler;
-pub mod cpu;
\ No newline at end of file
+pub mod cpu;
+pub mod signal;
+pub mod timer_wheel;
+pub mod futex;
+pub mod fd;
+pub mod elf;
@@ -4,6 +4,12 @@ use crate::process::state::State::*;
 use crate::process::state::{EventPollFn, State, ProcessSummaryState};
 use crate::interrupts::context_switch::TrapFrame;
 use crate::process::stack::Stack;
+use crate::process::signal::SignalState;
+use crate::process::cpu::CpuSet;
+use crate::process::fd::FdTable;
+use crate::ipc::shm::Mapping;
+use crate::fs::io::Request;
+use alloc::sync::Arc;
 
 /// Type alias for the type of a process ID.
 pub type Id = u64;
@@ -19,11 +25,25 @@ pub struct Process {
     //TODO Page Table
     /// The scheduling state of the process.
     pub state: State,
+    /// Pending signals and installed handlers.
+    pub signals: SignalState,
+    /// CPUs the process may be scheduled on.
+    pub affinity: CpuSet,
+    /// The affinity the process had before all of its CPUs went offline,
+    /// restored once one of them is back.
+    pub saved_affinity: Option<CpuSet>,
+    /// Open files, pipes and other kernel objects.
+    pub fds: FdTable,
+    /// Shared memory mapped by the process.
+    pub mappings: Vec<Mapping>,
+    /// A read or write handed to `fs::io` that the process waits for.
+    pub io: Option<Arc<Request>>,
 }
 
 impl Process {
     /// Creates a new process with a zeroed `TrapFrame` (the default), a zeroed
-    /// stack of the default size, and a state of `Ready`.
+    /// stack of the default size, and a state of `Ready`. The console is open
+    /// as standard input and output.
     ///
     /// If enough memory could not be allocated to start the process, returns
     /// `None`. Otherwise returns `Some` of the new `Process`.
@@ -33,6 +53,12 @@ impl Process {
             context: Box::new(TrapFrame::default()),
             stack: Stack::new(),
             state: State::Ready,
+            signals: SignalState::new(),
+            affinity: CpuSet::all(),
+            saved_affinity: None,
+            fds: FdTable::stdio(),
+            mappings: Vec::new(),
+            io: None,
         }
     }
 
@@ -42,7 +68,13 @@ impl Process {
             pid: 0,
             context: Box::new(Default::default()),
             stack: Stack::new(),
-            state: State::Ready
+            state: State::Ready,
+            signals: SignalState::new(),
+            affinity: CpuSet::all(),
+            saved_affinity: None,
+            fds: FdTable::new(),
+            mappings: Vec::new(),
+            io: None,
         };
         proc.context.rsp = proc.stack.as_ref().expect("stack").top().as_u64();
         proc.context.rip = f;
@@ -55,13 +87,24 @@ impl Process {
             pid: 0,
             context: Box::new(Default::default()),
             stack: Stack::new(),
-            state: State::Ready
+            state: State::Ready,
+            signals: SignalState::new(),
+            affinity: CpuSet::all(),
+            saved_affinity: None,
+            fds: FdTable::new(),
+            mappings: Vec::new(),
+            io: None,
         };
         proc.context.rsp = proc.stack.as_ref().expect("stack").top().as_u64();
         proc.context.rip = f;
         proc
     }
 
+    /// Restricts the process to the core with local APIC ID `apic_id`.
+    pub fn bind_to(&mut self, apic_id: u8) {
+        self.affinity = CpuSet::single(apic_id);
+    }
+
     /// Returns `true` if this process is ready to be scheduled.
     ///
     /// This functions returns `true` only if one of the following holds:
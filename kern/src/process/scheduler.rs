@@ -4,28 +4,44 @@ use alloc::vec::Vec;
 
 use core::fmt;
 use core::mem;
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::time::Duration;
 use crate::process::process::{Process, Id, ProcessSummary};
 use crate::process::state::State;
 use crate::interrupts::context_switch::{TrapFrame, restore_context_wrapper};
-use spin::Mutex;
+use crate::sync::{SpinMutex, SpinMutexGuard};
 use x86_64::instructions::hlt;
-use crate::sys::apic::GLOBAL_APIC;
+use crate::sys::apic::{GLOBAL_APIC, IPIDeliveryMode, IPIDestinationShorthand};
+use crate::sys::percpu;
+use crate::sys::resman::GLOBAL_RESMAN;
 use crate::{SCHEDULER, kernel_initialization_process};
 use crate::process::state::State::Running;
-use crate::process::cpu::Processors;
+use crate::process::cpu::{Processors, CpuSet};
 use x86_64::instructions::interrupts::{without_interrupts, enable_interrupts_and_hlt};
+use crate::process::timer_wheel::TimerWheel;
+use crate::interrupts::InterruptIndex;
+use crate::sys::pit::PIT;
+use kernel_api::OsError;
 
 /// Process scheduler for the entire machine.
 #[derive(Debug)]
-pub struct GlobalScheduler(Mutex<Option<Scheduler>>);
+pub struct GlobalScheduler(SpinMutex<Option<Scheduler>>);
 
 const SCHEDULER_TICK: Duration = Duration::from_millis(200);
+/// Longest an idle core sleeps without a deadline. Cores adding a process
+/// wake an idle core with an IPI, this bounds the latency for processes
+/// that become runnable on a busy core.
+const MAX_IDLE_SLEEP: Duration = Duration::from_secs(1);
+/// Delay before retrying a switch that lost the scheduler lock
+const SCHEDULER_RETRY: Duration = Duration::from_millis(1);
+
+/// Set by `GlobalScheduler::notify`, cleared by whoever holds the lock next
+static NOTIFIED: AtomicBool = AtomicBool::new(false);
 
 impl GlobalScheduler {
     /// Returns an uninitialized wrapper around a local scheduler.
     pub const fn uninitialized() -> GlobalScheduler {
-        GlobalScheduler(Mutex::new(None))
+        GlobalScheduler(SpinMutex::new("SCHEDULER", None))
     }
 
     /// Enters a critical region and execute the provided closure with a mutable
@@ -34,25 +50,61 @@ impl GlobalScheduler {
         where
             F: FnOnce(&mut Scheduler) -> R,
     {
-        without_interrupts(|| {
+        let result = without_interrupts(|| {
             let mut guard = self.0.lock();
-            f(guard.as_mut().expect("scheduler uninitialized"))
-        })
+            let result = f(guard.as_mut().expect("scheduler uninitialized"));
+            Self::unlock(guard);
+            result
+        });
+        self.check_notified();
+        result
     }
 
     pub fn try_critical<F, R>(&self, f: F) -> Option<R>
         where F: FnOnce(&mut Scheduler) -> R
     {
-        without_interrupts(|| {
+        let result = without_interrupts(|| {
             match self.0.try_lock() {
                 Some(mut g) => {
-                    Some(f(g.as_mut().expect("no scheduler?")))
+                    let result = f(g.as_mut().expect("no scheduler?"));
+                    Self::unlock(g);
+                    Some(result)
                 },
                 None => {
                     None
                 }
             }
-        })
+        });
+        self.check_notified();
+        result
+    }
+
+    /// Wakes the processes asked for by `notify` before the lock is released
+    fn unlock(mut guard: SpinMutexGuard<Option<Scheduler>>) {
+        while NOTIFIED.swap(false, Ordering::SeqCst) {
+            if let Some(scheduler) = guard.as_mut() {
+                scheduler.wake_waiting();
+            }
+        }
+    }
+
+    /// A `notify` that came after the holder of the lock looked is handled
+    /// here, or by the next holder if the lock is taken again
+    fn check_notified(&self) {
+        while NOTIFIED.load(Ordering::SeqCst) {
+            if without_interrupts(|| self.0.try_lock().map(Self::unlock)).is_none() {
+                break;
+            }
+        }
+    }
+
+    /// Makes the waiting processes whose event has arrived ready. Called by
+    /// event sources that do not know who waits for them, like pipes. It may
+    /// be called anywhere, even with the scheduler lock held, then the
+    /// holder wakes them before releasing it.
+    pub fn notify(&self) {
+        NOTIFIED.store(true, Ordering::SeqCst);
+        self.check_notified();
     }
 
     /// Adds a process to the scheduler's queue and returns that process's ID.
@@ -75,11 +127,20 @@ impl GlobalScheduler {
                 self.switch_to(tf)
             },
             _ => {
+                // The timer may be one-shot, so the next tick is not guaranteed
+                GLOBAL_APIC.read().timer_oneshot(InterruptIndex::ApicTimer.as_u8(), SCHEDULER_RETRY)
+                    .expect("unable to set timer");
                 0
             }
         }
     }
 
+    /// Marks `sig` pending on process `pid`. For more details, see
+    /// `Scheduler::signal()`.
+    pub fn signal(&self, pid: Id, sig: u64) -> Result<(), OsError> {
+        self.critical(|scheduler| scheduler.signal(pid, sig))
+    }
+
     pub fn summary(&self) -> Vec<ProcessSummary> {
         let mut vec = Vec::new();
         self.critical(|s| {
@@ -96,8 +157,13 @@ impl GlobalScheduler {
     ///
     /// Returns the process's ID when a ready process is found.
     pub fn switch_to(&self, tf: &mut TrapFrame) -> Id {
+        // Nothing runs on this core anymore if it is being taken offline
+        crate::init::smp::park_if_stopping();
         loop {
-            let rtn = self.critical(|scheduler| scheduler.switch_to(tf));
+            // Processes that died are dropped outside the lock, closing their
+            // files takes locks of its own
+            let (rtn, dead) = self.critical(|scheduler| (scheduler.switch_to(tf), mem::take(&mut scheduler.dead)));
+            drop(dead);
             if let Some(id) = rtn {
                 return id;
             }
@@ -111,15 +177,15 @@ impl GlobalScheduler {
     /// For more details, see the documentation on `Scheduler::kill()`.
     #[must_use]
     pub fn kill(&self, tf: &mut TrapFrame) -> Option<Id> {
-        self.critical(|scheduler| scheduler.kill(tf))
+        let (pid, dead) = self.critical(|scheduler| (scheduler.kill(tf), mem::take(&mut scheduler.dead)));
+        drop(dead);
+        pid
     }
 
     /// Starts executing processes in user space using timer interrupt based
     /// preemptive scheduling. This method should not return under normal
     /// conditions.
     pub fn start(&self) -> ! {
-        GLOBAL_APIC.write().set_timer_interval(SCHEDULER_TICK).expect("unable to set timer");
-
         let mut trap = TrapFrame::default();
         SCHEDULER.critical(|s| {
             s.idle(&mut trap)
@@ -151,6 +217,9 @@ pub struct Scheduler {
     pub processes: VecDeque<Process>,
     last_id: Option<Id>,
     pub cpus: Processors,
+    timers: TimerWheel,
+    /// Processes that left the queue, dropped once the lock is released
+    dead: Vec<Process>,
 }
 
 impl Scheduler {
@@ -160,6 +229,8 @@ impl Scheduler {
             processes: VecDeque::new(),
             last_id: None,
             cpus: Default::default(),
+            timers: TimerWheel::new(),
+            dead: Vec::new(),
         }
     }
 
@@ -186,8 +257,10 @@ impl Scheduler {
         match next_pid {
             Some(pid) => {
                 process.pid = pid;
+                let affinity = process.affinity;
                 self.processes.push_back(process);
                 self.last_id = Some(pid);
+                self.wake_idle(affinity);
                 Some(pid)
             }
             None => None
@@ -202,11 +275,11 @@ impl Scheduler {
     /// If the `processes` queue is empty or there is no current process,
     /// returns `false`. Otherwise, returns `true`.
     fn schedule_out(&mut self, new_state: State, tf: &mut TrapFrame) -> bool {
-        if self.processes.is_empty() || self.cpus.current_cpu().current_pid.is_none() {
+        if self.processes.is_empty() || self.cpus.current_cpu().current_pid().is_none() {
             return false;
         }
         let mut running_process_idx: Option<usize> = None;
-        let current_pid = self.cpus.current_cpu().current_pid.unwrap();
+        let current_pid = self.cpus.current_cpu().current_pid().unwrap();
 
         for (idx, proc) in self.processes.iter().enumerate() {
             if let State::Running = proc.state {
@@ -223,7 +296,7 @@ impl Scheduler {
                 *proc.context = *tf;
                 proc.state = new_state;
                 self.processes.push_back(proc);
-                self.cpus.current_cpu().current_pid = None;
+                self.cpus.current_cpu().set_current_pid(None);
                 true
             },
             _ => {
@@ -241,27 +314,216 @@ impl Scheduler {
     /// `Some` of the next process`s process ID.
     fn switch_to(&mut self, tf: &mut TrapFrame) -> Option<Id> {
         // debug!("Switch to on core {}", self.cpus.current_cpu().apic_id);
-        for i in 0..self.processes.len() {
-            let ready = self.processes[i].ready();
+        self.timers.expire(PIT::current_time());
+        self.reap_signalled();
+        let apic_id = self.cpus.current_cpu().apic_id;
+        let mut i = 0;
+        while i < self.processes.len() {
+            let ready = self.processes[i].affinity.contains(apic_id) && self.processes[i].ready();
             if ready {
                 let mut proc = self.processes.remove(i).expect("something");
+                if proc.signals.deliver(&mut proc.context).is_err() {
+                    warn!("[SIG] Unable to deliver signal to {}, killing it", proc.pid);
+                    self.remove_dead(proc);
+                    continue;
+                }
                 proc.state = Running;
                 let pid = proc.pid;
-                self.cpus.current_cpu().current_pid = Some(pid);
+                self.timers.remove(pid);
+                self.cpus.current_cpu().set_current_pid(Some(pid));
                 *tf = *proc.context;
                 self.processes.push_front(proc);
+                // Only needed when the core comes out of idle or a retry
+                let apic = GLOBAL_APIC.read();
+                if !apic.timer_is_periodic() {
+                    apic.timer_periodic(InterruptIndex::ApicTimer.as_u8(), SCHEDULER_TICK)
+                        .expect("unable to set timer");
+                }
                 return Some(pid);
             }
+            i += 1;
         }
         None
     }
 
+    /// Removes processes that have a pending signal which terminates them.
+    /// Processes running on other cores are reaped after they are scheduled out.
+    fn reap_signalled(&mut self) {
+        let mut i = 0;
+        while i < self.processes.len() {
+            let proc = &mut self.processes[i];
+            let fatal = match proc.state {
+                Running => None,
+                _ => proc.signals.take_fatal(&proc.context),
+            };
+            match fatal {
+                Some(sig) => {
+                    let proc = self.processes.remove(i).expect("reaped");
+                    debug!("[SIG] Process {} terminated by signal {}", proc.pid, sig);
+                    self.remove_dead(proc);
+                },
+                None => i += 1,
+            }
+        }
+    }
+
+    /// Releases scheduler resources held for a process leaving the queue
+    /// and keeps it for dropping outside the lock
+    fn remove_dead(&mut self, proc: Process) {
+        self.timers.remove(proc.pid);
+        crate::process::signal::clear_foreground(proc.pid);
+        self.dead.push(proc);
+    }
+
+    /// Marks `sig` pending on process `pid`. Returns `NoEntry` if there is no
+    /// such process and `InvalidArgument` if it is a kernel process, which
+    /// only takes `SIGKILL`.
+    pub fn signal(&mut self, pid: Id, sig: u64) -> Result<(), OsError> {
+        let proc = self.processes.iter_mut().find(|p| p.pid == pid).ok_or(OsError::NoEntry)?;
+        if !crate::process::signal::accepts(&proc.context, sig) {
+            return Err(OsError::InvalidArgument);
+        }
+        proc.signals.raise(sig);
+        // A waiting process killed by it is reaped by a core that schedules
+        let affinity = proc.affinity;
+        self.wake_idle(affinity);
+        Ok(())
+    }
+
+    /// Makes process `pid` ready if the event it waits for has arrived, so a
+    /// woken process does not wait for the scheduler to poll it
+    pub fn unpark(&mut self, pid: Id) {
+        if let Some(proc) = self.processes.iter_mut().find(|p| p.pid == pid) {
+            if matches!(proc.state, State::Waiting(_)) && proc.ready() {
+                proc.state = State::Ready;
+                self.timers.remove(pid);
+                let affinity = proc.affinity;
+                self.wake_idle(affinity);
+            }
+        }
+    }
+
+    /// Makes every waiting process whose event has arrived ready, see
+    /// `GlobalScheduler::notify`
+    fn wake_waiting(&mut self) {
+        let mut woken = Vec::new();
+        for proc in self.processes.iter_mut() {
+            if matches!(proc.state, State::Waiting(_)) && proc.ready() {
+                proc.state = State::Ready;
+                woken.push((proc.pid, proc.affinity));
+            }
+        }
+        for (pid, affinity) in woken {
+            self.timers.remove(pid);
+            self.wake_idle(affinity);
+        }
+    }
+
+    /// Restricts process `pid` to the CPUs in `set`. The set must contain at
+    /// least one online CPU.
+    pub fn set_affinity(&mut self, pid: Id, set: CpuSet) -> Result<(), OsError> {
+        if set.intersection(&self.cpus.cpu_set()).is_empty() {
+            return Err(OsError::InvalidArgument);
+        }
+        let proc = self.processes.iter_mut().find(|p| p.pid == pid).ok_or(OsError::NoEntry)?;
+        proc.affinity = set;
+        proc.saved_affinity = None;
+        self.wake_idle(set);
+        Ok(())
+    }
+
+    /// Sends the scheduler vector to an idle core that may run a process
+    /// with `affinity`, so it schedules now rather than when its one-shot
+    /// timer fires.
+    fn wake_idle(&self, affinity: CpuSet) {
+        let this = percpu::apic_id();
+        let resman = GLOBAL_RESMAN.read();
+        let idle = resman.percpu_blocks().find(|b| {
+            b.cpu.apic_id != this && b.cpu.is_online() && b.cpu.current_pid().is_none()
+                && affinity.contains(b.cpu.apic_id)
+        });
+        if let Some(block) = idle {
+            GLOBAL_APIC.read().send_ipi(block.cpu.apic_id.into(), InterruptIndex::ApicTimer.as_u8(),
+                                        IPIDeliveryMode::Fixed, IPIDestinationShorthand::NoShorthand);
+        }
+    }
+
+    /// Lets processes that may only run on `apic_id` run on any core, since
+    /// it is going offline. Their old affinity is kept for `restore_affinity`.
+    pub fn migrate_from(&mut self, apic_id: u8) {
+        let online = self.cpus.cpu_set();
+        for proc in self.processes.iter_mut() {
+            if proc.affinity.intersection(&online).is_empty() {
+                warn!("[SCHED] Core {} going offline, process {} may run anywhere", apic_id, proc.pid);
+                proc.saved_affinity.get_or_insert(proc.affinity);
+                proc.affinity = CpuSet::all();
+            }
+        }
+    }
+
+    /// Gives migrated processes their old affinity back once one of its
+    /// CPUs is online again
+    pub fn restore_affinity(&mut self) {
+        let online = self.cpus.cpu_set();
+        let mut restored = Vec::new();
+        for proc in self.processes.iter_mut() {
+            match proc.saved_affinity {
+                Some(set) if !set.intersection(&online).is_empty() => {
+                    proc.affinity = set;
+                    proc.saved_affinity = None;
+                    restored.push(set);
+                }
+                _ => {}
+            }
+        }
+        for set in restored {
+            self.wake_idle(set);
+        }
+    }
+
+    pub fn get_affinity(&self, pid: Id) -> Result<CpuSet, OsError> {
+        self.processes.iter().find(|p| p.pid == pid)
+            .map(|p| p.affinity)
+            .ok_or(OsError::NoEntry)
+    }
+
+    /// The process running on this core
+    pub fn current_process(&mut self) -> Option<&mut Process> {
+        let pid = self.cpus.current_cpu().current_pid()?;
+        self.processes.iter_mut().find(|p| p.pid == pid)
+    }
+
     fn idle(&mut self, tf: &mut TrapFrame) {
-        let task = &mut self.cpus.current_cpu().idle_task;
-        *task.context = TrapFrame::default();
-        task.context.rip = idle_process as u64;
-        task.context.rsp = task.stack.as_ref().expect("").top().as_u64();
-        *tf = *task.context;
+        self.arm_idle_timer();
+        // The idle task is never scheduled out, its context is not kept
+        let task = &self.cpus.current_cpu().idle_task;
+        *tf = TrapFrame::default();
+        tf.rip = idle_process as u64;
+        tf.rsp = task.stack.as_ref().expect("").top().as_u64();
+    }
+
+    /// Programs a one-shot timer for the next deadline instead of ticking
+    /// periodically while the core has nothing to run. Processes waiting on
+    /// an event without a deadline are made ready by its source, through
+    /// `unpark` or `GlobalScheduler::notify`.
+    fn arm_idle_timer(&self) {
+        let sleep = match self.timers.next_deadline() {
+            Some(deadline) => {
+                let remaining = deadline.checked_sub(PIT::current_time()).unwrap_or(Duration::from_millis(0));
+                core::cmp::min(remaining, MAX_IDLE_SLEEP)
+            },
+            None => MAX_IDLE_SLEEP,
+        };
+        GLOBAL_APIC.read().timer_oneshot(InterruptIndex::ApicTimer.as_u8(), sleep)
+            .expect("unable to set timer");
+    }
+
+    /// Registers a wake-up deadline for the process running on this core,
+    /// so idle cores know when it has to be polled again.
+    pub fn add_timer(&mut self, deadline: Duration) {
+        if let Some(pid) = self.cpus.current_cpu().current_pid() {
+            self.timers.add(pid, deadline);
+        }
     }
 
     /// Kills currently running process by scheduling out the current process
@@ -276,7 +538,9 @@ impl Scheduler {
             Running => {
                 if self.schedule_out(State::Dead, tf) {
                     let proc = self.processes.pop_back().expect("alskdjf");
-                    return Some(proc.pid);
+                    let pid = proc.pid;
+                    self.remove_dead(proc);
+                    return Some(pid);
                 }
             }
             _ => {}
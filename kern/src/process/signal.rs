@@ -0,0 +1,217 @@
+//! Signals
+//!
+//! Signals are recorded as pending on the target process and acted upon by
+//! the scheduler. Fatal signals terminate the process the next time it is
+//! not running, handled signals rewrite its `TrapFrame` to enter the user
+//! handler on its next return to user mode.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use kernel_api::{OsError, SIGINT, SIGKILL, SIG_DFL, SIG_IGN};
+use crate::interrupts::context_switch::TrapFrame;
+use crate::memory::user::is_user_writable;
+use crate::process::process::Id;
+use crate::SCHEDULER;
+
+/// Number of supported signals, signal 0 is invalid.
+pub const NSIG: u64 = 32;
+
+/// Bytes below the interrupted stack pointer the handler must not touch
+const RED_ZONE: u64 = 128;
+
+/// The process receiving console interrupts (Ctrl-C), 0 if none
+static FOREGROUND: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Copy, Clone)]
+pub enum SigAction {
+    Default,
+    Ignore,
+    Handler {
+        handler: u64,
+        /// Return address of the handler, calls `sigreturn`
+        restorer: u64,
+    },
+}
+
+impl SigAction {
+    /// Decodes the handler argument of the `sigaction` syscall
+    pub fn from_raw(handler: u64, restorer: u64) -> SigAction {
+        match handler {
+            SIG_DFL => SigAction::Default,
+            SIG_IGN => SigAction::Ignore,
+            _ => SigAction::Handler { handler, restorer },
+        }
+    }
+
+    /// Encodes the action as the handler argument of the `sigaction` syscall
+    pub fn as_raw(&self) -> u64 {
+        match self {
+            SigAction::Default => SIG_DFL,
+            SigAction::Ignore => SIG_IGN,
+            SigAction::Handler { handler, .. } => *handler,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SignalState {
+    pending: u32,
+    blocked: u32,
+    actions: [SigAction; NSIG as usize],
+    /// Contexts interrupted by running handlers, innermost last
+    saved: Vec<(TrapFrame, u32)>,
+}
+
+pub fn is_valid(sig: u64) -> bool {
+    sig > 0 && sig < NSIG
+}
+
+fn is_user(context: &TrapFrame) -> bool {
+    context.cs & 0b11 == 0b11
+}
+
+/// Can `sig` be sent to the process with saved `context`? Kernel processes
+/// only take `SIGKILL`, see `SignalState::take_fatal`.
+pub fn accepts(context: &TrapFrame, sig: u64) -> bool {
+    sig == SIGKILL || is_user(context)
+}
+
+impl SignalState {
+    pub fn new() -> SignalState {
+        SignalState {
+            pending: 0,
+            blocked: 0,
+            actions: [SigAction::Default; NSIG as usize],
+            saved: Vec::new(),
+        }
+    }
+
+    pub fn raise(&mut self, sig: u64) {
+        assert!(is_valid(sig), "invalid signal");
+        self.pending |= 1 << sig;
+    }
+
+    /// Raises a signal caused by the process itself. If it is blocked, its
+    /// handler is running already, so the default action is used instead.
+    pub fn force(&mut self, sig: u64) {
+        if self.blocked & (1 << sig) != 0 {
+            self.blocked &= !(1 << sig);
+            self.actions[sig as usize] = SigAction::Default;
+        }
+        self.raise(sig);
+    }
+
+    /// Installs `action` for `sig` and returns the previous one.
+    /// The action of `SIGKILL` cannot be changed.
+    pub fn set_action(&mut self, sig: u64, action: SigAction) -> Result<SigAction, ()> {
+        if !is_valid(sig) || sig == SIGKILL {
+            return Err(());
+        }
+        Ok(core::mem::replace(&mut self.actions[sig as usize], action))
+    }
+
+    /// Pending signals that are not blocked. `SIGKILL` cannot be blocked.
+    fn deliverable(&self) -> u32 {
+        self.pending & !(self.blocked & !(1 << SIGKILL))
+    }
+
+    /// Consumes pending signals that do not need a handler and returns the
+    /// first one that terminates the process.
+    ///
+    /// Kernel processes have no handlers and may hold locks, so only
+    /// `SIGKILL` terminates them and every other signal is ignored.
+    pub fn take_fatal(&mut self, context: &TrapFrame) -> Option<u64> {
+        let user = is_user(context);
+        for sig in 1..NSIG {
+            if self.deliverable() & (1 << sig) == 0 {
+                continue;
+            }
+            let action = self.actions[sig as usize];
+            if sig == SIGKILL || (user && matches!(action, SigAction::Default)) {
+                self.pending &= !(1 << sig);
+                return Some(sig);
+            }
+            if !user || matches!(action, SigAction::Ignore) {
+                self.pending &= !(1 << sig);
+            }
+        }
+        None
+    }
+
+    /// Rewrites `context` to enter the handler of the first deliverable
+    /// signal. The interrupted context is kept in the kernel until the
+    /// handler calls `sigreturn`, and the signal stays blocked meanwhile.
+    ///
+    /// Returns `Err` if the handler frame cannot be pushed to the user stack.
+    pub fn deliver(&mut self, context: &mut TrapFrame) -> Result<(), ()> {
+        let pending = self.deliverable();
+        if pending == 0 || !is_user(context) {
+            return Ok(());
+        }
+        let sig = pending.trailing_zeros() as u64;
+        let (handler, restorer) = match self.actions[sig as usize] {
+            SigAction::Handler { handler, restorer } => (handler, restorer),
+            // Everything else was consumed by `take_fatal`
+            _ => return Ok(()),
+        };
+        self.pending &= !(1 << sig);
+
+        // Push the restorer as the handler's return address, keeping the
+        // stack aligned the way a `call` would.
+        let sp = ((context.rsp - RED_ZONE) & !0xF) - 8;
+        if !is_user_writable(sp, core::mem::size_of::<u64>()) {
+            return Err(());
+        }
+        unsafe { *(sp as *mut u64) = restorer };
+
+        self.saved.push((*context, self.blocked));
+        self.blocked |= 1 << sig;
+        context.rip = handler;
+        context.rsp = sp;
+        context.rdi = sig;
+        Ok(())
+    }
+
+    /// Restores the context interrupted by the innermost handler.
+    pub fn restore(&mut self, context: &mut TrapFrame) -> Result<(), ()> {
+        let (saved, blocked) = self.saved.pop().ok_or(())?;
+        *context = saved;
+        self.blocked = blocked;
+        Ok(())
+    }
+}
+
+/// Sets the process that receives console interrupts, 0 clears it.
+pub fn set_foreground(pid: Id) {
+    FOREGROUND.store(pid, Ordering::Release);
+}
+
+pub fn foreground() -> Option<Id> {
+    match FOREGROUND.load(Ordering::Acquire) {
+        0 => None,
+        pid => Some(pid),
+    }
+}
+
+/// Forgets the foreground process if it is `pid`.
+pub fn clear_foreground(pid: Id) {
+    let _ = FOREGROUND.compare_exchange(pid, 0, Ordering::AcqRel, Ordering::Acquire);
+}
+
+/// Sends `SIGINT` to the foreground process.
+/// Returns `false` if there is no foreground process.
+pub fn interrupt_foreground() -> bool {
+    match foreground() {
+        Some(pid) => {
+            match SCHEDULER.signal(pid, SIGINT) {
+                Ok(()) => true,
+                Err(OsError::NoEntry) => {
+                    clear_foreground(pid);
+                    false
+                }
+                Err(_) => false,
+            }
+        }
+        None => false,
+    }
+}
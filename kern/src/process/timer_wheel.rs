@@ -0,0 +1,125 @@
+//! Timer Wheel
+//!
+//! Keeps the wake-up deadlines of sleeping processes, hashed into slots by
+//! deadline, so an idle core knows how long it may stay asleep. The slot of
+//! every process is indexed and the earliest deadline kept, only removing
+//! the earliest timer scans the wheel.
+
+use alloc::vec::Vec;
+use core::time::Duration;
+use hashbrown::HashMap;
+use crate::process::process::Id;
+
+const WHEEL_SLOTS: usize = 64;
+const SLOT_GRANULARITY_MS: u64 = 10;
+
+#[derive(Debug, Copy, Clone)]
+pub struct Timer {
+    pub pid: Id,
+    pub deadline: Duration,
+}
+
+pub struct TimerWheel {
+    slots: Vec<Vec<Timer>>,
+    /// Slot of the timer of each process
+    index: HashMap<Id, usize>,
+    earliest: Option<Duration>,
+    /// Slot tick up to which timers have been expired
+    current_tick: u64,
+}
+
+impl TimerWheel {
+    pub fn new() -> TimerWheel {
+        let mut slots = Vec::with_capacity(WHEEL_SLOTS);
+        for _ in 0..WHEEL_SLOTS {
+            slots.push(Vec::new());
+        }
+        TimerWheel {
+            slots,
+            index: HashMap::new(),
+            earliest: None,
+            current_tick: 0,
+        }
+    }
+
+    fn tick_of(time: Duration) -> u64 {
+        time.as_millis() as u64 / SLOT_GRANULARITY_MS
+    }
+
+    fn slot_of(tick: u64) -> usize {
+        tick as usize % WHEEL_SLOTS
+    }
+
+    /// Arms a timer for `pid`, replacing any timer it already has.
+    pub fn add(&mut self, pid: Id, deadline: Duration) {
+        self.remove(pid);
+        let slot = Self::slot_of(Self::tick_of(deadline));
+        self.slots[slot].push(Timer { pid, deadline });
+        self.index.insert(pid, slot);
+        self.earliest = Some(self.earliest.map_or(deadline, |earliest| core::cmp::min(earliest, deadline)));
+    }
+
+    /// Disarms the timer of `pid`. Returns `true` if there was one.
+    pub fn remove(&mut self, pid: Id) -> bool {
+        let slot = match self.index.remove(&pid) {
+            Some(slot) => &mut self.slots[slot],
+            None => return false,
+        };
+        let idx = slot.iter().position(|t| t.pid == pid).expect("indexed timer");
+        let deadline = slot.swap_remove(idx).deadline;
+        if self.earliest == Some(deadline) {
+            self.earliest = self.scan_earliest();
+        }
+        true
+    }
+
+    pub fn contains(&self, pid: Id) -> bool {
+        self.index.contains_key(&pid)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    fn scan_earliest(&self) -> Option<Duration> {
+        self.slots.iter()
+            .flat_map(|slot| slot.iter())
+            .map(|t| t.deadline)
+            .min()
+    }
+
+    /// Drops every timer whose deadline is at or before `now` by walking the
+    /// slots passed since the last call. Returns the number of expired timers.
+    pub fn expire(&mut self, now: Duration) -> usize {
+        let now_tick = Self::tick_of(now);
+        if self.earliest.map_or(true, |earliest| earliest > now) || now_tick < self.current_tick {
+            self.current_tick = core::cmp::max(self.current_tick, now_tick);
+            return 0;
+        }
+        let steps = core::cmp::min(now_tick - self.current_tick + 1, WHEEL_SLOTS as u64);
+        let mut expired = 0;
+        for tick in (now_tick + 1 - steps)..=now_tick {
+            let slot = &mut self.slots[Self::slot_of(tick)];
+            let index = &mut self.index;
+            let before = slot.len();
+            slot.retain(|t| {
+                if t.deadline > now {
+                    return true;
+                }
+                index.remove(&t.pid);
+                false
+            });
+            expired += before - slot.len();
+        }
+        self.current_tick = now_tick;
+        if expired > 0 {
+            self.earliest = self.scan_earliest();
+        }
+        expired
+    }
+
+    /// The earliest armed deadline
+    pub fn next_deadline(&self) -> Option<Duration> {
+        self.earliest
+    }
+}
@@ -10,7 +10,6 @@ use core::time::Duration;
 use crate::device::usb::G_USB;
 use x86_64::instructions::interrupts::without_interrupts;
 use crate::device::ahci::G_AHCI;
-use crate::sys::apic::GLOBAL_APIC;
 use crate::device::pci::class::PCIDeviceClass;
 use crate::device::uart::serial16650::{Serial16650, COM1_BASE_ADDR};
 use crate::device::uart::{UART, SERIAL_PORTS};
@@ -206,6 +205,131 @@ impl Shell {
                 }
                 Ok(0)
             }
+            "kill" => {
+                if command.args.len() < 2 {
+                    println!("Usage: kill <pid> [signal]");
+                    return Ok(1);
+                }
+                let pid = command.args[1].parse::<u64>().unwrap_or(0);
+                let sig = match command.args.get(2) {
+                    Some(s) => s.parse::<u64>().unwrap_or(0),
+                    None => kernel_api::SIGTERM,
+                };
+                if !crate::process::signal::is_valid(sig) {
+                    println!("invalid signal: {}", sig);
+                    return Ok(1);
+                }
+                match SCHEDULER.signal(pid, sig) {
+                    Ok(()) => {},
+                    Err(kernel_api::OsError::NoEntry) => println!("no such process: {}", pid),
+                    Err(_) => println!("kernel process {} only takes SIGKILL", pid),
+                }
+                Ok(0)
+            },
+            "fg" => {
+                use crate::process::signal::{foreground, set_foreground};
+                match command.args.get(1).map(|s| s.parse::<u64>()) {
+                    Some(Ok(pid)) => set_foreground(pid),
+                    Some(Err(_)) => println!("Usage: fg [pid]"),
+                    None => match foreground() {
+                        Some(pid) => println!("foreground: {}", pid),
+                        None => println!("no foreground process"),
+                    },
+                }
+                Ok(0)
+            },
+            "taskset" => {
+                use crate::process::cpu::CpuSet;
+                if command.args.len() < 2 {
+                    println!("Usage: taskset <pid> [apic_id,...]");
+                    return Ok(1);
+                }
+                let pid = command.args[1].parse::<u64>().unwrap_or(0);
+                match command.args.get(2) {
+                    Some(list) => {
+                        let mut set = CpuSet::empty();
+                        for id in list.split(',') {
+                            match id.parse::<u8>() {
+                                Ok(id) => set.insert(id),
+                                Err(_) => {
+                                    println!("invalid APIC ID: {}", id);
+                                    return Ok(1);
+                                }
+                            }
+                        }
+                        if let Err(e) = SCHEDULER.critical(|s| s.set_affinity(pid, set)) {
+                            println!("taskset: {:?}", e);
+                        }
+                    },
+                    None => {
+                        match SCHEDULER.critical(|s| s.get_affinity(pid)) {
+                            Ok(set) => println!("pid {} affinity: {:?}", pid, set),
+                            Err(e) => println!("taskset: {:?}", e),
+                        }
+                    }
+                }
+                Ok(0)
+            },
+            "cpus" => {
+                use crate::sys::resman::GLOBAL_RESMAN;
+                let acpi = ACPI.read();
+                let acpi = match acpi.as_ref() {
+                    Some(acpi) => acpi,
+                    None => {
+                        println!("cpus: no ACPI tables");
+                        return Ok(1);
+                    }
+                };
+                let resman = GLOBAL_RESMAN.read();
+                println!(" LAPIC | UID | STATE");
+                for p in acpi.boot_processor.iter().chain(acpi.application_processors.iter()) {
+                    let state = resman.percpu_blocks().find(|b| b.cpu.apic_id == p.local_apic_id)
+                        .map(|b| format!("{:?}", b.cpu.state()))
+                        .unwrap_or_else(|| format!("{:?}", p.state));
+                    println!("  {:03}  | {:03} | {}", p.local_apic_id, p.processor_uid, state);
+                }
+                Ok(0)
+            },
+            "online" | "offline" => {
+                use crate::init::smp::{cpu_down, cpu_up};
+                let apic_id = match command.args.get(1).map(|id| id.parse::<u8>()) {
+                    Some(Ok(id)) => id,
+                    _ => {
+                        println!("Usage: {} <apic_id>", command.path());
+                        return Ok(1);
+                    }
+                };
+                let result = if command.path() == "online" { cpu_up(apic_id) } else { cpu_down(apic_id) };
+                if let Err(e) = result {
+                    println!("{}: core {}: {:?}", command.path(), apic_id, e);
+                    return Ok(1);
+                }
+                Ok(0)
+            },
+            "cpustat" => {
+                let apic_id = match command.args.get(1) {
+                    Some(id) => match id.parse::<u8>() {
+                        Ok(id) => id,
+                        Err(_) => {
+                            println!("invalid APIC ID: {}", id);
+                            return Ok(1);
+                        }
+                    },
+                    None => crate::sys::percpu::apic_id(),
+                };
+                match crate::sys::ipi::cpu_stat(apic_id) {
+                    Some(stat) => {
+                        println!("Core {} (ACPI UID {}): running {:?}", stat.apic_id, stat.proc_id, stat.current_pid);
+                        println!("  TSC: {}, CR3: {:#x}, device IRQs: {}, x2APIC: {}",
+                                 stat.tsc, stat.cr3, stat.irqs, stat.x2apic);
+                        Ok(0)
+                    },
+                    None => {
+                        println!("cpustat: core {} is not online", apic_id);
+                        Ok(1)
+                    }
+                }
+            },
             "lspci" => {
                 use crate::device::pci::GLOBAL_PCI;
                 let scan = command.args.len() >= 2 && command.args[1].eq("-s");
@@ -227,6 +351,17 @@ impl Shell {
                 }
                 Ok(0)
             },
+            "lsirq" => {
+                use crate::interrupts::irq;
+                for stat in irq::stats() {
+                    print!("{:#04x} {:<24} handlers: {}", stat.vector, stat.name, stat.handlers);
+                    for (apic_id, count) in stat.per_cpu.iter() {
+                        print!(" | cpu{}: {}", apic_id, count);
+                    }
+                    println!(" | unhandled: {}", stat.unhandled);
+                }
+                Ok(0)
+            },
             "uart" => {
                 for s in SERIAL_PORTS.read().ports.values() {
                     s.lock().write_byte(0x69);
@@ -258,6 +393,97 @@ impl Shell {
                     Ok(-1)
                 }
             },
+            "mount" => {
+                use alloc::sync::Arc;
+                use crate::storage::block::G_BLOCK_DEV_MGR;
+                use crate::fs::ext2::Ext2;
+                use crate::fs::fat32::Fat32;
+                use crate::fs::traits::FileSystem;
+                use crate::fs::vfs;
+                if command.args.len() < 3 {
+                    for (path, fs) in vfs::mounts() {
+                        println!("{} on {}", fs, path.display());
+                    }
+                    return Ok(0);
+                }
+                let dev = match G_BLOCK_DEV_MGR.read().devices.get(&command.args[1]) {
+                    Some(dev) => dev.clone(),
+                    None => {
+                        println!("{} not found", &command.args[1]);
+                        return Ok(1);
+                    }
+                };
+                let fs: Result<Arc<dyn FileSystem>, _> = match Ext2::new(dev.clone()) {
+                    Ok(fs) => Ok(Arc::new(fs)),
+                    Err(_) => Fat32::new(dev).map(|fs| Arc::new(fs) as Arc<dyn FileSystem>),
+                };
+                match fs.and_then(|fs| vfs::mount(&command.args[2], fs)) {
+                    Ok(()) => Ok(0),
+                    Err(e) => {
+                        println!("mount: {:?}", e);
+                        Ok(1)
+                    }
+                }
+            },
+            "ls" => {
+                use crate::fs::vfs;
+                use kernel_api::O_READ;
+                let path = command.args.get(1).map_or("/", |p| p.as_str());
+                let dir = match vfs::open(path, O_READ) {
+                    Ok(dir) => dir,
+                    Err(e) => {
+                        println!("ls: {}: {:?}", path, e);
+                        return Ok(1);
+                    }
+                };
+                let mut entry = kernel_api::DirEnt::new();
+                loop {
+                    match dir.readdir(&mut entry) {
+                        Ok(true) => {},
+                        Ok(false) => break,
+                        Err(e) => {
+                            println!("ls: {}: {:?}", path, e);
+                            return Ok(1);
+                        }
+                    }
+                    let name = String::from_utf8_lossy(entry.name());
+                    let kind = match kernel_api::FileType::from(entry.kind) {
+                        kernel_api::FileType::Directory => 'd',
+                        kernel_api::FileType::Symlink => 'l',
+                        _ => '-',
+                    };
+                    let size = vfs::stat(format!("{}/{}", path, name), false).map_or(0, |m| m.size);
+                    println!("{} {:>10} {}", kind, size, name);
+                }
+                Ok(0)
+            },
+            "cat" => {
+                use crate::fs::vfs;
+                use kernel_api::O_READ;
+                if command.args.len() < 2 {
+                    println!("Usage: cat <path>");
+                    return Ok(1);
+                }
+                let file = match vfs::open(&command.args[1], O_READ) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        println!("cat: {}: {:?}", &command.args[1], e);
+                        return Ok(1);
+                    }
+                };
+                let mut buf = [0u8; 512];
+                loop {
+                    match file.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(count) => print!("{}", String::from_utf8_lossy(&buf[..count])),
+                        Err(e) => {
+                            println!("\ncat: {}: {:?}", &command.args[1], e);
+                            return Ok(1);
+                        }
+                    }
+                }
+                Ok(0)
+            },
             "lsusb" => {
                 // for dev in G_USB.devices.read().iter() {
                 //     println!("Bus {:03} Device {:03}: {:04x}:{:04x} {} {}",
@@ -275,4 +501,3 @@ impl Shell {
         }
     }
 }
-
@@ -3,7 +3,17 @@ pub trait BlockDevice {
     /// Sector size in bytes.
     fn sector_size(&self) -> u64 { 512 } // 512 is default
 
+    /// Number of sectors, `None` if the device does not know
+    fn sector_count(&self) -> Option<u64> { None }
+
     fn read_sector(&self, sector: u64, buf: &mut [u8]) -> core_io::Result<usize>;
 
     fn write_sector(&self, sector: u64, buf: &[u8]) -> core_io::Result<usize>;
+
+    /// `true` if `write_sector` always fails, file systems on the device are
+    /// opened read-only
+    fn read_only(&self) -> bool { false }
+
+    /// `false` once the device went away, see `storage::block::detacher`
+    fn is_present(&self) -> bool { true }
 }
\ No newline at end of file
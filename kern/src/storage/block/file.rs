@@ -0,0 +1,70 @@
+//! Block devices opened as files through `fs::devfs`. Reads and writes at a
+//! byte offset go through whole sectors.
+
+use core_io::error::ErrorKind;
+use kernel_api::{OsError, IOCTL_BLK_SECTOR_COUNT, IOCTL_BLK_SECTOR_SIZE};
+use crate::storage::block::device::BlockDevice;
+
+/// Reads from byte `offset` of `dev`, 0 at the end of the device
+pub fn read_at(dev: &dyn BlockDevice, offset: u64, buf: &mut [u8]) -> Result<usize, OsError> {
+    let ss = dev.sector_size();
+    let mut sector = vec![0u8; ss as usize];
+    let mut offset = offset;
+    let mut done = 0;
+    while done < buf.len() {
+        let within = (offset % ss) as usize;
+        match dev.read_sector(offset / ss, &mut sector) {
+            Ok(_) => {},
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(_) if done > 0 => break,
+            Err(_) => return Err(OsError::IoError),
+        }
+        let count = core::cmp::min(sector.len() - within, buf.len() - done);
+        buf[done..done + count].copy_from_slice(&sector[within..within + count]);
+        done += count;
+        offset += count as u64;
+    }
+    Ok(done)
+}
+
+/// Writes at byte `offset` of `dev`. Partial sectors are read back first.
+pub fn write_at(dev: &dyn BlockDevice, offset: u64, buf: &[u8]) -> Result<usize, OsError> {
+    if dev.read_only() {
+        return Err(OsError::ReadOnly);
+    }
+    let ss = dev.sector_size();
+    let mut sector = vec![0u8; ss as usize];
+    let mut offset = offset;
+    let mut done = 0;
+    while done < buf.len() {
+        let within = (offset % ss) as usize;
+        let count = core::cmp::min(sector.len() - within, buf.len() - done);
+        let result = if count < sector.len() {
+            dev.read_sector(offset / ss, &mut sector)
+        } else {
+            Ok(0)
+        };
+        let result = result.and_then(|_| {
+            sector[within..within + count].copy_from_slice(&buf[done..done + count]);
+            dev.write_sector(offset / ss, &sector)
+        });
+        match result {
+            Ok(_) => {},
+            Err(_) if done > 0 => break,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Err(OsError::IoErrorEof),
+            Err(_) => return Err(OsError::IoError),
+        }
+        done += count;
+        offset += count as u64;
+    }
+    Ok(done)
+}
+
+/// The `IOCTL_BLK_*` requests
+pub fn ioctl(dev: &dyn BlockDevice, request: u64, _arg: u64) -> Result<u64, OsError> {
+    match request {
+        IOCTL_BLK_SECTOR_SIZE => Ok(dev.sector_size()),
+        IOCTL_BLK_SECTOR_COUNT => dev.sector_count().ok_or(OsError::InvalidArgument),
+        _ => Err(OsError::InvalidArgument),
+    }
+}
@@ -6,8 +6,11 @@ use hashbrown::{HashMap, HashSet};
 use alloc::string::String;
 use alloc::sync::Arc;
 use crate::storage::partition::{scan_for_partitions, Partition};
+use crate::fs::devfs::{self, Device};
+use crate::sync::WorkQueue;
 
 pub mod device;
+pub mod file;
 lazy_static! {
     pub static ref G_BLOCK_DEV_MGR: RwLock<BlockDeviceManager> = {
         RwLock::new(BlockDeviceManager {
@@ -16,6 +19,9 @@ lazy_static! {
             next_device: 0,
         })
     };
+
+    /// Pushed to when a device may have gone away
+    static ref PRESENCE_CHANGED: WorkQueue<()> = WorkQueue::new();
 }
 
 pub struct BlockDeviceManager {
@@ -34,6 +40,10 @@ impl BlockDevice for PartitionBlockDevice {
         self.parent.sector_size()
     }
 
+    fn sector_count(&self) -> Option<u64> {
+        Some(self.part.size)
+    }
+
     fn read_sector(&self, sector: u64, buf: &mut [u8]) -> core_io::Result<usize> {
         if sector >= self.part.size {
             return Err(core_io::error::ErrorKind::UnexpectedEof.into())
@@ -47,6 +57,10 @@ impl BlockDevice for PartitionBlockDevice {
         }
         self.parent.write_sector(sector + self.part.offset, buf)
     }
+
+    fn read_only(&self) -> bool {
+        self.parent.read_only()
+    }
 }
 
 impl PartitionBlockDevice {
@@ -67,6 +81,7 @@ impl BlockDeviceManager {
         trace!("[BD] Registering device: {}", device_name);
         self.children_map.insert(device_name.clone(), Default::default());
         self.devices.insert(device_name.clone(), dev.clone().into());
+        devfs::add(&device_name, Device::Block(dev.clone()));
         let parts = scan_for_partitions(dev.as_ref());
         for p in parts {
             let child_dev = PartitionBlockDevice::new(p.clone(), dev.clone());
@@ -80,9 +95,10 @@ impl BlockDeviceManager {
             Some(vec) => {
                 let id = vec.len();
                 let name = format!("{}p{}", root, id);
-                if self.devices.insert(name.clone(), dev).is_some() {
+                if self.devices.insert(name.clone(), dev.clone()).is_some() {
                     panic!("WHAT?");
                 }
+                devfs::add(&name, Device::Block(dev));
                 vec.push(name.clone());
                 name
             },
@@ -92,9 +108,53 @@ impl BlockDeviceManager {
         }
     }
 
+    /// Removes a device that went away together with its partitions.
+    /// Returns `false` if `name` is not a registered root device.
+    pub fn unregister_root_device(&mut self, name: &str) -> bool {
+        let children = match self.children_map.remove(name) {
+            Some(children) => children,
+            None => return false,
+        };
+        trace!("[BD] Unregistering device: {}", name);
+        for child in children.iter().map(String::as_str).chain(core::iter::once(name)) {
+            self.devices.remove(child);
+            devfs::remove(child);
+        }
+        true
+    }
+
     pub fn list_devices(&self) -> Vec<String> {
         let mut a:Vec<String> = self.devices.keys().cloned().collect();
         a.sort();
         a
     }
-}
\ No newline at end of file
+}
+
+/// Has `detacher` look for root devices that went away. Drivers call it
+/// when they notice that a device may be gone. It neither allocates nor
+/// blocks, interrupt handlers may call it too.
+pub fn presence_changed() {
+    PRESENCE_CHANGED.push(());
+}
+
+/// Kernel process unregistering root devices that are no longer present,
+/// with their partitions
+pub extern fn detacher() -> ! {
+    loop {
+        // One look covers every change queued so far
+        PRESENCE_CHANGED.wait();
+        let roots: Vec<(String, Arc<dyn BlockDevice + Sync + Send>)> = {
+            let manager = G_BLOCK_DEV_MGR.read();
+            manager.children_map.keys()
+                .filter_map(|name| manager.devices.get(name).map(|dev| (name.clone(), dev.clone())))
+                .collect()
+        };
+        // Asked without the lock, a driver may have to talk to its device
+        for (name, dev) in roots {
+            if !dev.is_present() {
+                info!("[BD] {} went away", name);
+                G_BLOCK_DEV_MGR.write().unregister_root_device(&name);
+            }
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use x86_64::instructions::interrupts::without_interrupts;
+use super::mutex::MutexGuard;
+use super::wait_queue::WaitQueue;
+
+/// Condition variable for use with `sync::Mutex`. Waiters are woken in the
+/// order they started waiting.
+pub struct Condvar {
+    queue: spin::Mutex<WaitQueue>,
+}
+
+impl Condvar {
+    pub fn new() -> Condvar {
+        Condvar {
+            queue: spin::Mutex::new(WaitQueue::new()),
+        }
+    }
+
+    /// Releases `guard`, parks the calling process until it is notified and
+    /// takes the lock again. Like any condition variable it should be waited
+    /// on in a loop checking the condition.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        super::assert_may_sleep();
+        let mutex = guard.mutex;
+        // Queued before the lock is dropped, so a notification sent right
+        // after cannot be missed
+        let waiter = without_interrupts(|| self.queue.lock().enqueue());
+        drop(guard);
+        waiter.wait();
+        mutex.lock()
+    }
+
+    /// Wakes the longest waiting process. Returns `false` if none waits.
+    pub fn notify_one(&self) -> bool {
+        match without_interrupts(|| self.queue.lock().wake_one()) {
+            Some(wakeup) => {
+                wakeup.unpark();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn notify_all(&self) {
+        for wakeup in without_interrupts(|| self.queue.lock().wake_all()) {
+            wakeup.unpark();
+        }
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Condvar {
+        Condvar::new()
+    }
+}
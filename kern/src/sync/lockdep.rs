@@ -0,0 +1,653 @@
+//! Lock Dependency Checker
+//!
+//! Built with the `lockdep` feature. Every `SpinMutex` and `SpinRwLock` is a
+//! lock class of its own, identified by its address, which suits the statics
+//! they are used for.
+//!
+//! Taking a lock while holding others records that the held classes come
+//! first. An order that closes a cycle with the orders seen so far means two
+//! paths take the same locks in opposite orders, which can deadlock. Read
+//! locks only wait for writers, so a cycle needs a writer at every lock.
+//!
+//! Every class also remembers whether it was taken in an interrupt handler
+//! and whether it was taken with interrupts enabled. A core holding such a
+//! lock can be interrupted by a handler spinning on it.
+//!
+//! Problems are reported before the lock is taken, with the stack traces of
+//! both sides, and checking is turned off afterwards. The traces follow the
+//! frame pointer chain, `make LOCKDEP=1` builds with frame pointers.
+//!
+//! Locks are held by the core, and by the process running on it. When a trap
+//! switches to another process, the locks still held are parked with the old
+//! process until it runs again, on whichever core that is.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+use crate::memory::paging::PHYSMAP_BASE;
+use crate::sys::percpu;
+
+const MAX_CLASSES: usize = 64;
+const MAX_NODES: usize = MAX_CLASSES * 2;
+const MAX_EDGES: usize = 256;
+/// Cores with a higher local APIC ID are not checked
+const MAX_CPUS: usize = 32;
+const MAX_HELD: usize = 16;
+/// Processes preempted while holding a lock
+const MAX_PARKED: usize = 16;
+const MAX_CHAIN: usize = 8;
+const TRACE_DEPTH: usize = 8;
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+static STATE: Mutex<State> = Mutex::new(State::new());
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Mode {
+    Read = 0,
+    Write = 1,
+}
+
+impl Mode {
+    /// Can a lock held in `self` make an acquisition in `other` wait?
+    fn conflicts(self, other: Mode) -> bool {
+        self == Mode::Write || other == Mode::Write
+    }
+}
+
+/// Return addresses, innermost first, 0 terminated
+#[derive(Copy, Clone)]
+struct Trace([u64; TRACE_DEPTH]);
+
+impl Trace {
+    const EMPTY: Trace = Trace([0; TRACE_DEPTH]);
+
+    #[inline(always)]
+    fn capture() -> Trace {
+        let mut trace = Trace::EMPTY;
+        let mut rbp: u64;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+        for slot in trace.0.iter_mut() {
+            // Stop at user mode and at the zeroed frame a process starts with
+            if rbp < PHYSMAP_BASE || rbp % 8 != 0 {
+                break;
+            }
+            let ret = unsafe { *((rbp + 8) as *const u64) };
+            if ret == 0 {
+                break;
+            }
+            *slot = ret;
+            rbp = unsafe { *(rbp as *const u64) };
+        }
+        trace
+    }
+
+    fn print(&self) {
+        if self.0[0] == 0 {
+            error!("[LOCKDEP]     <no trace>");
+        }
+        for ret in self.0.iter().take_while(|r| **r != 0) {
+            error!("[LOCKDEP]     {:#x}", ret);
+        }
+    }
+}
+
+/// Index of a class taken in a mode, `class * 2 + mode`
+type Node = usize;
+
+fn node(class: usize, mode: Mode) -> Node {
+    class * 2 + mode as usize
+}
+
+fn node_class(node: Node) -> usize {
+    node / 2
+}
+
+fn node_mode(node: Node) -> Mode {
+    if node % 2 == 0 { Mode::Read } else { Mode::Write }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Usage {
+    InIrqRead = 0,
+    InIrqWrite = 1,
+    IrqsEnabledRead = 2,
+    IrqsEnabledWrite = 3,
+}
+
+/// Usages that deadlock together, the handler waits for the interrupted code
+const IRQ_CONFLICTS: [(Usage, Usage); 3] = [
+    (Usage::InIrqWrite, Usage::IrqsEnabledRead),
+    (Usage::InIrqWrite, Usage::IrqsEnabledWrite),
+    (Usage::InIrqRead, Usage::IrqsEnabledWrite),
+];
+
+#[derive(Copy, Clone)]
+struct Class {
+    lock: usize,
+    name: &'static str,
+    /// First acquisition of each `Usage`
+    usage: [Option<Trace>; 4],
+}
+
+impl Class {
+    const EMPTY: Class = Class { lock: 0, name: "", usage: [None; 4] };
+}
+
+/// First time `held` was seen held while taking `acquired`
+#[derive(Copy, Clone)]
+struct Edge {
+    held: Node,
+    acquired: Node,
+    held_at: Trace,
+    acquired_at: Trace,
+}
+
+impl Edge {
+    const EMPTY: Edge = Edge { held: 0, acquired: 0, held_at: Trace::EMPTY, acquired_at: Trace::EMPTY };
+}
+
+#[derive(Copy, Clone)]
+struct Held {
+    lock: usize,
+    class: usize,
+    mode: Mode,
+    at: Trace,
+}
+
+impl Held {
+    const EMPTY: Held = Held { lock: 0, class: 0, mode: Mode::Read, at: Trace::EMPTY };
+}
+
+/// Locks held by one context, innermost last
+#[derive(Copy, Clone)]
+struct HeldStack {
+    held: [Held; MAX_HELD],
+    len: usize,
+}
+
+impl HeldStack {
+    const EMPTY: HeldStack = HeldStack { held: [Held::EMPTY; MAX_HELD], len: 0 };
+
+    fn iter(&self) -> impl Iterator<Item=&Held> {
+        self.held[..self.len].iter()
+    }
+
+    fn push(&mut self, held: Held) -> Result<(), ()> {
+        if self.len == MAX_HELD {
+            return Err(());
+        }
+        self.held[self.len] = held;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Removes the innermost entry of `lock`
+    fn remove(&mut self, lock: usize) -> bool {
+        match self.held[..self.len].iter().rposition(|h| h.lock == lock) {
+            Some(idx) => {
+                self.held.copy_within(idx + 1..self.len, idx);
+                self.len -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Identifies whose locks a parked stack holds
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Context {
+    Process(u64),
+    /// Not running a process, the idle task or early boot
+    Core(u8),
+}
+
+#[derive(Copy, Clone)]
+struct CpuLocks {
+    stack: HeldStack,
+    /// Nested traps, bit `n` is set if level `n` is an interrupt
+    traps: usize,
+    irq_levels: u64,
+    /// Context the outermost trap interrupted
+    interrupted: Context,
+}
+
+impl CpuLocks {
+    const EMPTY: CpuLocks = CpuLocks {
+        stack: HeldStack::EMPTY,
+        traps: 0,
+        irq_levels: 0,
+        interrupted: Context::Core(0),
+    };
+
+    fn in_irq(&self) -> bool {
+        self.irq_levels != 0
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Parked {
+    context: Option<Context>,
+    stack: HeldStack,
+}
+
+impl Parked {
+    const EMPTY: Parked = Parked { context: None, stack: HeldStack::EMPTY };
+}
+
+#[derive(Copy, Clone)]
+struct Step {
+    held: &'static str,
+    held_mode: Mode,
+    held_at: Trace,
+    acquired: &'static str,
+    acquired_mode: Mode,
+    acquired_at: Trace,
+}
+
+impl Step {
+    const EMPTY: Step = Step {
+        held: "",
+        held_mode: Mode::Read,
+        held_at: Trace::EMPTY,
+        acquired: "",
+        acquired_mode: Mode::Read,
+        acquired_at: Trace::EMPTY,
+    };
+
+    fn print(&self) {
+        error!("[LOCKDEP]   holding {} ({:?}), taken at:", self.held, self.held_mode);
+        self.held_at.print();
+        error!("[LOCKDEP]   while taking {} ({:?}) at:", self.acquired, self.acquired_mode);
+        self.acquired_at.print();
+    }
+}
+
+enum Report {
+    Recursive(Step),
+    /// The new order first, then the recorded orders closing the cycle
+    Cycle([Step; MAX_CHAIN], usize),
+    IrqUnsafe {
+        class: &'static str,
+        in_irq: Trace,
+        irqs_enabled: Trace,
+        current_is_irq: bool,
+    },
+    /// Ran out of room, the checker cannot go on
+    Overflow(&'static str),
+}
+
+impl Report {
+    fn print(&self) {
+        error!("[LOCKDEP] ==================================================");
+        match self {
+            Report::Recursive(step) => {
+                error!("[LOCKDEP] Possible recursive locking on core {}", percpu_id());
+                step.print();
+            }
+            Report::Cycle(steps, len) => {
+                error!("[LOCKDEP] Possible deadlock, lock order cycle on core {}", percpu_id());
+                error!("[LOCKDEP] New order:");
+                steps[0].print();
+                error!("[LOCKDEP] Conflicts with the order recorded before:");
+                for step in steps[1..*len].iter() {
+                    step.print();
+                }
+            }
+            Report::IrqUnsafe { class, in_irq, irqs_enabled, current_is_irq } => {
+                error!("[LOCKDEP] {} is taken in interrupt handlers and with interrupts enabled, \
+                        a handler on the core holding it will spin forever", class);
+                error!("[LOCKDEP] Taken in an interrupt handler at:{}", if *current_is_irq { " (now)" } else { "" });
+                in_irq.print();
+                error!("[LOCKDEP] Taken with interrupts enabled at:{}", if *current_is_irq { "" } else { " (now)" });
+                irqs_enabled.print();
+            }
+            Report::Overflow(what) => {
+                error!("[LOCKDEP] Out of {}", what);
+            }
+        }
+        error!("[LOCKDEP] Turning off the lock dependency checker");
+        error!("[LOCKDEP] ==================================================");
+    }
+}
+
+fn percpu_id() -> u8 {
+    percpu::try_current().map_or(0, |b| b.cpu.apic_id)
+}
+
+fn current_context() -> Context {
+    match percpu::try_current() {
+        Some(block) => match block.cpu.current_pid() {
+            Some(pid) => Context::Process(pid),
+            None => Context::Core(block.cpu.apic_id),
+        },
+        None => Context::Core(0),
+    }
+}
+
+struct State {
+    classes: [Class; MAX_CLASSES],
+    class_count: usize,
+    /// Successors of each held node
+    deps: [u128; MAX_NODES],
+    edges: [Edge; MAX_EDGES],
+    edge_count: usize,
+    cpus: [CpuLocks; MAX_CPUS],
+    parked: [Parked; MAX_PARKED],
+}
+
+impl State {
+    const fn new() -> State {
+        State {
+            classes: [Class::EMPTY; MAX_CLASSES],
+            class_count: 0,
+            deps: [0; MAX_NODES],
+            edges: [Edge::EMPTY; MAX_EDGES],
+            edge_count: 0,
+            cpus: [CpuLocks::EMPTY; MAX_CPUS],
+            parked: [Parked::EMPTY; MAX_PARKED],
+        }
+    }
+
+    fn class_of(&mut self, lock: usize, name: &'static str) -> Result<usize, Report> {
+        if let Some(class) = self.classes[..self.class_count].iter().position(|c| c.lock == lock) {
+            return Ok(class);
+        }
+        if self.class_count == MAX_CLASSES {
+            return Err(Report::Overflow("lock classes"));
+        }
+        let class = self.class_count;
+        self.classes[class] = Class { lock, name, usage: [None; 4] };
+        self.class_count += 1;
+        Ok(class)
+    }
+
+    fn cpu(&mut self) -> Option<&mut CpuLocks> {
+        self.cpus.get_mut(percpu_id() as usize)
+    }
+
+    fn edge(&self, held: Node, acquired: Node) -> Option<&Edge> {
+        self.edges[..self.edge_count].iter().find(|e| e.held == held && e.acquired == acquired)
+    }
+
+    fn acquire(&mut self, lock: usize, name: &'static str, mode: Mode, trylock: bool,
+               irqs_enabled: bool, at: Trace) -> Result<(), Report> {
+        let class = self.class_of(lock, name)?;
+        let cpu = match self.cpu() {
+            Some(cpu) => *cpu,
+            None => return Ok(()),
+        };
+
+        // A failed try is never recorded, so it cannot wait on anything
+        if !trylock {
+            let usage = match (cpu.in_irq(), irqs_enabled, mode) {
+                (true, _, Mode::Read) => Some(Usage::InIrqRead),
+                (true, _, Mode::Write) => Some(Usage::InIrqWrite),
+                (false, true, Mode::Read) => Some(Usage::IrqsEnabledRead),
+                (false, true, Mode::Write) => Some(Usage::IrqsEnabledWrite),
+                (false, false, _) => None,
+            };
+            if let Some(usage) = usage {
+                self.record_usage(class, usage, at)?;
+            }
+
+            for held in cpu.stack.iter() {
+                if held.class == class {
+                    if held.mode.conflicts(mode) {
+                        return Err(Report::Recursive(Step {
+                            held: name,
+                            held_mode: held.mode,
+                            held_at: held.at,
+                            acquired: name,
+                            acquired_mode: mode,
+                            acquired_at: at,
+                        }));
+                    }
+                    continue;
+                }
+                self.add_edge(held, class, mode, at)?;
+            }
+        }
+
+        let held = Held { lock, class, mode, at };
+        match self.cpu() {
+            Some(cpu) => cpu.stack.push(held).map_err(|_| Report::Overflow("held lock slots")),
+            None => Ok(()),
+        }
+    }
+
+    fn record_usage(&mut self, class: usize, usage: Usage, at: Trace) -> Result<(), Report> {
+        if self.classes[class].usage[usage as usize].is_some() {
+            return Ok(());
+        }
+        self.classes[class].usage[usage as usize] = Some(at);
+        for (irq, enabled) in IRQ_CONFLICTS.iter() {
+            if *irq != usage && *enabled != usage {
+                continue;
+            }
+            let c = &self.classes[class];
+            if let (Some(in_irq), Some(irqs_enabled)) = (c.usage[*irq as usize], c.usage[*enabled as usize]) {
+                return Err(Report::IrqUnsafe {
+                    class: c.name,
+                    in_irq,
+                    irqs_enabled,
+                    current_is_irq: *irq == usage,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn add_edge(&mut self, held: &Held, class: usize, mode: Mode, at: Trace) -> Result<(), Report> {
+        let from = node(held.class, held.mode);
+        let to = node(class, mode);
+        if self.deps[from] & (1 << to) != 0 {
+            return Ok(());
+        }
+        if let Some(cycle) = self.find_cycle(held, to) {
+            let mut steps = [Step::EMPTY; MAX_CHAIN];
+            steps[0] = Step {
+                held: self.classes[held.class].name,
+                held_mode: held.mode,
+                held_at: held.at,
+                acquired: self.classes[class].name,
+                acquired_mode: mode,
+                acquired_at: at,
+            };
+            let mut len = 1;
+            for &(h, a) in cycle.0[..cycle.1].iter() {
+                if len == MAX_CHAIN {
+                    break;
+                }
+                let (held_at, acquired_at) = self.edge(h, a)
+                    .map_or((Trace::EMPTY, Trace::EMPTY), |e| (e.held_at, e.acquired_at));
+                steps[len] = Step {
+                    held: self.classes[node_class(h)].name,
+                    held_mode: node_mode(h),
+                    held_at,
+                    acquired: self.classes[node_class(a)].name,
+                    acquired_mode: node_mode(a),
+                    acquired_at,
+                };
+                len += 1;
+            }
+            return Err(Report::Cycle(steps, len));
+        }
+
+        self.deps[from] |= 1 << to;
+        // Without room the order is still checked, only its traces are lost
+        if self.edge_count < MAX_EDGES {
+            self.edges[self.edge_count] = Edge { held: from, acquired: to, held_at: held.at, acquired_at: at };
+            self.edge_count += 1;
+        }
+        Ok(())
+    }
+
+    /// Looks for recorded orders leading from `start` back to `held`. Each
+    /// lock on the way must be able to block, returns the path as
+    /// `(held, acquired)` node pairs.
+    fn find_cycle(&self, held: &Held, start: Node) -> Option<([(Node, Node); MAX_CHAIN], usize)> {
+        // For every reached acquired node, the edge it was reached through
+        let mut via = [(usize::MAX, usize::MAX); MAX_NODES];
+        let mut visited: u128 = 1 << start;
+        let mut queue = [0 as Node; MAX_NODES];
+        let (mut head, mut tail) = (0, 1);
+        queue[0] = start;
+
+        while head < tail {
+            let acquired = queue[head];
+            head += 1;
+            let class = node_class(acquired);
+            if class == held.class && node_mode(acquired).conflicts(held.mode) {
+                let mut path = [(0, 0); MAX_CHAIN];
+                let mut len = 0;
+                let mut at = acquired;
+                while at != start {
+                    let (h, prev) = via[at];
+                    if len < MAX_CHAIN {
+                        path[len] = (h, at);
+                        len += 1;
+                    }
+                    at = prev;
+                }
+                path[..len].reverse();
+                return Some((path, len));
+            }
+            for &mode in [Mode::Read, Mode::Write].iter() {
+                if !mode.conflicts(node_mode(acquired)) {
+                    continue;
+                }
+                let h = node(class, mode);
+                let mut next = self.deps[h] & !visited;
+                while next != 0 {
+                    let a = next.trailing_zeros() as Node;
+                    next &= next - 1;
+                    visited |= 1 << a;
+                    via[a] = (h, acquired);
+                    queue[tail] = a;
+                    tail += 1;
+                }
+            }
+        }
+        None
+    }
+
+    fn release(&mut self, lock: usize) {
+        if let Some(cpu) = self.cpu() {
+            if cpu.stack.remove(lock) {
+                return;
+            }
+        }
+        // Taken before the per-CPU block was loaded
+        for cpu in self.cpus.iter_mut() {
+            if cpu.stack.remove(lock) {
+                return;
+            }
+        }
+    }
+
+    fn trap_enter(&mut self, irq: bool) {
+        let context = current_context();
+        if let Some(cpu) = self.cpu() {
+            if cpu.traps == 0 {
+                cpu.interrupted = context;
+            }
+            if irq {
+                cpu.irq_levels |= 1 << cpu.traps;
+            }
+            cpu.traps += 1;
+        }
+    }
+
+    fn trap_exit(&mut self) -> Result<(), Report> {
+        let context = current_context();
+        let cpu = match self.cpu() {
+            Some(cpu) => cpu,
+            None => return Ok(()),
+        };
+        // The per-CPU block was loaded while in the trap
+        if cpu.traps == 0 {
+            return Ok(());
+        }
+        cpu.traps -= 1;
+        cpu.irq_levels &= !(1 << cpu.traps);
+        if cpu.traps != 0 || cpu.interrupted == context {
+            return Ok(());
+        }
+
+        // Switched to another process, which must not inherit the locks
+        let (old, stack) = (cpu.interrupted, cpu.stack);
+        cpu.stack = HeldStack::EMPTY;
+        if stack.len != 0 {
+            let slot = self.parked.iter_mut().find(|p| p.context.is_none())
+                .ok_or(Report::Overflow("parked lock slots"))?;
+            *slot = Parked { context: Some(old), stack };
+        }
+        if let Some(parked) = self.parked.iter_mut().find(|p| p.context == Some(context)) {
+            let stack = parked.stack;
+            *parked = Parked::EMPTY;
+            if let Some(cpu) = self.cpu() {
+                cpu.stack = stack;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn disable(report: Report) {
+    // Reporting takes locks of its own
+    if ENABLED.swap(false, Ordering::AcqRel) {
+        report.print();
+    }
+}
+
+/// Called before a lock is taken, `trylock` for attempts that do not spin
+#[inline(always)]
+pub fn acquire(lock: usize, name: &'static str, mode: Mode, trylock: bool) {
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+    let irqs_enabled = interrupts::are_enabled();
+    let at = Trace::capture();
+    let result = without_interrupts(|| {
+        STATE.lock().acquire(lock, name, mode, trylock, irqs_enabled, at)
+    });
+    if let Err(report) = result {
+        disable(report);
+    }
+}
+
+/// Called when a lock is released
+pub fn release(lock: usize) {
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+    without_interrupts(|| STATE.lock().release(lock));
+}
+
+/// Called on entry to a trap handler, `irq` if it handles an interrupt
+pub fn trap_enter(irq: bool) {
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+    without_interrupts(|| STATE.lock().trap_enter(irq));
+}
+
+/// Is the current core running a trap handler? `false` while lockdep is
+/// disabled, the depth is not tracked then.
+pub fn in_trap() -> bool {
+    if !ENABLED.load(Ordering::Acquire) {
+        return false;
+    }
+    without_interrupts(|| STATE.lock().cpu().map_or(false, |cpu| cpu.traps != 0))
+}
+
+/// Called when a trap handler returns, possibly to another process
+pub fn trap_exit() {
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+    if let Err(report) = without_interrupts(|| STATE.lock().trap_exit()) {
+        disable(report);
+    }
+}
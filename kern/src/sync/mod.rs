@@ -0,0 +1,39 @@
+//! Locks
+//!
+//! `spin::Mutex` is fine for short critical sections, but a process that
+//! holds one across a sleep keeps every other core spinning on it. `Mutex`,
+//! `Semaphore` and `Condvar` park the calling process in the scheduler
+//! instead and hand ownership to the longest waiting process when released.
+//! They may only be used by kernel processes with interrupts enabled, never
+//! from an interrupt handler.
+//!
+//! `WorkQueue` hands work that would sleep to a kernel process.
+//!
+//! `SpinMutex` and `SpinRwLock` are spin locks checked by `lockdep` when the
+//! feature of that name is enabled.
+
+mod wait_queue;
+mod mutex;
+mod semaphore;
+mod condvar;
+mod work_queue;
+mod spinlock;
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
+
+pub use self::wait_queue::{WaitQueue, Waiter, Wakeup};
+pub use self::mutex::{Mutex, MutexGuard};
+pub use self::semaphore::Semaphore;
+pub use self::condvar::Condvar;
+pub use self::work_queue::WorkQueue;
+pub use self::spinlock::{SpinMutex, SpinMutexGuard, SpinRwLock, SpinReadGuard, SpinWriteGuard};
+
+/// Sleeping is only possible from a process, not from a trap handler
+#[inline]
+fn assert_may_sleep() {
+    #[cfg(feature = "lockdep")]
+    debug_assert!(!lockdep::in_trap(), "sleeping lock taken in interrupt context");
+    #[cfg(not(feature = "lockdep"))]
+    debug_assert!(x86_64::instructions::interrupts::are_enabled(),
+                  "sleeping lock taken with interrupts disabled");
+}
@@ -0,0 +1,133 @@
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use x86_64::instructions::interrupts::without_interrupts;
+use super::wait_queue::WaitQueue;
+
+struct Inner {
+    locked: bool,
+    queue: WaitQueue,
+}
+
+/// Mutual exclusion lock that parks contending processes.
+///
+/// Unlocking hands the lock straight to the longest waiting process, so a
+/// process releasing and retaking the lock in a loop cannot starve others.
+pub struct Mutex<T: ?Sized> {
+    inner: spin::Mutex<Inner>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    pub(super) mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub fn new(data: T) -> Mutex<T> {
+        Mutex {
+            inner: spin::Mutex::new(Inner {
+                locked: false,
+                queue: WaitQueue::new(),
+            }),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Takes the lock, parking the calling process while it is held
+    pub fn lock(&self) -> MutexGuard<T> {
+        super::assert_may_sleep();
+        let waiter = without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            if !inner.locked {
+                inner.locked = true;
+                None
+            } else {
+                Some(inner.queue.enqueue())
+            }
+        });
+        // A woken waiter already owns the lock, see `unlock`
+        if let Some(waiter) = waiter {
+            waiter.wait();
+        }
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            if inner.locked {
+                None
+            } else {
+                inner.locked = true;
+                Some(MutexGuard { mutex: self })
+            }
+        })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        without_interrupts(|| self.inner.lock().locked)
+    }
+
+    /// Releases the lock, or passes it on to the first waiter
+    fn unlock(&self) {
+        let wakeup = without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            let wakeup = inner.queue.wake_one();
+            if wakeup.is_none() {
+                inner.locked = false;
+            }
+            wakeup
+        });
+        if let Some(wakeup) = wakeup {
+            wakeup.unpark();
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Mutex<T> {
+        Mutex::new(Default::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => write!(f, "Mutex {{ data: {:?} }}", &*guard),
+            None => write!(f, "Mutex {{ <locked> }}"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
@@ -0,0 +1,74 @@
+use x86_64::instructions::interrupts::without_interrupts;
+use super::wait_queue::WaitQueue;
+
+struct Inner {
+    count: usize,
+    queue: WaitQueue,
+}
+
+/// Counting semaphore that parks processes while no permit is available.
+/// A released permit goes to the longest waiting process.
+pub struct Semaphore {
+    inner: spin::Mutex<Inner>,
+}
+
+impl Semaphore {
+    pub fn new(count: usize) -> Semaphore {
+        Semaphore {
+            inner: spin::Mutex::new(Inner {
+                count,
+                queue: WaitQueue::new(),
+            }),
+        }
+    }
+
+    /// Takes a permit, parking the calling process until one is available
+    pub fn acquire(&self) {
+        super::assert_may_sleep();
+        let waiter = without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            if inner.count > 0 {
+                inner.count -= 1;
+                None
+            } else {
+                Some(inner.queue.enqueue())
+            }
+        });
+        // A woken waiter was handed the permit, see `release`
+        if let Some(waiter) = waiter {
+            waiter.wait();
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            if inner.count > 0 {
+                inner.count -= 1;
+                true
+            } else {
+                false
+            }
+        })
+    }
+
+    /// Returns a permit, or passes it on to the first waiter
+    pub fn release(&self) {
+        let wakeup = without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            let wakeup = inner.queue.wake_one();
+            if wakeup.is_none() {
+                inner.count += 1;
+            }
+            wakeup
+        });
+        if let Some(wakeup) = wakeup {
+            wakeup.unpark();
+        }
+    }
+
+    /// Permits available right now
+    pub fn available(&self) -> usize {
+        without_interrupts(|| self.inner.lock().count)
+    }
+}
@@ -0,0 +1,187 @@
+//! Named Spin Locks
+//!
+//! `spin::Mutex` and `spin::RwLock` with a name, for the global locks that
+//! are taken from many places. With the `lockdep` feature every acquisition
+//! goes through the lock dependency checker, see `sync::lockdep`. Without it
+//! they cost the same as the `spin` locks.
+
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+#[cfg(feature = "lockdep")]
+use super::lockdep::{self, Mode};
+
+pub struct SpinMutex<T: ?Sized> {
+    name: &'static str,
+    inner: spin::Mutex<T>,
+}
+
+pub struct SpinMutexGuard<'a, T: ?Sized> {
+    #[cfg(feature = "lockdep")]
+    lock: usize,
+    guard: spin::MutexGuard<'a, T>,
+}
+
+pub struct SpinRwLock<T: ?Sized> {
+    name: &'static str,
+    inner: spin::RwLock<T>,
+}
+
+pub struct SpinReadGuard<'a, T: ?Sized> {
+    #[cfg(feature = "lockdep")]
+    lock: usize,
+    guard: spin::RwLockReadGuard<'a, T>,
+}
+
+pub struct SpinWriteGuard<'a, T: ?Sized> {
+    #[cfg(feature = "lockdep")]
+    lock: usize,
+    guard: spin::RwLockWriteGuard<'a, T>,
+}
+
+impl<T> SpinMutex<T> {
+    pub const fn new(name: &'static str, data: T) -> SpinMutex<T> {
+        SpinMutex {
+            name,
+            inner: spin::Mutex::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> SpinMutex<T> {
+    #[inline(always)]
+    fn id(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+
+    #[inline(always)]
+    pub fn lock(&self) -> SpinMutexGuard<T> {
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(self.id(), self.name, Mode::Write, false);
+        SpinMutexGuard {
+            #[cfg(feature = "lockdep")]
+            lock: self.id(),
+            guard: self.inner.lock(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn try_lock(&self) -> Option<SpinMutexGuard<T>> {
+        let guard = self.inner.try_lock()?;
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(self.id(), self.name, Mode::Write, true);
+        Some(SpinMutexGuard {
+            #[cfg(feature = "lockdep")]
+            lock: self.id(),
+            guard,
+        })
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for SpinMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {:?}", self.name, self.inner)
+    }
+}
+
+impl<T> SpinRwLock<T> {
+    pub const fn new(name: &'static str, data: T) -> SpinRwLock<T> {
+        SpinRwLock {
+            name,
+            inner: spin::RwLock::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> SpinRwLock<T> {
+    #[inline(always)]
+    fn id(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+
+    #[inline(always)]
+    pub fn read(&self) -> SpinReadGuard<T> {
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(self.id(), self.name, Mode::Read, false);
+        SpinReadGuard {
+            #[cfg(feature = "lockdep")]
+            lock: self.id(),
+            guard: self.inner.read(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn try_read(&self) -> Option<SpinReadGuard<T>> {
+        let guard = self.inner.try_read()?;
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(self.id(), self.name, Mode::Read, true);
+        Some(SpinReadGuard {
+            #[cfg(feature = "lockdep")]
+            lock: self.id(),
+            guard,
+        })
+    }
+
+    #[inline(always)]
+    pub fn write(&self) -> SpinWriteGuard<T> {
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(self.id(), self.name, Mode::Write, false);
+        SpinWriteGuard {
+            #[cfg(feature = "lockdep")]
+            lock: self.id(),
+            guard: self.inner.write(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn try_write(&self) -> Option<SpinWriteGuard<T>> {
+        let guard = self.inner.try_write()?;
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(self.id(), self.name, Mode::Write, true);
+        Some(SpinWriteGuard {
+            #[cfg(feature = "lockdep")]
+            lock: self.id(),
+            guard,
+        })
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for SpinRwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {:?}", self.name, self.inner)
+    }
+}
+
+macro_rules! guard_impls {
+    ($guard:ident) => {
+        impl<'a, T: ?Sized> Deref for $guard<'a, T> {
+            type Target = T;
+
+            fn deref(&self) -> &T {
+                &*self.guard
+            }
+        }
+
+        #[cfg(feature = "lockdep")]
+        impl<'a, T: ?Sized> Drop for $guard<'a, T> {
+            fn drop(&mut self) {
+                lockdep::release(self.lock);
+            }
+        }
+    };
+}
+
+guard_impls!(SpinMutexGuard);
+guard_impls!(SpinReadGuard);
+guard_impls!(SpinWriteGuard);
+
+impl<'a, T: ?Sized> DerefMut for SpinMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut *self.guard
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for SpinWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut *self.guard
+    }
+}
@@ -0,0 +1,114 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::SCHEDULER;
+use crate::interrupts::syscall::NR_KERNEL_WAIT;
+use crate::process::process::Id;
+use crate::sys::percpu;
+
+/// A process parked on a `WaitQueue`
+#[derive(Debug, Default)]
+pub struct Waiter {
+    woken: AtomicBool,
+    /// The process that queued the waiter, made ready when it is woken
+    pid: Option<Id>,
+}
+
+impl Waiter {
+    pub fn is_woken(&self) -> bool {
+        self.woken.load(Ordering::Acquire)
+    }
+
+    fn wake(self: Arc<Self>) -> Wakeup {
+        self.woken.store(true, Ordering::Release);
+        Wakeup(self.pid)
+    }
+
+    /// Parks the calling process until the waiter is woken. Returns right
+    /// away if that already happened.
+    pub fn wait(self: &Arc<Self>) {
+        super::assert_may_sleep();
+        while !self.is_woken() {
+            // The reference is taken back by `sys_kernel_wait`. If the
+            // scheduler is busy the syscall returns without parking, so
+            // this is retried.
+            let raw = Arc::into_raw(self.clone()) as u64;
+            unsafe {
+                asm!("int 0x80",
+                     inout("rax") NR_KERNEL_WAIT => _,
+                     in("rdi") raw,
+                     lateout("rdx") _,
+                     );
+            }
+        }
+    }
+}
+
+/// A woken waiter whose process still has to be made ready. This takes the
+/// scheduler lock, so it is done once the lock protecting the queue has been
+/// released, see `unpark`.
+#[must_use]
+pub struct Wakeup(Option<Id>);
+
+impl Wakeup {
+    /// Marks the woken process ready instead of leaving it to the scheduler
+    /// to poll its wait. A process that has not parked yet sees the waiter
+    /// woken when it does.
+    pub fn unpark(self) {
+        if let Some(pid) = self.0 {
+            SCHEDULER.critical(|s| s.unpark(pid));
+        }
+    }
+}
+
+/// FIFO queue of parked processes. It does not lock itself, the primitive
+/// owning it keeps it next to its own state under a spin lock.
+#[derive(Debug, Default)]
+pub struct WaitQueue {
+    waiters: VecDeque<Arc<Waiter>>,
+}
+
+impl WaitQueue {
+    pub fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: VecDeque::new(),
+        }
+    }
+
+    /// Adds a waiter to the back of the queue. The caller parks on it with
+    /// `Waiter::wait` once it has released the lock protecting the queue.
+    pub fn enqueue(&mut self) -> Arc<Waiter> {
+        let waiter = Arc::new(Waiter {
+            woken: AtomicBool::new(false),
+            pid: percpu::current().cpu.current_pid(),
+        });
+        self.waiters.push_back(waiter.clone());
+        waiter
+    }
+
+    /// Wakes the longest waiting process. Returns `None` if there is none.
+    pub fn wake_one(&mut self) -> Option<Wakeup> {
+        self.waiters.pop_front().map(Waiter::wake)
+    }
+
+    /// Removes a waiter that gives up. Returns `false` if it is no longer
+    /// queued because it was woken.
+    pub fn remove(&mut self, waiter: &Arc<Waiter>) -> bool {
+        match self.waiters.iter().position(|w| Arc::ptr_eq(w, waiter)) {
+            Some(idx) => {
+                self.waiters.remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn wake_all(&mut self) -> Vec<Wakeup> {
+        self.waiters.drain(..).map(Waiter::wake).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.is_empty()
+    }
+}
@@ -0,0 +1,82 @@
+use alloc::vec::Vec;
+use x86_64::instructions::interrupts::without_interrupts;
+use super::wait_queue::WaitQueue;
+
+struct Inner<T> {
+    items: Vec<T>,
+    queue: WaitQueue,
+}
+
+/// Work that cannot be done where it comes up, because it would sleep or
+/// take a lock that is held there. It is queued with `push` and done later,
+/// either by a kernel process blocked in `wait` or `run`, or by the owner of
+/// the queue picking it up with `take`.
+///
+/// `push` never sleeps and only allocates to grow the queue, a queue of `()`
+/// may be pushed to from an interrupt handler.
+pub struct WorkQueue<T> {
+    inner: spin::Mutex<Inner<T>>,
+}
+
+impl<T> WorkQueue<T> {
+    pub fn new() -> WorkQueue<T> {
+        WorkQueue {
+            inner: spin::Mutex::new(Inner {
+                items: Vec::new(),
+                queue: WaitQueue::new(),
+            }),
+        }
+    }
+
+    /// Queues `item` and wakes the process waiting for work, if any
+    pub fn push(&self, item: T) {
+        let wakeup = without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            inner.items.push(item);
+            inner.queue.wake_one()
+        });
+        if let Some(wakeup) = wakeup {
+            wakeup.unpark();
+        }
+    }
+
+    /// Everything queued so far, in order
+    pub fn take(&self) -> Vec<T> {
+        without_interrupts(|| core::mem::take(&mut self.inner.lock().items))
+    }
+
+    /// Like `take`, but parks the calling process until there is something
+    pub fn wait(&self) -> Vec<T> {
+        super::assert_may_sleep();
+        loop {
+            let waiter = without_interrupts(|| {
+                let mut inner = self.inner.lock();
+                if inner.items.is_empty() {
+                    Err(inner.queue.enqueue())
+                } else {
+                    Ok(core::mem::take(&mut inner.items))
+                }
+            });
+            // Another waiter may have taken the work first, so check again
+            match waiter {
+                Ok(items) => return items,
+                Err(waiter) => waiter.wait(),
+            }
+        }
+    }
+
+    /// Body of a kernel process doing the queued work with `f`, in order
+    pub fn run<F: FnMut(T)>(&self, mut f: F) -> ! {
+        loop {
+            for item in self.wait() {
+                f(item);
+            }
+        }
+    }
+}
+
+impl<T> Default for WorkQueue<T> {
+    fn default() -> WorkQueue<T> {
+        WorkQueue::new()
+    }
+}
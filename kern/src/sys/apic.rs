@@ -5,16 +5,18 @@ use crate::memory::frame_allocator::FrameAllocWrapper;
 use x86_64::instructions::interrupts::without_interrupts;
 use crate::memory::mmio_bump_allocator::VMALLOC;
 use volatile::Volatile;
-use spin::RwLock;
+use crate::sync::SpinRwLock;
 use crate::sys::apic::timer::{APICTimerDividerOption, APICTimerMode};
 use crate::sys::pit::spin_wait;
 use core::time::Duration;
 use crate::interrupts::{PICS, InterruptIndex};
-use crate::memory::paging::PHYSMAP_BASE;
+use x86_64::registers::model_specific::Msr;
+use core::sync::atomic::{fence, Ordering};
+use core::arch::x86_64::__cpuid;
 
 pub mod timer;
 
-pub static GLOBAL_APIC: RwLock<APIC> = RwLock::new(APIC::uninitialized());
+pub static GLOBAL_APIC: SpinRwLock<APIC> = SpinRwLock::new("GLOBAL_APIC", APIC::uninitialized());
 
 #[repr(u8)]
 pub enum APICDeliveryMode {
@@ -25,16 +27,24 @@ pub enum APICDeliveryMode {
     INIT = 0b101,
 }
 
+/// Local APIC of the current core. In xAPIC mode the registers are accessed
+/// through an MMIO mapping, in x2APIC mode through MSRs.
 pub struct APIC {
     base_va: VirtAddr,
     base_pa: PhysAddr,
+    /// Registers are accessed as MSRs
+    x2apic: bool,
     /// Scale is measured in tics per microsecond
     scale: u32,
+    /// CPU supports the TSC-deadline timer mode
+    tsc_deadline: bool,
 }
 
 const APIC_OFFSET_APICID: u64 = 0x20;
 const APIC_OFFSET_SPURIOUS_LVT: u64 = 0xF0;
 const APIC_OFFSET_EOI: u64 = 0xB0;
+const APIC_OFFSET_ICR_LOW: u64 = 0x300;
+const APIC_OFFSET_ICR_HIGH: u64 = 0x310;
 // Timers
 const APIC_OFFSET_TIMER_LVT: u64 = 0x320;
 const APIC_OFFSET_LINT0_LVT: u64 = 0x350;
@@ -43,6 +53,15 @@ const APIC_OFFSET_TIMER_INITIAL: u64 = 0x380;
 const APIC_OFFSET_TIMER_CURRENT: u64 = 0x390;
 const APIC_OFFSET_TIMER_DIVIDE: u64 = 0x3E0;
 
+const IA32_APIC_BASE: u32 = 0x1B;
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+/// x2APIC registers are the MSRs at this base plus the MMIO offset / 16
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
 const MAX_TICK: u64 = 0xFFFF_FF00;
 const MEASURE_DURATION: Duration = Duration::from_millis(10);
 
@@ -51,15 +70,43 @@ impl APIC {
         APIC {
             base_va: VirtAddr::new_truncate(0),
             base_pa: PhysAddr::new_truncate(0),
+            x2apic: false,
             scale: 1,
+            tsc_deadline: false,
         }
     }
 
     pub fn initialize(&mut self) {
-        if self.base_va.as_u64() != 0 {
+        if self.base_va.as_u64() != 0 || self.x2apic {
             warn!("[APIC] double initialization");
             return;
         }
+        if (unsafe { __cpuid(1) }.ecx >> 21) & 0x1 == 1 {
+            self.x2apic = true;
+            self.enable_local_mode();
+            debug!("[APIC] x2APIC mode");
+        } else {
+            self.map_registers();
+        }
+
+        trace!("[APIC] Measure Start");
+        // Measurement
+        without_interrupts(||{
+            self.timer_set_divider(APICTimerDividerOption::DivideBy4);
+            self.timer_set_initial_value(0xFFFF_FFFF);
+        });
+        spin_wait(MEASURE_DURATION);
+        let diff = 0xFFFF_FFFF - self.timer_read_current_value();
+        let tick_scale = diff / MEASURE_DURATION.as_micros() as u32;
+        self.scale = tick_scale * 4;
+        debug!("[APIC] 0x{:x}t in {:?}, {} t/us (x4)", diff, MEASURE_DURATION, tick_scale);
+
+        self.tsc_deadline = (unsafe { __cpuid(1) }.ecx >> 24) & 0x1 == 1;
+        debug!("[APIC] TSC-deadline timer: {}", self.tsc_deadline);
+    }
+
+    /// Maps the xAPIC registers at the address in IA32_APIC_BASE
+    fn map_registers(&mut self) {
         let apic_base = x86_64::registers::model_specific::IA32ApicBase::read_apic_base_addr();
         let (va, size) = without_interrupts(|| {
             VMALLOC.lock().allocate(4096)
@@ -79,58 +126,75 @@ impl APIC {
         self.base_pa = apic_base;
         self.base_va = va;
         trace!("[APIC] Mapped");
+    }
 
+    /// Puts the calling core's LAPIC into the mode chosen by `initialize`.
+    /// APs must call this before accessing any other register.
+    pub fn enable_local_mode(&self) {
+        if !self.x2apic {
+            return;
+        }
+        unsafe {
+            let mut msr = Msr::new(IA32_APIC_BASE);
+            let base = msr.read();
+            if base & APIC_BASE_X2APIC == 0 {
+                // x2APIC can only be entered from the enabled xAPIC mode
+                msr.write(base | APIC_BASE_ENABLE);
+                msr.write(base | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+            }
+        }
+    }
 
-        trace!("[APIC] Measure Start");
-        // Measurement
-        without_interrupts(||{
-            self.timer_set_divider(APICTimerDividerOption::DivideBy4);
-            self.timer_set_initial_value(0xFFFF_FFFF);
-        });
-        spin_wait(MEASURE_DURATION);
-        let diff = 0xFFFF_FFFF - self.timer_read_current_value();
-        let tick_scale = diff / MEASURE_DURATION.as_micros() as u32;
-        self.scale = tick_scale * 4;
-        debug!("[APIC] 0x{:x}t in {:?}, {} t/us (x4)", diff, MEASURE_DURATION, tick_scale);
+    pub fn is_x2apic(&self) -> bool {
+        self.x2apic
     }
 
-    pub fn apic_id(&self) -> u8 {
-        let word = (self.base_va.as_u64() + APIC_OFFSET_APICID) as *const Volatile<u32>;
-        (unsafe {
-            &*word
-        }.read() >> 24) as u8
+    fn read(&self, offset: u64) -> u32 {
+        if self.x2apic {
+            unsafe { Msr::new(X2APIC_MSR_BASE + (offset >> 4) as u32).read() as u32 }
+        } else {
+            unsafe { &*((self.base_va.as_u64() + offset) as *const Volatile<u32>) }.read()
+        }
+    }
+
+    fn write(&self, offset: u64, value: u32) {
+        if self.x2apic {
+            unsafe { Msr::new(X2APIC_MSR_BASE + (offset >> 4) as u32).write(value as u64) };
+        } else {
+            unsafe { &mut *((self.base_va.as_u64() + offset) as *mut Volatile<u32>) }.write(value);
+        }
+    }
+
+    /// The full 32 bit ID in x2APIC mode, 8 bit in xAPIC mode
+    pub fn apic_id(&self) -> u32 {
+        if self.x2apic {
+            self.read(APIC_OFFSET_APICID)
+        } else {
+            self.read(APIC_OFFSET_APICID) >> 24
+        }
     }
 
     /// Enables the LAPIC also sets the spurious vector
     pub fn enable_lapic(&self, spurious_vector: u8, enable: bool) {
-        let word = (self.base_va.as_u64() + APIC_OFFSET_SPURIOUS_LVT) as *mut Volatile<u32>;
-        unsafe {
-            &mut *word
-        }.write(spurious_vector as u32 | (if enable { 1u32 } else { 0u32 }) << 8);
+        self.write(APIC_OFFSET_SPURIOUS_LVT, spurious_vector as u32 | (if enable { 1u32 } else { 0u32 }) << 8);
     }
 
     pub fn get_apic_spurious_lvt(&self) -> u32 {
-        let word = unsafe { &*((self.base_va.as_u64() + APIC_OFFSET_SPURIOUS_LVT) as *const Volatile<u32>) };
-        word.read()
+        self.read(APIC_OFFSET_SPURIOUS_LVT)
     }
 
     pub fn end_of_interrupt(&self) {
-        let eoi = unsafe { &mut *((self.base_va.as_u64() + APIC_OFFSET_EOI) as *mut Volatile<u32>) };
-        eoi.write(0);
+        self.write(APIC_OFFSET_EOI, 0);
     }
 
     pub fn timer_read_divider(&self) -> APICTimerDividerOption {
-        let read = unsafe { &*((self.base_va.as_u64() + APIC_OFFSET_TIMER_DIVIDE) as *const Volatile<u32>) };
-        let read = read.read();
-        ((read & 0b1011) as u8).into()
+        ((self.read(APIC_OFFSET_TIMER_DIVIDE) & 0b1011) as u8).into()
     }
 
 
     pub fn timer_set_divider(&self, opt: APICTimerDividerOption) {
-        let read = unsafe { &mut *((self.base_va.as_u64() + APIC_OFFSET_TIMER_DIVIDE) as *mut Volatile<u32>) };
-        read.write(opt as u8 as u32);
+        self.write(APIC_OFFSET_TIMER_DIVIDE, opt as u8 as u32);
     }
-
     pub fn set_timer_interval(&self, duration: Duration) -> Result<(), ()> {
         use crate::sys::apic::timer::APICTimerDividerOption::*;
         let ticks: u64 = duration.as_micros() as u64 * self.scale as u64;
@@ -159,36 +223,81 @@ impl APIC {
         Ok(())
     }
 
+    /// Fires `vector` every `interval`
+    pub fn timer_periodic(&self, vector: u8, interval: Duration) -> Result<(), ()> {
+        self.timer_set_lvt(vector, APICTimerMode::Periodic, false);
+        self.set_timer_interval(interval)
+    }
+
+    /// Fires `vector` once after `delay`. Uses the TSC-deadline mode when the
+    /// CPU supports it, otherwise the one-shot mode of the count-down timer.
+    pub fn timer_oneshot(&self, vector: u8, delay: Duration) -> Result<(), ()> {
+        if self.tsc_deadline {
+            self.timer_set_lvt(vector, APICTimerMode::Deadline, false);
+            // The LVT write must be globally visible before the deadline is armed
+            fence(Ordering::SeqCst);
+            let deadline = crate::sys::tsc::read() + crate::sys::tsc::duration_to_ticks(delay);
+            unsafe { Msr::new(IA32_TSC_DEADLINE).write(deadline) };
+            Ok(())
+        } else {
+            self.timer_set_lvt(vector, APICTimerMode::OneShot, false);
+            // An initial count of 0 stops the timer instead of firing it
+            self.set_timer_interval(core::cmp::max(delay, Duration::from_micros(1)))
+        }
+    }
+
+    /// The timer is armed in periodic mode
+    pub fn timer_is_periodic(&self) -> bool {
+        let lvt = self.timer_get_lvt();
+        lvt >> 17 & 0b11 == APICTimerMode::Periodic as u32 && lvt & 1 << 16 == 0
+            && self.read(APIC_OFFSET_TIMER_INITIAL) != 0
+    }
+
+    /// Masks and disarms the timer
+    pub fn timer_stop(&self, vector: u8) {
+        self.timer_set_lvt(vector, APICTimerMode::OneShot, true);
+        self.timer_set_initial_value(0);
+    }
+
     pub fn timer_set_initial_value(&self, val: u32) {
-        let read = unsafe { &mut *((self.base_va.as_u64() + APIC_OFFSET_TIMER_INITIAL) as *mut Volatile<u32>) };
-        read.write(val);
+        self.write(APIC_OFFSET_TIMER_INITIAL, val);
     }
 
     pub fn timer_read_current_value(&self) -> u32 {
-        let read = unsafe { &*((self.base_va.as_u64() + APIC_OFFSET_TIMER_CURRENT) as *const Volatile<u32>) };
-        read.read()
+        self.read(APIC_OFFSET_TIMER_CURRENT)
     }
 
     pub fn timer_get_lvt(&self) -> u32 {
-        let lol = unsafe { &*((self.base_va.as_u64() + APIC_OFFSET_TIMER_LVT) as *mut Volatile<u32>) };
-        lol.read()
+        self.read(APIC_OFFSET_TIMER_LVT)
     }
 
     pub fn timer_set_lvt(&self, vector: u8, mode: APICTimerMode, masked: bool) {
         let value: u32 = vector as u32 | (mode as u32) << 17 | (if masked { 1u32 } else { 0u32 }) << 16;
-        let lol = unsafe { &mut *((self.base_va.as_u64() + APIC_OFFSET_TIMER_LVT) as *mut Volatile<u32>) };
-        lol.write(value);
+        self.write(APIC_OFFSET_TIMER_LVT, value);
     }
 
     pub fn lint0_set_lvt(&self, mode: APICDeliveryMode, masked: bool) {
         let value: u32 = InterruptIndex::XHCI as u32 | (mode as u32) << 8 | (if masked { 1u32 } else { 0u32 }) << 16;
-        let lol = unsafe { &mut *((self.base_va.as_u64() + APIC_OFFSET_LINT0_LVT) as *mut Volatile<u32>) };
-        lol.write(value);
+        self.write(APIC_OFFSET_LINT0_LVT, value);
     }
-}
 
-const LAPIC_ICR_LOW: *mut Volatile<u32> = (0xFEE0_0300 + PHYSMAP_BASE) as *mut Volatile<u32>;
-const LAPIC_ICR_HIGH: *mut Volatile<u32> = (0xFEE0_0310 + PHYSMAP_BASE) as *mut Volatile<u32>;
+    /// Sends an IPI. `lapic_id` is ignored if a shorthand is used.
+    pub fn send_ipi(&self, lapic_id: u32, vector: u8, mode: IPIDeliveryMode, shorthand: IPIDestinationShorthand) {
+        // Level assert
+        let low = (vector as u32) | (mode as u32) << 8 | 1 << 14 | (shorthand as u32) << 18;
+        if self.x2apic {
+            // A single 64 bit write, delivery does not have to be polled
+            let icr = X2APIC_MSR_BASE + (APIC_OFFSET_ICR_LOW >> 4) as u32;
+            unsafe { Msr::new(icr).write((lapic_id as u64) << 32 | low as u64) };
+        } else {
+            assert!(lapic_id <= 0xFF, "xAPIC destination is 8 bit");
+            while self.read(APIC_OFFSET_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {}
+            self.write(APIC_OFFSET_ICR_HIGH, lapic_id << 24);
+            self.write(APIC_OFFSET_ICR_LOW, low);
+            while self.read(APIC_OFFSET_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {}
+        }
+    }
+}
 
 #[derive(PartialEq, Debug, Copy, Clone)]
 pub enum IPIDeliveryMode {
@@ -209,16 +318,6 @@ pub enum IPIDestinationShorthand {
 }
 
 /// Send IPI
-pub fn send_ipi(lapic_id: u8, vector: u8, mode: IPIDeliveryMode, shorthand: IPIDestinationShorthand) {
-    // TODO Use constants
-    unsafe {
-        let mut value = (vector as u32) | (mode as u32) << 8 | (shorthand as u32) << 18;
-        value |= 1 << 14;
-        while (*LAPIC_ICR_LOW).read() & (1 << 12) == 1 {}
-        if lapic_id != 0xFF {
-            (*LAPIC_ICR_HIGH).write((lapic_id as u32) << 24);
-        }
-        (*LAPIC_ICR_LOW).write(value);
-        while (*LAPIC_ICR_LOW).read() & (1 << 12) == 1 {}
-    }
-}
\ No newline at end of file
+pub fn send_ipi(lapic_id: u32, vector: u8, mode: IPIDeliveryMode, shorthand: IPIDestinationShorthand) {
+    GLOBAL_APIC.read().send_ipi(lapic_id, vector, mode, shorthand)
+}
@@ -0,0 +1,295 @@
+//! I/O APIC
+//!
+//! Routes device interrupts to local APICs. The I/O APICs and the ISA
+//! interrupt source overrides are taken from the ACPI MADT. Once the I/O
+//! APICs are initialized the 8259 PICs are masked and every device interrupt
+//! is acknowledged at the local APIC.
+
+use alloc::vec::Vec;
+use spin::RwLock;
+use volatile::Volatile;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use crate::{ACPI, PAGE_TABLE};
+use crate::interrupts::irq;
+use crate::memory::frame_allocator::FrameAllocWrapper;
+use crate::memory::mmio_bump_allocator::VMALLOC;
+
+pub static GLOBAL_IOAPIC: RwLock<IoApicManager> = RwLock::new(IoApicManager::uninitialized());
+
+const IOAPIC_OFFSET_REGSEL: u64 = 0x00;
+const IOAPIC_OFFSET_WINDOW: u64 = 0x10;
+
+const IOAPIC_REG_VERSION: u32 = 0x01;
+const IOAPIC_REG_REDIRECTION: u32 = 0x10;
+
+const REDIRECTION_POLARITY_LOW: u64 = 1 << 13;
+const REDIRECTION_TRIGGER_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+const ISA_IRQS: usize = 16;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Where an ISA IRQ is wired to. Without an override ISA IRQs are identity
+/// mapped, edge triggered and active high.
+#[derive(Debug, Copy, Clone)]
+pub struct IsaRoute {
+    pub gsi: u32,
+    pub trigger: TriggerMode,
+    pub polarity: Polarity,
+}
+
+struct IoApic {
+    id: u8,
+    base_va: VirtAddr,
+    /// First global system interrupt handled by this I/O APIC
+    gsi_base: u32,
+    /// Number of redirection entries
+    entries: u32,
+}
+
+impl IoApic {
+    fn new(id: u8, address: u32, gsi_base: u32) -> IoApic {
+        let pa = PhysAddr::new(address as u64);
+        let offset = pa.as_u64() & 0xFFF;
+        let (va, _) = without_interrupts(|| {
+            VMALLOC.lock().allocate(4096)
+        });
+        let mut alloc_wrapper = FrameAllocWrapper {};
+        unsafe {
+            without_interrupts(|| {
+                PAGE_TABLE.write().map_to(
+                    Page::<Size4KiB>::from_start_address(va).expect("valid page bound"),
+                    PhysFrame::containing_address(pa),
+                    PageTableFlags::PRESENT | PageTableFlags::NO_CACHE | PageTableFlags::WRITABLE,
+                    &mut alloc_wrapper,
+                ).expect("Unable to map I/O APIC").flush()
+            })
+        };
+        let mut ioapic = IoApic {
+            id,
+            base_va: va + offset,
+            gsi_base,
+            entries: 0,
+        };
+        ioapic.entries = ((ioapic.read(IOAPIC_REG_VERSION) >> 16) & 0xFF) + 1;
+        ioapic
+    }
+
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            (&mut *((self.base_va.as_u64() + IOAPIC_OFFSET_REGSEL) as *mut Volatile<u32>)).write(reg);
+            (&*((self.base_va.as_u64() + IOAPIC_OFFSET_WINDOW) as *const Volatile<u32>)).read()
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            (&mut *((self.base_va.as_u64() + IOAPIC_OFFSET_REGSEL) as *mut Volatile<u32>)).write(reg);
+            (&mut *((self.base_va.as_u64() + IOAPIC_OFFSET_WINDOW) as *mut Volatile<u32>)).write(value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        self.gsi_base <= gsi && gsi < self.gsi_base + self.entries
+    }
+
+    fn read_entry(&self, pin: u32) -> u64 {
+        let low = self.read(IOAPIC_REG_REDIRECTION + pin * 2) as u64;
+        let high = self.read(IOAPIC_REG_REDIRECTION + pin * 2 + 1) as u64;
+        high << 32 | low
+    }
+
+    fn write_entry(&self, pin: u32, entry: u64) {
+        // Mask first so the entry never fires half written
+        self.write(IOAPIC_REG_REDIRECTION + pin * 2, REDIRECTION_MASKED as u32);
+        self.write(IOAPIC_REG_REDIRECTION + pin * 2 + 1, (entry >> 32) as u32);
+        self.write(IOAPIC_REG_REDIRECTION + pin * 2, entry as u32);
+    }
+}
+
+pub struct IoApicManager {
+    ioapics: Vec<IoApic>,
+    isa: [IsaRoute; ISA_IRQS],
+    /// Vectors handed out by `claim_*`, devices on the same GSI share one
+    claimed: Vec<(u32, u8)>,
+}
+
+impl IoApicManager {
+    const fn uninitialized() -> IoApicManager {
+        const IDENTITY: IsaRoute = IsaRoute { gsi: 0, trigger: TriggerMode::Edge, polarity: Polarity::ActiveHigh };
+        IoApicManager {
+            ioapics: Vec::new(),
+            isa: [IDENTITY; ISA_IRQS],
+            claimed: Vec::new(),
+        }
+    }
+
+    /// Maps the I/O APICs of the MADT and masks all of their inputs.
+    /// Returns `false` if there is no I/O APIC to route interrupts through.
+    pub fn initialize(&mut self) -> bool {
+        use acpi::{InterruptModel, Polarity as AcpiPolarity, TriggerMode as AcpiTrigger};
+
+        if !self.ioapics.is_empty() {
+            warn!("[IOAPIC] double initialization");
+            return true;
+        }
+        for (irq, route) in self.isa.iter_mut().enumerate() {
+            route.gsi = irq as u32;
+        }
+
+        let acpi = ACPI.read();
+        let (io_apics, overrides) = match acpi.as_ref().and_then(|a| a.interrupt_model.as_ref()) {
+            Some(InterruptModel::Apic { io_apics, interrupt_source_overrides, .. }) => {
+                (io_apics, interrupt_source_overrides)
+            },
+            _ => {
+                warn!("[IOAPIC] MADT has no APIC interrupt model");
+                return false;
+            }
+        };
+
+        for entry in io_apics.iter() {
+            let ioapic = IoApic::new(entry.id, entry.address, entry.global_system_interrupt_base);
+            debug!("[IOAPIC] ID: {} at {:#x}, GSI {}..{}", ioapic.id, entry.address,
+                   ioapic.gsi_base, ioapic.gsi_base + ioapic.entries);
+            for pin in 0..ioapic.entries {
+                ioapic.write_entry(pin, REDIRECTION_MASKED);
+            }
+            self.ioapics.push(ioapic);
+        }
+
+        for o in overrides.iter() {
+            if o.isa_source as usize >= ISA_IRQS {
+                continue;
+            }
+            let route = &mut self.isa[o.isa_source as usize];
+            route.gsi = o.global_system_interrupt;
+            // "Same as bus" means ISA conventions
+            route.trigger = match o.trigger_mode {
+                AcpiTrigger::Level => TriggerMode::Level,
+                _ => TriggerMode::Edge,
+            };
+            route.polarity = match o.polarity {
+                AcpiPolarity::ActiveLow => Polarity::ActiveLow,
+                _ => Polarity::ActiveHigh,
+            };
+            debug!("[IOAPIC] ISA IRQ {} -> {:?}", o.isa_source, route);
+        }
+        !self.ioapics.is_empty()
+    }
+
+    /// Device interrupts are delivered through the I/O APICs
+    pub fn is_enabled(&self) -> bool {
+        !self.ioapics.is_empty()
+    }
+
+    pub fn isa_route(&self, irq: u8) -> IsaRoute {
+        self.isa[irq as usize]
+    }
+
+    fn ioapic_of(&self, gsi: u32) -> Result<&IoApic, ()> {
+        self.ioapics.iter().find(|io| io.handles(gsi)).ok_or(())
+    }
+
+    /// Delivers `gsi` as `vector` to the core with local APIC ID `apic_id`
+    pub fn route(&self, gsi: u32, vector: u8, apic_id: u8, trigger: TriggerMode, polarity: Polarity) -> Result<(), ()> {
+        let ioapic = self.ioapic_of(gsi)?;
+        let mut entry = vector as u64 | (apic_id as u64) << 56;
+        if trigger == TriggerMode::Level {
+            entry |= REDIRECTION_TRIGGER_LEVEL;
+        }
+        if polarity == Polarity::ActiveLow {
+            entry |= REDIRECTION_POLARITY_LOW;
+        }
+        without_interrupts(|| ioapic.write_entry(gsi - ioapic.gsi_base, entry));
+        debug!("[IOAPIC] GSI {} -> vector {:#x} on LAPIC {}", gsi, vector, apic_id);
+        Ok(())
+    }
+
+    /// Routes ISA IRQ `irq`, following the interrupt source overrides
+    pub fn route_isa(&self, irq: u8, vector: u8, apic_id: u8) -> Result<(), ()> {
+        if irq as usize >= ISA_IRQS {
+            return Err(());
+        }
+        let route = self.isa_route(irq);
+        self.route(route.gsi, vector, apic_id, route.trigger, route.polarity)
+    }
+
+    /// GSI of the INTx pin of a PCI device by the interrupt line the firmware
+    /// assigned. Evaluating `_PRT` needs an AML interpreter, so this fails
+    /// unless the chipset delivers INTx on that ISA IRQ, see
+    /// `pci::intx_on_isa_line`. Such devices need MSI.
+    fn pci_gsi(&self, line: u8) -> Result<u32, ()> {
+        if !crate::device::pci::intx_on_isa_line() {
+            warn!("[IOAPIC] PCI INTx routing is unknown without ACPI _PRT, line {} ignored", line);
+            return Err(());
+        }
+        if line as usize >= ISA_IRQS {
+            // 0xFF: not connected
+            return Err(());
+        }
+        Ok(self.isa_route(line).gsi)
+    }
+
+    /// Routes the INTx pin of a PCI device, see `pci_gsi`
+    pub fn route_pci(&self, line: u8, vector: u8, apic_id: u8) -> Result<(), ()> {
+        let gsi = self.pci_gsi(line)?;
+        self.route(gsi, vector, apic_id, TriggerMode::Level, Polarity::ActiveLow)
+    }
+
+    /// Vector that `gsi` is delivered as, allocating and routing one on the
+    /// first claim. Later claims of the same GSI share the vector, their
+    /// handlers are chained by `irq::register_irq_handler`.
+    fn claim(&mut self, gsi: u32, apic_id: u8, trigger: TriggerMode, polarity: Polarity, name: &str) -> Result<u8, ()> {
+        if let Some(&(_, vector)) = self.claimed.iter().find(|(g, _)| *g == gsi) {
+            return Ok(vector);
+        }
+        self.ioapic_of(gsi)?;
+        let vector = irq::allocate_vectors(1, name).ok_or(())?;
+        if let Err(()) = self.route(gsi, vector, apic_id, trigger, polarity) {
+            irq::free_vectors(vector, 1);
+            return Err(());
+        }
+        self.claimed.push((gsi, vector));
+        Ok(vector)
+    }
+
+    /// Claims a vector for ISA IRQ `irq`, see `route_isa`
+    pub fn claim_isa(&mut self, irq: u8, apic_id: u8, name: &str) -> Result<u8, ()> {
+        if irq as usize >= ISA_IRQS {
+            return Err(());
+        }
+        let route = self.isa_route(irq);
+        self.claim(route.gsi, apic_id, route.trigger, route.polarity, name)
+    }
+
+    /// Claims a vector for the INTx pin of a PCI device, see `pci_gsi`
+    pub fn claim_pci(&mut self, line: u8, apic_id: u8, name: &str) -> Result<u8, ()> {
+        let gsi = self.pci_gsi(line)?;
+        self.claim(gsi, apic_id, TriggerMode::Level, Polarity::ActiveLow, name)
+    }
+
+    pub fn set_masked(&self, gsi: u32, masked: bool) -> Result<(), ()> {
+        let ioapic = self.ioapic_of(gsi)?;
+        let pin = gsi - ioapic.gsi_base;
+        without_interrupts(|| {
+            let entry = ioapic.read_entry(pin);
+            let entry = if masked { entry | REDIRECTION_MASKED } else { entry & !REDIRECTION_MASKED };
+            ioapic.write_entry(pin, entry);
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+//! Cross-CPU Calls
+//!
+//! `smp_call_function` runs a closure on other cores. Requests are queued
+//! in the per-CPU block of each target and announced with an IPI on a fixed
+//! vector. A core waiting for its own request keeps running the requests
+//! queued for it, so two cores calling each other cannot deadlock.
+//!
+//! A panicking core stops every other core with an NMI. The stopped cores
+//! record their interrupted state and halt, the panicking core prints it
+//! since it owns the console from then on. `cpu_stat` works the same way:
+//! the target records a snapshot in its per-CPU block and the requesting
+//! core prints it, nothing is printed in interrupt context.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::control::{Cr2, Cr3};
+use x86_64::structures::idt::InterruptStackFrame;
+use crate::interrupts::InterruptIndex;
+use crate::interrupts::context_switch::TrapFrame;
+use crate::process::cpu::{CpuSet, CpuState};
+use crate::process::process::Id;
+use crate::sys::apic::{GLOBAL_APIC, IPIDeliveryMode, IPIDestinationShorthand};
+use crate::sys::percpu::{self, PerCpu};
+use crate::sys::resman::GLOBAL_RESMAN;
+use crate::sys::tsc;
+
+/// How long a panicking core waits for the others to stop
+const STOP_TIMEOUT: Duration = Duration::from_millis(100);
+
+const NO_PANIC: u64 = u64::MAX;
+/// Local APIC ID of the panicking core
+static PANIC_CPU: AtomicU64 = AtomicU64::new(NO_PANIC);
+
+pub struct CallRequest {
+    func: Box<dyn Fn() + Send + Sync>,
+    /// Targets that have not run `func` yet
+    remaining: AtomicUsize,
+}
+
+/// State of a core stopped by the panic NMI
+#[derive(Debug, Copy, Clone)]
+pub struct StopState {
+    pub context: TrapFrame,
+    pub cr2: u64,
+    pub cr3: u64,
+}
+
+/// Snapshot of a core, taken on that core by `cpu_stat`
+#[derive(Debug, Copy, Clone)]
+pub struct CpuStat {
+    pub apic_id: u8,
+    pub proc_id: u8,
+    pub current_pid: Option<Id>,
+    pub tsc: u64,
+    pub cr3: u64,
+    /// Device interrupts handled so far
+    pub irqs: u64,
+    pub x2apic: bool,
+}
+
+/// Runs the requests queued for the current core
+fn service() {
+    let block = match percpu::try_current() {
+        Some(block) => block,
+        None => return,
+    };
+    loop {
+        let request = without_interrupts(|| block.call_queue.lock().pop_front());
+        match request {
+            Some(request) => {
+                (request.func)();
+                request.remaining.fetch_sub(1, Ordering::AcqRel);
+            }
+            None => break,
+        }
+    }
+}
+
+/// Runs `func` on every running core in `cpus`, including the current one,
+/// and returns the cores it was run on. Offline cores are skipped.
+///
+/// `func` runs in interrupt context on the other cores, it must not sleep
+/// or take locks that are held with interrupts enabled. With `wait` this
+/// returns once every core has finished, otherwise once the IPIs are sent.
+pub fn smp_call_function<F>(cpus: CpuSet, func: F, wait: bool) -> CpuSet
+    where F: Fn() + Send + Sync + 'static
+{
+    let this = percpu::apic_id();
+    let mut targets = CpuSet::empty();
+    let mut count = 0;
+    for block in GLOBAL_RESMAN.read().percpu_blocks() {
+        let id = block.cpu.apic_id;
+        if id != this && cpus.contains(id) && block.cpu.is_running() {
+            targets.insert(id);
+            count += 1;
+        }
+    }
+
+    let request = Arc::new(CallRequest {
+        func: Box::new(func),
+        remaining: AtomicUsize::new(count),
+    });
+    let posted = targets;
+    for id in posted.iter() {
+        let block = GLOBAL_RESMAN.read().get_percpu(id);
+        // Checked under the queue lock, see `set_offline`
+        let queued = without_interrupts(|| {
+            let mut queue = block.call_queue.lock();
+            if block.cpu.is_running() {
+                queue.push_back(request.clone());
+            }
+            block.cpu.is_running()
+        });
+        if queued {
+            GLOBAL_APIC.read().send_ipi(id.into(), InterruptIndex::CallFunction.as_u8(),
+                                        IPIDeliveryMode::Fixed, IPIDestinationShorthand::NoShorthand);
+        } else {
+            request.remaining.fetch_sub(1, Ordering::AcqRel);
+            targets.remove(id);
+        }
+    }
+
+    if cpus.contains(this) {
+        without_interrupts(|| (request.func)());
+        targets.insert(this);
+    }
+    if wait {
+        while request.remaining.load(Ordering::Acquire) != 0 {
+            service();
+        }
+    }
+    targets
+}
+
+/// Runs `func` on the core with local APIC ID `apic_id` and waits for it.
+/// Returns `false` if the core is not running.
+pub fn call_on<F>(apic_id: u8, func: F) -> bool
+    where F: Fn() + Send + Sync + 'static
+{
+    !smp_call_function(CpuSet::single(apic_id), func, true).is_empty()
+}
+
+/// Takes a snapshot of the core with local APIC ID `apic_id`, `None` if it
+/// is not running
+pub fn cpu_stat(apic_id: u8) -> Option<CpuStat> {
+    let collected = call_on(apic_id, || {
+        let block = percpu::current();
+        let stat = CpuStat {
+            apic_id: block.cpu.apic_id,
+            proc_id: block.cpu.proc_id,
+            current_pid: block.cpu.current_pid(),
+            tsc: tsc::read(),
+            cr3: Cr3::read().0.start_address().as_u64(),
+            irqs: block.irq_counts.iter().map(|c| c.load(Ordering::Relaxed)).sum(),
+            x2apic: GLOBAL_APIC.read().is_x2apic(),
+        };
+        *block.stat.lock() = Some(stat);
+    });
+    if !collected {
+        return None;
+    }
+    let block = GLOBAL_RESMAN.read().get_percpu(apic_id);
+    without_interrupts(|| block.stat.lock().take())
+}
+
+pub(crate) extern "x86-interrupt" fn call_function_handler(stack_frame: &mut InterruptStackFrame) {
+    let _trap = crate::interrupts::TrapGuard::enter(stack_frame, true);
+    GLOBAL_APIC.read().end_of_interrupt();
+    service();
+}
+
+/// Marks the current core `Offline` and runs the requests still queued for
+/// it. No request is queued for an offline core, so none is left behind.
+pub fn set_offline() {
+    let block = percpu::current();
+    without_interrupts(|| {
+        let _queue = block.call_queue.lock();
+        block.cpu.set_state(CpuState::Offline);
+    });
+    service();
+}
+
+/// Stops every other core and waits for them to record their state.
+/// Returns `false` if another core panicked first.
+pub fn stop_other_cpus(apic_id: u8) -> bool {
+    if PANIC_CPU.compare_exchange(NO_PANIC, apic_id as u64, Ordering::AcqRel, Ordering::Acquire).is_err() {
+        return false;
+    }
+    // Without a per-CPU block the local APIC may not be set up, and no
+    // other core has been started
+    if percpu::from_msr().is_none() {
+        return true;
+    }
+    // The vector is ignored for NMIs
+    GLOBAL_APIC.read().send_ipi(0, 0, IPIDeliveryMode::NMI, IPIDestinationShorthand::AllExcludingSelf);
+
+    // The resource manager may be locked by a core that was just stopped
+    let resman = match GLOBAL_RESMAN.try_read() {
+        Some(resman) => resman,
+        None => return true,
+    };
+
+    let deadline = tsc::read() + tsc::duration_to_ticks(STOP_TIMEOUT);
+    while tsc::read() < deadline {
+        let running = resman.percpu_blocks().any(|b| {
+            b.cpu.apic_id != apic_id && b.cpu.is_running() && !is_stopped(b)
+        });
+        if !running {
+            break;
+        }
+    }
+    true
+}
+
+fn is_stopped(block: &PerCpu) -> bool {
+    block.stopped.try_lock().map_or(false, |s| s.is_some())
+}
+
+/// Prints the state of the cores stopped by `stop_other_cpus`
+pub fn print_stopped_cpus() {
+    let resman = match GLOBAL_RESMAN.try_read() {
+        Some(resman) => resman,
+        None => return,
+    };
+    for block in resman.percpu_blocks() {
+        if let Some(state) = block.stopped.try_lock().and_then(|s| *s) {
+            println!("Core {} stopped: {:x?}", block.cpu.apic_id, state);
+        } else if block.cpu.is_running() && block.cpu.apic_id as u64 != PANIC_CPU.load(Ordering::Acquire) {
+            println!("Core {} did not stop", block.cpu.apic_id);
+        }
+    }
+}
+
+/// Called by the NMI handler. Records the state of the current core and
+/// halts it forever if it is being stopped for a panic.
+pub fn nmi_stop(tf: &TrapFrame) {
+    if PANIC_CPU.load(Ordering::Acquire) == NO_PANIC {
+        return;
+    }
+    if let Some(block) = percpu::from_msr() {
+        *block.stopped.lock() = Some(StopState {
+            context: *tf,
+            cr2: Cr2::read().as_u64(),
+            cr3: Cr3::read().0.start_address().as_u64(),
+        });
+    }
+    loop {
+        x86_64::instructions::interrupts::disable();
+        x86_64::instructions::hlt();
+    }
+}
@@ -1,8 +1,14 @@
 pub mod pic;
 pub mod apic;
+pub mod ioapic;
 pub mod keyboard;
 pub mod pit;
+pub mod tsc;
+pub mod rtc;
 pub mod stdin;
+pub mod percpu;
+pub mod ipi;
+pub mod random;
 
 /// Resource Manager
 pub mod resman;
\ No newline at end of file
@@ -0,0 +1,161 @@
+//! Per-CPU Data
+//!
+//! Every core owns a `PerCpu` block that it reaches through its GS base,
+//! so finding the current core does not need a lock, MMIO or CPUID.
+//!
+//! While the core runs kernel code IA32_GS_BASE points to the block. User
+//! mode runs with the GS base at an empty slot and the block parked in
+//! IA32_KERNEL_GS_BASE, `save_context` and `restore_context` swap the two
+//! with SWAPGS on ring transitions, `x86-interrupt` handlers use `KernelGs`.
+//! The empty slot is also the GS base of a core that has not loaded its
+//! block yet, so `gs:[0]` reads either the block or null.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicU64};
+use spin::Mutex;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::tss::TaskStateSegment;
+use crate::interrupts::irq::IRQ_VECTOR_COUNT;
+use crate::memory::allocator::HeapCache;
+use crate::process::cpu::LocalCPU;
+use crate::sys::ipi::{CallRequest, CpuStat, StopState};
+
+const IA32_GS_BASE: u32 = 0xC000_0101;
+const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+/// GS base of a core without a block, and of user mode
+static NO_BLOCK: u64 = 0;
+
+#[repr(C)]
+pub struct PerCpu {
+    /// Address of this block, read through `gs:[0]`
+    this: *const PerCpu,
+    /// Task state segment of the core, kept while the core is offline so it
+    /// is loaded again when the core comes back
+    pub tss: &'static TaskStateSegment,
+    /// Scheduler state of the core, only touched with the scheduler lock held
+    pub cpu: LocalCPU,
+    /// Small blocks freed on this core, handed out without the heap lock
+    pub heap_cache: HeapCache,
+    /// Device interrupts handled on this core, indexed from `IRQ_VECTOR_BASE`
+    pub irq_counts: [AtomicU64; IRQ_VECTOR_COUNT],
+    /// A TLB shootdown waits for this core to flush, see `memory::tlb`
+    pub tlb_pending: AtomicBool,
+    /// Requests of `smp_call_function` waiting to run on this core
+    pub call_queue: Mutex<VecDeque<Arc<CallRequest>>>,
+    /// Set by the panic NMI before the core halts
+    pub stopped: Mutex<Option<StopState>>,
+    /// Filled in on this core for `ipi::cpu_stat`
+    pub stat: Mutex<Option<CpuStat>>,
+}
+
+// Shared state is atomic or locked, the heap cache is only touched by the
+// owning core
+unsafe impl Send for PerCpu {}
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    /// Allocates the block of a core. The block is never freed.
+    pub fn new(apic_id: u8, proc_id: u8, tss: &'static TaskStateSegment) -> &'static PerCpu {
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        let block = Box::leak(Box::new(PerCpu {
+            this: core::ptr::null(),
+            tss,
+            cpu: LocalCPU::new(apic_id, proc_id),
+            heap_cache: HeapCache::new(),
+            irq_counts: [ZERO; IRQ_VECTOR_COUNT],
+            tlb_pending: AtomicBool::new(false),
+            call_queue: Mutex::new(VecDeque::new()),
+            stopped: Mutex::new(None),
+            stat: Mutex::new(None),
+        }));
+        block.this = block as *const PerCpu;
+        block
+    }
+
+    /// Makes this block the current core's.
+    ///
+    /// # Safety
+    /// Must be called in kernel mode on the core that owns the block.
+    pub unsafe fn load(&'static self) {
+        Msr::new(IA32_GS_BASE).write(self.this as u64);
+        Msr::new(IA32_KERNEL_GS_BASE).write(&NO_BLOCK as *const u64 as u64);
+    }
+}
+
+/// Points both GS bases at the empty slot, so `try_current` returns `None`
+/// until the core loads its block.
+///
+/// # Safety
+/// Must be the first thing a core does in the kernel, before it takes any
+/// lock or allocates.
+pub unsafe fn unload() {
+    let empty = &NO_BLOCK as *const u64 as u64;
+    Msr::new(IA32_GS_BASE).write(empty);
+    Msr::new(IA32_KERNEL_GS_BASE).write(empty);
+}
+
+/// The block of the current core.
+///
+/// Panics before the block is loaded and in `x86-interrupt` handlers
+/// entered from user mode that do not hold a `KernelGs`.
+pub fn current() -> &'static PerCpu {
+    try_current().expect("per-CPU block not loaded")
+}
+
+/// The block of the current core, or `None` before it is loaded
+pub fn try_current() -> Option<&'static PerCpu> {
+    let block: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) block, options(nostack, readonly, preserves_flags));
+        block.as_ref()
+    }
+}
+
+/// The block of the current core regardless of the SWAPGS state, at the
+/// cost of reading the MSRs. For the NMI and panic paths, which may run
+/// anywhere.
+pub fn from_msr() -> Option<&'static PerCpu> {
+    let slot = |msr| unsafe {
+        match Msr::new(msr).read() {
+            0 => 0,
+            base => *(base as *const u64),
+        }
+    };
+    let block = match slot(IA32_GS_BASE) {
+        0 => slot(IA32_KERNEL_GS_BASE),
+        block => block,
+    };
+    unsafe { (block as *const PerCpu).as_ref() }
+}
+
+/// Loads the kernel GS base for an `x86-interrupt` handler entered from user
+/// mode until it is dropped, see `interrupts::TrapGuard`. The assembly stubs
+/// swap it themselves.
+pub struct KernelGs(bool);
+
+impl KernelGs {
+    pub fn enter(stack_frame: &InterruptStackFrame) -> KernelGs {
+        let user = stack_frame.code_segment & 0b11 == 0b11;
+        if user {
+            unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+        }
+        KernelGs(user)
+    }
+}
+
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        if self.0 {
+            unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+        }
+    }
+}
+
+/// Local APIC ID of the current core
+pub fn apic_id() -> u8 {
+    current().cpu.apic_id
+}
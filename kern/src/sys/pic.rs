@@ -114,6 +114,14 @@ impl ChainedPics {
         wait();
     }
 
+    /// Masks every line, interrupts are then routed by the I/O APIC
+    pub fn disable(&mut self) {
+        unsafe {
+            self.pics[0].data.write(0xFF);
+            self.pics[1].data.write(0xFF);
+        }
+    }
+
     pub fn handles_interrupt(&self, int_id: u8) -> bool {
         self.pics.iter().any(|p| p.handles_interrupt(int_id))
     }
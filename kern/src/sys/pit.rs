@@ -11,7 +11,6 @@ const PIT_CH2: u16 = 0x42;
 const PIT_CMD: u16 = 0x43;
 
 pub static GLOBAL_PIT: RwLock<PIT> = RwLock::new(PIT::new());
-pub static SYSTEM_TIME: AtomicU64 = AtomicU64::new(0);
 
 #[repr(u8)]
 #[derive(Clone, Copy, Debug)]
@@ -103,12 +102,10 @@ impl PIT {
         self.setup(crate::config::SYSTEM_TIME_RESOLUTION, PITMode::SquareWave);
     }
 
-    pub fn interrupt(&self) {
-        SYSTEM_TIME.fetch_add(self.interval.as_millis() as u64, Ordering::Acquire);
-    }
-
+    /// System time is kept by the TSC clocksource, the PIT is only used
+    /// to calibrate it and for short spin waits.
     pub fn current_time() -> Duration {
-        Duration::from_millis(SYSTEM_TIME.load(Ordering::Relaxed))
+        crate::sys::tsc::current_time()
     }
 }
 
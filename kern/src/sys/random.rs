@@ -0,0 +1,61 @@
+//! Random Numbers
+//!
+//! Taken from RDRAND where the CPU has it. Otherwise a xorshift64* generator
+//! seeded from the TSC is used, which is fine for test data but must not be
+//! used for secrets.
+
+use core::arch::x86_64::{__cpuid, _rdrand64_step};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// RDRAND may fail while the entropy source refills, Intel recommends 10
+/// attempts
+const RDRAND_RETRIES: usize = 10;
+
+/// State of the fallback generator, zero until seeded
+static STATE: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref HAS_RDRAND: bool = unsafe { __cpuid(1) }.ecx & (1 << 30) != 0;
+}
+
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand() -> Option<u64> {
+    let mut value = 0;
+    for _ in 0..RDRAND_RETRIES {
+        if _rdrand64_step(&mut value) == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn xorshift() -> u64 {
+    let mut current = STATE.load(Ordering::Relaxed);
+    loop {
+        let mut x = if current == 0 { crate::sys::tsc::read() | 1 } else { current };
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        match STATE.compare_exchange_weak(current, x, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return x.wrapping_mul(0x2545_f491_4f6c_dd1d),
+            Err(actual) => current = actual,
+        }
+    }
+}
+
+pub fn next_u64() -> u64 {
+    if *HAS_RDRAND {
+        if let Some(value) = unsafe { rdrand() } {
+            return value;
+        }
+    }
+    xorshift()
+}
+
+/// Fills `buf` with random bytes
+pub fn fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        let bytes = next_u64().to_le_bytes();
+        chunk.copy_from_slice(&bytes[..chunk.len()]);
+    }
+}
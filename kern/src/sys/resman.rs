@@ -6,6 +6,8 @@ use spin::RwLock;
 use crate::sys::apic::GLOBAL_APIC;
 use alloc::boxed::Box;
 use x86_64::VirtAddr;
+use crate::sys::percpu::PerCpu;
+use x86_64::structures::tss::TaskStateSegment;
 
 pub static GLOBAL_RESMAN: RwLock<ResourceManager> = RwLock::new(ResourceManager::uninitialized());
 
@@ -13,6 +15,7 @@ pub struct ResourceManager {
     // Per-Core Resources
     gdts: Option<HashMap<u8, GDTInfo>>,
     tsses: Option<HashMap<u8, TSSInfo>>,
+    percpu: Option<HashMap<u8, &'static PerCpu>>,
     // Global Resource
 }
 
@@ -20,7 +23,8 @@ impl ResourceManager {
     const fn uninitialized() -> Self {
         Self {
             gdts: None,
-            tsses: None
+            tsses: None,
+            percpu: None,
         }
     }
 
@@ -30,18 +34,63 @@ impl ResourceManager {
 
         // Initialize TSSes
         self.tsses = Some(HashMap::new());
+
+        // Initialize Per-CPU blocks
+        self.percpu = Some(HashMap::new());
+    }
+
+    /// The TSS of a core, created on first use. TSSes are never dropped,
+    /// the per-CPU block of the core refers to it.
+    fn tss(&mut self, lapic_id: u8) -> &'static TaskStateSegment {
+        self.tsses.as_mut().unwrap()
+            .entry(lapic_id)
+            .or_insert_with(crate::arch::x86_64::descriptor_table::create_tss)
+            .get_tss_ptr()
     }
 
+    /// Creates the GDT of a core, pointing at the TSS it had before if the
+    /// core was registered already
     pub fn register_core(&mut self, lapic_id: u8) {
         debug!("[RESMAN] Registering Core {}", lapic_id);
-        let tss = crate::arch::x86_64::descriptor_table::create_tss();
-        let gdt = crate::arch::x86_64::descriptor_table::create_gdt(tss.get_tss_ptr());
+        let tss = self.tss(lapic_id);
+        let gdt = crate::arch::x86_64::descriptor_table::create_gdt(tss);
 
         self.gdts.as_mut().unwrap().insert(lapic_id, gdt);
-        self.tsses.as_mut().unwrap().insert(lapic_id, tss);
+    }
+
+    /// Drops the GDT of a core. The core must not be executing, e.g. it was
+    /// put back into INIT by `cpu_down`.
+    pub fn unregister_core(&mut self, lapic_id: u8) {
+        debug!("[RESMAN] Unregistering Core {}", lapic_id);
+        self.gdts.as_mut().unwrap().remove(&lapic_id);
     }
 
     pub fn get_gdt(&self, lapic_id: u8) -> &GDTInfo {
         self.gdts.as_ref().unwrap().get(&lapic_id).unwrap()
     }
+
+    /// Allocates the per-CPU block of a core, or returns the existing one if
+    /// the core was registered before. `proc_id` is the ACPI processor UID.
+    pub fn register_percpu(&mut self, lapic_id: u8, proc_id: u8) -> &'static PerCpu {
+        if let Some(block) = self.try_get_percpu(lapic_id) {
+            return block;
+        }
+        let tss = self.tss(lapic_id);
+        let block: &'static PerCpu = PerCpu::new(lapic_id, proc_id, tss);
+        self.percpu.as_mut().unwrap().insert(lapic_id, block);
+        block
+    }
+
+    pub fn get_percpu(&self, lapic_id: u8) -> &'static PerCpu {
+        self.percpu.as_ref().unwrap().get(&lapic_id).expect("no per-cpu block")
+    }
+
+    pub fn try_get_percpu(&self, lapic_id: u8) -> Option<&'static PerCpu> {
+        self.percpu.as_ref().unwrap().get(&lapic_id).copied()
+    }
+
+    /// Per-CPU blocks of all registered cores
+    pub fn percpu_blocks(&self) -> impl Iterator<Item=&'static PerCpu> + '_ {
+        self.percpu.as_ref().unwrap().values().copied()
+    }
 }
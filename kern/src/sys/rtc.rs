@@ -0,0 +1,130 @@
+//! CMOS Real-Time Clock
+//!
+//! The date and time are read once during boot and the wall clock is kept
+//! by the TSC from there on. The RTC is assumed to run in UTC and in the
+//! 21st century, the century register is not in the same place everywhere.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use crate::sys::tsc;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+/// Status A: the registers are being updated and may be inconsistent
+const UPDATE_IN_PROGRESS: u8 = 0x80;
+/// Status B: hours run from 0 to 23 instead of 1 to 12
+const HOURS_24: u8 = 0x02;
+/// Status B: values are binary instead of BCD
+const BINARY: u8 = 0x04;
+/// Set in the hours register for PM in 12 hour mode
+const HOUR_PM: u8 = 0x80;
+
+/// Seconds since 1970-01-01 at time zero of the TSC, zero until `init`
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+fn read_register(reg: u8) -> u8 {
+    let mut address: Port<u8> = Port::new(CMOS_ADDRESS);
+    let mut data: Port<u8> = Port::new(CMOS_DATA);
+    unsafe {
+        address.write(reg);
+        data.read()
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+struct DateTime {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+}
+
+fn read_date_time() -> DateTime {
+    while read_register(REG_STATUS_A) & UPDATE_IN_PROGRESS != 0 {}
+    DateTime {
+        seconds: read_register(REG_SECONDS),
+        minutes: read_register(REG_MINUTES),
+        hours: read_register(REG_HOURS),
+        day: read_register(REG_DAY),
+        month: read_register(REG_MONTH),
+        year: read_register(REG_YEAR),
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+/// Days from 1970-01-01 to the given date of the proleptic Gregorian
+/// calendar, after Howard Hinnant's `days_from_civil`
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Reads the RTC and sets the wall clock, the TSC has to be calibrated
+pub fn init() {
+    let (mut time, status) = without_interrupts(|| {
+        // Read until two reads agree, an update may start in between
+        let mut time = read_date_time();
+        loop {
+            let again = read_date_time();
+            if again == time {
+                break;
+            }
+            time = again;
+        }
+        (time, read_register(REG_STATUS_B))
+    });
+    let elapsed = tsc::current_time().as_secs();
+
+    let pm = time.hours & HOUR_PM != 0;
+    time.hours &= !HOUR_PM;
+    if status & BINARY == 0 {
+        time.seconds = from_bcd(time.seconds);
+        time.minutes = from_bcd(time.minutes);
+        time.hours = from_bcd(time.hours);
+        time.day = from_bcd(time.day);
+        time.month = from_bcd(time.month);
+        time.year = from_bcd(time.year);
+    }
+    if status & HOURS_24 == 0 {
+        time.hours %= 12;
+        if pm {
+            time.hours += 12;
+        }
+    }
+
+    if time.month < 1 || time.month > 12 || time.day < 1 || time.day > 31 || time.hours > 23 {
+        warn!("[RTC] Invalid date {:02}-{:02}-{:02}, the wall clock is not set", time.year, time.month, time.day);
+        return;
+    }
+    let days = days_from_civil(2000 + time.year as i64, time.month as u32, time.day as u32);
+    let now = days * 86_400 + time.hours as i64 * 3600 + time.minutes as i64 * 60 + time.seconds as i64;
+    BOOT_TIME.store((now as u64).saturating_sub(elapsed), Ordering::Release);
+    info!("[RTC] 20{:02}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+          time.year, time.month, time.day, time.hours, time.minutes, time.seconds);
+}
+
+/// Seconds since 1970-01-01 UTC, counted from boot if the RTC was not read
+pub fn now() -> u64 {
+    BOOT_TIME.load(Ordering::Relaxed) + tsc::current_time().as_secs()
+}
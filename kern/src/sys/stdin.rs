@@ -19,7 +19,13 @@ pub struct StandardInput {
 
 impl StandardInput {
     pub fn insert(&self, char: u8) {
+        // Ctrl-C interrupts the foreground process, if there is one
+        if char == 3 && crate::process::signal::interrupt_foreground() {
+            return;
+        }
         self.queue.enqueue(char);
+        // Readers of the console are parked until there is input
+        crate::SCHEDULER.notify();
     }
 
     pub fn pop(&self) -> Option<u8> {
@@ -27,7 +33,7 @@ impl StandardInput {
     }
 
     pub fn has_data(&self) -> bool {
-        self.queue.is_empty()
+        !self.queue.is_empty()
     }
 
     pub fn blocking_get_char(&self) -> u8 {
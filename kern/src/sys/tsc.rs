@@ -0,0 +1,63 @@
+//! TSC Clocksource
+//!
+//! System time is derived from the Time Stamp Counter, calibrated against the
+//! PIT once during boot. Reading the clock does not depend on counting timer
+//! interrupts, so a core is free to skip ticks while it is idle.
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use crate::sys::pit::spin_wait;
+
+const CALIBRATION_DURATION: Duration = Duration::from_millis(50);
+
+/// TSC ticks per millisecond, zero until calibrated
+static TSC_PER_MS: AtomicU64 = AtomicU64::new(0);
+/// TSC value that corresponds to time zero
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Reads the raw Time Stamp Counter
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Invariant TSC runs at a constant rate in all ACPI P-, C- and T-states
+pub fn is_invariant() -> bool {
+    let max_ext_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_ext_leaf >= 0x8000_0007 && (unsafe { __cpuid(0x8000_0007) }.edx >> 8) & 0x1 == 1
+}
+
+/// Measures the TSC frequency against the PIT and marks the current
+/// TSC value as time zero.
+pub fn calibrate() {
+    if !is_invariant() {
+        warn!("[TSC] TSC is not invariant, system time may drift");
+    }
+    let start = read();
+    spin_wait(CALIBRATION_DURATION);
+    let diff = read() - start;
+    let per_ms = diff / CALIBRATION_DURATION.as_millis() as u64;
+    TSC_BASE.store(start, Ordering::Release);
+    TSC_PER_MS.store(per_ms, Ordering::Release);
+    debug!("[TSC] {} ticks in {:?}, {} kHz", diff, CALIBRATION_DURATION, per_ms);
+}
+
+/// Converts a duration to a number of TSC ticks
+pub fn duration_to_ticks(d: Duration) -> u64 {
+    let per_ms = TSC_PER_MS.load(Ordering::Relaxed) as u128;
+    (d.as_nanos() * per_ms / 1_000_000) as u64
+}
+
+/// Converts a number of TSC ticks to a duration
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let per_ms = TSC_PER_MS.load(Ordering::Relaxed) as u128;
+    if per_ms == 0 {
+        return Duration::from_nanos(0);
+    }
+    Duration::from_nanos((ticks as u128 * 1_000_000 / per_ms) as u64)
+}
+
+/// Time elapsed since calibration
+pub fn current_time() -> Duration {
+    ticks_to_duration(read().saturating_sub(TSC_BASE.load(Ordering::Relaxed)))
+}
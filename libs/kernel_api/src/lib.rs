@@ -2,6 +2,7 @@
 #![no_std]
 
 pub mod syscall;
+pub mod sync;
 
 pub type OsResult<T> = core::result::Result<T, OsError>;
 
@@ -16,13 +17,24 @@ pub enum OsError {
     NoAccess = 40,
     BadAddress = 50,
     FileExists = 60,
+    NotADirectory = 61,
+    IsADirectory = 62,
+    DirectoryNotEmpty = 63,
+    FilesystemLoop = 64,
+    NoSpace = 65,
+    ReadOnly = 66,
+    NameTooLong = 67,
+    Busy = 68,
     InvalidArgument = 70,
+    WouldBlock = 80,
+    BadDescriptor = 90,
 
     IoError = 101,
     IoErrorEof = 102,
     IoErrorInvalidData = 103,
     IoErrorInvalidInput = 104,
     IoErrorTimedOut = 105,
+    IoErrorBrokenPipe = 106,
 
     InvalidSocket = 200,
     SocketAlreadyOpen = 201,
@@ -40,12 +52,24 @@ impl core::convert::From<u64> for OsError {
             40 => OsError::NoAccess,
             50 => OsError::BadAddress,
             60 => OsError::FileExists,
+            61 => OsError::NotADirectory,
+            62 => OsError::IsADirectory,
+            63 => OsError::DirectoryNotEmpty,
+            64 => OsError::FilesystemLoop,
+            65 => OsError::NoSpace,
+            66 => OsError::ReadOnly,
+            67 => OsError::NameTooLong,
+            68 => OsError::Busy,
             70 => OsError::InvalidArgument,
+            80 => OsError::WouldBlock,
+            90 => OsError::BadDescriptor,
 
             101 => OsError::IoError,
             102 => OsError::IoErrorEof,
             103 => OsError::IoErrorInvalidData,
             104 => OsError::IoErrorInvalidInput,
+            105 => OsError::IoErrorTimedOut,
+            106 => OsError::IoErrorBrokenPipe,
 
             200 => OsError::InvalidSocket,
             201 => OsError::SocketAlreadyOpen,
@@ -74,3 +98,167 @@ pub const NR_SLEEP: u64 = 1;
 // pub const NR_EXIT: usize = 3;
 // pub const NR_WRITE: usize = 4;
 // pub const NR_GETPID: usize = 5;
+pub const NR_KILL: u64 = 6;
+pub const NR_SIGACTION: u64 = 7;
+pub const NR_SIGRETURN: u64 = 8;
+pub const NR_SCHED_SETAFFINITY: u64 = 9;
+pub const NR_SCHED_GETAFFINITY: u64 = 10;
+pub const NR_FUTEX_WAIT: u64 = 11;
+pub const NR_FUTEX_WAKE: u64 = 12;
+pub const NR_PIPE: u64 = 13;
+pub const NR_READ: u64 = 14;
+pub const NR_WRITE: u64 = 15;
+pub const NR_CLOSE: u64 = 16;
+pub const NR_CHANNEL_CREATE: u64 = 17;
+pub const NR_CHANNEL_SEND: u64 = 18;
+pub const NR_CHANNEL_RECV: u64 = 19;
+pub const NR_SHM_CREATE: u64 = 20;
+pub const NR_SHM_MAP: u64 = 21;
+pub const NR_SHM_UNMAP: u64 = 22;
+pub const NR_DUP: u64 = 23;
+pub const NR_DUP2: u64 = 24;
+pub const NR_FD_GETFLAGS: u64 = 25;
+pub const NR_FD_SETFLAGS: u64 = 26;
+pub const NR_OPEN: u64 = 27;
+pub const NR_SEEK: u64 = 28;
+pub const NR_STAT: u64 = 29;
+pub const NR_FSTAT: u64 = 30;
+pub const NR_READDIR: u64 = 31;
+pub const NR_MKDIR: u64 = 32;
+pub const NR_UNLINK: u64 = 33;
+pub const NR_IOCTL: u64 = 34;
+
+/// Timeout argument of blocking syscalls waiting forever
+pub const NO_TIMEOUT: u64 = u64::MAX;
+
+/// Largest channel message in bytes
+pub const MAX_MESSAGE_SIZE: usize = 4096;
+/// Most handles a channel message can carry
+pub const MAX_MESSAGE_HANDLES: usize = 8;
+
+/// Descriptors of the console in a new process
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+/// Descriptor flag, closed when the process executes a new program
+pub const FD_CLOEXEC: u64 = 1 << 0;
+
+/// Largest shared memory object in bytes
+pub const MAX_SHM_SIZE: usize = 16 << 20;
+/// Shared memory protection, mappings are always readable
+pub const PROT_READ: u64 = 1 << 0;
+pub const PROT_WRITE: u64 = 1 << 1;
+
+/// `open` flags
+pub const O_READ: u64 = 1 << 0;
+pub const O_WRITE: u64 = 1 << 1;
+/// Creates a regular file if the path does not exist
+pub const O_CREATE: u64 = 1 << 2;
+/// With `O_CREATE`, fails if the path exists
+pub const O_EXCL: u64 = 1 << 3;
+pub const O_TRUNC: u64 = 1 << 4;
+/// Every write goes to the end of the file
+pub const O_APPEND: u64 = 1 << 5;
+/// Fails unless the path is a directory
+pub const O_DIRECTORY: u64 = 1 << 6;
+/// Does not follow a symlink in the last component
+pub const O_NOFOLLOW: u64 = 1 << 7;
+/// Sets `FD_CLOEXEC` on the new descriptor
+pub const O_CLOEXEC: u64 = 1 << 8;
+
+/// `seek` origins
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+/// `ioctl` requests for block devices
+/// Sector size in bytes
+pub const IOCTL_BLK_SECTOR_SIZE: u64 = 0x100;
+/// Number of sectors, fails if the device does not know
+pub const IOCTL_BLK_SECTOR_COUNT: u64 = 0x101;
+/// `ioctl` requests for serial ports
+/// Sets the baud rate to the argument
+pub const IOCTL_TTY_SET_BAUDRATE: u64 = 0x200;
+
+/// Longest name of a directory entry in bytes
+pub const MAX_NAME_LEN: usize = 255;
+/// Longest path passed to a syscall in bytes
+pub const MAX_PATH_LEN: usize = 4096;
+
+#[repr(u64)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FileType {
+    Unknown = 0,
+    Regular = 1,
+    Directory = 2,
+    Symlink = 3,
+    CharDevice = 4,
+    BlockDevice = 5,
+    Fifo = 6,
+}
+
+impl From<u64> for FileType {
+    fn from(e: u64) -> Self {
+        match e {
+            1 => FileType::Regular,
+            2 => FileType::Directory,
+            3 => FileType::Symlink,
+            4 => FileType::CharDevice,
+            5 => FileType::BlockDevice,
+            6 => FileType::Fifo,
+            _ => FileType::Unknown,
+        }
+    }
+}
+
+/// Result of `stat` and `fstat`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Stat {
+    /// Inode number, unique within the file system
+    pub ino: u64,
+    /// A `FileType`
+    pub kind: u64,
+    pub size: u64,
+    pub nlink: u64,
+    /// Permission bits
+    pub mode: u64,
+}
+
+/// Directory entry returned by `readdir`
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct DirEnt {
+    pub ino: u64,
+    /// A `FileType`
+    pub kind: u64,
+    pub name_len: u64,
+    pub name: [u8; MAX_NAME_LEN],
+}
+
+impl DirEnt {
+    pub const fn new() -> DirEnt {
+        DirEnt {
+            ino: 0,
+            kind: 0,
+            name_len: 0,
+            name: [0; MAX_NAME_LEN],
+        }
+    }
+
+    pub fn name(&self) -> &[u8] {
+        &self.name[..core::cmp::min(self.name_len as usize, MAX_NAME_LEN)]
+    }
+}
+
+// Signals
+pub const SIGINT: u64 = 2;
+pub const SIGKILL: u64 = 9;
+pub const SIGSEGV: u64 = 11;
+pub const SIGTERM: u64 = 15;
+
+/// `sigaction` handler value restoring the default action
+pub const SIG_DFL: u64 = 0;
+/// `sigaction` handler value ignoring the signal
+pub const SIG_IGN: u64 = 1;
@@ -1,3 +1,4 @@
+use core::sync::atomic::AtomicU32;
 use core::time::Duration;
 
 use crate::*;
@@ -41,6 +42,540 @@ pub fn sleep(span: Duration) -> OsResult<Duration> {
     err_or!(ecode, Duration::from_millis(elapsed_ms))
 }
 
+/// Sends signal `sig` to process `pid`. Kernel processes only take `SIGKILL`,
+/// other signals fail with `InvalidArgument`.
+pub fn kill(pid: u64, sig: u64) -> OsResult<()> {
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_KILL => ecode,
+             in("rdi") pid,
+             in("rsi") sig,
+             lateout("rdx") _,
+             );
+    }
+
+    err_or!(ecode, ())
+}
+
+/// Installs `handler` for signal `sig`. `handler` is either `SIG_DFL`,
+/// `SIG_IGN` or the address of an `extern "C" fn(sig: u64)`.
+///
+/// Returns the previously installed handler.
+pub fn sigaction(sig: u64, handler: u64) -> OsResult<u64> {
+    let mut ecode: u64;
+    let mut old_handler: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_SIGACTION => ecode,
+             in("rdi") sig,
+             in("rsi") handler,
+             inout("rdx") sigreturn_trampoline as u64 => old_handler,
+             );
+    }
+
+    err_or!(ecode, old_handler)
+}
+
+/// Returns from a signal handler, restoring the context it interrupted.
+pub fn sigreturn() -> ! {
+    unsafe {
+        asm!("int 0x80",
+             in("rax") NR_SIGRETURN,
+             options(noreturn)
+             );
+    }
+}
+
+/// Restricts process `pid` (0 for the caller) to the CPUs in `mask`, a
+/// bitmap where bit `n` is the CPU with local APIC ID `n`.
+pub fn sched_setaffinity(pid: u64, mask: &[u8]) -> OsResult<()> {
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_SCHED_SETAFFINITY => ecode,
+             in("rdi") pid,
+             in("rsi") mask.as_ptr(),
+             inout("rdx") mask.len() => _,
+             );
+    }
+
+    err_or!(ecode, ())
+}
+
+/// Writes the CPU affinity bitmap of process `pid` (0 for the caller) into
+/// `mask`. Returns the number of bytes written.
+pub fn sched_getaffinity(pid: u64, mask: &mut [u8]) -> OsResult<usize> {
+    let mut ecode: u64;
+    let mut len: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_SCHED_GETAFFINITY => ecode,
+             in("rdi") pid,
+             in("rsi") mask.as_mut_ptr(),
+             inout("rdx") mask.len() => len,
+             );
+    }
+
+    err_or!(ecode, len as usize)
+}
+
+/// Blocks until `futex_wake` is called on `word`, unless `word` no longer
+/// holds `expected`, in which case this fails with `WouldBlock` right away.
+/// Fails with `IoErrorTimedOut` once `timeout` has passed. Can return
+/// without a wake, callers have to check `word` again.
+pub fn futex_wait(word: &AtomicU32, expected: u32, timeout: Option<Duration>) -> OsResult<()> {
+    let ms = match timeout {
+        Some(timeout) => core::cmp::min(timeout.as_millis(), (NO_TIMEOUT - 1) as u128) as u64,
+        None => NO_TIMEOUT,
+    };
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_FUTEX_WAIT => ecode,
+             in("rdi") word as *const AtomicU32,
+             in("rsi") expected as u64,
+             inout("rdx") ms => _,
+             );
+    }
+
+    err_or!(ecode, ())
+}
+
+/// Wakes up to `count` processes blocked in `futex_wait` on `word`, also
+/// those that map it at another address. Returns how many were woken.
+pub fn futex_wake(word: &AtomicU32, count: usize) -> OsResult<usize> {
+    let mut ecode: u64;
+    let mut woken: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_FUTEX_WAKE => ecode,
+             in("rdi") word as *const AtomicU32,
+             in("rsi") count as u64,
+             lateout("rdx") woken,
+             );
+    }
+
+    err_or!(ecode, woken as usize)
+}
+
+/// Creates a pipe and returns its read end and write end
+pub fn pipe() -> OsResult<(u64, u64)> {
+    let mut fds = [0u64; 2];
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_PIPE => ecode,
+             in("rdi") fds.as_mut_ptr(),
+             lateout("rdx") _,
+             );
+    }
+
+    err_or!(ecode, (fds[0], fds[1]))
+}
+
+/// Reads into `buf` from `fd`, blocking until data is available. Returns
+/// the number of bytes read, 0 at the end of the file.
+pub fn read(fd: u64, buf: &mut [u8]) -> OsResult<usize> {
+    let mut ecode: u64;
+    let mut count: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_READ => ecode,
+             in("rdi") fd,
+             in("rsi") buf.as_mut_ptr(),
+             inout("rdx") buf.len() => count,
+             );
+    }
+
+    err_or!(ecode, count as usize)
+}
+
+/// Writes `buf` to `fd`, blocking until some of it fits. Returns the number
+/// of bytes written, which can be less than `buf.len()`.
+pub fn write(fd: u64, buf: &[u8]) -> OsResult<usize> {
+    let mut ecode: u64;
+    let mut count: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_WRITE => ecode,
+             in("rdi") fd,
+             in("rsi") buf.as_ptr(),
+             inout("rdx") buf.len() => count,
+             );
+    }
+
+    err_or!(ecode, count as usize)
+}
+
+/// Writes all of `buf` to `fd`
+pub fn write_all(fd: u64, mut buf: &[u8]) -> OsResult<()> {
+    while !buf.is_empty() {
+        let count = write(fd, buf)?;
+        buf = &buf[count..];
+    }
+    Ok(())
+}
+
+pub fn close(fd: u64) -> OsResult<()> {
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_CLOSE => ecode,
+             in("rdi") fd,
+             lateout("rdx") _,
+             );
+    }
+
+    err_or!(ecode, ())
+}
+
+/// Opens what `fd` refers to again at the lowest free descriptor
+pub fn dup(fd: u64) -> OsResult<u64> {
+    let mut ecode: u64;
+    let mut new: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_DUP => ecode,
+             in("rdi") fd,
+             lateout("rdx") new,
+             );
+    }
+
+    err_or!(ecode, new)
+}
+
+/// Makes `new` refer to what `old` refers to, closing `new` first if it is
+/// open
+pub fn dup2(old: u64, new: u64) -> OsResult<u64> {
+    let mut ecode: u64;
+    let mut fd: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_DUP2 => ecode,
+             in("rdi") old,
+             in("rsi") new,
+             lateout("rdx") fd,
+             );
+    }
+
+    err_or!(ecode, fd)
+}
+
+/// Descriptor flags of `fd`, see `FD_CLOEXEC`
+pub fn fd_get_flags(fd: u64) -> OsResult<u64> {
+    let mut ecode: u64;
+    let mut flags: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_FD_GETFLAGS => ecode,
+             in("rdi") fd,
+             lateout("rdx") flags,
+             );
+    }
+
+    err_or!(ecode, flags)
+}
+
+pub fn fd_set_flags(fd: u64, flags: u64) -> OsResult<()> {
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_FD_SETFLAGS => ecode,
+             in("rdi") fd,
+             in("rsi") flags,
+             lateout("rdx") _,
+             );
+    }
+
+    err_or!(ecode, ())
+}
+
+/// Opens `path` with the `O_*` flags in `flags` and returns its descriptor.
+/// Relative paths start at the root directory.
+pub fn open(path: &str, flags: u64) -> OsResult<u64> {
+    let mut ecode: u64;
+    let mut fd: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_OPEN => ecode,
+             in("rdi") path.as_ptr(),
+             in("rsi") path.len(),
+             inout("rdx") flags => fd,
+             );
+    }
+
+    err_or!(ecode, fd)
+}
+
+/// Moves the offset of `fd` to `offset` from `whence`, one of the `SEEK_*`
+/// origins, and returns the new offset
+pub fn seek(fd: u64, offset: i64, whence: u64) -> OsResult<u64> {
+    let mut ecode: u64;
+    let mut pos: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_SEEK => ecode,
+             in("rdi") fd,
+             in("rsi") offset,
+             inout("rdx") whence => pos,
+             );
+    }
+
+    err_or!(ecode, pos)
+}
+
+/// Metadata of `path`, following symlinks
+pub fn stat(path: &str) -> OsResult<Stat> {
+    let mut stat = Stat::default();
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_STAT => ecode,
+             in("rdi") path.as_ptr(),
+             in("rsi") path.len(),
+             inout("rdx") &mut stat as *mut Stat => _,
+             );
+    }
+
+    err_or!(ecode, stat)
+}
+
+/// Metadata of the open file `fd`
+pub fn fstat(fd: u64) -> OsResult<Stat> {
+    let mut stat = Stat::default();
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_FSTAT => ecode,
+             in("rdi") fd,
+             in("rsi") &mut stat as *mut Stat,
+             lateout("rdx") _,
+             );
+    }
+
+    err_or!(ecode, stat)
+}
+
+/// Reads the next entry of the open directory `fd`, `None` after the last
+pub fn readdir(fd: u64) -> OsResult<Option<DirEnt>> {
+    let mut entry = DirEnt::new();
+    let mut ecode: u64;
+    let mut found: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_READDIR => ecode,
+             in("rdi") fd,
+             in("rsi") &mut entry as *mut DirEnt,
+             lateout("rdx") found,
+             );
+    }
+
+    err_or!(ecode, if found != 0 { Some(entry) } else { None })
+}
+
+pub fn mkdir(path: &str) -> OsResult<()> {
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_MKDIR => ecode,
+             in("rdi") path.as_ptr(),
+             in("rsi") path.len(),
+             lateout("rdx") _,
+             );
+    }
+
+    err_or!(ecode, ())
+}
+
+/// Removes `path`, a file, symlink or empty directory. Open descriptors of
+/// a removed file keep working.
+pub fn unlink(path: &str) -> OsResult<()> {
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_UNLINK => ecode,
+             in("rdi") path.as_ptr(),
+             in("rsi") path.len(),
+             lateout("rdx") _,
+             );
+    }
+
+    err_or!(ecode, ())
+}
+
+/// Sends the device specific request `request`, one of the `IOCTL_*`
+/// constants, with argument `arg` to the device open as `fd`
+pub fn ioctl(fd: u64, request: u64, arg: u64) -> OsResult<u64> {
+    let mut ecode: u64;
+    let mut result: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_IOCTL => ecode,
+             in("rdi") fd,
+             in("rsi") request,
+             inout("rdx") arg => result,
+             );
+    }
+
+    err_or!(ecode, result)
+}
+
+/// Creates a channel and returns its two endpoints
+pub fn channel_create() -> OsResult<(u64, u64)> {
+    let mut fds = [0u64; 2];
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_CHANNEL_CREATE => ecode,
+             in("rdi") fds.as_mut_ptr(),
+             lateout("rdx") _,
+             );
+    }
+
+    err_or!(ecode, (fds[0], fds[1]))
+}
+
+/// Sends `data` and the descriptors in `handles` to the peer of endpoint
+/// `fd`, blocking while its queue is full. The descriptors are closed in
+/// the caller once sent. Fails with `IoErrorBrokenPipe` if the peer is
+/// closed.
+pub fn channel_send(fd: u64, data: &[u8], handles: &[u64]) -> OsResult<()> {
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_CHANNEL_SEND => ecode,
+             in("rdi") fd,
+             in("rsi") data.as_ptr(),
+             inout("rdx") data.len() => _,
+             inout("rcx") handles.as_ptr() => _,
+             in("r8") handles.len(),
+             );
+    }
+
+    err_or!(ecode, ())
+}
+
+/// Receives the next message of endpoint `fd` into `buf` and its handles,
+/// opened as new descriptors, into `handles`. Returns the length of the
+/// data and the number of handles.
+///
+/// Blocks until a message arrives or `timeout` passes, which fails with
+/// `IoErrorTimedOut`. Fails with `IoErrorEof` once the peer is closed and
+/// all messages are received, and with `InvalidArgument` if the message
+/// does not fit, it can then be received with larger buffers.
+pub fn channel_recv(fd: u64, buf: &mut [u8], handles: &mut [u64], timeout: Option<Duration>) -> OsResult<(usize, usize)> {
+    let ms = match timeout {
+        Some(timeout) => core::cmp::min(timeout.as_millis(), (NO_TIMEOUT - 1) as u128) as u64,
+        None => NO_TIMEOUT,
+    };
+    let mut ecode: u64;
+    let mut len: u64;
+    let mut count: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_CHANNEL_RECV => ecode,
+             in("rdi") fd,
+             in("rsi") buf.as_mut_ptr(),
+             inout("rdx") buf.len() => len,
+             inout("rcx") handles.as_mut_ptr() => count,
+             in("r8") handles.len(),
+             in("r9") ms,
+             );
+    }
+
+    err_or!(ecode, (len as usize, count as usize))
+}
+
+/// Creates a zeroed shared memory object of at least `size` bytes and
+/// returns a descriptor for it. The memory lives until the descriptor is
+/// closed and every mapping of it is unmapped. The descriptor does not
+/// restrict who can access the memory, see `shm_map`.
+pub fn shm_create(size: usize) -> OsResult<u64> {
+    let mut ecode: u64;
+    let mut fd: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_SHM_CREATE => ecode,
+             in("rdi") size,
+             lateout("rdx") fd,
+             );
+    }
+
+    err_or!(ecode, fd)
+}
+
+/// Maps the shared memory object `fd` with `prot`, a combination of
+/// `PROT_READ` and `PROT_WRITE`, and returns the address of the mapping.
+/// Every mapping gets its own address, also within one process.
+///
+/// Processes share one address space, so the mapping is not private to the
+/// caller: any process using its address can read it, and write it if
+/// `prot` has `PROT_WRITE`, without holding the descriptor.
+pub fn shm_map(fd: u64, prot: u64) -> OsResult<*mut u8> {
+    let mut ecode: u64;
+    let mut addr: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_SHM_MAP => ecode,
+             in("rdi") fd,
+             in("rsi") prot,
+             lateout("rdx") addr,
+             );
+    }
+
+    err_or!(ecode, addr as *mut u8)
+}
+
+/// Unmaps the mapping at `addr` returned by `shm_map`. The kernel removes
+/// it in the background, it must not be used after this returns.
+pub fn shm_unmap(addr: *mut u8) -> OsResult<()> {
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("int 0x80",
+             inout("rax") NR_SHM_UNMAP => ecode,
+             in("rdi") addr,
+             lateout("rdx") _,
+             );
+    }
+
+    err_or!(ecode, ())
+}
+
+/// Signal handlers return here, the kernel pushes its address as their
+/// return address.
+extern "C" fn sigreturn_trampoline() -> ! {
+    sigreturn()
+}
+
 
 // struct Console;
 //